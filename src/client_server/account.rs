@@ -1,21 +1,28 @@
 use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
+    net::IpAddr,
     sync::Arc,
+    time::Duration,
 };
 
 use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Ruma};
+use crate::{
+    client_server,
+    database::{appservice::Namespaces, audit::AuditAction, jobs::JobKind, DatabaseGuard},
+    pdu::PduBuilder,
+    utils, ConduitResult, Database, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
-        error::ErrorKind,
+        error::{ErrorBody, ErrorKind},
         r0::{
             account::{
                 change_password, deactivate, get_username_availability, register, whoami,
                 ThirdPartyIdRemovalStatus,
             },
             contact::get_contacts,
-            uiaa::{AuthFlow, UiaaInfo},
+            uiaa::{AuthFlow, IncomingAuthData, UiaaInfo},
         },
     },
     events::{
@@ -28,7 +35,8 @@ use ruma::{
     identifiers::RoomName,
     push, RoomAliasId, RoomId, RoomVersionId, UserId,
 };
-use tracing::info;
+use serde::Deserialize;
+use tracing::{info, warn};
 
 use register::RegistrationKind;
 #[cfg(feature = "conduit_bin")]
@@ -44,6 +52,7 @@ const GUEST_NAME_LENGTH: usize = 10;
 /// - The user id is not historical
 /// - The server name of the user id matches this server
 /// - No user or appservice on this server already claimed this username
+/// - The username is not reserved and no existing user has the same username in another case
 ///
 /// Note: This will not reserve the username, so the username might become invalid when trying to register
 #[cfg_attr(
@@ -66,15 +75,7 @@ pub async fn get_register_available_route(
             "Username is invalid.",
         ))?;
 
-    // Check if username is creative enough
-    if db.users.exists(&user_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::UserInUse,
-            "Desired user ID is already taken.",
-        ));
-    }
-
-    // TODO add check for appservice namespaces
+    check_new_user_id(&db, &user_id, false)?;
 
     // If no if check is true we have an username that's available to be used.
     Ok(get_username_availability::Response { available: true }.into())
@@ -92,6 +93,9 @@ pub async fn get_register_available_route(
 /// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
 /// - If type is not guest and no username is given: Always fails after UIAA check
 /// - Creates a new account and populates it with default account data
+/// - If not guest or appservice: Joins the new user to the configured `auto_join_rooms` in the
+/// background, local aliases that don't exist yet are created as public rooms of the @conduit bot
+/// - If not guest or appservice: Sends the `welcome_message` in a direct chat with the @conduit bot
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
 #[cfg_attr(
     feature = "conduit_bin",
@@ -109,6 +113,23 @@ pub async fn register_route(
         ));
     }
 
+    // Too many registrations from the same address need a captcha, or are rejected if no
+    // captcha is configured
+    let retry_after = if body.from_appservice {
+        None
+    } else {
+        registration_retry_after(&db, body.client_ip)?
+    };
+    let captcha_required = retry_after.is_some();
+    if captcha_required && db.globals.recaptcha_keys().is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: retry_after,
+            },
+            "Too many registrations from your address, try again later.",
+        ));
+    }
+
     let is_guest = body.kind == RegistrationKind::Guest;
 
     let mut missing_username = false;
@@ -136,13 +157,7 @@ pub async fn register_route(
         "Username is invalid.",
     ))?;
 
-    // Check if username is creative enough
-    if db.users.exists(&user_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::UserInUse,
-            "Desired user ID is already taken.",
-        ));
-    }
+    check_new_user_id(&db, &user_id, body.from_appservice)?;
 
     // UIAA
     let mut uiaainfo = UiaaInfo {
@@ -155,19 +170,54 @@ pub async fn register_route(
         auth_error: None,
     };
 
+    if captcha_required {
+        let (public_key, _) = db.globals.recaptcha_keys().expect("checked above");
+        uiaainfo.flows = vec![AuthFlow {
+            stages: vec!["m.login.recaptcha".to_owned()],
+        }];
+        uiaainfo.params = serde_json::value::to_raw_value(&serde_json::json!({
+            "m.login.recaptcha": { "public_key": public_key }
+        }))
+        .expect("json is valid raw value");
+    }
+
     if !body.from_appservice {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = db.uiaa.try_auth(
-                &UserId::parse_with_server_name("", db.globals.server_name())
-                    .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-                &db.users,
-                &db.globals,
-            )?;
-            if !worked {
-                return Err(Error::Uiaa(uiaainfo));
+            match auth {
+                IncomingAuthData::ReCaptcha(captcha) if captcha_required => {
+                    if !verify_recaptcha(&db, &captcha.response, body.client_ip).await? {
+                        uiaainfo.session = captcha.session.clone();
+                        uiaainfo.auth_error = Some(ErrorBody {
+                            kind: ErrorKind::Forbidden,
+                            message: "The captcha was not solved.".to_owned(),
+                        });
+                        return Err(Error::Uiaa(uiaainfo));
+                    }
+                }
+                // Sessions that were started before the captcha was required only have the dummy
+                // flow and must not be completed anymore
+                _ if captcha_required => {
+                    uiaainfo.session = auth.session().map(ToOwned::to_owned);
+                    uiaainfo.auth_error = Some(ErrorBody {
+                        kind: ErrorKind::Forbidden,
+                        message: "Solve the captcha to register.".to_owned(),
+                    });
+                    return Err(Error::Uiaa(uiaainfo));
+                }
+                _ => {
+                    let (worked, uiaainfo) = db.uiaa.try_auth(
+                        &UserId::parse_with_server_name("", db.globals.server_name())
+                            .expect("we know this is valid"),
+                        "".into(),
+                        auth,
+                        &uiaainfo,
+                        body.client_ip,
+                        &db,
+                    )?;
+                    if !worked {
+                        return Err(Error::Uiaa(uiaainfo));
+                    }
+                }
             }
         // Success!
         } else if let Some(json) = body.json_body {
@@ -201,6 +251,10 @@ pub async fn register_route(
     // Create user
     db.users.create(&user_id, password)?;
 
+    if let Some(client_ip) = body.client_ip {
+        db.users.record_registration(&user_id, &client_ip)?;
+    }
+
     // Default to pretty displayname
     let displayname = format!("{} ⚡️", user_id.localpart());
     db.users
@@ -219,8 +273,14 @@ pub async fn register_route(
         &db.globals,
     )?;
 
+    let db = Arc::new(db);
+
     // Inhibit login does not work for guests
     if !is_guest && body.inhibit_login {
+        if !body.from_appservice {
+            client_server::spawn_auto_join_rooms(Arc::clone(&db), user_id.clone());
+        }
+
         return Ok(register::Response {
             access_token: None,
             user_id,
@@ -522,6 +582,17 @@ pub async fn register_route(
         )?;
     }
 
+    // After the admin room, so the @conduit bot exists when rooms have to be created
+    if !is_guest && !body.from_appservice {
+        client_server::spawn_auto_join_rooms(Arc::clone(&db), user_id.clone());
+
+        if let Some(welcome_message) = db.globals.welcome_message() {
+            if let Err(e) = send_welcome_message(&db, &user_id, welcome_message).await {
+                warn!("Failed to send welcome message to {}: {}", user_id, e);
+            }
+        }
+    }
+
     info!("{} registered on this server", user_id);
 
     db.flush()?;
@@ -534,6 +605,267 @@ pub async fn register_route(
     .into())
 }
 
+/// Creates a direct chat between the @conduit bot and the new user and sends the welcome message
+/// there. The user is joined right away, so the message shows up without accepting an invite.
+async fn send_welcome_message(db: &Database, user_id: &UserId, markdown: &str) -> Result<()> {
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    let room_id = RoomId::new(db.globals.server_name());
+
+    db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let mut content = ruma::events::room::create::CreateEventContent::new(conduit_user.clone());
+    content.federate = true;
+    content.predecessor = None;
+    content.room_version = RoomVersionId::Version6;
+
+    let mut users = BTreeMap::new();
+    users.insert(conduit_user.clone(), 100.into());
+
+    let member_content = |membership, is_direct| member::MemberEventContent {
+        membership,
+        displayname: None,
+        avatar_url: None,
+        is_direct,
+        third_party_invite: None,
+        blurhash: None,
+        reason: None,
+    };
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(markdown));
+
+    let events = vec![
+        (
+            &conduit_user,
+            EventType::RoomCreate,
+            serde_json::to_value(content),
+            Some("".to_owned()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomMember,
+            serde_json::to_value(member_content(member::MembershipState::Join, None)),
+            Some(conduit_user.to_string()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomPowerLevels,
+            serde_json::to_value(ruma::events::room::power_levels::PowerLevelsEventContent {
+                users,
+                ..Default::default()
+            }),
+            Some("".to_owned()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomJoinRules,
+            serde_json::to_value(join_rules::JoinRulesEventContent::new(
+                join_rules::JoinRule::Invite,
+            )),
+            Some("".to_owned()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomHistoryVisibility,
+            serde_json::to_value(history_visibility::HistoryVisibilityEventContent::new(
+                history_visibility::HistoryVisibility::Shared,
+            )),
+            Some("".to_owned()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomGuestAccess,
+            serde_json::to_value(guest_access::GuestAccessEventContent::new(
+                guest_access::GuestAccess::Forbidden,
+            )),
+            Some("".to_owned()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomMember,
+            serde_json::to_value(member_content(member::MembershipState::Invite, Some(true))),
+            Some(user_id.to_string()),
+        ),
+        (
+            user_id,
+            EventType::RoomMember,
+            serde_json::to_value(member_content(member::MembershipState::Join, Some(true))),
+            Some(user_id.to_string()),
+        ),
+        (
+            &conduit_user,
+            EventType::RoomMessage,
+            serde_json::to_value(message::MessageEventContent::text_html(
+                markdown.to_owned(),
+                html,
+            )),
+            None,
+        ),
+    ];
+
+    for (sender, event_type, content, state_key) in events {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type,
+                content: content.expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key,
+                redacts: None,
+            },
+            sender,
+            &room_id,
+            db,
+            &state_lock,
+        )?;
+    }
+
+    // Joining the direct chat invite adds the room to the m.direct account data of the user
+
+    Ok(())
+}
+
+/// Returns true if the localpart only uses the characters the spec allows for new user ids.
+pub(crate) fn is_valid_localpart(localpart: &str) -> bool {
+    !localpart.is_empty()
+        && localpart
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'=' | b'-' | b'/'))
+}
+
+/// Makes sure that nobody else has or claims a new user id.
+///
+/// - The localpart must be valid and the user id can't be longer than 255 bytes
+/// - Reserved usernames and exclusive appservice namespaces are only available to appservices
+/// - User ids that only differ in case from an existing user count as taken, so nobody can
+/// impersonate historical users with uppercase letters
+fn check_new_user_id(db: &Database, user_id: &UserId, from_appservice: bool) -> Result<()> {
+    if !is_valid_localpart(user_id.localpart()) || user_id.as_str().len() > 255 {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Username is invalid.",
+        ));
+    }
+
+    if !from_appservice {
+        let reserved_by_appservice = db.appservice.all()?.iter().any(|(_, registration)| {
+            Namespaces::from_registration(registration, db.globals.server_name())
+                .matches_exclusive_user(user_id.as_str())
+        });
+
+        if reserved_by_appservice {
+            return Err(Error::BadRequest(
+                ErrorKind::Exclusive,
+                "Desired user ID is reserved by an appservice.",
+            ));
+        }
+
+        if db.globals.is_reserved_username(user_id.localpart()) {
+            return Err(Error::BadRequest(
+                ErrorKind::UserInUse,
+                "Desired user ID is reserved.",
+            ));
+        }
+    }
+
+    if db.users.exists(user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    if db.users.lowercase_localpart_exists(user_id.localpart())? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns how long the address has to wait until it can register without a captcha again, or
+/// None if it didn't register `registrations_per_ip` accounts recently.
+fn registration_retry_after(db: &Database, client_ip: Option<IpAddr>) -> Result<Option<Duration>> {
+    let (limit, client_ip) = match (db.globals.registrations_per_ip(), client_ip) {
+        (Some(limit), Some(client_ip)) => (limit as usize, client_ip),
+        _ => return Ok(None),
+    };
+
+    let window = db.globals.registration_ip_window().as_millis() as u64;
+    let now = utils::millis_since_unix_epoch();
+    let since = now.saturating_sub(window);
+
+    let mut recent = Vec::new();
+    for registration in db.users.registrations_from_ip(&client_ip) {
+        let (registered_at, _) = registration?;
+        if registered_at >= since {
+            recent.push(registered_at);
+        }
+    }
+
+    if recent.len() < limit {
+        return Ok(None);
+    }
+
+    // The address can register again when enough registrations left the window
+    recent.sort_unstable();
+    let blocking = recent
+        .get(recent.len() - limit)
+        .map_or(now, |registered_at| registered_at + window);
+
+    Ok(Some(Duration::from_millis(blocking.saturating_sub(now))))
+}
+
+#[derive(Deserialize)]
+struct RecaptchaResponse {
+    success: bool,
+}
+
+/// Asks Google if the captcha response is valid.
+async fn verify_recaptcha(
+    db: &Database,
+    response: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<bool> {
+    let (_, private_key) = db.globals.recaptcha_keys().expect("captcha is configured");
+
+    let mut form = vec![
+        ("secret", private_key.to_owned()),
+        ("response", response.to_owned()),
+    ];
+    if let Some(client_ip) = client_ip {
+        form.push(("remoteip", client_ip.to_string()));
+    }
+
+    let body = db
+        .globals
+        .reqwest_client()?
+        .build()?
+        .post("https://www.google.com/recaptcha/api/siteverify")
+        .form(&form)
+        .send()
+        .await?
+        .bytes()
+        .await?;
+
+    Ok(serde_json::from_slice::<RecaptchaResponse>(&body)
+        .map(|r| r.success)
+        .unwrap_or(false))
+}
+
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
@@ -557,8 +889,8 @@ pub async fn change_password_route(
     db: DatabaseGuard,
     body: Ruma<change_password::Request<'_>>,
 ) -> ConduitResult<change_password::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
@@ -576,14 +908,14 @@ pub async fn change_password_route(
             sender_device,
             auth,
             &uiaainfo,
-            &db.users,
-            &db.globals,
+            body.client_ip,
+            &db,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
     // Success!
-    } else if let Some(json) = body.json_body {
+    } else if let Some(json) = &body.json_body {
         uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
         db.uiaa
             .create(&sender_user, &sender_device, &uiaainfo, &json)?;
@@ -594,6 +926,13 @@ pub async fn change_password_route(
 
     db.users
         .set_password(&sender_user, Some(&body.new_password))?;
+    db.audit.record(
+        &db.globals,
+        AuditAction::PasswordChanged {
+            user_id: sender_user.clone(),
+        },
+        body.client_ip,
+    )?;
 
     if body.logout_devices {
         // Logout all devices except the current one
@@ -601,7 +940,7 @@ pub async fn change_password_route(
             .users
             .all_device_ids(&sender_user)
             .filter_map(|id| id.ok())
-            .filter(|id| id != sender_device)
+            .filter(|id| &**id != sender_device)
         {
             db.users.remove_device(&sender_user, &id)?;
         }
@@ -623,7 +962,7 @@ pub async fn change_password_route(
 )]
 #[tracing::instrument(skip(body))]
 pub async fn whoami_route(body: Ruma<whoami::Request>) -> ConduitResult<whoami::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
     Ok(whoami::Response {
         user_id: sender_user.clone(),
     }
@@ -634,7 +973,8 @@ pub async fn whoami_route(body: Ruma<whoami::Request>) -> ConduitResult<whoami::
 ///
 /// Deactivate sender user account.
 ///
-/// - Leaves all rooms and rejects all invitations
+/// - Leaves all rooms and rejects all invitations in the background
+/// - Removes aliases the user created, pushers and optionally uploaded media in the background
 /// - Invalidates all access tokens
 /// - Deletes all device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets all to-device events
@@ -649,8 +989,8 @@ pub async fn deactivate_route(
     db: DatabaseGuard,
     body: Ruma<deactivate::Request<'_>>,
 ) -> ConduitResult<deactivate::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
@@ -668,14 +1008,14 @@ pub async fn deactivate_route(
             &sender_device,
             auth,
             &uiaainfo,
-            &db.users,
-            &db.globals,
+            body.client_ip,
+            &db,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
     // Success!
-    } else if let Some(json) = body.json_body {
+    } else if let Some(json) = &body.json_body {
         uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
         db.uiaa
             .create(&sender_user, &sender_device, &uiaainfo, &json)?;
@@ -684,58 +1024,26 @@ pub async fn deactivate_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    // Leave all joined rooms and reject all invitations
-    // TODO: work over federation invites
-    let all_rooms = db
-        .rooms
-        .rooms_joined(&sender_user)
-        .chain(
-            db.rooms
-                .rooms_invited(&sender_user)
-                .map(|t| t.map(|(r, _)| r)),
-        )
-        .collect::<Vec<_>>();
-
-    for room_id in all_rooms {
-        let room_id = room_id?;
-        let event = member::MemberEventContent {
-            membership: member::MembershipState::Leave,
-            displayname: None,
-            avatar_url: None,
-            is_direct: None,
-            third_party_invite: None,
-            blurhash: None,
-            reason: None,
-        };
-
-        let mutex_state = Arc::clone(
-            db.globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let state_lock = mutex_state.lock().await;
-
-        db.rooms.build_and_append_pdu(
-            PduBuilder {
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(event).expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(sender_user.to_string()),
-                redacts: None,
-            },
-            &sender_user,
-            &room_id,
-            &db,
-            &state_lock,
-        )?;
-    }
-
     // Remove devices and mark account as deactivated
     db.users.deactivate_account(&sender_user)?;
 
+    // Leaving all rooms can take a while, so it's done in the background
+    db.jobs.queue(
+        JobKind::CleanupDeactivatedUser {
+            user_id: sender_user.clone(),
+            purge_media: db.globals.purge_media_of_deactivated_users(),
+        },
+        &db.globals,
+    )?;
+
+    db.audit.record(
+        &db.globals,
+        AuditAction::AccountDeactivated {
+            user_id: sender_user.clone(),
+        },
+        body.client_ip,
+    )?;
+
     info!("{} deactivated their account", sender_user);
 
     db.flush()?;
@@ -758,7 +1066,7 @@ pub async fn deactivate_route(
 pub async fn third_party_route(
     body: Ruma<get_contacts::Request>,
 ) -> ConduitResult<get_contacts::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let _sender_user = body.authenticated_user()?;
 
     Ok(get_contacts::Response::new(Vec::new()).into())
 }
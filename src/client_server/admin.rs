@@ -0,0 +1,503 @@
+use crate::{
+    database::DatabaseGuard, utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
+};
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{room::create::CreateEventContent, EventType},
+    serde::Raw,
+    state_res::{self, RoomVersion},
+    RoomAliasId, RoomId, UserId,
+};
+use std::{
+    collections::{hash_map, HashMap},
+    convert::TryInto,
+    io,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// Custom endpoint for admins to find out why an event was accepted, soft failed or rejected.
+pub mod event_auth {
+    use ruma::{api::ruma_api, EventId, RoomId};
+    use serde::{Deserialize, Serialize};
+
+    ruma_api! {
+        metadata: {
+            description: "Explain how the auth rules judged an event.",
+            method: GET,
+            name: "event_auth",
+            path: "/_conduit/admin/v1/events/:event_id/auth",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The event to explain.
+            #[ruma_api(path)]
+            pub event_id: EventId,
+        }
+
+        response: {
+            pub room_id: RoomId,
+
+            /// One of `accepted`, `outlier`, `soft_failed` or `rejected`.
+            pub status: String,
+
+            /// Why the event was rejected when it arrived.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub rejection_reason: Option<String>,
+
+            /// Why the event was soft failed when it arrived.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub soft_fail_reason: Option<String>,
+
+            /// The auth events the event cited, in the order it cited them.
+            pub auth_events: Vec<CitedAuthEvent>,
+
+            /// The checks evaluated again now, in the order the server runs them.
+            pub checks: Vec<AuthCheck>,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct CitedAuthEvent {
+        pub event_id: EventId,
+
+        /// The type of the auth event, missing if the server doesn't have it.
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+        pub event_type: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub state_key: Option<String>,
+
+        pub rejected: bool,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct AuthCheck {
+        pub check: String,
+        pub passed: bool,
+
+        /// What went wrong, or the log of the auth rules for the auth rule checks.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub details: Vec<String>,
+    }
+}
+
+/// # `GET /_conduit/admin/v1/events/{eventId}/auth`
+///
+/// Explains how the auth rules judged an event, to debug events that were rejected over
+/// federation.
+///
+/// - Only members of the admin room can use this
+/// - Returns the auth events the event cited and whether they are rejected
+/// - Runs the auth rules again, once with the cited auth events and once with the current state
+/// of the room like the soft fail check does. The current state may differ from the state when
+/// the event arrived
+/// - The auth rule checks contain what the auth rules logged. Release builds only log why a rule
+/// failed
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/admin/v1/events/<_>/auth", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn event_auth_route(
+    db: DatabaseGuard,
+    body: Ruma<event_auth::Request>,
+) -> ConduitResult<event_auth::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !is_admin(&db, sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only members of the admin room can debug events.",
+        ));
+    }
+
+    let pdu = db
+        .rooms
+        .get_pdu_including_rejected(&body.event_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+    let rejection_reason = db.rooms.rejection_reason(&pdu.event_id)?;
+    let soft_fail_reason = db.rooms.soft_fail_reason(&pdu.event_id)?;
+
+    let status = if rejection_reason.is_some() {
+        "rejected"
+    } else if soft_fail_reason.is_some() {
+        "soft_failed"
+    } else if db.rooms.get_pdu_id(&pdu.event_id)?.is_none() {
+        "outlier"
+    } else {
+        "accepted"
+    };
+
+    let create_event = db
+        .rooms
+        .room_state_get(&pdu.room_id, &EventType::RoomCreate, "")?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "The server doesn't know the room of the event.",
+        ))?;
+
+    let room_version_id =
+        serde_json::from_value::<Raw<CreateEventContent>>(create_event.content.clone())
+            .expect("Raw::from_value always works.")
+            .deserialize()
+            .map_err(|_| Error::bad_database("Invalid create event in db."))?
+            .room_version;
+
+    let room_version = RoomVersion::new(&room_version_id).map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::UnsupportedRoomVersion,
+            "The room version is not supported.",
+        )
+    })?;
+
+    let mut cited_auth_events = Vec::new();
+    let mut missing = Vec::new();
+    let mut rejected = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut auth_events = HashMap::new();
+
+    for id in &pdu.auth_events {
+        let auth_event = db.rooms.get_pdu_including_rejected(id)?;
+        let is_rejected = db.rooms.is_event_rejected(id)?;
+
+        cited_auth_events.push(event_auth::CitedAuthEvent {
+            event_id: id.clone(),
+            event_type: auth_event.as_ref().map(|e| e.kind.to_string()),
+            state_key: auth_event.as_ref().and_then(|e| e.state_key.clone()),
+            rejected: is_rejected,
+        });
+
+        let auth_event = match auth_event {
+            Some(e) => e,
+            None => {
+                missing.push(id.to_string());
+                continue;
+            }
+        };
+
+        // Rejected events are kept around, but they can't authorize anything
+        if is_rejected {
+            rejected.push(id.to_string());
+            continue;
+        }
+
+        match auth_events.entry((
+            auth_event.kind.clone(),
+            auth_event.state_key.clone().unwrap_or_default(),
+        )) {
+            hash_map::Entry::Vacant(v) => {
+                v.insert(auth_event);
+            }
+            hash_map::Entry::Occupied(_) => duplicates.push(id.to_string()),
+        }
+    }
+
+    let cites_create_event = auth_events
+        .get(&(EventType::RoomCreate, "".to_owned()))
+        .map(|a| a.as_ref())
+        == Some(&*create_event);
+
+    // If the previous event was the create event special rules apply
+    let previous_create = if pdu.auth_events.len() == 1 && pdu.prev_events == pdu.auth_events {
+        db.rooms
+            .get_pdu(&pdu.auth_events[0])?
+            .filter(|maybe_create| **maybe_create == *create_event)
+    } else {
+        None
+    };
+
+    let current_auth_events = db.rooms.get_auth_events(
+        &pdu.room_id,
+        &pdu.kind,
+        &pdu.sender,
+        pdu.state_key.as_deref(),
+        &pdu.content,
+    )?;
+
+    let checks = vec![
+        event_auth::AuthCheck {
+            check: "auth_events_known".to_owned(),
+            passed: missing.is_empty(),
+            details: missing,
+        },
+        event_auth::AuthCheck {
+            check: "auth_events_not_rejected".to_owned(),
+            passed: rejected.is_empty(),
+            details: rejected,
+        },
+        event_auth::AuthCheck {
+            check: "auth_events_unique".to_owned(),
+            passed: duplicates.is_empty(),
+            details: duplicates,
+        },
+        event_auth::AuthCheck {
+            check: "cites_create_event".to_owned(),
+            passed: cites_create_event,
+            details: Vec::new(),
+        },
+        traced_auth_check(
+            "auth_rules_with_auth_events",
+            &room_version,
+            &pdu,
+            previous_create.clone(),
+            |k, s| auth_events.get(&(k.clone(), s.to_owned())).map(Arc::clone),
+        ),
+        traced_auth_check(
+            "auth_rules_with_current_state",
+            &room_version,
+            &pdu,
+            previous_create,
+            |k, s| {
+                current_auth_events
+                    .get(&(k.clone(), s.to_owned()))
+                    .map(Arc::clone)
+            },
+        ),
+    ];
+
+    Ok(event_auth::Response {
+        room_id: pdu.room_id.clone(),
+        status: status.to_owned(),
+        rejection_reason,
+        soft_fail_reason,
+        auth_events: cited_auth_events,
+        checks,
+    }
+    .into())
+}
+
+/// How many audit log entries are returned if the admin doesn't set a limit.
+const DEFAULT_AUDIT_LIMIT: u64 = 100;
+/// Admins can't ask for more audit log entries per request than this.
+const MAX_AUDIT_LIMIT: u64 = 1000;
+/// How many entries one request looks at when filtering by action.
+const MAX_SCANNED_AUDIT_ENTRIES: usize = 10000;
+
+/// Custom endpoint for admins to read the audit log.
+pub mod audit_log {
+    use crate::database::audit::AuditEntry;
+    use ruma::{api::ruma_api, UInt, UserId};
+
+    ruma_api! {
+        metadata: {
+            description: "Read the audit log, newest entries first.",
+            method: GET,
+            name: "audit_log",
+            path: "/_conduit/admin/v1/audit",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The next_batch of a previous response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            /// Only return entries about this user.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub user_id: Option<UserId>,
+
+            /// Only return entries of this action, e.g. `login_failed`.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub action: Option<String>,
+        }
+
+        response: {
+            pub entries: Vec<AuditEntry>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_conduit/admin/v1/audit`
+///
+/// Returns entries of the audit log, newest first.
+///
+/// - Only members of the admin room can use this
+/// - `user_id` and `action` only return matching entries. At most 10000 entries are looked at,
+/// so a page can be short even though there are older matching entries
+/// - Empty if `audit_log` is disabled in the config and no entries were recorded before
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/admin/v1/audit", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn audit_log_route(
+    db: DatabaseGuard,
+    body: Ruma<audit_log::Request>,
+) -> ConduitResult<audit_log::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !is_admin(&db, sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only members of the admin room can read the audit log.",
+        ));
+    }
+
+    let limit = body
+        .limit
+        .map_or(DEFAULT_AUDIT_LIMIT, u64::from)
+        .min(MAX_AUDIT_LIMIT)
+        .max(1) as usize;
+
+    let from = body.from.as_deref().map(parse_audit_token).transpose()?;
+
+    let candidates: Box<dyn Iterator<Item = _>> = match &body.user_id {
+        Some(user_id) => Box::new(db.audit.user_entries_before(user_id, from)),
+        None => Box::new(db.audit.entries_before(from)),
+    };
+
+    let mut entries = Vec::new();
+    let mut next_batch = None;
+    for (scanned, entry) in candidates.enumerate() {
+        let (key, entry) = entry?;
+
+        if body
+            .action
+            .as_ref()
+            .map_or(true, |action| entry.action.name() == action)
+        {
+            entries.push(entry);
+        }
+
+        if entries.len() == limit || scanned + 1 == MAX_SCANNED_AUDIT_ENTRIES {
+            next_batch = Some(audit_token(&key)?);
+            break;
+        }
+    }
+
+    Ok(audit_log::Response {
+        entries,
+        next_batch,
+    }
+    .into())
+}
+
+/// Audit log tokens are the timestamp and count of the last returned entry.
+fn audit_token(key: &[u8]) -> Result<String> {
+    if key.len() != 16 {
+        return Err(Error::bad_database("Invalid key in auditid_entry."));
+    }
+
+    let ts = utils::u64_from_bytes(&key[..8])
+        .map_err(|_| Error::bad_database("Invalid key in auditid_entry."))?;
+    let count = utils::u64_from_bytes(&key[8..])
+        .map_err(|_| Error::bad_database("Invalid key in auditid_entry."))?;
+
+    Ok(format!("{}_{}", ts, count))
+}
+
+fn parse_audit_token(token: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid pagination token.");
+
+    let mut parts = token.splitn(2, '_');
+    let ts = parts
+        .next()
+        .and_then(|ts| ts.parse::<u64>().ok())
+        .ok_or_else(invalid)?;
+    let count = parts
+        .next()
+        .and_then(|count| count.parse::<u64>().ok())
+        .ok_or_else(invalid)?;
+
+    let mut key = ts.to_be_bytes().to_vec();
+    key.extend_from_slice(&count.to_be_bytes());
+    Ok(key)
+}
+
+/// Admins are the members of the admin room.
+pub(crate) fn is_admin(db: &Database, user_id: &UserId) -> Result<bool> {
+    let admin_room_alias: RoomAliasId = format!("#admins:{}", db.globals.server_name())
+        .try_into()
+        .expect("#admins:server_name is a valid alias name");
+
+    let admin_room: Option<RoomId> = db.rooms.id_from_alias(&admin_room_alias)?;
+
+    match admin_room {
+        Some(room_id) => db.rooms.is_joined(user_id, &room_id),
+        None => Ok(false),
+    }
+}
+
+/// Collects the output of a tracing subscriber.
+#[derive(Clone, Default)]
+struct TraceWriter(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for TraceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the auth rules and records what they logged on the way.
+fn traced_auth_check<F>(
+    check: &str,
+    room_version: &RoomVersion,
+    pdu: &Arc<PduEvent>,
+    previous_create: Option<Arc<PduEvent>>,
+    fetch_state: F,
+) -> event_auth::AuthCheck
+where
+    F: Fn(&EventType, &str) -> Option<Arc<PduEvent>>,
+{
+    let writer = TraceWriter::default();
+    let subscriber = {
+        let writer = writer.clone();
+        tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .with_target(false)
+            .with_max_level(tracing::Level::TRACE)
+            .finish()
+    };
+
+    let result = tracing::subscriber::with_default(subscriber, || {
+        state_res::event_auth::auth_check(room_version, pdu, previous_create, None, fetch_state)
+    });
+
+    let mut details = String::from_utf8_lossy(&writer.0.lock().unwrap())
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let passed = match result {
+        Ok(passed) => passed,
+        Err(e) => {
+            details.push(format!("Auth check failed: {}", e));
+            false
+        }
+    };
+
+    event_auth::AuthCheck {
+        check: check.to_owned(),
+        passed,
+        details,
+    }
+}
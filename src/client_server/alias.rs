@@ -1,4 +1,4 @@
-use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Ruma};
+use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma};
 use regex::Regex;
 use ruma::{
     api::{
@@ -9,12 +9,121 @@ use ruma::{
         },
         federation,
     },
-    RoomAliasId,
+    RoomAliasId, RoomId, ServerName,
 };
+use std::collections::HashSet;
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{delete, get, put};
 
+/// Room aliases can be at most 255 bytes long, including the sigil and the server name.
+const MAX_ALIAS_LENGTH: usize = 255;
+
+/// How many servers to join via are suggested at most when resolving an alias.
+const MAX_VIA_SERVERS: usize = 10;
+
+/// Characters other than letters and digits that may appear in the localpart of a new alias.
+const ALIAS_PUNCTUATION: &str = "._=-/+";
+
+/// Checks that the localpart of a new local alias is not empty, fits in the maximum length and
+/// only contains letters, digits and a few punctuation characters.
+pub(crate) fn validate_alias_localpart(localpart: &str, server_name: &ServerName) -> Result<()> {
+    if localpart.is_empty() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Alias localpart is empty.",
+        ));
+    }
+
+    // # + localpart + : + server name
+    if localpart.len() + server_name.as_str().len() + 2 > MAX_ALIAS_LENGTH {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Alias is longer than 255 bytes.",
+        ));
+    }
+
+    if !localpart
+        .chars()
+        .all(|c| c.is_alphanumeric() || ALIAS_PUNCTUATION.contains(c))
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Alias may only contain letters, digits and ._=-/+",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Rejects aliases that look like an existing alias on this server if the server is configured
+/// to do so.
+pub(crate) fn check_confusable_alias(db: &Database, alias: &RoomAliasId) -> Result<()> {
+    if !db.globals.reject_confusable_aliases() {
+        return Ok(());
+    }
+
+    let skeleton = confusable_skeleton(alias.alias());
+
+    for existing in db.rooms.all_aliases() {
+        let existing = existing?;
+
+        if &existing != alias
+            && existing.server_name() == alias.server_name()
+            && confusable_skeleton(existing.alias()) == skeleton
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Alias is too similar to an existing alias.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps a localpart to a form in which characters that are easily mistaken for each other are
+/// the same, e.g. case, cyrillic and greek look-alikes of latin letters and fullwidth forms.
+fn confusable_skeleton(localpart: &str) -> String {
+    localpart
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            // Fullwidth forms of ascii characters
+            '\u{ff01}'..='\u{ff5e}' => std::char::from_u32(c as u32 - 0xff01 + 0x21).unwrap_or(c),
+            _ => c,
+        })
+        .map(|c| match c {
+            'а' | 'α' => 'a',
+            'в' | 'β' => 'b',
+            'с' | 'ϲ' => 'c',
+            'ԁ' => 'd',
+            'е' | 'ε' => 'e',
+            'ɡ' => 'g',
+            'һ' | 'н' => 'h',
+            'і' | 'ι' => 'i',
+            'ј' => 'j',
+            'к' | 'κ' => 'k',
+            '1' => 'l',
+            'м' => 'm',
+            'η' => 'n',
+            '0' | 'о' | 'ο' => 'o',
+            'р' | 'ρ' => 'p',
+            'ԛ' => 'q',
+            'ѕ' => 's',
+            'т' | 'τ' => 't',
+            'υ' => 'u',
+            'ν' => 'v',
+            'ԝ' | 'ω' => 'w',
+            'х' | 'χ' => 'x',
+            'у' => 'y',
+            '_' | '.' => '-',
+            _ => c,
+        })
+        .collect()
+}
+
 /// # `PUT /_matrix/client/r0/directory/room/{roomAlias}`
 ///
 /// Creates a new room alias on this server.
@@ -38,8 +147,13 @@ pub async fn create_alias_route(
         return Err(Error::Conflict("Alias already exists."));
     }
 
+    validate_alias_localpart(body.room_alias.alias(), body.room_alias.server_name())?;
+    check_confusable_alias(&db, &body.room_alias)?;
+
     db.rooms
         .set_alias(&body.room_alias, Some(&body.room_id), &db.globals)?;
+    db.rooms
+        .set_alias_creator(&body.room_alias, body.authenticated_user()?)?;
 
     db.flush()?;
 
@@ -81,7 +195,9 @@ pub async fn delete_alias_route(
 ///
 /// Resolve an alias locally or over federation.
 ///
-/// - TODO: Suggest more servers to join via
+/// - Remote aliases are asked from the server of the alias
+/// - The servers to join via start with the server of the alias, followed by the servers it
+/// suggested and other servers this server knows to be in the room
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/directory/room/<_>", data = "<body>")
@@ -106,9 +222,17 @@ pub(crate) async fn get_alias_helper(
                 room_alias.server_name(),
                 federation::query::get_room_information::v1::Request { room_alias },
             )
-            .await?;
+            .await
+            .map_err(|e| {
+                warn!("Failed to resolve {} over federation: {}", room_alias, e);
+                Error::BadRequest(ErrorKind::NotFound, "Room with alias not found.")
+            })?;
 
-        return Ok(get_alias::Response::new(response.room_id, response.servers).into());
+        let mut servers = vec![room_alias.server_name().to_owned()];
+        servers.extend(response.servers);
+        let servers = via_servers(db, &response.room_id, servers);
+
+        return Ok(get_alias::Response::new(response.room_id, servers).into());
     }
 
     let mut room_id = None;
@@ -159,5 +283,106 @@ pub(crate) async fn get_alias_helper(
         }
     };
 
-    Ok(get_alias::Response::new(room_id, vec![db.globals.server_name().to_owned()]).into())
+    let servers = via_servers(db, &room_id, vec![db.globals.server_name().to_owned()]);
+
+    Ok(get_alias::Response::new(room_id, servers).into())
+}
+
+/// Appends the servers this server knows to be in the room to the suggested servers, without
+/// duplicates and up to `MAX_VIA_SERVERS`.
+fn via_servers(
+    db: &Database,
+    room_id: &RoomId,
+    suggested: Vec<Box<ServerName>>,
+) -> Vec<Box<ServerName>> {
+    let mut seen = HashSet::new();
+
+    suggested
+        .into_iter()
+        .chain(db.rooms.room_servers(room_id).filter_map(|r| r.ok()))
+        .filter(|server| seen.insert(server.clone()))
+        .take(MAX_VIA_SERVERS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{confusable_skeleton, validate_alias_localpart};
+    use crate::Error;
+    use ruma::{api::client::error::ErrorKind, ServerName};
+    use std::convert::TryFrom;
+
+    fn is_valid(localpart: &str) -> bool {
+        let server_name = Box::<ServerName>::try_from("example.com").unwrap();
+
+        match validate_alias_localpart(localpart, &server_name) {
+            Ok(()) => true,
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _)) => false,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn accepts_common_localparts() {
+        assert!(is_valid("conduit"));
+        assert!(is_valid("Conduit-Dev_2021"));
+        assert!(is_valid("a.b=c/d+e"));
+        assert!(is_valid("ünïcödé"));
+        assert!(is_valid("日本語"));
+    }
+
+    #[test]
+    fn rejects_empty_localpart() {
+        assert!(!is_valid(""));
+    }
+
+    #[test]
+    fn rejects_invalid_characters() {
+        assert!(!is_valid("with space"));
+        assert!(!is_valid("colon:inside"));
+        assert!(!is_valid("#sigil"));
+        assert!(!is_valid("zero\u{200b}width"));
+        assert!(!is_valid("new\nline"));
+        assert!(!is_valid("emoji🎉"));
+    }
+
+    #[test]
+    fn length_limit_counts_bytes_of_the_whole_alias() {
+        // "#" + localpart + ":example.com" must fit in 255 bytes
+        assert!(is_valid(&"a".repeat(242)));
+        assert!(!is_valid(&"a".repeat(243)));
+        // "ü" takes two bytes
+        assert!(is_valid(&"ü".repeat(121)));
+        assert!(!is_valid(&"ü".repeat(122)));
+    }
+
+    #[test]
+    fn skeleton_matches_look_alikes() {
+        let matrix = confusable_skeleton("matrix");
+
+        // Cyrillic "а"
+        assert_eq!(confusable_skeleton("m\u{0430}trix"), matrix);
+        assert_eq!(confusable_skeleton("MATRIX"), matrix);
+        assert_eq!(confusable_skeleton("ｍａｔｒｉｘ"), matrix);
+        assert_eq!(confusable_skeleton("f00"), confusable_skeleton("foo"));
+        assert_eq!(confusable_skeleton("1ounge"), confusable_skeleton("lounge"));
+        assert_eq!(
+            confusable_skeleton("my_room"),
+            confusable_skeleton("my.room")
+        );
+        assert_eq!(
+            confusable_skeleton("my_room"),
+            confusable_skeleton("my-room")
+        );
+    }
+
+    #[test]
+    fn skeleton_keeps_different_names_apart() {
+        assert_ne!(confusable_skeleton("matrix"), confusable_skeleton("matrlx"));
+        assert_ne!(confusable_skeleton("mail"), confusable_skeleton("mall"));
+        assert_ne!(
+            confusable_skeleton("myroom"),
+            confusable_skeleton("my-room")
+        );
+    }
 }
@@ -1,12 +1,19 @@
-use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    r0::backup::{
-        add_backup_key_session, add_backup_key_sessions, add_backup_keys, create_backup,
-        delete_backup, delete_backup_key_session, delete_backup_key_sessions, delete_backup_keys,
-        get_backup, get_backup_key_session, get_backup_key_sessions, get_backup_keys,
-        get_latest_backup, update_backup,
+use crate::{
+    database::{users::is_signed_by_cross_signing_key, DatabaseGuard},
+    ConduitResult, Database, Error, Result, Ruma,
+};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::backup::{
+            add_backup_key_session, add_backup_key_sessions, add_backup_keys, create_backup,
+            delete_backup, delete_backup_key_session, delete_backup_key_sessions,
+            delete_backup_keys, get_backup, get_backup_key_session, get_backup_key_sessions,
+            get_backup_keys, get_latest_backup, update_backup, BackupAlgorithm,
+        },
     },
+    serde::{CanonicalJsonObject, CanonicalJsonValue},
+    UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -15,6 +22,9 @@ use rocket::{delete, get, post, put};
 /// # `POST /_matrix/client/r0/room_keys/version`
 ///
 /// Creates a new backup.
+///
+/// - If the user has cross-signing keys, signatures from them in `auth_data` must be valid
+/// - If `require_verified_key_backups` is set, `auth_data` must be signed by a cross-signing key
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/unstable/room_keys/version", data = "<body>")
@@ -24,7 +34,10 @@ pub async fn create_backup_route(
     db: DatabaseGuard,
     body: Ruma<create_backup::Request>,
 ) -> ConduitResult<create_backup::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    check_backup_auth_data(&db, sender_user, &body.algorithm)?;
+
     let version = db
         .key_backups
         .create_backup(&sender_user, &body.algorithm, &db.globals)?;
@@ -37,6 +50,8 @@ pub async fn create_backup_route(
 /// # `PUT /_matrix/client/r0/room_keys/version/{version}`
 ///
 /// Update information about an existing backup. Only `auth_data` can be modified.
+///
+/// - The new `auth_data` is checked like in [`POST /_matrix/client/r0/room_keys/version`](fn.create_backup_route.html)
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/unstable/room_keys/version/<_>", data = "<body>")
@@ -46,7 +61,10 @@ pub async fn update_backup_route(
     db: DatabaseGuard,
     body: Ruma<update_backup::Request<'_>>,
 ) -> ConduitResult<update_backup::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    check_backup_auth_data(&db, sender_user, &body.algorithm)?;
+
     db.key_backups
         .update_backup(&sender_user, &body.version, &body.algorithm, &db.globals)?;
 
@@ -67,7 +85,7 @@ pub async fn get_latest_backup_route(
     db: DatabaseGuard,
     body: Ruma<get_latest_backup::Request>,
 ) -> ConduitResult<get_latest_backup::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let (version, algorithm) =
         db.key_backups
@@ -98,7 +116,7 @@ pub async fn get_backup_route(
     db: DatabaseGuard,
     body: Ruma<get_backup::Request<'_>>,
 ) -> ConduitResult<get_backup::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
     let algorithm = db
         .key_backups
         .get_backup(&sender_user, &body.version)?
@@ -130,7 +148,7 @@ pub async fn delete_backup_route(
     db: DatabaseGuard,
     body: Ruma<delete_backup::Request<'_>>,
 ) -> ConduitResult<delete_backup::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.key_backups.delete_backup(&sender_user, &body.version)?;
 
@@ -155,7 +173,7 @@ pub async fn add_backup_keys_route(
     db: DatabaseGuard,
     body: Ruma<add_backup_keys::Request<'_>>,
 ) -> ConduitResult<add_backup_keys::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if Some(&body.version)
         != db
@@ -207,7 +225,7 @@ pub async fn add_backup_key_sessions_route(
     db: DatabaseGuard,
     body: Ruma<add_backup_key_sessions::Request<'_>>,
 ) -> ConduitResult<add_backup_key_sessions::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if Some(&body.version)
         != db
@@ -257,7 +275,7 @@ pub async fn add_backup_key_session_route(
     db: DatabaseGuard,
     body: Ruma<add_backup_key_session::Request<'_>>,
 ) -> ConduitResult<add_backup_key_session::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if Some(&body.version)
         != db
@@ -301,7 +319,7 @@ pub async fn get_backup_keys_route(
     db: DatabaseGuard,
     body: Ruma<get_backup_keys::Request<'_>>,
 ) -> ConduitResult<get_backup_keys::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let rooms = db.key_backups.get_all(&sender_user, &body.version)?;
 
@@ -320,7 +338,7 @@ pub async fn get_backup_key_sessions_route(
     db: DatabaseGuard,
     body: Ruma<get_backup_key_sessions::Request<'_>>,
 ) -> ConduitResult<get_backup_key_sessions::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let sessions = db
         .key_backups
@@ -341,7 +359,7 @@ pub async fn get_backup_key_session_route(
     db: DatabaseGuard,
     body: Ruma<get_backup_key_session::Request<'_>>,
 ) -> ConduitResult<get_backup_key_session::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let key_data = db
         .key_backups
@@ -366,7 +384,7 @@ pub async fn delete_backup_keys_route(
     db: DatabaseGuard,
     body: Ruma<delete_backup_keys::Request<'_>>,
 ) -> ConduitResult<delete_backup_keys::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.key_backups
         .delete_all_keys(&sender_user, &body.version)?;
@@ -392,7 +410,7 @@ pub async fn delete_backup_key_sessions_route(
     db: DatabaseGuard,
     body: Ruma<delete_backup_key_sessions::Request<'_>>,
 ) -> ConduitResult<delete_backup_key_sessions::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.key_backups
         .delete_room_keys(&sender_user, &body.version, &body.room_id)?;
@@ -418,7 +436,7 @@ pub async fn delete_backup_key_session_route(
     db: DatabaseGuard,
     body: Ruma<delete_backup_key_session::Request<'_>>,
 ) -> ConduitResult<delete_backup_key_session::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.key_backups
         .delete_room_key(&sender_user, &body.version, &body.room_id, &body.session_id)?;
@@ -431,3 +449,70 @@ pub async fn delete_backup_key_session_route(
     }
     .into())
 }
+
+/// Checks the signatures of the backup's `auth_data` against the user's cross-signing keys.
+fn check_backup_auth_data(
+    db: &Database,
+    user_id: &UserId,
+    algorithm: &BackupAlgorithm,
+) -> Result<()> {
+    let auth_data = match serde_json::to_value(algorithm)
+        .expect("BackupAlgorithm::to_value always works")
+        .get("auth_data")
+        .map(|auth_data| serde_json::from_value::<CanonicalJsonObject>(auth_data.clone()))
+    {
+        Some(Ok(auth_data)) => auth_data,
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Backup has invalid auth_data.",
+            ))
+        }
+    };
+
+    let signature_key_ids = match auth_data.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => match signatures.get(user_id.as_str()) {
+            Some(CanonicalJsonValue::Object(user_signatures)) => {
+                user_signatures.keys().cloned().collect()
+            }
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+
+    let cross_signing_keys = db
+        .users
+        .get_master_key(user_id, |_| false)?
+        .into_iter()
+        .chain(db.users.get_self_signing_key(user_id, |_| false)?);
+
+    let mut verified = false;
+    for cross_signing_key in cross_signing_keys {
+        // Signatures from other keys (e.g. device keys) can't be checked here
+        if !cross_signing_key
+            .keys
+            .keys()
+            .any(|key_id| signature_key_ids.contains(key_id))
+        {
+            continue;
+        }
+
+        if !is_signed_by_cross_signing_key(user_id, &cross_signing_key, &auth_data) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Backup auth_data has an invalid signature.",
+            ));
+        }
+
+        verified = true;
+    }
+
+    if !verified && db.globals.require_verified_key_backups() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Backup auth_data must be signed by a cross-signing key.",
+        ));
+    }
+
+    Ok(())
+}
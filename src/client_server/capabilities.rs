@@ -1,5 +1,4 @@
-use crate::ConduitResult;
-use crate::Ruma;
+use crate::{database::DatabaseGuard, ConduitResult, Ruma};
 use ruma::{
     api::client::r0::capabilities::{
         get_capabilities, Capabilities, RoomVersionStability, RoomVersionsCapability,
@@ -18,13 +17,15 @@ use rocket::get;
     feature = "conduit_bin",
     get("/_matrix/client/r0/capabilities", data = "<_body>")
 )]
-#[tracing::instrument(skip(_body))]
+#[tracing::instrument(skip(db, _body))]
 pub async fn get_capabilities_route(
+    db: DatabaseGuard,
     _body: Ruma<get_capabilities::Request>,
 ) -> ConduitResult<get_capabilities::Response> {
     let mut available = BTreeMap::new();
-    available.insert(RoomVersionId::Version5, RoomVersionStability::Stable);
-    available.insert(RoomVersionId::Version6, RoomVersionStability::Stable);
+    for room_version in db.globals.supported_room_versions() {
+        available.insert(room_version, RoomVersionStability::Stable);
+    }
 
     let mut capabilities = Capabilities::new();
     capabilities.room_versions = RoomVersionsCapability {
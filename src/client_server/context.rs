@@ -1,5 +1,8 @@
-use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
-use ruma::api::client::{error::ErrorKind, r0::context::get_context};
+use crate::{client_server, database::DatabaseGuard, ConduitResult, Error, PduEvent, Ruma};
+use ruma::{
+    api::client::{error::ErrorKind, r0::context::get_context},
+    events::EventType,
+};
 use std::convert::TryFrom;
 
 #[cfg(feature = "conduit_bin")]
@@ -9,8 +12,12 @@ use rocket::get;
 ///
 /// Allows loading room history around an event.
 ///
-/// - Only works if the user is joined (TODO: always allow, but only show events if the user was
-/// joined, depending on history_visibility)
+/// - Only works if the user is joined or the room is world readable (TODO: always allow, but only
+/// show events if the user was joined, depending on history_visibility)
+/// - The filter applies to the events before and after, not to the event itself. At most 1000
+/// events are looked at in each direction
+/// - With lazy loading, the only member events in `state` are those of the senders of the returned
+/// events
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/context/<_>", data = "<body>")
@@ -20,9 +27,10 @@ pub async fn get_context_route(
     db: DatabaseGuard,
     body: Ruma<get_context::Request<'_>>,
 ) -> ConduitResult<get_context::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+    let world_readable_only = !db.rooms.is_joined(sender_user, &body.room_id)?;
+    if world_readable_only && !db.rooms.is_world_readable(&body.room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
@@ -39,54 +47,107 @@ pub async fn get_context_route(
 
     let base_token = db.rooms.pdu_count(&base_pdu_id)?;
 
-    let base_event = db
-        .rooms
-        .get_pdu_from_id(&base_pdu_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Base event not found.",
-        ))?
-        .to_room_event();
+    let base_pdu = match db.rooms.get_pdu_from_id(&base_pdu_id)? {
+        Some(pdu)
+            if pdu.room_id == *body.room_id
+                && (!world_readable_only || db.rooms.is_world_readable_at(&pdu.event_id)?) =>
+        {
+            pdu
+        }
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Base event not found.",
+            ))
+        }
+    };
 
-    let events_before = db
-        .rooms
-        .pdus_until(&sender_user, &body.room_id, base_token)?
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
-        .filter_map(|r| r.ok()) // Remove buggy events
-        .collect::<Vec<_>>();
+    let mut limit = u32::try_from(body.limit)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid."))?
+        as usize;
+
+    if let Some(filter_limit) = body.filter.as_ref().and_then(|filter| filter.limit) {
+        limit = limit.min(u64::from(filter_limit) as usize);
+    }
+
+    let filter_matches = |pdu: &PduEvent| {
+        (!world_readable_only
+            || db
+                .rooms
+                .is_world_readable_at(&pdu.event_id)
+                .unwrap_or(false))
+            && body.filter.as_ref().map_or(true, |filter| {
+                client_server::room_event_filter_matches(
+                    filter,
+                    &body.room_id,
+                    Some(&pdu.sender),
+                    pdu.kind.as_ref(),
+                ) && client_server::contains_url_matches(filter, &pdu.content)
+            })
+    };
+
+    let lazy_load_members = body
+        .filter
+        .as_ref()
+        .map_or(false, client_server::lazy_load_members);
+
+    let (events_before, start_token) = client_server::collect_events(
+        &db,
+        db.rooms
+            .pdus_until(&sender_user, &body.room_id, base_token)?,
+        None,
+        limit / 2,
+        filter_matches,
+    );
+    let start_token = start_token.map(|count| count.to_string());
 
-    let start_token = events_before
-        .last()
-        .and_then(|(pdu_id, _)| db.rooms.pdu_count(pdu_id).ok())
-        .map(|count| count.to_string());
+    let (events_after, end_token) = client_server::collect_events(
+        &db,
+        db.rooms
+            .pdus_after(&sender_user, &body.room_id, base_token)?,
+        None,
+        limit / 2,
+        filter_matches,
+    );
+    let end_token = end_token.map(|count| count.to_string());
+
+    // TODO: State at event
+    let state = if lazy_load_members {
+        // Big rooms have lots of members, so only the needed member events are loaded
+        let mut state = Vec::new();
+        if let Some(shortstatehash) = db.rooms.current_shortstatehash(&body.room_id)? {
+            for (shortstatekey, event_id) in db.rooms.state_full_ids(shortstatehash)? {
+                if db.rooms.get_statekey_from_short(shortstatekey)?.0 == EventType::RoomMember {
+                    continue;
+                }
+
+                if let Some(pdu) = db.rooms.get_pdu(&event_id)? {
+                    state.push(pdu.to_state_event());
+                }
+            }
+        }
+
+        let senders = events_before
+            .iter()
+            .chain(&events_after)
+            .map(|(_, pdu)| &pdu.sender)
+            .chain(Some(&base_pdu.sender));
+        state.extend(client_server::member_events(&db, &body.room_id, senders)?);
+
+        state
+    } else {
+        db.rooms
+            .room_state_full(&body.room_id)?
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_state_event())
+            .collect()
+    };
 
     let events_before = events_before
         .into_iter()
         .map(|(_, pdu)| pdu.to_room_event())
         .collect::<Vec<_>>();
 
-    let events_after = db
-        .rooms
-        .pdus_after(&sender_user, &body.room_id, base_token)?
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
-        .filter_map(|r| r.ok()) // Remove buggy events
-        .collect::<Vec<_>>();
-
-    let end_token = events_after
-        .last()
-        .and_then(|(pdu_id, _)| db.rooms.pdu_count(pdu_id).ok())
-        .map(|count| count.to_string());
-
     let events_after = events_after
         .into_iter()
         .map(|(_, pdu)| pdu.to_room_event())
@@ -96,14 +157,9 @@ pub async fn get_context_route(
     resp.start = start_token;
     resp.end = end_token;
     resp.events_before = events_before;
-    resp.event = Some(base_event);
+    resp.event = Some(base_pdu.to_room_event());
     resp.events_after = events_after;
-    resp.state = db // TODO: State at event
-        .rooms
-        .room_state_full(&body.room_id)?
-        .values()
-        .map(|pdu| pdu.to_state_event())
-        .collect();
+    resp.state = state;
 
     Ok(resp.into())
 }
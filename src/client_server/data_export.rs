@@ -0,0 +1,74 @@
+use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
+use ruma::api::client::error::ErrorKind;
+use std::fs;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// Custom endpoint to download the data export an admin created for the user.
+pub mod get_data_export {
+    use ruma::api::ruma_api;
+
+    ruma_api! {
+        metadata: {
+            description: "Download a data export of the user.",
+            method: GET,
+            name: "get_data_export",
+            path: "/_conduit/client/v1/data_exports/:export_id",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub export_id: String,
+        }
+
+        response: {
+            #[ruma_api(raw_body)]
+            pub file: Vec<u8>,
+
+            #[ruma_api(header = CONTENT_TYPE)]
+            pub content_type: Option<String>,
+
+            #[ruma_api(header = CONTENT_DISPOSITION)]
+            pub content_disposition: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_conduit/client/v1/data_exports/{exportId}`
+///
+/// Downloads a data export of the sender user, see the `export_user_data` admin command.
+///
+/// - Exports of other users and expired exports are not found
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/v1/data_exports/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_data_export_route(
+    db: DatabaseGuard,
+    body: Ruma<get_data_export::Request>,
+) -> ConduitResult<get_data_export::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if db.users.data_export_owner(&body.export_id)?.as_ref() != Some(sender_user) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Export not found."));
+    }
+
+    let file = fs::read(db.globals.get_data_export_file(&body.export_id))
+        .map_err(|_| Error::BadRequest(ErrorKind::NotFound, "Export not found."))?;
+
+    Ok(get_data_export::Response {
+        file,
+        content_type: Some("application/json".to_owned()),
+        content_disposition: Some(format!(
+            "attachment; filename={}-export.json",
+            sender_user.localpart()
+        )),
+    }
+    .into())
+}
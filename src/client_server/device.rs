@@ -1,4 +1,7 @@
-use crate::{database::DatabaseGuard, utils, ConduitResult, Error, Ruma};
+use crate::{
+    database::{audit::AuditAction, DatabaseGuard},
+    utils, ConduitResult, Error, Ruma,
+};
 use ruma::api::client::{
     error::ErrorKind,
     r0::{
@@ -23,7 +26,7 @@ pub async fn get_devices_route(
     db: DatabaseGuard,
     body: Ruma<get_devices::Request>,
 ) -> ConduitResult<get_devices::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let devices = db
         .users
@@ -46,7 +49,7 @@ pub async fn get_device_route(
     db: DatabaseGuard,
     body: Ruma<get_device::Request<'_>>,
 ) -> ConduitResult<get_device::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let device = db
         .users
@@ -68,7 +71,7 @@ pub async fn update_device_route(
     db: DatabaseGuard,
     body: Ruma<update_device::Request<'_>>,
 ) -> ConduitResult<update_device::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let mut device = db
         .users
@@ -103,8 +106,8 @@ pub async fn delete_device_route(
     db: DatabaseGuard,
     body: Ruma<delete_device::Request<'_>>,
 ) -> ConduitResult<delete_device::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     // UIAA
     let mut uiaainfo = UiaaInfo {
@@ -123,14 +126,14 @@ pub async fn delete_device_route(
             &sender_device,
             auth,
             &uiaainfo,
-            &db.users,
-            &db.globals,
+            body.client_ip,
+            &db,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
     // Success!
-    } else if let Some(json) = body.json_body {
+    } else if let Some(json) = &body.json_body {
         uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
         db.uiaa
             .create(&sender_user, &sender_device, &uiaainfo, &json)?;
@@ -140,6 +143,14 @@ pub async fn delete_device_route(
     }
 
     db.users.remove_device(&sender_user, &body.device_id)?;
+    db.audit.record(
+        &db.globals,
+        AuditAction::DevicesDeleted {
+            user_id: sender_user.clone(),
+            device_ids: vec![body.device_id.clone()],
+        },
+        body.client_ip,
+    )?;
 
     db.flush()?;
 
@@ -166,8 +177,8 @@ pub async fn delete_devices_route(
     db: DatabaseGuard,
     body: Ruma<delete_devices::Request<'_>>,
 ) -> ConduitResult<delete_devices::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     // UIAA
     let mut uiaainfo = UiaaInfo {
@@ -186,14 +197,14 @@ pub async fn delete_devices_route(
             &sender_device,
             auth,
             &uiaainfo,
-            &db.users,
-            &db.globals,
+            body.client_ip,
+            &db,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
     // Success!
-    } else if let Some(json) = body.json_body {
+    } else if let Some(json) = &body.json_body {
         uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
         db.uiaa
             .create(&sender_user, &sender_device, &uiaainfo, &json)?;
@@ -205,6 +216,14 @@ pub async fn delete_devices_route(
     for device_id in &body.devices {
         db.users.remove_device(&sender_user, &device_id)?
     }
+    db.audit.record(
+        &db.globals,
+        AuditAction::DevicesDeleted {
+            user_id: sender_user.clone(),
+            device_ids: body.devices.clone(),
+        },
+        body.client_ip,
+    )?;
 
     db.flush()?;
 
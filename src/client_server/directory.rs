@@ -1,6 +1,11 @@
-use std::convert::TryInto;
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
 
 use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma};
+use rocket::futures::future;
 use ruma::{
     api::{
         client::{
@@ -21,18 +26,114 @@ use ruma::{
         EventType,
     },
     serde::Raw,
-    ServerName, UInt,
+    RoomId, ServerName, UInt,
 };
 use tracing::{info, warn};
 
+use get_public_rooms_filtered_with_types::PublicRoomsChunkWithType;
+
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post, put};
 
+/// How many rooms are fetched from each federated directory peer.
+const PEER_ROOM_LIMIT: u32 = 100;
+
+/// `POST /_matrix/client/r0/publicRooms` with the room type filter and the room types of MSC3827.
+pub mod get_public_rooms_filtered_with_types {
+    use ruma::{api::ruma_api, directory::PublicRoomsChunk, ServerName, UInt};
+    use serde::{Deserialize, Serialize};
+
+    /// A filter to apply to the results of a room directory query.
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct Filter {
+        /// A string to search for in the room metadata.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub generic_search_term: Option<String>,
+
+        /// Only return rooms of these types. `None` stands for rooms without a type.
+        #[serde(
+            default,
+            alias = "org.matrix.msc3827.room_types",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub room_types: Option<Vec<Option<String>>>,
+    }
+
+    impl Filter {
+        pub fn is_empty(&self) -> bool {
+            self.generic_search_term.is_none() && self.room_types.is_none()
+        }
+    }
+
+    /// A room in the room directory together with the type from its create event.
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct PublicRoomsChunkWithType {
+        #[serde(flatten)]
+        pub chunk: PublicRoomsChunk,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub room_type: Option<String>,
+    }
+
+    ruma_api! {
+        metadata: {
+            description: "Get the list of rooms in this homeserver's public directory.",
+            method: POST,
+            name: "get_public_rooms_filtered_with_types",
+            path: "/_matrix/client/r0/publicRooms",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The server to fetch the public room lists from.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub server: Option<Box<ServerName>>,
+
+            /// Limit for the number of results to return.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            /// Pagination token from a previous request.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub since: Option<String>,
+
+            /// Filter to apply to the results.
+            #[serde(default, skip_serializing_if = "Filter::is_empty")]
+            pub filter: Filter,
+        }
+
+        response: {
+            /// A paginated chunk of public rooms.
+            pub chunk: Vec<PublicRoomsChunkWithType>,
+
+            /// A pagination token for the response.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+
+            /// A pagination token that allows fetching more results.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            /// An estimate on the total number of public rooms.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub total_room_count_estimate: Option<UInt>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
 /// # `POST /_matrix/client/r0/publicRooms`
 ///
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - The directory of this server also contains the public rooms of the
+/// `federated_directory_peers`. Peers that don't answer in time are left out
+/// - `room_types` only returns rooms with one of the given types (MSC3827). The rooms of other
+/// servers have no known type, so they are only returned if rooms without a type are requested
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/publicRooms", data = "<body>")
@@ -40,15 +141,21 @@ use rocket::{get, post, put};
 #[tracing::instrument(skip(db, body))]
 pub async fn get_public_rooms_filtered_route(
     db: DatabaseGuard,
-    body: Ruma<get_public_rooms_filtered::Request<'_>>,
-) -> ConduitResult<get_public_rooms_filtered::Response> {
+    body: Ruma<get_public_rooms_filtered_with_types::Request>,
+) -> ConduitResult<get_public_rooms_filtered_with_types::Response> {
+    let filter = IncomingFilter {
+        generic_search_term: body.filter.generic_search_term.clone(),
+    };
+
     get_public_rooms_filtered_helper(
         &db,
         body.server.as_deref(),
         body.limit,
         body.since.as_deref(),
-        &body.filter,
-        &body.room_network,
+        &filter,
+        body.filter.room_types.as_deref(),
+        &IncomingRoomNetwork::Matrix,
+        true,
     )
     .await
 }
@@ -58,6 +165,8 @@ pub async fn get_public_rooms_filtered_route(
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - The directory of this server also contains the public rooms of the
+/// `federated_directory_peers`. Peers that don't answer in time are left out
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/publicRooms", data = "<body>")
@@ -73,13 +182,15 @@ pub async fn get_public_rooms_route(
         body.limit,
         body.since.as_deref(),
         &IncomingFilter::default(),
+        None,
         &IncomingRoomNetwork::Matrix,
+        true,
     )
     .await?
     .0;
 
     Ok(get_public_rooms::Response {
-        chunk: response.chunk,
+        chunk: response.chunk.into_iter().map(|c| c.chunk).collect(),
         prev_batch: response.prev_batch,
         next_batch: response.next_batch,
         total_room_count_estimate: response.total_room_count_estimate,
@@ -101,7 +212,7 @@ pub async fn set_room_visibility_route(
     db: DatabaseGuard,
     body: Ruma<set_room_visibility::Request<'_>>,
 ) -> ConduitResult<set_room_visibility::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     match &body.visibility {
         room::Visibility::Public => {
@@ -150,45 +261,26 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     limit: Option<UInt>,
     since: Option<&str>,
     filter: &IncomingFilter,
+    room_types: Option<&[Option<String>]>,
     _network: &IncomingRoomNetwork,
-) -> ConduitResult<get_public_rooms_filtered::Response> {
+    include_peers: bool,
+) -> ConduitResult<get_public_rooms_filtered_with_types::Response> {
     if let Some(other_server) = server.filter(|server| *server != db.globals.server_name().as_str())
     {
-        let response = db
-            .sending
-            .send_federation_request(
-                &db.globals,
-                other_server,
-                federation::directory::get_public_rooms_filtered::v1::Request {
-                    limit,
-                    since: since.as_deref(),
-                    filter: Filter {
-                        generic_search_term: filter.generic_search_term.as_deref(),
-                    },
-                    room_network: RoomNetwork::Matrix,
-                },
-            )
-            .await?;
-
-        return Ok(get_public_rooms_filtered::Response {
-            chunk: response
-                .chunk
-                .into_iter()
-                .map(|c| {
-                    // Convert ruma::api::federation::directory::get_public_rooms::v1::PublicRoomsChunk
-                    // to ruma::api::client::r0::directory::PublicRoomsChunk
-                    serde_json::from_str(
-                        &serde_json::to_string(&c)
-                            .expect("PublicRoomsChunk::to_string always works"),
-                    )
-                    .expect("federation and client-server PublicRoomsChunk are the same type")
-                })
-                .collect(),
-            prev_batch: response.prev_batch,
-            next_batch: response.next_batch,
-            total_room_count_estimate: response.total_room_count_estimate,
+        // Rooms of other servers have no known type, so they only match if rooms without a type
+        // do, like the rooms of peers
+        if room_types.map_or(false, |types| !types.is_empty() && !types.contains(&None)) {
+            return Ok(get_public_rooms_filtered_with_types::Response {
+                chunk: Vec::new(),
+                prev_batch: None,
+                next_batch: None,
+                total_room_count_estimate: Some(0_u32.into()),
+            }
+            .into());
         }
-        .into());
+
+        let response = remote_public_rooms(db, other_server, limit, since, filter).await?;
+        return Ok(without_room_types(response).into());
     }
 
     let limit = limit.map_or(10, u64::from);
@@ -217,159 +309,98 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         }
     }
 
-    let mut all_rooms =
-        db.rooms
-            .public_rooms()
-            .map(|room_id| {
-                let room_id = room_id?;
-
-                let chunk = PublicRoomsChunk {
-                    aliases: Vec::new(),
-                    canonical_alias: db
-                        .rooms
-                        .room_state_get(&room_id, &EventType::RoomCanonicalAlias, "")?
-                        .map_or(Ok::<_, Error>(None), |s| {
-                            Ok(serde_json::from_value::<
-                                Raw<canonical_alias::CanonicalAliasEventContent>,
-                            >(s.content.clone())
-                            .expect("from_value::<Raw<..>> can never fail")
-                            .deserialize()
-                            .map_err(|_| {
-                                Error::bad_database("Invalid canonical alias event in database.")
-                            })?
-                            .alias)
-                        })?,
-                    name: db
-                        .rooms
-                        .room_state_get(&room_id, &EventType::RoomName, "")?
-                        .map_or(Ok::<_, Error>(None), |s| {
-                            Ok(serde_json::from_value::<Raw<name::NameEventContent>>(
-                                s.content.clone(),
-                            )
-                            .expect("from_value::<Raw<..>> can never fail")
-                            .deserialize()
-                            .map_err(|_| {
-                                Error::bad_database("Invalid room name event in database.")
-                            })?
-                            .name)
-                        })?,
-                    num_joined_members: db
-                        .rooms
-                        .room_joined_count(&room_id)?
-                        .unwrap_or_else(|| {
-                            warn!("Room {} has no member count", room_id);
-                            0
-                        })
-                        .try_into()
-                        .expect("user count should not be that big"),
-                    topic: db
-                        .rooms
-                        .room_state_get(&room_id, &EventType::RoomTopic, "")?
-                        .map_or(Ok::<_, Error>(None), |s| {
-                            Ok(Some(
-                                serde_json::from_value::<Raw<topic::TopicEventContent>>(
-                                    s.content.clone(),
-                                )
-                                .expect("from_value::<Raw<..>> can never fail")
-                                .deserialize()
-                                .map_err(|_| {
-                                    Error::bad_database("Invalid room topic event in database.")
-                                })?
-                                .topic,
-                            ))
-                        })?,
-                    world_readable: db
-                        .rooms
-                        .room_state_get(&room_id, &EventType::RoomHistoryVisibility, "")?
-                        .map_or(Ok::<_, Error>(false), |s| {
-                            Ok(serde_json::from_value::<
-                                Raw<history_visibility::HistoryVisibilityEventContent>,
-                            >(s.content.clone())
-                            .expect("from_value::<Raw<..>> can never fail")
-                            .deserialize()
-                            .map_err(|_| {
-                                Error::bad_database(
-                                    "Invalid room history visibility event in database.",
-                                )
-                            })?
-                            .history_visibility
-                                == history_visibility::HistoryVisibility::WorldReadable)
-                        })?,
-                    guest_can_join: db
-                        .rooms
-                        .room_state_get(&room_id, &EventType::RoomGuestAccess, "")?
-                        .map_or(Ok::<_, Error>(false), |s| {
-                            Ok(
-                            serde_json::from_value::<Raw<guest_access::GuestAccessEventContent>>(
-                                s.content.clone(),
-                            )
-                            .expect("from_value::<Raw<..>> can never fail")
-                            .deserialize()
-                            .map_err(|_| {
-                                Error::bad_database("Invalid room guest access event in database.")
-                            })?
-                            .guest_access
-                                == guest_access::GuestAccess::CanJoin,
-                        )
-                        })?,
-                    avatar_url: db
-                        .rooms
-                        .room_state_get(&room_id, &EventType::RoomAvatar, "")?
-                        .map(|s| {
-                            Ok::<_, Error>(
-                                serde_json::from_value::<Raw<avatar::AvatarEventContent>>(
-                                    s.content.clone(),
-                                )
-                                .expect("from_value::<Raw<..>> can never fail")
-                                .deserialize()
-                                .map_err(|_| {
-                                    Error::bad_database("Invalid room avatar event in database.")
-                                })?
-                                .url,
-                            )
-                        })
-                        .transpose()?
-                        // url is now an Option<String> so we must flatten
-                        .flatten(),
-                    room_id,
-                };
-                Ok(chunk)
+    let mut all_rooms = db
+        .rooms
+        .public_rooms()
+        .map(|room_id| public_rooms_chunk(db, &room_id?))
+        .filter_map(|r: Result<_>| r.ok()) // Filter out buggy rooms
+        .filter(|c| {
+            room_types.map_or(true, |types| {
+                types.is_empty() || types.contains(&c.room_type)
             })
-            .filter_map(|r: Result<_>| r.ok()) // Filter out buggy rooms
-            .filter(|chunk| {
-                if let Some(query) = filter
-                    .generic_search_term
-                    .as_ref()
-                    .map(|q| q.to_lowercase())
-                {
-                    if let Some(name) = &chunk.name {
-                        if name.as_str().to_lowercase().contains(&query) {
-                            return true;
-                        }
+        })
+        .filter(|PublicRoomsChunkWithType { chunk, .. }| {
+            if let Some(query) = filter
+                .generic_search_term
+                .as_ref()
+                .map(|q| q.to_lowercase())
+            {
+                if let Some(name) = &chunk.name {
+                    if name.as_str().to_lowercase().contains(&query) {
+                        return true;
+                    }
+                }
+
+                if let Some(topic) = &chunk.topic {
+                    if topic.to_lowercase().contains(&query) {
+                        return true;
                     }
+                }
 
-                    if let Some(topic) = &chunk.topic {
-                        if topic.to_lowercase().contains(&query) {
-                            return true;
-                        }
+                if let Some(canonical_alias) = &chunk.canonical_alias {
+                    if canonical_alias.as_str().to_lowercase().contains(&query) {
+                        return true;
                     }
+                }
+
+                false
+            } else {
+                // No search term
+                true
+            }
+        })
+        // We need to collect all, so we can sort by member count
+        .collect::<Vec<_>>();
 
-                    if let Some(canonical_alias) = &chunk.canonical_alias {
-                        if canonical_alias.as_str().to_lowercase().contains(&query) {
-                            return true;
+    // Rooms of peers have no known type, so they only match if rooms without a type do
+    if include_peers && room_types.map_or(true, |types| types.is_empty() || types.contains(&None)) {
+        let peer_responses = future::join_all(
+            db.globals
+                .federated_directory_peers()
+                .iter()
+                .filter(|peer| &***peer != db.globals.server_name())
+                .map(|peer| async move {
+                    match tokio::time::timeout(
+                        db.globals.federated_directory_timeout(),
+                        remote_public_rooms(db, peer, Some(PEER_ROOM_LIMIT.into()), None, filter),
+                    )
+                    .await
+                    {
+                        Ok(Ok(response)) => Some(response),
+                        Ok(Err(e)) => {
+                            warn!("Failed to fetch room directory of peer {}: {}", peer, e);
+                            None
+                        }
+                        Err(_) => {
+                            warn!("Room directory of peer {} timed out", peer);
+                            None
                         }
                     }
+                }),
+        )
+        .await;
 
-                    false
-                } else {
-                    // No search term
-                    true
-                }
-            })
-            // We need to collect all, so we can sort by member count
-            .collect::<Vec<_>>();
+        // Local rooms win over the copies of peers, and peers may list the same rooms
+        let mut seen = all_rooms
+            .iter()
+            .map(|room| room.chunk.room_id.clone())
+            .collect::<HashSet<_>>();
 
-    all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
+        for chunk in peer_responses
+            .into_iter()
+            .flatten()
+            .flat_map(|response| response.chunk)
+        {
+            if seen.insert(chunk.room_id.clone()) {
+                all_rooms.push(PublicRoomsChunkWithType {
+                    chunk,
+                    room_type: None,
+                });
+            }
+        }
+    }
+
+    all_rooms.sort_by(|l, r| r.chunk.num_joined_members.cmp(&l.chunk.num_joined_members));
 
     let total_room_count_estimate = (all_rooms.len() as u32).into();
 
@@ -391,7 +422,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         Some(format!("n{}", num_since + limit))
     };
 
-    Ok(get_public_rooms_filtered::Response {
+    Ok(get_public_rooms_filtered_with_types::Response {
         chunk,
         prev_batch,
         next_batch,
@@ -399,3 +430,227 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     }
     .into())
 }
+
+/// Returns what the room directory shows about a room we have the state of.
+pub(crate) fn public_rooms_chunk(
+    db: &Database,
+    room_id: &RoomId,
+) -> Result<PublicRoomsChunkWithType> {
+    let chunk = PublicRoomsChunk {
+        aliases: Vec::new(),
+        canonical_alias: db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomCanonicalAlias, "")?
+            .map_or(Ok::<_, Error>(None), |s| {
+                Ok(
+                    serde_json::from_value::<Raw<canonical_alias::CanonicalAliasEventContent>>(
+                        s.content.clone(),
+                    )
+                    .expect("from_value::<Raw<..>> can never fail")
+                    .deserialize()
+                    .map_err(|_| Error::bad_database("Invalid canonical alias event in database."))?
+                    .alias,
+                )
+            })?,
+        name: db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomName, "")?
+            .map_or(Ok::<_, Error>(None), |s| {
+                Ok(
+                    serde_json::from_value::<Raw<name::NameEventContent>>(s.content.clone())
+                        .expect("from_value::<Raw<..>> can never fail")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid room name event in database."))?
+                        .name,
+                )
+            })?,
+        num_joined_members: db
+            .rooms
+            .room_joined_count(room_id)?
+            .unwrap_or_else(|| {
+                warn!("Room {} has no member count", room_id);
+                0
+            })
+            .try_into()
+            .expect("user count should not be that big"),
+        topic: db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomTopic, "")?
+            .map_or(Ok::<_, Error>(None), |s| {
+                Ok(Some(
+                    serde_json::from_value::<Raw<topic::TopicEventContent>>(s.content.clone())
+                        .expect("from_value::<Raw<..>> can never fail")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid room topic event in database."))?
+                        .topic,
+                ))
+            })?,
+        world_readable: db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomHistoryVisibility, "")?
+            .map_or(Ok::<_, Error>(false), |s| {
+                Ok(serde_json::from_value::<
+                    Raw<history_visibility::HistoryVisibilityEventContent>,
+                >(s.content.clone())
+                .expect("from_value::<Raw<..>> can never fail")
+                .deserialize()
+                .map_err(|_| {
+                    Error::bad_database(
+                        "Invalid room history visibility event in database.",
+                    )
+                })?
+                .history_visibility
+                    == history_visibility::HistoryVisibility::WorldReadable)
+            })?,
+        guest_can_join: db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomGuestAccess, "")?
+            .map_or(Ok::<_, Error>(false), |s| {
+                Ok(
+                    serde_json::from_value::<Raw<guest_access::GuestAccessEventContent>>(
+                        s.content.clone(),
+                    )
+                    .expect("from_value::<Raw<..>> can never fail")
+                    .deserialize()
+                    .map_err(|_| {
+                        Error::bad_database("Invalid room guest access event in database.")
+                    })?
+                    .guest_access
+                        == guest_access::GuestAccess::CanJoin,
+                )
+            })?,
+        avatar_url: db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomAvatar, "")?
+            .map(|s| {
+                Ok::<_, Error>(
+                    serde_json::from_value::<Raw<avatar::AvatarEventContent>>(s.content.clone())
+                        .expect("from_value::<Raw<..>> can never fail")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid room avatar event in database."))?
+                        .url,
+                )
+            })
+            .transpose()?
+            // url is now an Option<String> so we must flatten
+            .flatten(),
+        room_id: room_id.clone(),
+    };
+
+    let room_type = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomCreate, "")?
+        .and_then(|s| {
+            s.content
+                .get("type")
+                .and_then(|t| t.as_str())
+                .map(ToOwned::to_owned)
+        });
+
+    Ok(PublicRoomsChunkWithType { chunk, room_type })
+}
+
+/// Fetches the room directory of another server. Directories are cached for
+/// `public_rooms_cache_ttl_secs`, and an outdated directory is used if the server can't be reached.
+async fn remote_public_rooms(
+    db: &Database,
+    other_server: &ServerName,
+    limit: Option<UInt>,
+    since: Option<&str>,
+    filter: &IncomingFilter,
+) -> Result<get_public_rooms_filtered::Response> {
+    let cache_key = (
+        other_server.to_owned(),
+        limit,
+        since.map(ToOwned::to_owned),
+        filter.generic_search_term.clone(),
+    );
+
+    let cached = db
+        .globals
+        .public_rooms_cache
+        .read()
+        .unwrap()
+        .get(&cache_key)
+        .cloned();
+
+    if let Some((fetched, response)) = &cached {
+        if fetched.elapsed() < db.globals.public_rooms_cache_ttl() {
+            return Ok(response.clone());
+        }
+    }
+
+    let response = match db
+        .sending
+        .send_federation_request(
+            &db.globals,
+            other_server,
+            federation::directory::get_public_rooms_filtered::v1::Request {
+                limit,
+                since: since.as_deref(),
+                filter: Filter {
+                    generic_search_term: filter.generic_search_term.as_deref(),
+                },
+                room_network: RoomNetwork::Matrix,
+            },
+        )
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            // Better show an outdated directory than none at all
+            if let Some((_, response)) = cached {
+                warn!(
+                    "Failed to fetch room directory of {}, using cached one: {}",
+                    other_server, e
+                );
+                return Ok(response);
+            }
+            return Err(e);
+        }
+    };
+
+    let response = get_public_rooms_filtered::Response {
+        chunk: response
+            .chunk
+            .into_iter()
+            .map(|c| {
+                // Convert ruma::api::federation::directory::get_public_rooms::v1::PublicRoomsChunk
+                // to ruma::api::client::r0::directory::PublicRoomsChunk
+                serde_json::from_str(
+                    &serde_json::to_string(&c).expect("PublicRoomsChunk::to_string always works"),
+                )
+                .expect("federation and client-server PublicRoomsChunk are the same type")
+            })
+            .collect(),
+        prev_batch: response.prev_batch,
+        next_batch: response.next_batch,
+        total_room_count_estimate: response.total_room_count_estimate,
+    };
+
+    let mut cache = db.globals.public_rooms_cache.write().unwrap();
+    // Stale entries are only kept for a day
+    cache.retain(|_, (fetched, _)| fetched.elapsed() < Duration::from_secs(24 * 60 * 60));
+    cache.insert(cache_key, (Instant::now(), response.clone()));
+
+    Ok(response)
+}
+
+/// Remote directories are fetched without room types, because the federation API doesn't tell us.
+fn without_room_types(
+    response: get_public_rooms_filtered::Response,
+) -> get_public_rooms_filtered_with_types::Response {
+    get_public_rooms_filtered_with_types::Response {
+        chunk: response
+            .chunk
+            .into_iter()
+            .map(|chunk| PublicRoomsChunkWithType {
+                chunk,
+                room_type: None,
+            })
+            .collect(),
+        prev_batch: response.prev_batch,
+        next_batch: response.next_batch,
+        total_room_count_estimate: response.total_room_count_estimate,
+    }
+}
@@ -1,32 +1,231 @@
-use crate::{utils, ConduitResult};
-use ruma::api::client::r0::filter::{self, create_filter, get_filter};
+use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::{
+            filter::{
+                self, create_filter, get_filter, IncomingFilterDefinition, IncomingRoomEventFilter,
+                IncomingRoomFilter, LazyLoadOptions,
+            },
+            sync::sync_events,
+        },
+    },
+    RoomId, UserId,
+};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
+/// Sync returns this many timeline events per room if the filter doesn't set a limit.
+const DEFAULT_TIMELINE_LIMIT: usize = 10;
+/// Clients can't ask for more timeline events per room than this.
+const MAX_TIMELINE_LIMIT: usize = 100;
+/// Filtered timelines look at no more than this many events per request, so that a filter which
+/// matches few events can't make the server walk through the whole room.
+pub(crate) const MAX_SCANNED_EVENTS: usize = 1000;
+
 /// # `GET /_matrix/client/r0/user/{userId}/filter/{filterId}`
 ///
-/// TODO: Loads a filter that was previously created.
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/user/<_>/filter/<_>"))]
-#[tracing::instrument]
-pub async fn get_filter_route() -> ConduitResult<get_filter::Response> {
-    // TODO
-    Ok(get_filter::Response::new(filter::IncomingFilterDefinition {
+/// Loads a filter that was previously created.
+///
+/// - A user can only load their own filters
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/user/<_>/filter/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_filter_route(
+    db: DatabaseGuard,
+    body: Ruma<get_filter::Request<'_>>,
+) -> ConduitResult<get_filter::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only load your own filters.",
+        ));
+    }
+
+    let filter = db
+        .users
+        .get_filter(sender_user, &body.filter_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))?;
+
+    Ok(get_filter::Response::new(filter).into())
+}
+
+/// # `POST /_matrix/client/r0/user/{userId}/filter`
+///
+/// Creates a new filter to be used by other endpoints.
+///
+/// - A user can only create filters for themselves
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/user/<_>/filter", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn create_filter_route(
+    db: DatabaseGuard,
+    body: Ruma<create_filter::Request<'_>>,
+) -> ConduitResult<create_filter::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only create filters for yourself.",
+        ));
+    }
+
+    // Store the filter the way the client sent it, so it can load it again unchanged
+    let json = body
+        .json_body
+        .as_ref()
+        .ok_or(Error::BadRequest(ErrorKind::NotJson, "Not json."))?;
+
+    let filter_id = db.users.create_filter(sender_user, json)?;
+
+    db.flush()?;
+
+    Ok(create_filter::Response::new(filter_id).into())
+}
+
+/// A filter that lets everything through.
+pub(crate) fn empty_filter() -> IncomingFilterDefinition {
+    IncomingFilterDefinition {
         event_fields: None,
         event_format: filter::EventFormat::default(),
         account_data: filter::IncomingFilter::default(),
-        room: filter::IncomingRoomFilter::default(),
+        room: IncomingRoomFilter::default(),
         presence: filter::IncomingFilter::default(),
+    }
+}
+
+/// Returns the filter definition of a /sync request, loading filter ids from the database.
+pub(crate) fn load_sync_filter(
+    db: &Database,
+    user_id: &UserId,
+    filter: Option<&sync_events::IncomingFilter>,
+) -> Result<IncomingFilterDefinition> {
+    match filter {
+        None => Ok(empty_filter()),
+        Some(sync_events::IncomingFilter::FilterDefinition(definition)) => Ok(definition.clone()),
+        Some(sync_events::IncomingFilter::FilterId(filter_id)) => db
+            .users
+            .get_filter(user_id, filter_id)?
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found.")),
+    }
+}
+
+/// Returns how many timeline events sync should return for each room.
+pub(crate) fn timeline_limit(filter: &IncomingRoomEventFilter) -> usize {
+    filter
+        .limit
+        .map_or(DEFAULT_TIMELINE_LIMIT, |limit| {
+            u64::from(limit).min(MAX_TIMELINE_LIMIT as u64) as usize
+        })
+        .max(1)
+}
+
+/// Returns true if the room filter lets the room through.
+pub(crate) fn room_filter_matches(filter: &IncomingRoomFilter, room_id: &RoomId) -> bool {
+    !contains(&filter.not_rooms, room_id.as_str())
+        && filter
+            .rooms
+            .as_ref()
+            .map_or(true, |rooms| contains(rooms, room_id.as_str()))
+}
+
+/// Returns true if the filter lets a room event of this type and sender through. Events without a
+/// sender, like receipts or room account data, are only filtered by room and type.
+pub(crate) fn room_event_filter_matches(
+    filter: &IncomingRoomEventFilter,
+    room_id: &RoomId,
+    sender: Option<&UserId>,
+    kind: &str,
+) -> bool {
+    !contains(&filter.not_rooms, room_id.as_str())
+        && filter
+            .rooms
+            .as_ref()
+            .map_or(true, |rooms| contains(rooms, room_id.as_str()))
+        && sender.map_or(true, |sender| {
+            senders_match(&filter.senders, &filter.not_senders, sender)
+        })
+        && types_match(&filter.types, &filter.not_types, kind)
+}
+
+/// Returns true if `contains_url` of the filter lets an event with this content through. Events
+/// with a url are e.g. images and files.
+pub(crate) fn contains_url_matches(
+    filter: &IncomingRoomEventFilter,
+    content: &serde_json::Value,
+) -> bool {
+    filter.contains_url.map_or(true, |contains_url| {
+        content.get("url").map_or(false, |url| url.is_string()) == contains_url
     })
-    .into())
 }
 
-/// # `PUT /_matrix/client/r0/user/{userId}/filter`
-///
-/// TODO: Creates a new filter to be used by other endpoints.
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/user/<_>/filter"))]
-#[tracing::instrument]
-pub async fn create_filter_route() -> ConduitResult<create_filter::Response> {
-    // TODO
-    Ok(create_filter::Response::new(utils::random_string(10)).into())
+/// Returns true if the client only wants the members of the senders of the events it gets.
+pub(crate) fn lazy_load_members(filter: &IncomingRoomEventFilter) -> bool {
+    matches!(filter.lazy_load_options, LazyLoadOptions::Enabled { .. })
+}
+
+/// Returns true if the filter lets an event of this type and sender through. Events without a
+/// sender, like global account data, are only filtered by type.
+pub(crate) fn event_filter_matches(
+    filter: &filter::IncomingFilter,
+    sender: Option<&UserId>,
+    kind: &str,
+) -> bool {
+    sender.map_or(true, |sender| {
+        senders_match(&filter.senders, &filter.not_senders, sender)
+    }) && types_match(&filter.types, &filter.not_types, kind)
+}
+
+fn contains<T: AsRef<str>>(list: &[T], value: &str) -> bool {
+    list.iter().any(|entry| entry.as_ref() == value)
+}
+
+fn senders_match<T: AsRef<str>>(allowed: &Option<Vec<T>>, denied: &[T], sender: &UserId) -> bool {
+    !contains(denied, sender.as_str())
+        && allowed
+            .as_ref()
+            .map_or(true, |allowed| contains(allowed, sender.as_str()))
+}
+
+/// Event types in filters can use `*` as a wildcard. Excluded types win over allowed types.
+fn types_match(allowed: &Option<Vec<String>>, denied: &[String], kind: &str) -> bool {
+    !denied.iter().any(|pattern| wildcard_matches(pattern, kind))
+        && allowed.as_ref().map_or(true, |allowed| {
+            allowed
+                .iter()
+                .any(|pattern| wildcard_matches(pattern, kind))
+        })
+}
+
+fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // No wildcard at all
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
 }
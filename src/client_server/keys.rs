@@ -1,5 +1,9 @@
 use super::SESSION_ID_LENGTH;
-use crate::{database::DatabaseGuard, utils, ConduitResult, Database, Error, Result, Ruma};
+use crate::{
+    database::{globals::RemoteUserKeys, DatabaseGuard},
+    ruma_wrapper::ExtendedResponse,
+    utils, ConduitResult, Database, Error, Result, Ruma,
+};
 use rocket::futures::{prelude::*, stream::FuturesUnordered};
 use ruma::{
     api::{
@@ -16,10 +20,14 @@ use ruma::{
         federation,
     },
     encryption::UnsignedDeviceInfo,
-    DeviceId, DeviceKeyAlgorithm, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
 };
 use serde_json::json;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::watch;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
@@ -29,6 +37,7 @@ use rocket::{get, post};
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
+/// - Replaces the fallback keys of the device, also under the unstable `org.matrix.msc2732` name
 /// - If there are no device keys yet: Adds device keys (TODO: merge with existing keys?)
 #[cfg_attr(
     feature = "conduit_bin",
@@ -39,8 +48,8 @@ pub async fn upload_keys_route(
     db: DatabaseGuard,
     body: Ruma<upload_keys::Request>,
 ) -> ConduitResult<upload_keys::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     if let Some(one_time_keys) = &body.one_time_keys {
         for (key_key, key_value) in one_time_keys {
@@ -54,6 +63,27 @@ pub async fn upload_keys_route(
         }
     }
 
+    // Ruma doesn't know fallback keys yet, so they are read from the json body
+    if let Some(json) = body.json_body.as_ref().and_then(|json| json.as_object()) {
+        for name in &["fallback_keys", "org.matrix.msc2732.fallback_keys"] {
+            let fallback_keys = match json.get(*name) {
+                Some(fallback_keys) => {
+                    serde_json::to_value(fallback_keys).expect("canonical json is valid json")
+                }
+                None => continue,
+            };
+            let fallback_keys = serde_json::from_value::<BTreeMap<DeviceKeyId, serde_json::Value>>(
+                fallback_keys,
+            )
+            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Fallback keys are invalid."))?;
+
+            for (key_id, key) in fallback_keys {
+                db.users
+                    .add_fallback_key(sender_user, sender_device, &key_id, key)?;
+            }
+        }
+    }
+
     if let Some(device_keys) = &body.device_keys {
         // TODO: merge this and the existing event?
         // This check is needed to assure that signatures are kept
@@ -96,7 +126,7 @@ pub async fn get_keys_route(
     db: DatabaseGuard,
     body: Ruma<get_keys::Request<'_>>,
 ) -> ConduitResult<get_keys::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let response = get_keys_helper(
         Some(sender_user),
@@ -120,12 +150,16 @@ pub async fn get_keys_route(
 pub async fn claim_keys_route(
     db: DatabaseGuard,
     body: Ruma<claim_keys::Request>,
-) -> ConduitResult<claim_keys::Response> {
-    let response = claim_keys_helper(&body.one_time_keys, &db).await?;
+) -> Result<ExtendedResponse<claim_keys::Response>> {
+    let (response, overrides) = claim_keys_helper(&body.one_time_keys, &db).await?;
 
     db.flush()?;
 
-    Ok(response.into())
+    Ok(ExtendedResponse {
+        response,
+        extra_fields: serde_json::Map::new(),
+        overrides,
+    })
 }
 
 /// # `POST /_matrix/client/r0/keys/device_signing/upload`
@@ -142,8 +176,8 @@ pub async fn upload_signing_keys_route(
     db: DatabaseGuard,
     body: Ruma<upload_signing_keys::Request<'_>>,
 ) -> ConduitResult<upload_signing_keys::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     // UIAA
     let mut uiaainfo = UiaaInfo {
@@ -162,14 +196,14 @@ pub async fn upload_signing_keys_route(
             &sender_device,
             auth,
             &uiaainfo,
-            &db.users,
-            &db.globals,
+            body.client_ip,
+            &db,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
     // Success!
-    } else if let Some(json) = body.json_body {
+    } else if let Some(json) = &body.json_body {
         uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
         db.uiaa
             .create(&sender_user, &sender_device, &uiaainfo, &json)?;
@@ -206,7 +240,7 @@ pub async fn upload_signatures_route(
     db: DatabaseGuard,
     body: Ruma<upload_signatures::Request>,
 ) -> ConduitResult<upload_signatures::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     for (user_id, signed_keys) in &body.signed_keys {
         for (key_id, signed_key) in signed_keys {
@@ -262,6 +296,7 @@ pub async fn upload_signatures_route(
 ///
 /// Gets a list of users who have updated their device identity keys since the previous sync token.
 ///
+/// - Fails with M_UNKNOWN_TOKEN if `from` or `to` are not valid sync tokens
 /// - TODO: left users
 #[cfg_attr(
     feature = "conduit_bin",
@@ -272,38 +307,23 @@ pub async fn get_key_changes_route(
     db: DatabaseGuard,
     body: Ruma<get_key_changes::Request<'_>>,
 ) -> ConduitResult<get_key_changes::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    let from = db.globals.parse_sync_token(&body.from)?;
+    let to = db.globals.parse_sync_token(&body.to)?;
 
     let mut device_list_updates = HashSet::new();
 
     device_list_updates.extend(
         db.users
-            .keys_changed(
-                &sender_user.to_string(),
-                body.from
-                    .parse()
-                    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`."))?,
-                Some(
-                    body.to
-                        .parse()
-                        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`."))?,
-                ),
-            )
+            .keys_changed(&sender_user.to_string(), from, Some(to))
             .filter_map(|r| r.ok()),
     );
 
     for room_id in db.rooms.rooms_joined(sender_user).filter_map(|r| r.ok()) {
         device_list_updates.extend(
             db.users
-                .keys_changed(
-                    &room_id.to_string(),
-                    body.from.parse().map_err(|_| {
-                        Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`.")
-                    })?,
-                    Some(body.to.parse().map_err(|_| {
-                        Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`.")
-                    })?),
-                )
+                .keys_changed(&room_id.to_string(), from, Some(to))
                 .filter_map(|r| r.ok()),
         );
     }
@@ -326,13 +346,43 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     let mut device_keys = BTreeMap::new();
 
     let mut get_over_federation = HashMap::new();
+    // Remote users whose keys are in the cache or are being fetched by another request
+    let mut cached_remote_keys = Vec::new();
+    let mut waiting_for = Vec::new();
+    let mut in_flight = InFlightKeyQueries {
+        db,
+        user_ids: Vec::new(),
+    };
 
     for (user_id, device_ids) in device_keys_input {
         if user_id.server_name() != db.globals.server_name() {
-            get_over_federation
-                .entry(user_id.server_name())
-                .or_insert_with(Vec::new)
-                .push((user_id, device_ids));
+            let version = db.users.remote_devicelist_id(user_id)?;
+            if let Some(keys) =
+                version.and_then(|version| db.globals.cached_remote_keys(user_id, version))
+            {
+                cached_remote_keys.push((user_id, device_ids, keys));
+                continue;
+            }
+
+            match db
+                .globals
+                .remote_keys_receivers
+                .lock()
+                .unwrap()
+                .entry(user_id.clone())
+            {
+                Entry::Occupied(o) => waiting_for.push((user_id, device_ids, o.get().clone())),
+                Entry::Vacant(v) => {
+                    let (tx, rx) = watch::channel(None);
+                    v.insert(rx);
+                    in_flight.user_ids.push(user_id.clone());
+
+                    get_over_federation
+                        .entry(user_id.server_name())
+                        .or_insert_with(Vec::new)
+                        .push((user_id, device_ids, version, tx));
+                }
+            }
             continue;
         }
 
@@ -398,12 +448,15 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     let mut futures = get_over_federation
         .into_iter()
         .map(|(server, vec)| async move {
-            let mut device_keys_input_fed = BTreeMap::new();
-            for (user_id, keys) in vec {
-                device_keys_input_fed.insert(user_id.clone(), keys.clone());
-            }
+            // We always ask for all devices, so the answer can be cached and shared with other
+            // requests
+            let device_keys_input_fed = vec
+                .iter()
+                .map(|(user_id, ..)| ((*user_id).clone(), Vec::new()))
+                .collect();
             (
                 server,
+                vec,
                 db.sending
                     .send_federation_request(
                         &db.globals,
@@ -417,19 +470,67 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
         })
         .collect::<FuturesUnordered<_>>();
 
-    while let Some((server, response)) = futures.next().await {
+    let mut fetched_remote_keys = Vec::new();
+    while let Some((server, vec, response)) = futures.next().await {
         match response {
-            Ok(response) => {
-                master_keys.extend(response.master_keys);
-                self_signing_keys.extend(response.self_signing_keys);
-                device_keys.extend(response.device_keys);
+            Ok(mut response) => {
+                for (user_id, device_ids, version, tx) in vec {
+                    let keys = Arc::new(RemoteUserKeys {
+                        device_keys: response.device_keys.remove(user_id).unwrap_or_default(),
+                        master_key: response.master_keys.remove(user_id),
+                        self_signing_key: response.self_signing_keys.remove(user_id),
+                    });
+
+                    if let Some(version) = version {
+                        db.globals
+                            .remote_keys_cache
+                            .lock()
+                            .unwrap()
+                            .insert(user_id.clone(), (version, Arc::clone(&keys)));
+                    }
+
+                    let _ = tx.send(Some(Arc::clone(&keys)));
+                    fetched_remote_keys.push((user_id, device_ids, keys));
+                }
             }
             Err(_e) => {
+                // Dropping the senders tells waiting requests about the failure
                 failures.insert(server.to_string(), json!({}));
             }
         }
     }
 
+    for (user_id, device_ids, mut rx) in waiting_for {
+        let mut keys = rx.borrow().clone();
+        if keys.is_none() && rx.changed().await.is_ok() {
+            keys = rx.borrow().clone();
+        }
+
+        match keys {
+            Some(keys) => fetched_remote_keys.push((user_id, device_ids, keys)),
+            None => {
+                failures.insert(user_id.server_name().to_string(), json!({}));
+            }
+        }
+    }
+
+    for (user_id, device_ids, keys) in cached_remote_keys.into_iter().chain(fetched_remote_keys) {
+        let container = keys
+            .device_keys
+            .iter()
+            .filter(|(device_id, _)| device_ids.is_empty() || device_ids.contains(device_id))
+            .map(|(device_id, keys)| (device_id.clone(), keys.clone()))
+            .collect();
+        device_keys.insert(user_id.clone(), container);
+
+        if let Some(master_key) = &keys.master_key {
+            master_keys.insert(user_id.clone(), master_key.clone());
+        }
+        if let Some(self_signing_key) = &keys.self_signing_key {
+            self_signing_keys.insert(user_id.clone(), self_signing_key.clone());
+        }
+    }
+
     Ok(get_keys::Response {
         master_keys,
         self_signing_keys,
@@ -439,11 +540,32 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     })
 }
 
+/// Forgets the running key queries of a request when it's done, even if it was cancelled.
+struct InFlightKeyQueries<'a> {
+    db: &'a Database,
+    user_ids: Vec<UserId>,
+}
+
+impl Drop for InFlightKeyQueries<'_> {
+    fn drop(&mut self) {
+        let mut receivers = self.db.globals.remote_keys_receivers.lock().unwrap();
+        for user_id in &self.user_ids {
+            receivers.remove(user_id);
+        }
+    }
+}
+
+/// Claims the keys and returns them with the fallback keys as they were uploaded. Ruma drops
+/// fields like `fallback` from them, so they have to be merged into the response.
 pub(crate) async fn claim_keys_helper(
     one_time_keys_input: &BTreeMap<UserId, BTreeMap<Box<DeviceId>, DeviceKeyAlgorithm>>,
     db: &Database,
-) -> Result<claim_keys::Response> {
+) -> Result<(
+    claim_keys::Response,
+    serde_json::Map<String, serde_json::Value>,
+)> {
     let mut one_time_keys = BTreeMap::new();
+    let mut raw_fallback_keys = serde_json::Map::new();
 
     let mut get_over_federation = BTreeMap::new();
 
@@ -456,17 +578,43 @@ pub(crate) async fn claim_keys_helper(
         }
 
         let mut container = BTreeMap::new();
+        let mut raw_container = serde_json::Map::new();
         for (device_id, key_algorithm) in map {
-            if let Some(one_time_keys) =
-                db.users
+            let one_time_key =
+                match db
+                    .users
                     .take_one_time_key(user_id, device_id, key_algorithm, &db.globals)?
-            {
+                {
+                    Some(one_time_key) => Some(one_time_key),
+                    // Devices that ran out of one-time keys can still be reached with their fallback key
+                    None => match db
+                        .users
+                        .claim_fallback_key(user_id, device_id, key_algorithm)?
+                    {
+                        Some((key_id, raw_key)) => {
+                            let key = serde_json::from_value(raw_key.clone()).map_err(|_| {
+                                Error::bad_database("FallbackKey in db is invalid.")
+                            })?;
+                            raw_container.insert(
+                                device_id.to_string(),
+                                json!({ key_id.to_string(): raw_key }),
+                            );
+                            Some((key_id, key))
+                        }
+                        None => None,
+                    },
+                };
+
+            if let Some(one_time_key) = one_time_key {
                 let mut c = BTreeMap::new();
-                c.insert(one_time_keys.0, one_time_keys.1);
+                c.insert(one_time_key.0, one_time_key.1);
                 container.insert(device_id.clone(), c);
             }
         }
         one_time_keys.insert(user_id.clone(), container);
+        if !raw_container.is_empty() {
+            raw_fallback_keys.insert(user_id.to_string(), raw_container.into());
+        }
     }
 
     let mut failures = BTreeMap::new();
@@ -494,8 +642,16 @@ pub(crate) async fn claim_keys_helper(
         }
     }
 
-    Ok(claim_keys::Response {
-        failures,
-        one_time_keys,
-    })
+    let mut overrides = serde_json::Map::new();
+    if !raw_fallback_keys.is_empty() {
+        overrides.insert("one_time_keys".to_owned(), raw_fallback_keys.into());
+    }
+
+    Ok((
+        claim_keys::Response {
+            failures,
+            one_time_keys,
+        },
+        overrides,
+    ))
 }
@@ -41,6 +41,8 @@ pub async fn create_content_route(
     db: DatabaseGuard,
     body: Ruma<create_content::Request<'_>>,
 ) -> ConduitResult<create_content::Response> {
+    let sender_user = body.authenticated_user()?;
+
     let mxc = format!(
         "mxc://{}/{}",
         db.globals.server_name(),
@@ -60,6 +62,7 @@ pub async fn create_content_route(
             &body.file,
         )
         .await?;
+    db.media.record_upload(sender_user, &mxc)?;
 
     db.flush()?;
 
@@ -75,6 +78,7 @@ pub async fn create_content_route(
 /// Load media from our server or over federation.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Quarantined media is not found
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/media/r0/download/<_>/<_>", data = "<body>")
@@ -86,6 +90,10 @@ pub async fn get_content_route(
 ) -> ConduitResult<get_content::Response> {
     let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
+    if db.media.is_quarantined(&mxc)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
     if let Some(FileMeta {
         content_disposition,
         content_type,
@@ -133,6 +141,7 @@ pub async fn get_content_route(
 /// Load media thumbnail from our server or over federation.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Quarantined media is not found
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/media/r0/thumbnail/<_>/<_>", data = "<body>")
@@ -144,6 +153,10 @@ pub async fn get_content_thumbnail_route(
 ) -> ConduitResult<get_content_thumbnail::Response> {
     let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
+    if db.media.is_quarantined(&mxc)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
     if let Some(FileMeta {
         content_type, file, ..
     }) = db
@@ -1,6 +1,6 @@
 use crate::{
     client_server,
-    database::DatabaseGuard,
+    database::{appservice::Namespaces, users::InvitePolicy, DatabaseGuard},
     pdu::{PduBuilder, PduEvent},
     server_server, utils, ConduitResult, Database, Error, Result, Ruma,
 };
@@ -9,30 +9,43 @@ use ruma::{
     api::{
         client::{
             error::ErrorKind,
-            r0::membership::{
-                ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
-                join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
-                unban_user, IncomingThirdPartySigned,
+            r0::{
+                knock::knock_room,
+                membership::{
+                    ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
+                    join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
+                    unban_user, IncomingThirdPartySigned,
+                },
             },
         },
         federation::{self, membership::create_invite},
     },
     events::{
         pdu::Pdu,
-        room::{create::CreateEventContent, member},
+        room::{
+            canonical_alias::CanonicalAliasEventContent,
+            create::CreateEventContent,
+            guest_access::{GuestAccess, GuestAccessEventContent},
+            history_visibility::{HistoryVisibility, HistoryVisibilityEventContent},
+            join_rules::{JoinRule, JoinRulesEventContent},
+            member,
+            power_levels::PowerLevelsEventContent,
+        },
         EventType,
     },
     serde::{to_canonical_value, CanonicalJsonObject, CanonicalJsonValue, Raw},
     state_res::{self, RoomVersion},
-    uint, EventId, RoomId, RoomVersionId, ServerName, UserId,
+    uint, EventId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
+use serde::Deserialize;
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
-use tracing::{debug, error, warn};
+use tokio::sync::MutexGuard;
+use tracing::{debug, error, info, warn};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
@@ -52,7 +65,7 @@ pub async fn join_room_by_id_route(
     db: DatabaseGuard,
     body: Ruma<join_room_by_id::Request<'_>>,
 ) -> ConduitResult<join_room_by_id::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let mut servers = db
         .rooms
@@ -99,7 +112,7 @@ pub async fn join_room_by_id_or_alias_route(
     db: DatabaseGuard,
     body: Ruma<join_room_by_id_or_alias::Request<'_>>,
 ) -> ConduitResult<join_room_by_id_or_alias::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let (servers, room_id) = match RoomId::try_from(body.room_id_or_alias.clone()) {
         Ok(room_id) => {
@@ -144,6 +157,93 @@ pub async fn join_room_by_id_or_alias_route(
     .into())
 }
 
+/// # `POST /_matrix/client/r0/knock/{roomIdOrAlias}`
+///
+/// Asks to be let into a room with the `knock` join rule.
+///
+/// - Only works for rooms a user of this server is joined to. Knocking over federation is not
+/// supported yet: This server neither calls nor answers `make_knock` and `send_knock`, so users
+/// can't knock on rooms of other servers and users of other servers can't knock on our rooms
+/// - Moderators answer the knock by inviting or kicking the user
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/knock/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn knock_room_route(
+    db: DatabaseGuard,
+    body: Ruma<knock_room::Request<'_>>,
+) -> ConduitResult<knock_room::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let room_id = match RoomId::try_from(body.room_id_or_alias.clone()) {
+        Ok(room_id) => room_id,
+        Err(room_alias) => {
+            client_server::get_alias_helper(&db, &room_alias)
+                .await?
+                .0
+                .room_id
+        }
+    };
+
+    if !db
+        .rooms
+        .server_in_room(db.globals.server_name(), &room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Knocking on rooms this server is not in is not supported yet.",
+        ));
+    }
+
+    if db.rooms.is_joined(sender_user, &room_id)? || db.rooms.is_invited(sender_user, &room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are already in the room or invited to it.",
+        ));
+    }
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let event = member::MemberEventContent {
+        membership: member::MembershipState::Knock,
+        displayname: db.users.displayname(&sender_user)?,
+        avatar_url: db.users.avatar_url(&sender_user)?,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: db.users.blurhash(&sender_user)?,
+        reason: body.reason.clone(),
+    };
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(event).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(sender_user.to_string()),
+            redacts: None,
+        },
+        &sender_user,
+        &room_id,
+        &db,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    db.flush()?;
+
+    Ok(knock_room::Response { room_id }.into())
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -158,7 +258,7 @@ pub async fn leave_room_route(
     db: DatabaseGuard,
     body: Ruma<leave_room::Request<'_>>,
 ) -> ConduitResult<leave_room::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.rooms.leave_room(sender_user, &body.room_id, &db).await?;
 
@@ -179,7 +279,7 @@ pub async fn invite_user_route(
     db: DatabaseGuard,
     body: Ruma<invite_user::Request<'_>>,
 ) -> ConduitResult<invite_user::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if let invite_user::IncomingInvitationRecipient::UserId { user_id } = &body.recipient {
         invite_helper(sender_user, user_id, &body.room_id, &db, false).await?;
@@ -202,7 +302,7 @@ pub async fn kick_user_route(
     db: DatabaseGuard,
     body: Ruma<kick_user::Request<'_>>,
 ) -> ConduitResult<kick_user::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let mut event = serde_json::from_value::<Raw<ruma::events::room::member::MemberEventContent>>(
         db.rooms
@@ -223,7 +323,8 @@ pub async fn kick_user_route(
     .map_err(|_| Error::bad_database("Invalid member event in database."))?;
 
     event.membership = ruma::events::room::member::MembershipState::Leave;
-    // TODO: reason
+    // Don't repeat the reason of the previous membership, e.g. the one the user gave for knocking
+    event.reason = body.reason.clone();
 
     let mutex_state = Arc::clone(
         db.globals
@@ -268,25 +369,41 @@ pub async fn ban_user_route(
     db: DatabaseGuard,
     body: Ruma<ban_user::Request<'_>>,
 ) -> ConduitResult<ban_user::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    // TODO: reason
+    send_ban(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.user_id,
+        body.reason.clone(),
+    )
+    .await?;
+
+    db.flush()?;
+
+    Ok(ban_user::Response::new().into())
+}
 
-    let event = db
+/// Sends a ban event for `user_id` into the room. The profile of an existing member event is kept.
+async fn send_ban(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    user_id: &UserId,
+    reason: Option<String>,
+) -> Result<()> {
+    let mut event = db
         .rooms
-        .room_state_get(
-            &body.room_id,
-            &EventType::RoomMember,
-            &body.user_id.to_string(),
-        )?
+        .room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?
         .map_or(
             Ok::<_, Error>(member::MemberEventContent {
                 membership: member::MembershipState::Ban,
-                displayname: db.users.displayname(&body.user_id)?,
-                avatar_url: db.users.avatar_url(&body.user_id)?,
+                displayname: db.users.displayname(user_id)?,
+                avatar_url: db.users.avatar_url(user_id)?,
                 is_direct: None,
                 third_party_invite: None,
-                blurhash: db.users.blurhash(&body.user_id)?,
+                blurhash: db.users.blurhash(user_id)?,
                 reason: None,
             }),
             |event| {
@@ -300,13 +417,14 @@ pub async fn ban_user_route(
                 Ok(event)
             },
         )?;
+    event.reason = reason;
 
     let mutex_state = Arc::clone(
         db.globals
             .roomid_mutex_state
             .write()
             .unwrap()
-            .entry(body.room_id.clone())
+            .entry(room_id.clone())
             .or_default(),
     );
     let state_lock = mutex_state.lock().await;
@@ -316,20 +434,18 @@ pub async fn ban_user_route(
             event_type: EventType::RoomMember,
             content: serde_json::to_value(event).expect("event is valid, we just created it"),
             unsigned: None,
-            state_key: Some(body.user_id.to_string()),
+            state_key: Some(user_id.to_string()),
             redacts: None,
         },
-        &sender_user,
-        &body.room_id,
-        &db,
+        sender_user,
+        room_id,
+        db,
         &state_lock,
     )?;
 
     drop(state_lock);
 
-    db.flush()?;
-
-    Ok(ban_user::Response::new().into())
+    Ok(())
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/unban`
@@ -344,7 +460,7 @@ pub async fn unban_user_route(
     db: DatabaseGuard,
     body: Ruma<unban_user::Request<'_>>,
 ) -> ConduitResult<unban_user::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let mut event = serde_json::from_value::<Raw<ruma::events::room::member::MemberEventContent>>(
         db.rooms
@@ -414,7 +530,7 @@ pub async fn forget_room_route(
     db: DatabaseGuard,
     body: Ruma<forget_room::Request<'_>>,
 ) -> ConduitResult<forget_room::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.rooms.forget(&body.room_id, &sender_user)?;
 
@@ -435,7 +551,7 @@ pub async fn joined_rooms_route(
     db: DatabaseGuard,
     body: Ruma<joined_rooms::Request>,
 ) -> ConduitResult<joined_rooms::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     Ok(joined_rooms::Response {
         joined_rooms: db
@@ -447,11 +563,13 @@ pub async fn joined_rooms_route(
     .into())
 }
 
-/// # `POST /_matrix/client/r0/rooms/{roomId}/members`
+/// # `GET /_matrix/client/r0/rooms/{roomId}/members`
 ///
-/// Lists all joined users in a room (TODO: at a specific point in time, with a specific membership).
+/// Lists the member events of a room.
 ///
 /// - Only works if the user is currently joined
+/// - With `at`, returns the members at that sync or pagination token instead of the current ones
+/// - `membership` and `not_membership` only return members with or without that membership
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/members", data = "<body>")
@@ -461,7 +579,7 @@ pub async fn get_member_events_route(
     db: DatabaseGuard,
     body: Ruma<get_member_events::Request<'_>>,
 ) -> ConduitResult<get_member_events::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     // TODO: check history visibility?
     if !db.rooms.is_joined(sender_user, &body.room_id)? {
@@ -471,18 +589,379 @@ pub async fn get_member_events_route(
         ));
     }
 
+    let state = match body.at {
+        Some(at) => {
+            // Clients pass the next_batch of a sync or a prev_batch
+            let at = db.globals.parse_pagination_token(at)?;
+
+            match state_at_token(&db, sender_user, &body.room_id, at)? {
+                Some(shortstatehash) => db.rooms.state_full(shortstatehash)?,
+                None => HashMap::new(),
+            }
+        }
+        None => db.rooms.room_state_full(&body.room_id)?,
+    };
+
     Ok(get_member_events::Response {
-        chunk: db
-            .rooms
-            .room_state_full(&body.room_id)?
+        chunk: state
             .iter()
             .filter(|(key, _)| key.0 == EventType::RoomMember)
+            .filter(|(_, pdu)| {
+                let membership = pdu
+                    .content
+                    .get("membership")
+                    .and_then(|membership| membership.as_str());
+
+                body.membership
+                    .as_ref()
+                    .map_or(true, |filter| Some(filter.as_ref()) == membership)
+                    && body
+                        .not_membership
+                        .as_ref()
+                        .map_or(true, |filter| Some(filter.as_ref()) != membership)
+            })
             .map(|(_, pdu)| pdu.to_member_event())
             .collect(),
     }
     .into())
 }
 
+/// Returns the state of the room a client saw at a sync token. Tokens this server never handed
+/// out fall back to the state after the last event before the token. Returns None if the room
+/// had no events yet.
+fn state_at_token(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    token: u64,
+) -> Result<Option<u64>> {
+    if let Some(shortstatehash) = db.rooms.get_token_shortstatehash(room_id, token)? {
+        return Ok(Some(shortstatehash));
+    }
+
+    match db
+        .rooms
+        .pdus_until(user_id, room_id, token.saturating_add(1))?
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .next()
+    {
+        Some((_, pdu)) => db.rooms.pdu_shortstatehash(&pdu.event_id),
+        None => Ok(None),
+    }
+}
+
+/// Returns the current power levels of a room, or the defaults if the room has none.
+fn room_power_levels(db: &Database, room_id: &RoomId) -> Result<PowerLevelsEventContent> {
+    db.rooms
+        .room_state_get(room_id, &EventType::RoomPowerLevels, "")?
+        .map(|pdu| {
+            serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+pub mod get_knocks {
+    use ruma::{
+        api::ruma_api,
+        events::{room::member::MemberEventContent, StateEvent},
+        serde::Raw,
+        RoomId,
+    };
+
+    ruma_api! {
+        metadata: {
+            description: "Get the membership events of users that knocked on a room.",
+            method: GET,
+            name: "get_knocks",
+            path: "/_matrix/client/unstable/rs.conduit/rooms/:room_id/knocks",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room to list the knocks of.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+        }
+
+        response: {
+            /// The knock membership events, oldest first.
+            pub knocks: Vec<Raw<StateEvent<MemberEventContent>>>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/unstable/rs.conduit/rooms/{roomId}/knocks`
+///
+/// Lists the users that knocked on a room and are waiting for an answer. Knocks are accepted by
+/// inviting the user and rejected by kicking them.
+///
+/// - The sender user must be in the room and be allowed to invite or kick users
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/unstable/rs.conduit/rooms/<_>/knocks",
+        data = "<body>"
+    )
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_knocks_route(
+    db: DatabaseGuard,
+    body: Ruma<get_knocks::Request>,
+) -> ConduitResult<get_knocks::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You aren't a member of the room.",
+        ));
+    }
+
+    let power_levels = room_power_levels(&db, &body.room_id)?;
+
+    let sender_level = power_levels
+        .users
+        .get(sender_user)
+        .unwrap_or(&power_levels.users_default);
+
+    if sender_level < &power_levels.invite && sender_level < &power_levels.kick {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not allowed to answer knocks in this room.",
+        ));
+    }
+
+    // The knock counts grow over time, so sorting by them puts the oldest knocks first
+    let mut knocked = db
+        .rooms
+        .room_members_knocked(&body.room_id)
+        .filter_map(|r| r.ok())
+        .collect::<Vec<_>>();
+    knocked.sort_unstable_by_key(|(_, count)| *count);
+
+    let mut knocks = Vec::new();
+    for (user_id, _) in knocked {
+        if let Some(pdu) =
+            db.rooms
+                .room_state_get(&body.room_id, &EventType::RoomMember, user_id.as_str())?
+        {
+            if pdu.content.get("membership").and_then(|m| m.as_str()) == Some("knock") {
+                knocks.push(pdu.to_member_event());
+            }
+        }
+    }
+
+    Ok(get_knocks::Response { knocks }.into())
+}
+
+pub mod get_bans {
+    use ruma::{api::ruma_api, RoomId, UserId};
+    use serde::{Deserialize, Serialize};
+
+    /// A banned user and the reason of the ban.
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct BanEntry {
+        pub user_id: UserId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub reason: Option<String>,
+    }
+
+    ruma_api! {
+        metadata: {
+            description: "Export the ban list of a room.",
+            method: GET,
+            name: "get_bans",
+            path: "/_matrix/client/unstable/rs.conduit/rooms/:room_id/bans",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room to export the bans of.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+        }
+
+        response: {
+            /// The banned users.
+            pub bans: Vec<BanEntry>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub mod import_bans {
+    use super::get_bans::BanEntry;
+    use ruma::{api::ruma_api, RoomId, UserId};
+    use std::collections::BTreeMap;
+
+    ruma_api! {
+        metadata: {
+            description: "Ban all users of a ban list in a room.",
+            method: POST,
+            name: "import_bans",
+            path: "/_matrix/client/unstable/rs.conduit/rooms/:room_id/bans",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room to ban the users in.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The users to ban, e.g. from the export of another room.
+            pub bans: Vec<BanEntry>,
+        }
+
+        response: {
+            /// The users that were banned. Users that were already banned are not included.
+            pub banned: Vec<UserId>,
+
+            /// The users that could not be banned and why.
+            pub failed: BTreeMap<UserId, String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/unstable/rs.conduit/rooms/{roomId}/bans`
+///
+/// Lists the banned users of a room and the reasons of their bans.
+///
+/// - The sender user must be in the room
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/unstable/rs.conduit/rooms/<_>/bans", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_bans_route(
+    db: DatabaseGuard,
+    body: Ruma<get_bans::Request>,
+) -> ConduitResult<get_bans::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You aren't a member of the room.",
+        ));
+    }
+
+    let mut bans = db
+        .rooms
+        .room_state_full(&body.room_id)?
+        .into_iter()
+        .filter(|((kind, _), _)| kind == &EventType::RoomMember)
+        .filter(|(_, pdu)| pdu.content.get("membership").and_then(|m| m.as_str()) == Some("ban"))
+        .filter_map(|((_, state_key), pdu)| {
+            Some(get_bans::BanEntry {
+                user_id: UserId::try_from(state_key).ok()?,
+                reason: pdu
+                    .content
+                    .get("reason")
+                    .and_then(|r| r.as_str())
+                    .map(ToOwned::to_owned),
+            })
+        })
+        .collect::<Vec<_>>();
+    bans.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+    Ok(get_bans::Response { bans }.into())
+}
+
+/// # `POST /_matrix/client/unstable/rs.conduit/rooms/{roomId}/bans`
+///
+/// Bans every user of a ban list in a room, e.g. to apply the bans of one room to all rooms of a
+/// community.
+///
+/// - The sender user must be in the room and be allowed to ban users
+/// - Users that are already banned are skipped
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/unstable/rs.conduit/rooms/<_>/bans", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn import_bans_route(
+    db: DatabaseGuard,
+    body: Ruma<import_bans::Request>,
+) -> ConduitResult<import_bans::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You aren't a member of the room.",
+        ));
+    }
+
+    let power_levels = room_power_levels(&db, &body.room_id)?;
+
+    let sender_level = power_levels
+        .users
+        .get(sender_user)
+        .unwrap_or(&power_levels.users_default);
+
+    if sender_level < &power_levels.ban {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not allowed to ban users in this room.",
+        ));
+    }
+
+    let mut banned = Vec::new();
+    let mut failed = BTreeMap::new();
+
+    for ban in &body.bans {
+        let already_banned = db
+            .rooms
+            .room_state_get(&body.room_id, &EventType::RoomMember, ban.user_id.as_str())?
+            .map_or(false, |pdu| {
+                pdu.content.get("membership").and_then(|m| m.as_str()) == Some("ban")
+            });
+
+        if already_banned || banned.contains(&ban.user_id) {
+            continue;
+        }
+
+        match send_ban(
+            &db,
+            sender_user,
+            &body.room_id,
+            &ban.user_id,
+            ban.reason.clone(),
+        )
+        .await
+        {
+            Ok(()) => banned.push(ban.user_id.clone()),
+            Err(e) => {
+                // Only tell clients what went wrong if the error was meant for them
+                let reason = match e {
+                    Error::BadRequest(_, message) => message.to_owned(),
+                    e => {
+                        warn!("Failed to ban {}: {}", ban.user_id, e);
+                        "Internal server error.".to_owned()
+                    }
+                };
+                failed.insert(ban.user_id.clone(), reason);
+            }
+        }
+    }
+
+    db.flush()?;
+
+    Ok(import_bans::Response { banned, failed }.into())
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/joined_members`
 ///
 /// Lists all members of a room.
@@ -498,7 +977,7 @@ pub async fn joined_members_route(
     db: DatabaseGuard,
     body: Ruma<joined_members::Request<'_>>,
 ) -> ConduitResult<joined_members::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if !db.rooms.is_joined(&sender_user, &body.room_id)? {
         return Err(Error::BadRequest(
@@ -532,7 +1011,12 @@ async fn join_room_by_id_helper(
     servers: &HashSet<Box<ServerName>>,
     _third_party_signed: Option<&IncomingThirdPartySigned>,
 ) -> ConduitResult<join_room_by_id::Response> {
-    let sender_user = sender_user.expect("user is authenticated");
+    let sender_user = sender_user.ok_or(Error::BadRequest(
+        ErrorKind::MissingToken,
+        "This endpoint requires an access token.",
+    ))?;
+
+    check_join_limits(db, sender_user, room_id)?;
 
     let mutex_state = Arc::clone(
         db.globals
@@ -559,7 +1043,7 @@ async fn join_room_by_id_helper(
                     federation::membership::create_join_event_template::v1::Request {
                         room_id,
                         user_id: sender_user,
-                        ver: &[RoomVersionId::Version5, RoomVersionId::Version6],
+                        ver: &db.globals.supported_room_versions(),
                     },
                 )
                 .await;
@@ -574,12 +1058,7 @@ async fn join_room_by_id_helper(
         let (make_join_response, remote_server) = make_join_response_and_server?;
 
         let room_version = match make_join_response.room_version {
-            Some(room_version)
-                if room_version == RoomVersionId::Version5
-                    || room_version == RoomVersionId::Version6 =>
-            {
-                room_version
-            }
+            Some(room_version) if db.globals.supports_room_version(&room_version) => room_version,
             _ => return Err(Error::BadServerResponse("Room version is not supported")),
         };
 
@@ -602,35 +1081,49 @@ async fn join_room_by_id_helper(
                     .expect("Timestamp is valid js_int value"),
             ),
         );
-        join_event_stub.insert(
-            "content".to_owned(),
-            to_canonical_value(member::MemberEventContent {
-                membership: member::MembershipState::Join,
-                displayname: db.users.displayname(&sender_user)?,
-                avatar_url: db.users.avatar_url(&sender_user)?,
-                is_direct: None,
-                third_party_invite: None,
-                blurhash: db.users.blurhash(&sender_user)?,
-                reason: None,
-            })
-            .expect("event is valid, we just created it"),
-        );
-
-        // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
-        join_event_stub.remove("event_id");
 
-        // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
-        ruma::signatures::hash_and_sign_event(
-            db.globals.server_name().as_str(),
-            db.globals.keypair(),
-            &mut join_event_stub,
-            &room_version,
-        )
-        .expect("event is valid, we just created it");
+        // Joins to restricted rooms name the user of the resident server that authorised them
+        let join_authorised_via_users_server = match join_event_stub.get("content") {
+            Some(CanonicalJsonValue::Object(content)) => {
+                content.get("join_authorised_via_users_server").cloned()
+            }
+            _ => None,
+        };
 
-        // Generate event id
-        let event_id = EventId::try_from(&*format!(
-            "${}",
+        let mut content = to_canonical_value(member::MemberEventContent {
+            membership: member::MembershipState::Join,
+            displayname: db.users.displayname(&sender_user)?,
+            avatar_url: db.users.avatar_url(&sender_user)?,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: db.users.blurhash(&sender_user)?,
+            reason: None,
+        })
+        .expect("event is valid, we just created it");
+
+        if let (CanonicalJsonValue::Object(content), Some(authoriser)) =
+            (&mut content, join_authorised_via_users_server)
+        {
+            content.insert("join_authorised_via_users_server".to_owned(), authoriser);
+        }
+
+        join_event_stub.insert("content".to_owned(), content);
+
+        // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
+        join_event_stub.remove("event_id");
+
+        // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
+        ruma::signatures::hash_and_sign_event(
+            db.globals.server_name().as_str(),
+            &*db.globals.keypair(),
+            &mut join_event_stub,
+            &room_version,
+        )
+        .expect("event is valid, we just created it");
+
+        // Generate event id
+        let event_id = EventId::try_from(&*format!(
+            "${}",
             ruma::signatures::reference_hash(&join_event_stub, &room_version)
                 .expect("ruma can calculate reference hashes")
         ))
@@ -643,11 +1136,11 @@ async fn join_room_by_id_helper(
         );
 
         // It has enough fields to be called a proper event now
-        let join_event = join_event_stub;
+        let mut join_event = join_event_stub;
 
-        let send_join_response = db
+        let (send_join_response, send_join_body) = db
             .sending
-            .send_federation_request(
+            .send_federation_request_with_body(
                 &db.globals,
                 remote_server,
                 federation::membership::create_join_event::v2::Request {
@@ -658,6 +1151,35 @@ async fn join_room_by_id_helper(
             )
             .await?;
 
+        // Restricted joins come back with the signature of the authorising server, which other
+        // servers need to accept the join
+        if let Some(signed_event) = serde_json::from_slice::<serde_json::Value>(&send_join_body)
+            .ok()
+            .and_then(|mut body| body.get_mut("event").map(|event| event.take()))
+        {
+            let mut signed_event = serde_json::from_value::<CanonicalJsonObject>(signed_event)
+                .map_err(|_| Error::BadServerResponse("Invalid signed join event."))?;
+
+            let signed_event_id = EventId::try_from(&*format!(
+                "${}",
+                ruma::signatures::reference_hash(&signed_event, &room_version)
+                    .map_err(|_| Error::BadServerResponse("Invalid signed join event."))?
+            ))
+            .expect("ruma's reference hashes are valid event ids");
+
+            if signed_event_id != event_id {
+                return Err(Error::BadServerResponse(
+                    "Signed join event does not match our join event.",
+                ));
+            }
+
+            signed_event.insert(
+                "event_id".to_owned(),
+                CanonicalJsonValue::String(event_id.as_str().to_owned()),
+            );
+            join_event = signed_event;
+        }
+
         db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
 
         let pdu = PduEvent::from_id_val(&event_id, join_event.clone())
@@ -755,7 +1277,17 @@ async fn join_room_by_id_helper(
         // We set the room state after inserting the pdu, so that we never have a moment in time
         // where events in the current room state do not exist
         db.rooms.set_room_state(&room_id, statehashid)?;
+
+        if let Some(join_rules) =
+            db.rooms
+                .room_state_get(&room_id, &EventType::RoomJoinRules, "")?
+        {
+            db.rooms
+                .update_restricted_allow_rooms(&room_id, &join_rules.content)?;
+        }
     } else {
+        check_restricted_join(&db, &sender_user, &room_id)?;
+
         let event = member::MemberEventContent {
             membership: member::MembershipState::Join,
             displayname: db.users.displayname(&sender_user)?,
@@ -766,10 +1298,16 @@ async fn join_room_by_id_helper(
             reason: None,
         };
 
+        let mut content = serde_json::to_value(event).expect("event is valid, we just created it");
+
+        if let Some(authoriser) = restricted_join_authoriser(&db, &sender_user, &room_id)? {
+            content["join_authorised_via_users_server"] = authoriser.as_str().into();
+        }
+
         db.rooms.build_and_append_pdu(
             PduBuilder {
                 event_type: EventType::RoomMember,
-                content: serde_json::to_value(event).expect("event is valid, we just created it"),
+                content,
                 unsigned: None,
                 state_key: Some(sender_user.to_string()),
                 redacts: None,
@@ -870,12 +1408,9 @@ pub(crate) async fn invite_helper<'a>(
             );
             let state_lock = mutex_state.lock().await;
 
-            let prev_events = db
+            let (prev_events, _) = db
                 .rooms
-                .get_pdu_leaves(room_id)?
-                .into_iter()
-                .take(20)
-                .collect::<Vec<_>>();
+                .select_prev_events(room_id, db.globals.max_forward_extremities() as usize)?;
 
             let create_event = db
                 .rooms
@@ -1008,7 +1543,7 @@ pub(crate) async fn invite_helper<'a>(
 
             ruma::signatures::hash_and_sign_event(
                 db.globals.server_name().as_str(),
-                db.globals.keypair(),
+                &*db.globals.keypair(),
                 &mut pdu_json,
                 &room_version_id,
             )
@@ -1104,6 +1639,9 @@ pub(crate) async fn invite_helper<'a>(
         return Ok(());
     }
 
+    check_invite_limits(db, user_id, room_id)?;
+    check_invite_policy(db, sender_user, user_id)?;
+
     let mutex_state = Arc::clone(
         db.globals
             .roomid_mutex_state
@@ -1137,7 +1675,719 @@ pub(crate) async fn invite_helper<'a>(
         &state_lock,
     )?;
 
+    if let Err(e) = grant_appservice_power_level(sender_user, user_id, room_id, db, &state_lock) {
+        warn!(
+            "Failed to grant the default power level to appservice user {} in {}: {}",
+            user_id, room_id, e
+        );
+    }
+
     drop(state_lock);
 
     Ok(())
 }
+
+/// Raises the power level of an invited appservice user to the default level of its appservice.
+/// Only happens if the inviter could change the power levels to that level by hand.
+fn grant_appservice_power_level(
+    sender_user: &UserId,
+    user_id: &UserId,
+    room_id: &RoomId,
+    db: &Database,
+    state_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
+    let level = match db.appservice.default_power_level(user_id, &db.globals)? {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+
+    let mut power_levels = room_power_levels(db, room_id)?;
+
+    let user_level = |user: &UserId| {
+        power_levels
+            .users
+            .get(user)
+            .copied()
+            .unwrap_or(power_levels.users_default)
+    };
+    let sender_level = user_level(sender_user);
+    let required_level = power_levels
+        .events
+        .get(&EventType::RoomPowerLevels)
+        .copied()
+        .unwrap_or(power_levels.state_default);
+
+    if user_level(user_id) >= level || sender_level < level || sender_level < required_level {
+        return Ok(());
+    }
+
+    power_levels.users.insert(user_id.clone(), level);
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: EventType::RoomPowerLevels,
+            content: serde_json::to_value(power_levels)
+                .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some("".to_owned()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        db,
+        state_lock,
+    )?;
+
+    Ok(())
+}
+
+/// Custom endpoint for appservices to change many memberships in a room at once.
+pub mod batch_membership {
+    use ruma::{api::ruma_api, events::room::member::MembershipState, EventId, RoomId, UserId};
+    use serde::{Deserialize, Serialize};
+
+    ruma_api! {
+        metadata: {
+            description: "Change the memberships of many appservice users in a room.",
+            method: POST,
+            name: "batch_membership",
+            path: "/_conduit/appservice/v1/rooms/:room_id/batch_membership",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room in which the memberships are changed.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The membership changes in the order they should be applied.
+            pub changes: Vec<MembershipChange>,
+        }
+
+        response: {
+            /// One result for every requested change.
+            pub results: Vec<MembershipChangeResult>,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct MembershipChange {
+        pub user_id: UserId,
+        pub membership: MembershipState,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct MembershipChangeResult {
+        pub user_id: UserId,
+
+        /// The new member event, missing if the user already had the requested membership.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub event_id: Option<EventId>,
+
+        /// Why the membership could not be changed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+}
+
+const MAX_BATCH_MEMBERSHIP_CHANGES: usize = 1000;
+
+/// # `POST /_conduit/appservice/v1/rooms/{roomId}/batch_membership`
+///
+/// Changes the memberships of many users of an appservice in a room at once.
+///
+/// - Only appservices can use this and all users have to be in the namespace of the appservice
+/// - The room state is locked once for the whole batch instead of once per membership
+/// - Joins and leaves are sent by the users themselves, invites by the sender of the request
+/// - Only works in rooms this server is already in and only for local users
+/// - Users that already have the requested membership are skipped
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_conduit/appservice/v1/rooms/<_>/batch_membership", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn batch_membership_route(
+    db: DatabaseGuard,
+    body: Ruma<batch_membership::Request>,
+) -> ConduitResult<batch_membership::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !body.from_appservice {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only appservices can change memberships in batches.",
+        ));
+    }
+
+    if body.changes.len() > MAX_BATCH_MEMBERSHIP_CHANGES {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Too many membership changes in one batch.",
+        ));
+    }
+
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Batches only work in rooms this server is already in.",
+        ));
+    }
+
+    let namespaces = requesting_appservice_namespaces(&db, body.appservice_id.as_deref())?;
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let results = body
+        .changes
+        .iter()
+        .map(|change| {
+            let result = apply_membership_change(
+                &db,
+                sender_user,
+                &body.room_id,
+                change,
+                &namespaces,
+                &state_lock,
+            );
+
+            batch_membership::MembershipChangeResult {
+                user_id: change.user_id.clone(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                event_id: result.ok().flatten(),
+            }
+        })
+        .collect();
+
+    drop(state_lock);
+
+    db.flush()?;
+
+    Ok(batch_membership::Response { results }.into())
+}
+
+/// Returns the namespaces of the appservice that sent the request.
+fn requesting_appservice_namespaces(
+    db: &Database,
+    appservice_id: Option<&str>,
+) -> Result<Namespaces> {
+    let registration = appservice_id
+        .map(|id| db.appservice.get_registration(id))
+        .transpose()?
+        .flatten()
+        .ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Sender is not a user of an appservice.",
+        ))?;
+
+    Ok(Namespaces::from_registration(
+        &registration,
+        db.globals.server_name(),
+    ))
+}
+
+/// Applies one change of a membership batch and returns the new member event, if any.
+fn apply_membership_change(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    change: &batch_membership::MembershipChange,
+    namespaces: &Namespaces,
+    state_lock: &MutexGuard<'_, ()>,
+) -> Result<Option<EventId>> {
+    let user_id = &change.user_id;
+
+    if user_id.server_name() != db.globals.server_name()
+        || !namespaces.matches_user(user_id.as_str())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "User is not in the namespace of the appservice.",
+        ));
+    }
+
+    if !db.users.exists(user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "User does not exist.",
+        ));
+    }
+
+    let (unchanged, sender) = match change.membership {
+        MembershipState::Join => {
+            check_join_limits(db, user_id, room_id)?;
+            check_restricted_join(db, user_id, room_id)?;
+            (db.rooms.is_joined(user_id, room_id)?, user_id)
+        }
+        MembershipState::Invite => {
+            check_invite_limits(db, user_id, room_id)?;
+            check_invite_policy(db, sender_user, user_id)?;
+            (
+                db.rooms.is_joined(user_id, room_id)? || db.rooms.is_invited(user_id, room_id)?,
+                sender_user,
+            )
+        }
+        MembershipState::Leave => (
+            !db.rooms.is_joined(user_id, room_id)? && !db.rooms.is_invited(user_id, room_id)?,
+            user_id,
+        ),
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Only join, invite and leave can be used in batches.",
+            ))
+        }
+    };
+
+    if unchanged {
+        return Ok(None);
+    }
+
+    let with_profile = !matches!(change.membership, MembershipState::Leave);
+    let content = MemberEventContent {
+        membership: change.membership.clone(),
+        displayname: if with_profile {
+            db.users.displayname(user_id)?
+        } else {
+            None
+        },
+        avatar_url: if with_profile {
+            db.users.avatar_url(user_id)?
+        } else {
+            None
+        },
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: if with_profile {
+            db.users.blurhash(user_id)?
+        } else {
+            None
+        },
+        reason: None,
+    };
+
+    let event_id = db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(content).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        },
+        sender,
+        room_id,
+        db,
+        state_lock,
+    )?;
+
+    Ok(Some(event_id))
+}
+
+/// Makes sure that joining the room does not exceed the configured resource limits.
+///
+/// - Users that are already joined can always send a new join event (e.g. profile changes)
+/// - Checks the number of rooms the local user is joined to
+/// - Checks the number of local users that are joined to the room
+pub(crate) fn check_join_limits(db: &Database, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    if user_id.server_name() != db.globals.server_name() || db.rooms.is_joined(user_id, room_id)? {
+        return Ok(());
+    }
+
+    if let Some(max) = db.globals.max_joined_rooms_per_user() {
+        if db.rooms.rooms_joined(user_id).count() >= max as usize {
+            return Err(Error::BadRequest(
+                ErrorKind::ResourceLimitExceeded {
+                    admin_contact: db.globals.admin_contact(),
+                },
+                "User is already joined to the maximum number of rooms.",
+            ));
+        }
+    }
+
+    if let Some(max) = db.globals.max_local_members_per_room() {
+        let local_members = db
+            .rooms
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|member| member.server_name() == db.globals.server_name())
+            .count();
+
+        if local_members >= max as usize {
+            return Err(Error::BadRequest(
+                ErrorKind::ResourceLimitExceeded {
+                    admin_contact: db.globals.admin_contact(),
+                },
+                "Room already has the maximum number of local members.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure that a local user does not collect more pending invites than configured.
+pub(crate) fn check_invite_limits(db: &Database, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    if user_id.server_name() != db.globals.server_name() || db.rooms.is_invited(user_id, room_id)? {
+        return Ok(());
+    }
+
+    if let Some(max) = db.globals.max_pending_invites_per_user() {
+        if db.rooms.rooms_invited(user_id).count() >= max as usize {
+            return Err(Error::BadRequest(
+                ErrorKind::ResourceLimitExceeded {
+                    admin_contact: db.globals.admin_contact(),
+                },
+                "User has too many pending invites.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct InvitePolicyEvent {
+    content: InvitePolicyEventContent,
+}
+
+#[derive(Deserialize)]
+struct InvitePolicyEventContent {
+    policy: InvitePolicy,
+}
+
+/// Rejects invites the local user doesn't want to receive according to their
+/// `rs.conduit.invite_policy` account data or the server's default policy.
+pub(crate) fn check_invite_policy(db: &Database, sender: &UserId, user_id: &UserId) -> Result<()> {
+    if user_id.server_name() != db.globals.server_name() {
+        return Ok(());
+    }
+
+    let policy = db
+        .account_data
+        .get::<InvitePolicyEvent>(None, user_id, EventType::from("rs.conduit.invite_policy"))?
+        .map_or_else(
+            || db.globals.default_invite_policy(),
+            |event| event.content.policy,
+        );
+
+    let allowed = match policy {
+        InvitePolicy::Everyone => true,
+        InvitePolicy::KnownServers => {
+            sender.server_name() == db.globals.server_name()
+                || db
+                    .rooms
+                    .rooms_joined(user_id)
+                    .filter_map(|r| r.ok())
+                    .any(|room_id| {
+                        db.rooms
+                            .server_in_room(sender.server_name(), &room_id)
+                            .unwrap_or(false)
+                    })
+        }
+        InvitePolicy::SharedRooms => db
+            .rooms
+            .get_shared_rooms(vec![sender.clone(), user_id.clone()])?
+            .next()
+            .is_some(),
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This user does not accept invites from you.",
+        ))
+    }
+}
+
+/// Makes sure that users only join restricted rooms if they are a member of one of the rooms in
+/// the allow list, unless they were invited.
+pub(crate) fn check_restricted_join(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> Result<()> {
+    if !matches!(room_join_rule(db, room_id)?, Some(JoinRule::Restricted(_)))
+        || db.rooms.is_joined(user_id, room_id)?
+        || db.rooms.is_invited(user_id, room_id)?
+    {
+        return Ok(());
+    }
+
+    if db.rooms.can_join_restricted(user_id, room_id)? {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "User is not a member of any room in the allow list of this room.",
+        ))
+    }
+}
+
+/// Picks a user of this server that may invite to the room, to authorise a join to a restricted
+/// room in the `join_authorised_via_users_server` field of room versions 8 and 9. Returns None if
+/// the join doesn't need it.
+pub(crate) fn restricted_join_authoriser(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> Result<Option<UserId>> {
+    if !matches!(
+        db.rooms.room_version(room_id)?,
+        Some(RoomVersionId::Version8) | Some(RoomVersionId::Version9)
+    ) || !matches!(room_join_rule(db, room_id)?, Some(JoinRule::Restricted(_)))
+        || db.rooms.is_joined(user_id, room_id)?
+        || db.rooms.is_invited(user_id, room_id)?
+    {
+        return Ok(None);
+    }
+
+    let power_levels = room_power_levels(db, room_id)?;
+
+    for member in db.rooms.room_members(room_id) {
+        let member = member?;
+
+        if member.server_name() == db.globals.server_name()
+            && power_levels
+                .users
+                .get(&member)
+                .unwrap_or(&power_levels.users_default)
+                >= &power_levels.invite
+        {
+            return Ok(Some(member));
+        }
+    }
+
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "No user of this server may authorise joins to this room.",
+    ))
+}
+
+fn room_join_rule(db: &Database, room_id: &RoomId) -> Result<Option<JoinRule>> {
+    Ok(db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomJoinRules, "")?
+        .map(|join_rules| {
+            serde_json::from_value::<Raw<JoinRulesEventContent>>(join_rules.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid join rules event in db."))
+        })
+        .transpose()?
+        .map(|content| content.join_rule))
+}
+
+/// Joins a newly registered user to the `auto_join_rooms` of the config. The @conduit bot invites
+/// the user to rooms that can't be joined without an invite first. Local aliases that don't exist
+/// yet are created as public rooms owned by the @conduit bot.
+///
+/// Failures are only logged, because they should not block the registration.
+async fn auto_join_rooms(db: &Database, user_id: &UserId) {
+    for room in db.globals.auto_join_rooms() {
+        if let Err(e) = auto_join_room(db, user_id, room).await {
+            warn!("Failed to auto-join {} to {}: {}", user_id, room, e);
+        }
+    }
+}
+
+/// Runs `auto_join_rooms` in the background, so the registration doesn't wait for joins over
+/// federation.
+pub(crate) fn spawn_auto_join_rooms(db: Arc<DatabaseGuard>, user_id: UserId) {
+    tokio::spawn(async move {
+        auto_join_rooms(&db, &user_id).await;
+
+        if let Err(e) = db.flush() {
+            warn!("Failed to flush after auto-joining {}: {}", user_id, e);
+        }
+    });
+}
+
+async fn auto_join_room(db: &Database, user_id: &UserId, room: &RoomIdOrAliasId) -> Result<()> {
+    let (servers, room_id) = match RoomId::try_from(room.clone()) {
+        Ok(room_id) => {
+            let mut servers = HashSet::new();
+            servers.insert(room_id.server_name().to_owned());
+            (servers, room_id)
+        }
+        Err(room_alias) => match client_server::get_alias_helper(db, &room_alias).await {
+            Ok(response) => (response.0.servers.into_iter().collect(), response.0.room_id),
+            Err(_)
+                if room_alias.server_name() == db.globals.server_name()
+                    && db.rooms.id_from_alias(&room_alias)?.is_none() =>
+            {
+                let room_id = create_auto_join_room(db, &room_alias).await?;
+                (HashSet::new(), room_id)
+            }
+            Err(e) => return Err(e),
+        },
+    };
+
+    let join_rule = db
+        .rooms
+        .room_state_get(&room_id, &EventType::RoomJoinRules, "")?
+        .map(|join_rules| {
+            serde_json::from_value::<Raw<JoinRulesEventContent>>(join_rules.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid join rules event in db."))
+        })
+        .transpose()?
+        .map(|content| content.join_rule);
+
+    if join_rule.map_or(false, |join_rule| !matches!(join_rule, JoinRule::Public))
+        && !db.rooms.is_invited(user_id, &room_id)?
+    {
+        let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+            .expect("@conduit:server_name is valid");
+
+        if !db.rooms.is_joined(&conduit_user, &room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Room is not public and the server user is not in it.",
+            ));
+        }
+
+        invite_helper(&conduit_user, user_id, &room_id, db, false).await?;
+    }
+
+    join_room_by_id_helper(db, Some(user_id), &room_id, &servers, None).await?;
+
+    Ok(())
+}
+
+/// Creates a public room owned by the @conduit bot for an auto-join alias that doesn't exist yet.
+/// Returns the room of the alias if another registration created it in the meantime.
+async fn create_auto_join_room(db: &Database, alias: &RoomAliasId) -> Result<RoomId> {
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    if !db.users.exists(&conduit_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "The server user doesn't exist yet.",
+        ));
+    }
+
+    // Concurrent registrations would each create a room and only the last one keeps the alias
+    let mutex_createroom = Arc::clone(
+        db.globals
+            .userid_mutex_createroom
+            .write()
+            .unwrap()
+            .entry(conduit_user.clone())
+            .or_default(),
+    );
+    let _createroom_lock = mutex_createroom.lock().await;
+
+    if let Some(room_id) = db.rooms.id_from_alias(alias)? {
+        return Ok(room_id);
+    }
+
+    let room_id = RoomId::new(db.globals.server_name());
+    db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let mut create_content = CreateEventContent::new(conduit_user.clone());
+    create_content.federate = true;
+    create_content.room_version = RoomVersionId::Version6;
+
+    let mut users = BTreeMap::new();
+    users.insert(conduit_user.clone(), 100.into());
+
+    let events = vec![
+        (
+            EventType::RoomCreate,
+            serde_json::to_value(create_content),
+            String::new(),
+        ),
+        (
+            EventType::RoomMember,
+            serde_json::to_value(MemberEventContent {
+                membership: MembershipState::Join,
+                displayname: None,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+                blurhash: None,
+                reason: None,
+            }),
+            conduit_user.to_string(),
+        ),
+        (
+            EventType::RoomPowerLevels,
+            serde_json::to_value(PowerLevelsEventContent {
+                users,
+                ..Default::default()
+            }),
+            String::new(),
+        ),
+        (
+            EventType::RoomJoinRules,
+            serde_json::to_value(JoinRulesEventContent::new(JoinRule::Public)),
+            String::new(),
+        ),
+        (
+            EventType::RoomHistoryVisibility,
+            serde_json::to_value(HistoryVisibilityEventContent::new(
+                HistoryVisibility::Shared,
+            )),
+            String::new(),
+        ),
+        (
+            EventType::RoomGuestAccess,
+            serde_json::to_value(GuestAccessEventContent::new(GuestAccess::Forbidden)),
+            String::new(),
+        ),
+        (
+            EventType::RoomCanonicalAlias,
+            serde_json::to_value(CanonicalAliasEventContent {
+                alias: Some(alias.clone()),
+                alt_aliases: Vec::new(),
+            }),
+            String::new(),
+        ),
+    ];
+
+    for (event_type, content, state_key) in events {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type,
+                content: content.expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(state_key),
+                redacts: None,
+            },
+            &conduit_user,
+            &room_id,
+            db,
+            &state_lock,
+        )?;
+    }
+
+    db.rooms.set_alias(alias, Some(&room_id), &db.globals)?;
+
+    info!("Created {} for the auto-join alias {}", room_id, alias);
+
+    Ok(room_id)
+}
@@ -1,14 +1,18 @@
-use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Ruma};
+use crate::{
+    client_server, database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Database, Error,
+    PduEvent, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::message::{get_message_events, send_message_event},
     },
-    events::EventType,
-    EventId,
+    events::{AnyStateEvent, EventType},
+    serde::Raw,
+    EventId, RoomId, UserId,
 };
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     convert::{TryFrom, TryInto},
     sync::Arc,
 };
@@ -23,6 +27,7 @@ use rocket::{get, put};
 /// - Is a NOOP if the txn id was already used before and returns the same event id again
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
+/// - Fails with M_LIMIT_EXCEEDED if the user exhausted their message budget
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/send/<_>/<_>", data = "<body>")
@@ -32,7 +37,7 @@ pub async fn send_message_event_route(
     db: DatabaseGuard,
     body: Ruma<send_message_event::Request<'_>>,
 ) -> ConduitResult<send_message_event::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
     let sender_device = body.sender_device.as_deref();
 
     let mutex_state = Arc::clone(
@@ -67,14 +72,31 @@ pub async fn send_message_event_route(
         return Ok(send_message_event::Response { event_id }.into());
     }
 
+    db.globals.check_message_limit(
+        sender_user,
+        body.from_appservice,
+        db.reports.is_under_review(sender_user)?,
+    )?;
+
+    let event_type = EventType::from(&body.event_type);
+    let content = serde_json::from_str(body.body.body.json().get())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    if event_type == EventType::Reaction {
+        db.rooms.check_annotation(
+            sender_user,
+            &content,
+            db.globals.max_reaction_keys_per_event(),
+        )?;
+    }
+
     let mut unsigned = BTreeMap::new();
     unsigned.insert("transaction_id".to_owned(), body.txn_id.clone().into());
 
     let event_id = db.rooms.build_and_append_pdu(
         PduBuilder {
-            event_type: EventType::from(&body.event_type),
-            content: serde_json::from_str(body.body.body.json().get())
-                .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?,
+            event_type,
+            content,
             unsigned: Some(unsigned),
             state_key: None,
             redacts: None,
@@ -103,8 +125,14 @@ pub async fn send_message_event_route(
 ///
 /// Allows paginating through room history.
 ///
-/// - Only works if the user is joined (TODO: always allow, but only show events where the user was
-/// joined, depending on history_visibility)
+/// - Only works if the user is joined or the room is world readable (TODO: always allow, but only
+/// show events where the user was joined, depending on history_visibility)
+/// - The filter can restrict the types and senders of the returned events and whether they have a
+/// url (`contains_url`)
+/// - With lazy loading, `state` contains the current member events of the senders
+/// - `from` and `to` can also be the `next_batch` of a sync
+/// - Looks at no more than 1000 events per request, also with `contains_url`, so a filtered
+/// response can contain fewer events than the limit while `end` still points further into the room
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/messages", data = "<body>")
@@ -114,46 +142,82 @@ pub async fn get_message_events_route(
     db: DatabaseGuard,
     body: Ruma<get_message_events::Request<'_>>,
 ) -> ConduitResult<get_message_events::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+    // Users that are not in the room only see the events that were world readable when they were
+    // sent
+    let world_readable_only = !db.rooms.is_joined(sender_user, &body.room_id)?;
+    if world_readable_only && !db.rooms.is_world_readable(&body.room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
         ));
     }
 
-    let from = body
-        .from
-        .clone()
-        .parse()
+    let from = db
+        .globals
+        .parse_pagination_token(&body.from)
         .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
 
-    let to = body.to.as_ref().map(|t| t.parse());
+    let to = body
+        .to
+        .as_deref()
+        .map(|to| db.globals.parse_pagination_token(to))
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to` value."))?;
 
     // Use limit or else 10
-    let limit = body
+    let mut limit = body
         .limit
         .try_into()
         .map_or(Ok::<_, Error>(10_usize), |l: u32| Ok(l as usize))?;
 
+    if let Some(filter_limit) = body.filter.as_ref().and_then(|filter| filter.limit) {
+        limit = limit.min(u64::from(filter_limit) as usize);
+    }
+
+    let filter_matches = |pdu: &PduEvent| {
+        !pdu.is_dummy()
+            && (!world_readable_only
+                || db
+                    .rooms
+                    .is_world_readable_at(&pdu.event_id)
+                    .unwrap_or(false))
+            && body.filter.as_ref().map_or(true, |filter| {
+                client_server::room_event_filter_matches(
+                    filter,
+                    &body.room_id,
+                    Some(&pdu.sender),
+                    pdu.kind.as_ref(),
+                ) && client_server::contains_url_matches(filter, &pdu.content)
+            })
+    };
+
+    let lazy_load_members = body
+        .filter
+        .as_ref()
+        .map_or(false, client_server::lazy_load_members);
+
     match body.dir {
         get_message_events::Direction::Forward => {
-            let events_after = db
-                .rooms
-                .pdus_after(&sender_user, &body.room_id, from)?
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .filter_map(|(pdu_id, pdu)| {
-                    db.rooms
-                        .pdu_count(&pdu_id)
-                        .map(|pdu_count| (pdu_count, pdu))
-                        .ok()
-                })
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
-                .collect::<Vec<_>>();
+            let (events_after, end_token) = collect_events(
+                &db,
+                db.rooms.pdus_after(&sender_user, &body.room_id, from)?,
+                to,
+                limit,
+                filter_matches,
+            );
+            let end_token = end_token.map(|count| count.to_string());
 
-            let end_token = events_after.last().map(|(count, _)| count.to_string());
+            let state = if lazy_load_members {
+                member_events(
+                    &db,
+                    &body.room_id,
+                    events_after.iter().map(|(_, pdu)| &pdu.sender),
+                )?
+            } else {
+                Vec::new()
+            };
 
             let events_after = events_after
                 .into_iter()
@@ -164,26 +228,29 @@ pub async fn get_message_events_route(
             resp.start = Some(body.from.to_owned());
             resp.end = end_token;
             resp.chunk = events_after;
-            resp.state = Vec::new();
+            resp.state = state;
 
             Ok(resp.into())
         }
         get_message_events::Direction::Backward => {
-            let events_before = db
-                .rooms
-                .pdus_until(&sender_user, &body.room_id, from)?
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .filter_map(|(pdu_id, pdu)| {
-                    db.rooms
-                        .pdu_count(&pdu_id)
-                        .map(|pdu_count| (pdu_count, pdu))
-                        .ok()
-                })
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
-                .collect::<Vec<_>>();
+            let (events_before, start_token) = collect_events(
+                &db,
+                db.rooms.pdus_until(&sender_user, &body.room_id, from)?,
+                to,
+                limit,
+                filter_matches,
+            );
+            let start_token = start_token.map(|count| count.to_string());
 
-            let start_token = events_before.last().map(|(count, _)| count.to_string());
+            let state = if lazy_load_members {
+                member_events(
+                    &db,
+                    &body.room_id,
+                    events_before.iter().map(|(_, pdu)| &pdu.sender),
+                )?
+            } else {
+                Vec::new()
+            };
 
             let events_before = events_before
                 .into_iter()
@@ -194,9 +261,76 @@ pub async fn get_message_events_route(
             resp.start = Some(body.from.to_owned());
             resp.end = start_token;
             resp.chunk = events_before;
-            resp.state = Vec::new();
+            resp.state = state;
 
             Ok(resp.into())
         }
     }
 }
+
+/// Collects up to `limit` events that match the filter, stopping at `to` or after
+/// `MAX_SCANNED_EVENTS` events, so selective filters like `contains_url` don't walk the whole room.
+/// Also returns the count of the last event that was looked at, which is where the next request
+/// continues.
+pub(crate) fn collect_events(
+    db: &Database,
+    pdus: impl Iterator<Item = Result<(Vec<u8>, PduEvent)>>,
+    to: Option<u64>,
+    limit: usize,
+    filter_matches: impl Fn(&PduEvent) -> bool,
+) -> (Vec<(u64, PduEvent)>, Option<u64>) {
+    let mut events = Vec::new();
+    let mut last_count = None;
+
+    for (pdu_id, pdu) in pdus
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .take(client_server::MAX_SCANNED_EVENTS)
+    {
+        if events.len() == limit {
+            break;
+        }
+
+        let count = match db.rooms.pdu_count(&pdu_id) {
+            Ok(count) => count,
+            Err(_) => continue,
+        };
+
+        // Stop at `to`
+        if Some(count) == to {
+            break;
+        }
+
+        last_count = Some(count);
+
+        if filter_matches(&pdu) {
+            events.push((count, pdu));
+        }
+    }
+
+    (events, last_count)
+}
+
+/// Returns the current member events of the senders, every sender once.
+pub(crate) fn member_events<'a>(
+    db: &Database,
+    room_id: &RoomId,
+    senders: impl Iterator<Item = &'a UserId>,
+) -> Result<Vec<Raw<AnyStateEvent>>> {
+    let mut seen = HashSet::new();
+    let mut events = Vec::new();
+
+    for sender in senders {
+        if !seen.insert(sender) {
+            continue;
+        }
+
+        if let Some(member) =
+            db.rooms
+                .room_state_get(room_id, &EventType::RoomMember, sender.as_str())?
+        {
+            events.push(member.to_state_event());
+        }
+    }
+
+    Ok(events)
+}
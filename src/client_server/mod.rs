@@ -1,9 +1,11 @@
 mod account;
+mod admin;
 mod alias;
 mod backup;
 mod capabilities;
 mod config;
 mod context;
+mod data_export;
 mod device;
 mod directory;
 mod filter;
@@ -11,18 +13,24 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod peek;
 mod presence;
 mod profile;
 mod push;
 mod read_marker;
 mod redact;
+mod relations;
+mod report;
 mod room;
+mod scoped_tokens;
 mod search;
 mod session;
+mod space;
 mod state;
 mod sync;
 mod tag;
 mod thirdparty;
+mod threads;
 mod to_device;
 mod typing;
 mod unversioned;
@@ -30,11 +38,13 @@ mod user_directory;
 mod voip;
 
 pub use account::*;
+pub use admin::*;
 pub use alias::*;
 pub use backup::*;
 pub use capabilities::*;
 pub use config::*;
 pub use context::*;
+pub use data_export::*;
 pub use device::*;
 pub use directory::*;
 pub use filter::*;
@@ -42,18 +52,24 @@ pub use keys::*;
 pub use media::*;
 pub use membership::*;
 pub use message::*;
+pub use peek::*;
 pub use presence::*;
 pub use profile::*;
 pub use push::*;
 pub use read_marker::*;
 pub use redact::*;
+pub use relations::*;
+pub use report::*;
 pub use room::*;
+pub use scoped_tokens::*;
 pub use search::*;
 pub use session::*;
+pub use space::*;
 pub use state::*;
 pub use sync::*;
 pub use tag::*;
 pub use thirdparty::*;
+pub use threads::*;
 pub use to_device::*;
 pub use typing::*;
 pub use unversioned::*;
@@ -73,7 +89,7 @@ pub const SESSION_ID_LENGTH: usize = 256;
 
 /// # `OPTIONS`
 ///
-/// Web clients use this to get CORS headers.
+/// Web clients use this to get CORS headers. The headers themselves are added by the CORS fairing.
 #[cfg(feature = "conduit_bin")]
 #[options("/<_..>")]
 #[tracing::instrument]
@@ -0,0 +1,140 @@
+use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma};
+use ruma::{api::client::error::ErrorKind, events::AnyRoomEvent, serde::Raw, RoomId, UserId};
+use std::{iter, time::Duration};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// How many events a peek returns at most.
+const PEEK_LIMIT: usize = 100;
+
+/// `GET /_matrix/client/r0/events` with a `room_id`, the peeking API of the room previews in the
+/// spec, which ruma doesn't have.
+pub mod peek_events {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Wait for new events in a room without joining it.",
+            method: GET,
+            name: "peek_events",
+            path: "/_matrix/client/r0/events",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The end of a previous response. Without it only events that happen from now on
+            /// are returned.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            /// How long to wait for new events, in milliseconds.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub timeout: Option<UInt>,
+
+            /// The room to peek into.
+            #[ruma_api(query)]
+            pub room_id: RoomId,
+        }
+
+        response: {
+            pub start: String,
+            pub end: String,
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/r0/events`
+///
+/// Waits for new events in a room, so users can follow rooms whose history is world readable
+/// without joining them.
+///
+/// - Works for rooms the user is in too
+/// - Returns at most 100 events, `end` is the `from` of the next request
+/// - Waits at most `max_sync_timeout_secs` like /sync
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/events", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn peek_events_route(
+    db: DatabaseGuard,
+    body: Ruma<peek_events::Request>,
+) -> ConduitResult<peek_events::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let world_readable_only = !db.rooms.is_joined(sender_user, &body.room_id)?;
+    if world_readable_only && !db.rooms.is_world_readable(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only peek into world readable rooms.",
+        ));
+    }
+
+    let from = match &body.from {
+        Some(from) => from
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?,
+        None => db.globals.current_count()?,
+    };
+
+    let max_timeout = db.globals.max_sync_timeout();
+    let timeout = body
+        .timeout
+        .map_or(max_timeout, |ms| Duration::from_millis(ms.into()))
+        .min(max_timeout);
+
+    // Watch before looking, so events that arrive in between wake us up
+    let watcher = db.activity.watch_rooms(iter::once(body.room_id.clone()));
+
+    let (mut events, mut end) =
+        events_after(&db, sender_user, &body.room_id, from, world_readable_only)?;
+    if end == from {
+        let _ = tokio::time::timeout(timeout, watcher).await;
+        let (new_events, new_end) =
+            events_after(&db, sender_user, &body.room_id, from, world_readable_only)?;
+        events = new_events;
+        end = new_end;
+    }
+
+    Ok(peek_events::Response {
+        start: from.to_string(),
+        end: end.to_string(),
+        chunk: events.into_iter().map(|(_, event)| event).collect(),
+    }
+    .into())
+}
+
+/// Returns the events after `from` and the count of the last event that was looked at. With
+/// `world_readable_only`, events that were not world readable when they were sent are skipped.
+fn events_after(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    from: u64,
+    world_readable_only: bool,
+) -> Result<(Vec<(u64, Raw<AnyRoomEvent>)>, u64)> {
+    let mut end = from;
+    let mut events = Vec::new();
+
+    for (pdu_id, pdu) in db
+        .rooms
+        .pdus_after(user_id, room_id, from)?
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .take(PEEK_LIMIT)
+    {
+        end = db.rooms.pdu_count(&pdu_id)?;
+
+        if !world_readable_only || db.rooms.is_world_readable_at(&pdu.event_id)? {
+            events.push((end, pdu.to_room_event()));
+        }
+    }
+
+    Ok((events, end))
+}
@@ -1,5 +1,19 @@
-use crate::{database::DatabaseGuard, utils, ConduitResult, Ruma};
-use ruma::api::client::r0::presence::{get_presence, set_presence};
+use crate::{
+    database::{
+        rooms::{presence_is_active, UserPresence, BUSY},
+        DatabaseGuard,
+    },
+    utils, ConduitResult, Database, Error, Result, Ruma,
+};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::presence::{get_presence, set_presence},
+    },
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    UserId,
+};
 use std::{convert::TryInto, time::Duration};
 
 #[cfg(feature = "conduit_bin")]
@@ -8,6 +22,9 @@ use rocket::{get, put};
 /// # `PUT /_matrix/client/r0/presence/{userId}/status`
 ///
 /// Sets the presence state of the sender user.
+///
+/// - Also accepts the `busy` state of MSC3026
+/// - The status message expires after the `status_msg_ttl_secs` of the config
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/presence/<_>/status", data = "<body>")
@@ -17,33 +34,31 @@ pub async fn set_presence_route(
     db: DatabaseGuard,
     body: Ruma<set_presence::Request<'_>>,
 ) -> ConduitResult<set_presence::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-
-    for room_id in db.rooms.rooms_joined(&sender_user) {
-        let room_id = room_id?;
+    let sender_user = body.authenticated_user()?;
 
-        db.rooms.edus.update_presence(
-            &sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(&sender_user)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(&sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: body.presence.clone(),
-                    status_msg: body.status_msg.clone(),
-                },
-                sender: sender_user.clone(),
-            },
-            &db.globals,
-        )?;
+    if !matches!(
+        body.presence.as_ref(),
+        "online" | "offline" | "unavailable" | BUSY
+    ) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Unknown presence state.",
+        ));
     }
 
+    let now = utils::millis_since_unix_epoch();
+
+    update_presence_in_joined_rooms(
+        &db,
+        sender_user,
+        UserPresence {
+            presence: body.presence.clone(),
+            status_msg: body.status_msg.clone(),
+            status_msg_ts: body.status_msg.as_ref().map(|_| now),
+            last_active_ts: now,
+        },
+    )?;
+
     db.flush()?;
 
     Ok(set_presence::Response {}.into())
@@ -54,6 +69,7 @@ pub async fn set_presence_route(
 /// Gets the presence state of the given user.
 ///
 /// - Only works if you share a room with the user
+/// - Expired status messages are not returned
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/presence/<_>/status", data = "<body>")
@@ -63,39 +79,154 @@ pub async fn get_presence_route(
     db: DatabaseGuard,
     body: Ruma<get_presence::Request<'_>>,
 ) -> ConduitResult<get_presence::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-
-    let mut presence_event = None;
+    let sender_user = body.authenticated_user()?;
 
-    for room_id in db
-        .rooms
-        .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
-    {
-        let room_id = room_id?;
-
-        if let Some(presence) = db
+    let shares_room = sender_user == &body.user_id
+        || db
             .rooms
-            .edus
-            .get_last_presence_event(&sender_user, &room_id)?
-        {
-            presence_event = Some(presence);
-            break;
+            .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
+            .next()
+            .is_some();
+
+    let presence = match db.rooms.edus.user_presence(&body.user_id)? {
+        Some(presence) if shares_room => presence,
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Presence state of this user was not found.",
+            ))
         }
+    };
+
+    let status_msg_expired = presence.status_msg_expired(db.globals.status_msg_ttl());
+    let currently_active = presence_is_active(&presence.presence);
+
+    Ok(get_presence::Response {
+        status_msg: presence.status_msg.filter(|_| !status_msg_expired),
+        currently_active: Some(currently_active),
+        last_active_ago: (!currently_active).then(|| {
+            Duration::from_millis(
+                utils::millis_since_unix_epoch().saturating_sub(presence.last_active_ts),
+            )
+        }),
+        presence: presence.presence,
+    }
+    .into())
+}
+
+/// Applies the `set_presence` parameter of /sync.
+///
+/// - online (the default) marks the user as online, unless they are busy
+/// - unavailable marks the user as idle
+/// - offline doesn't touch the presence, so clients that sync in the background don't make the
+/// user look online
+/// - Clears the status message once it expired, so the other users see it go away
+pub(crate) fn update_presence_from_sync(
+    db: &Database,
+    user_id: &UserId,
+    set_presence: &PresenceState,
+) -> Result<()> {
+    if *set_presence == PresenceState::Offline {
+        return Ok(());
     }
 
-    if let Some(presence) = presence_event {
-        Ok(get_presence::Response {
-            // TODO: Should ruma just use the presenceeventcontent type here?
-            status_msg: presence.content.status_msg,
-            currently_active: presence.content.currently_active,
-            last_active_ago: presence
-                .content
-                .last_active_ago
-                .map(|millis| Duration::from_millis(millis.into())),
-            presence: presence.content.presence,
+    let current = db.rooms.edus.user_presence(user_id)?;
+
+    // Busy users stay busy while their clients keep syncing
+    let presence = match &current {
+        Some(current)
+            if *set_presence == PresenceState::Online && current.presence.as_ref() == BUSY =>
+        {
+            current.presence.clone()
         }
-        .into())
+        _ => set_presence.clone(),
+    };
+
+    let status_msg_expired = current.as_ref().map_or(false, |current| {
+        current.status_msg.is_some() && current.status_msg_expired(db.globals.status_msg_ttl())
+    });
+
+    // Only store a new presence event if something changes, everything else would wake up the
+    // syncs of every user in the rooms for nothing
+    if current.as_ref().map(|c| &c.presence) != Some(&presence) || status_msg_expired {
+        let (status_msg, status_msg_ts) = match current {
+            Some(current) if !status_msg_expired => (current.status_msg, current.status_msg_ts),
+            _ => (None, None),
+        };
+
+        update_presence_in_joined_rooms(
+            db,
+            user_id,
+            UserPresence {
+                presence,
+                status_msg,
+                status_msg_ts,
+                last_active_ts: utils::millis_since_unix_epoch(),
+            },
+        )?;
     } else {
-        todo!();
+        db.rooms.edus.ping_presence(user_id)?;
+    }
+
+    Ok(())
+}
+
+/// Remembers the new presence of the user and stores a presence event for them in all rooms they
+/// joined, so everyone who shares a room with them sees it.
+fn update_presence_in_joined_rooms(
+    db: &Database,
+    user_id: &UserId,
+    presence: UserPresence,
+) -> Result<()> {
+    db.rooms.edus.set_user_presence(user_id, &presence)?;
+
+    let presence_event = presence_event(db, user_id, presence)?;
+    for room_id in db.rooms.rooms_joined(user_id) {
+        let room_id = room_id?;
+
+        db.rooms
+            .edus
+            .update_presence(user_id, &room_id, presence_event.clone(), &db.globals)?;
     }
+
+    Ok(())
+}
+
+/// Returns the presence event for the current presence of the user, e.g. for profile changes.
+/// Users who never set a presence are online.
+pub(crate) fn current_presence_event(db: &Database, user_id: &UserId) -> Result<PresenceEvent> {
+    let presence = match db.rooms.edus.user_presence(user_id)? {
+        Some(mut presence) => {
+            if presence.status_msg_expired(db.globals.status_msg_ttl()) {
+                presence.status_msg = None;
+            }
+            presence
+        }
+        None => UserPresence {
+            presence: PresenceState::Online,
+            status_msg: None,
+            status_msg_ts: None,
+            last_active_ts: utils::millis_since_unix_epoch(),
+        },
+    };
+
+    presence_event(db, user_id, presence)
+}
+
+fn presence_event(
+    db: &Database,
+    user_id: &UserId,
+    presence: UserPresence,
+) -> Result<PresenceEvent> {
+    Ok(PresenceEvent {
+        content: PresenceEventContent {
+            avatar_url: db.users.avatar_url(user_id)?,
+            currently_active: None,
+            displayname: db.users.displayname(user_id)?,
+            last_active_ago: Some(presence.last_active_ts.try_into().expect("time is valid")),
+            presence: presence.presence,
+            status_msg: presence.status_msg,
+        },
+        sender: user_id.clone(),
+    })
 }
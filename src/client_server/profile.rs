@@ -1,4 +1,4 @@
-use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Ruma};
+use crate::{client_server, database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Error, Ruma};
 use ruma::{
     api::{
         client::{
@@ -12,7 +12,7 @@ use ruma::{
     events::EventType,
     serde::Raw,
 };
-use std::{convert::TryInto, sync::Arc};
+use std::sync::Arc;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
@@ -31,7 +31,7 @@ pub async fn set_displayname_route(
     db: DatabaseGuard,
     body: Ruma<set_display_name::Request<'_>>,
 ) -> ConduitResult<set_display_name::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.users
         .set_displayname(&sender_user, body.displayname.clone())?;
@@ -93,25 +93,11 @@ pub async fn set_displayname_route(
             db.rooms
                 .build_and_append_pdu(pdu_builder, &sender_user, &room_id, &db, &state_lock);
 
-        // Presence update
+        // Presence update, keeping the presence and status message the user set
         db.rooms.edus.update_presence(
             &sender_user,
             &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(&sender_user)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(&sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
+            client_server::current_presence_event(&db, &sender_user)?,
             &db.globals,
         )?;
     }
@@ -174,7 +160,7 @@ pub async fn set_avatar_url_route(
     db: DatabaseGuard,
     body: Ruma<set_avatar_url::Request<'_>>,
 ) -> ConduitResult<set_avatar_url::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.users
         .set_avatar_url(&sender_user, body.avatar_url.clone())?;
@@ -238,25 +224,11 @@ pub async fn set_avatar_url_route(
             db.rooms
                 .build_and_append_pdu(pdu_builder, &sender_user, &room_id, &db, &state_lock);
 
-        // Presence update
+        // Presence update, keeping the presence and status message the user set
         db.rooms.edus.update_presence(
             &sender_user,
             &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(&sender_user)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(&sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
+            client_server::current_presence_event(&db, &sender_user)?,
             &db.globals,
         )?;
     }
@@ -27,7 +27,7 @@ pub async fn get_pushrules_all_route(
     db: DatabaseGuard,
     body: Ruma<get_pushrules_all::Request>,
 ) -> ConduitResult<get_pushrules_all::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let event = db
         .account_data
@@ -55,7 +55,7 @@ pub async fn get_pushrule_route(
     db: DatabaseGuard,
     body: Ruma<get_pushrule::Request<'_>>,
 ) -> ConduitResult<get_pushrule::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let event = db
         .account_data
@@ -112,7 +112,7 @@ pub async fn set_pushrule_route(
     db: DatabaseGuard,
     req: Ruma<set_pushrule::Request<'_>>,
 ) -> ConduitResult<set_pushrule::Response> {
-    let sender_user = req.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = req.authenticated_user()?.clone();
     let body = req.body;
 
     if body.scope != "global" {
@@ -218,7 +218,7 @@ pub async fn get_pushrule_actions_route(
     db: DatabaseGuard,
     body: Ruma<get_pushrule_actions::Request<'_>>,
 ) -> ConduitResult<get_pushrule_actions::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if body.scope != "global" {
         return Err(Error::BadRequest(
@@ -280,7 +280,7 @@ pub async fn set_pushrule_actions_route(
     db: DatabaseGuard,
     body: Ruma<set_pushrule_actions::Request<'_>>,
 ) -> ConduitResult<set_pushrule_actions::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if body.scope != "global" {
         return Err(Error::BadRequest(
@@ -357,7 +357,7 @@ pub async fn get_pushrule_enabled_route(
     db: DatabaseGuard,
     body: Ruma<get_pushrule_enabled::Request<'_>>,
 ) -> ConduitResult<get_pushrule_enabled::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if body.scope != "global" {
         return Err(Error::BadRequest(
@@ -421,7 +421,7 @@ pub async fn set_pushrule_enabled_route(
     db: DatabaseGuard,
     body: Ruma<set_pushrule_enabled::Request<'_>>,
 ) -> ConduitResult<set_pushrule_enabled::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if body.scope != "global" {
         return Err(Error::BadRequest(
@@ -503,7 +503,7 @@ pub async fn delete_pushrule_route(
     db: DatabaseGuard,
     body: Ruma<delete_pushrule::Request<'_>>,
 ) -> ConduitResult<delete_pushrule::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     if body.scope != "global" {
         return Err(Error::BadRequest(
@@ -575,7 +575,7 @@ pub async fn get_pushers_route(
     db: DatabaseGuard,
     body: Ruma<get_pushers::Request>,
 ) -> ConduitResult<get_pushers::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     Ok(get_pushers::Response {
         pushers: db.pusher.get_pushers(sender_user)?,
@@ -597,7 +597,7 @@ pub async fn set_pushers_route(
     db: DatabaseGuard,
     body: Ruma<set_pusher::Request>,
 ) -> ConduitResult<set_pusher::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
     let pusher = body.pusher.clone();
 
     db.pusher.set_pusher(sender_user, pusher)?;
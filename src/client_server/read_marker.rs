@@ -28,7 +28,7 @@ pub async fn set_read_marker_route(
     db: DatabaseGuard,
     body: Ruma<set_read_marker::Request<'_>>,
 ) -> ConduitResult<set_read_marker::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let fully_read_event = ruma::events::fully_read::FullyReadEvent {
         content: ruma::events::fully_read::FullyReadEventContent {
@@ -98,7 +98,7 @@ pub async fn create_receipt_route(
     db: DatabaseGuard,
     body: Ruma<create_receipt::Request<'_>>,
 ) -> ConduitResult<create_receipt::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     db.rooms.edus.private_read_set(
         &body.room_id,
@@ -1,9 +1,13 @@
 use std::sync::Arc;
 
-use crate::{database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Ruma};
+use crate::{database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Error, Ruma};
 use ruma::{
-    api::client::r0::redact::redact_event,
-    events::{room::redaction, EventType},
+    api::client::{error::ErrorKind, r0::redact::redact_event},
+    events::{
+        room::{power_levels::PowerLevelsEventContent, redaction},
+        EventType,
+    },
+    serde::Raw,
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -13,6 +17,8 @@ use rocket::put;
 ///
 /// Tries to send a redaction event into the room.
 ///
+/// - Fails with M_LIMIT_EXCEEDED if the user exhausted their message budget
+/// - Users can always redact their own events, the events of others need the redact power level
 /// - TODO: Handle txn id
 #[cfg_attr(
     feature = "conduit_bin",
@@ -23,7 +29,13 @@ pub async fn redact_event_route(
     db: DatabaseGuard,
     body: Ruma<redact_event::Request<'_>>,
 ) -> ConduitResult<redact_event::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    db.globals.check_message_limit(
+        sender_user,
+        body.from_appservice,
+        db.reports.is_under_review(sender_user)?,
+    )?;
 
     let mutex_state = Arc::clone(
         db.globals
@@ -35,6 +47,35 @@ pub async fn redact_event_route(
     );
     let state_lock = mutex_state.lock().await;
 
+    let power_levels = db
+        .rooms
+        .room_state_get(&body.room_id, &EventType::RoomPowerLevels, "")?
+        .map(|pdu| {
+            serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    if db.rooms.event_sender(&body.event_id)?.is_none() {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
+    if !db.rooms.redaction_allowed(
+        &body.room_id,
+        sender_user,
+        Some(db.globals.server_name()),
+        &body.event_id,
+        &power_levels,
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to redact this event.",
+        ));
+    }
+
     let event_id = db.rooms.build_and_append_pdu(
         PduBuilder {
             event_type: EventType::RoomRedaction,
@@ -0,0 +1,366 @@
+use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma};
+use ruma::{
+    api::client::error::ErrorKind, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt, UserId,
+};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// How many relations /relations returns if the client doesn't set a limit.
+const DEFAULT_RELATIONS_LIMIT: u64 = 10;
+/// Clients can't ask for more relations per /relations request than this.
+const MAX_RELATIONS_LIMIT: u64 = 100;
+
+/// `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}` of MSC2675, which ruma doesn't
+/// know yet.
+pub mod get_relating_events {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the events that relate to an event.",
+            method: GET,
+            name: "get_relating_events",
+            path: "/_matrix/client/v1/rooms/:room_id/relations/:event_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The event the returned events relate to.
+            #[ruma_api(path)]
+            pub event_id: EventId,
+
+            /// The next_batch of a previous response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            /// Stop at this token, e.g. a prev_batch.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<String>,
+
+            /// The maximum number of events in the response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}` of MSC2675.
+pub mod get_relating_events_with_rel_type {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the events that relate to an event with a relation type.",
+            method: GET,
+            name: "get_relating_events_with_rel_type",
+            path: "/_matrix/client/v1/rooms/:room_id/relations/:event_id/:rel_type",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The event the returned events relate to.
+            #[ruma_api(path)]
+            pub event_id: EventId,
+
+            /// The relation type, e.g. `m.annotation`.
+            #[ruma_api(path)]
+            pub rel_type: String,
+
+            /// The next_batch of a previous response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            /// Stop at this token, e.g. a prev_batch.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<String>,
+
+            /// The maximum number of events in the response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}` of MSC2675.
+pub mod get_relating_events_with_rel_type_and_event_type {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the events of a type that relate to an event with a relation type.",
+            method: GET,
+            name: "get_relating_events_with_rel_type_and_event_type",
+            path: "/_matrix/client/v1/rooms/:room_id/relations/:event_id/:rel_type/:event_type",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The event the returned events relate to.
+            #[ruma_api(path)]
+            pub event_id: EventId,
+
+            /// The relation type, e.g. `m.annotation`.
+            #[ruma_api(path)]
+            pub rel_type: String,
+
+            /// The event type, e.g. `m.reaction`.
+            #[ruma_api(path)]
+            pub event_type: String,
+
+            /// The next_batch of a previous response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            /// Stop at this token, e.g. a prev_batch.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<String>,
+
+            /// The maximum number of events in the response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}`
+///
+/// Lists the events that relate to an event, e.g. its reactions and edits, newest first.
+///
+/// - Only works if the user is joined or the room is world readable
+/// - Redacted events don't relate to anything anymore
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/rooms/<_>/relations/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_relating_events_route(
+    db: DatabaseGuard,
+    body: Ruma<get_relating_events::Request>,
+) -> ConduitResult<get_relating_events::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let (chunk, next_batch) = relations_helper(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        None,
+        None,
+        body.from.as_deref(),
+        body.to.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_relating_events::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    }
+    .into())
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}`
+///
+/// Lists the events that relate to an event with this relation type, newest first.
+///
+/// - Only works if the user is joined or the room is world readable
+/// - Redacted events don't relate to anything anymore
+/// - `m.thread` lists the replies of the thread the event is the root of
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/rooms/<_>/relations/<_>/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_relating_events_with_rel_type_route(
+    db: DatabaseGuard,
+    body: Ruma<get_relating_events_with_rel_type::Request>,
+) -> ConduitResult<get_relating_events_with_rel_type::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let (chunk, next_batch) = relations_helper(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        Some(&body.rel_type),
+        None,
+        body.from.as_deref(),
+        body.to.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_relating_events_with_rel_type::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    }
+    .into())
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`
+///
+/// Lists the events of this type that relate to an event with this relation type, newest first.
+///
+/// - Only works if the user is joined or the room is world readable
+/// - Redacted events don't relate to anything anymore
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/rooms/<_>/relations/<_>/<_>/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_relating_events_with_rel_type_and_event_type_route(
+    db: DatabaseGuard,
+    body: Ruma<get_relating_events_with_rel_type_and_event_type::Request>,
+) -> ConduitResult<get_relating_events_with_rel_type_and_event_type::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let (chunk, next_batch) = relations_helper(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        Some(&body.rel_type),
+        Some(&body.event_type),
+        body.from.as_deref(),
+        body.to.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_relating_events_with_rel_type_and_event_type::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    }
+    .into())
+}
+
+/// Returns a page of the events relating to `target` and the token of the next page, if there
+/// might be one.
+#[allow(clippy::too_many_arguments)]
+fn relations_helper(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    target: &EventId,
+    rel_type: Option<&str>,
+    event_type: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<UInt>,
+) -> Result<(Vec<Raw<AnyRoomEvent>>, Option<String>)> {
+    let world_readable_only = !db.rooms.is_joined(sender_user, room_id)?;
+    if world_readable_only && !db.rooms.is_world_readable(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    match db.rooms.get_pdu(target)? {
+        Some(pdu)
+            if pdu.room_id == *room_id
+                && (!world_readable_only || db.rooms.is_world_readable_at(&pdu.event_id)?) => {}
+        _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
+    }
+
+    let parse_token = |token: &str| {
+        token
+            .parse::<u64>()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid pagination token."))
+    };
+    let from = from.map(parse_token).transpose()?;
+    let to = to.map(parse_token).transpose()?;
+
+    let limit = limit
+        .map_or(DEFAULT_RELATIONS_LIMIT, u64::from)
+        .min(MAX_RELATIONS_LIMIT)
+        .max(1) as usize;
+
+    let relations = db
+        .rooms
+        .relating_pdus(sender_user, room_id, target, rel_type, from, true)?
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .take_while(|(count, _, _)| to.map_or(true, |to| *count > to))
+        .filter(|(_, _, pdu)| event_type.map_or(true, |event_type| pdu.kind.as_ref() == event_type))
+        .filter(|(_, _, pdu)| {
+            !world_readable_only
+                || db
+                    .rooms
+                    .is_world_readable_at(&pdu.event_id)
+                    .unwrap_or(false)
+        })
+        .take(limit)
+        .collect::<Vec<_>>();
+
+    let next_batch = if relations.len() == limit {
+        relations.last().map(|(count, _, _)| count.to_string())
+    } else {
+        None
+    };
+
+    Ok((
+        relations
+            .into_iter()
+            .map(|(_, _, pdu)| pdu.to_room_event())
+            .collect(),
+        next_batch,
+    ))
+}
@@ -0,0 +1,175 @@
+use crate::{
+    client_server,
+    database::{
+        admin::AdminCommand,
+        reports::{Escalation, Report},
+        DatabaseGuard,
+    },
+    utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
+};
+use ruma::{
+    api::client::{error::ErrorKind, r0::room::report_content},
+    events::room::message,
+    int,
+};
+use tracing::info;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::post;
+
+/// # `POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}`
+///
+/// Reports an event to the admins of this server.
+///
+/// - Only works for events of rooms the sender user is in
+/// - Once `report_escalation_threshold` different users reported the event or its sender, the
+/// sender is escalated: the admin room is told, their messages are rate limited and if
+/// `quarantine_reported_media` is set, the media of the reported events can't be downloaded until
+/// an admin runs `resolve_reports`. Admins are never escalated
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/rooms/<_>/report/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn report_event_route(
+    db: DatabaseGuard,
+    body: Ruma<report_content::Request<'_>>,
+) -> ConduitResult<report_content::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not in this room.",
+        ));
+    }
+
+    let pdu = match db.rooms.get_pdu(&body.event_id)? {
+        Some(pdu) if pdu.room_id == *body.room_id => pdu,
+        _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
+    };
+
+    if pdu.sender == *sender_user {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "You can't report your own events.",
+        ));
+    }
+
+    if body.score < int!(-100) || body.score > int!(0) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The score has to be between -100 and 0.",
+        ));
+    }
+
+    if body.reason.chars().count() > 1000 {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The reason can't be longer than 1000 characters.",
+        ));
+    }
+
+    let (event_reporters, sender_reporters) = db.reports.add(&Report {
+        room_id: body.room_id.clone(),
+        event_id: body.event_id.clone(),
+        sender: pdu.sender.clone(),
+        reporter: sender_user.clone(),
+        score: body.score.into(),
+        reason: body.reason.to_owned(),
+        ts: utils::millis_since_unix_epoch(),
+    })?;
+
+    info!(
+        "{} reported {} of {}",
+        sender_user, pdu.event_id, pdu.sender
+    );
+
+    if let Some(threshold) = db.globals.report_escalation_threshold() {
+        let threshold = threshold as usize;
+        // Otherwise a few throwaway accounts could rate limit the admins
+        if (event_reporters >= threshold || sender_reporters >= threshold)
+            && !client_server::is_admin(&db, &pdu.sender)?
+        {
+            escalate(&db, &pdu)?;
+        }
+    }
+
+    db.flush()?;
+
+    Ok(report_content::Response {}.into())
+}
+
+/// Puts the sender of a reported event under review and quarantines the media of the event. The
+/// admin room is only told the first time.
+fn escalate(db: &Database, pdu: &PduEvent) -> Result<()> {
+    let existing = db.reports.escalation(&pdu.sender)?;
+    let is_new = existing.is_none();
+    let mut escalation = existing.unwrap_or_else(|| Escalation {
+        ts: utils::millis_since_unix_epoch(),
+        quarantined_media: Vec::new(),
+    });
+
+    if db.globals.quarantine_reported_media() {
+        let mut media = Vec::new();
+        mxc_uris(&pdu.content, &mut media);
+
+        for mxc in media {
+            if !escalation.quarantined_media.contains(&mxc) {
+                db.media.quarantine(&mxc)?;
+                escalation.quarantined_media.push(mxc);
+            }
+        }
+    }
+
+    db.reports.escalate(&pdu.sender, &escalation)?;
+
+    if is_new {
+        let reasons = db
+            .reports
+            .reports_of_event(&pdu.event_id)
+            .filter_map(|r| r.ok())
+            .map(|report| {
+                format!(
+                    "- {} ({}): {}",
+                    report.reporter, report.score, report.reason
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        db.admin.send(AdminCommand::SendMessage(
+            message::MessageEventContent::text_plain(format!(
+                "{} was reported by too many users, most recently for {} in {}. Quarantined {} files. Their messages are rate limited until you run `resolve_reports {}`.\n\nReports of the event:\n{}",
+                pdu.sender,
+                pdu.event_id,
+                pdu.room_id,
+                escalation.quarantined_media.len(),
+                pdu.sender,
+                reasons
+            )),
+        ));
+
+        info!("Escalated the reports against {}", pdu.sender);
+    }
+
+    Ok(())
+}
+
+/// Collects the mxc uris anywhere in the content, e.g. the file and the thumbnail of an image.
+fn mxc_uris(value: &serde_json::Value, uris: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(s) if s.starts_with("mxc://") => uris.push(s.clone()),
+        serde_json::Value::Array(values) => {
+            for value in values {
+                mxc_uris(value, uris);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for value in map.values() {
+                mxc_uris(value, uris);
+            }
+        }
+        _ => {}
+    }
+}
@@ -1,6 +1,14 @@
 use crate::{
-    client_server::invite_helper, database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Error,
-    Ruma,
+    client_server::{
+        check_confusable_alias, check_join_limits, invite_helper, validate_alias_localpart,
+    },
+    database::DatabaseGuard,
+    pdu::PduBuilder,
+    ConduitResult, Database, Error, Result, Ruma,
+};
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
 };
 use ruma::{
     api::client::{
@@ -8,13 +16,18 @@ use ruma::{
         r0::room::{self, aliases, create_room, get_room_event, upgrade_room},
     },
     events::{
-        room::{guest_access, history_visibility, join_rules, member, name, topic},
+        room::{encryption, guest_access, history_visibility, join_rules, member, name, topic},
         EventType,
     },
     serde::Raw,
-    RoomAliasId, RoomId, RoomVersionId,
+    EventEncryptionAlgorithm, RoomAliasId, RoomId, RoomVersionId, UserId,
+};
+use std::{
+    cmp::{max, min},
+    collections::BTreeMap,
+    convert::TryFrom,
+    sync::Arc,
 };
-use std::{cmp::max, collections::BTreeMap, convert::TryFrom, sync::Arc};
 use tracing::{info, warn};
 
 #[cfg(feature = "conduit_bin")]
@@ -24,17 +37,23 @@ use rocket::{get, post};
 ///
 /// Creates a new room.
 ///
+/// - Fails if the sender user is already joined to the maximum number of rooms
+/// - Returns the room of an earlier request with the same `rs.conduit.transaction_id` or
+/// `Idempotency-Key` header, if it was created recently
 /// - Room ID is randomly generated
 /// - Create alias if room_alias_name is set
-/// - Send create event
+/// - Send create event with the fields of `creation_content`, e.g. the room type of spaces. The
+/// server sets `creator` and `room_version`
 /// - Join sender user
-/// - Send power levels event
+/// - Send power levels event: Server defaults from `default_power_levels`, then the template,
+/// then `power_level_content_override`
 /// - Send canonical room alias
 /// - Send join rules
 /// - Send history visibility
 /// - Send guest access
-/// - Send events listed in initial state
-/// - Send events implied by `name` and `topic`
+/// - Send events of the template from `creation_content` and listed in the initial state
+/// - Send events implied by `name`, `topic` and `avatar`
+/// - Fails if a room avatar points to a file of this server that is not in the media repository
 /// - Send invite events
 #[cfg_attr(
     feature = "conduit_bin",
@@ -43,12 +62,78 @@ use rocket::{get, post};
 #[tracing::instrument(skip(db, body))]
 pub async fn create_room_route(
     db: DatabaseGuard,
+    idempotency_key: IdempotencyKey,
     body: Ruma<create_room::Request<'_>>,
 ) -> ConduitResult<create_room::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    // Clients can send a transaction id, so retrying the request doesn't create a second room
+    let txn_id = body
+        .json_body
+        .as_ref()
+        .and_then(|json| json.as_object())
+        .and_then(|json| json.get("rs.conduit.transaction_id"))
+        .and_then(|txn_id| txn_id.as_str())
+        .map(ToOwned::to_owned)
+        .or(idempotency_key.0);
+
+    let mutex_createroom = Arc::clone(
+        db.globals
+            .userid_mutex_createroom
+            .write()
+            .unwrap()
+            .entry(sender_user.clone())
+            .or_default(),
+    );
+    // Only requests with a transaction id have to wait for each other
+    let _createroom_lock = match &txn_id {
+        Some(_) => Some(mutex_createroom.lock().await),
+        None => None,
+    };
+
+    if let Some(txn_id) = &txn_id {
+        if let Some(room_id) = db.transaction_ids.existing_create_room_txnid(
+            sender_user,
+            body.sender_device.as_deref(),
+            txn_id,
+            db.globals.create_room_txn_id_ttl(),
+        )? {
+            return Ok(create_room::Response::new(room_id).into());
+        }
+    }
+
+    // Conduit extension: clients can set the room avatar like the name and topic
+    let avatar = body
+        .json_body
+        .as_ref()
+        .and_then(|json| json.get("avatar"))
+        .and_then(|avatar| avatar.as_str())
+        .map(ToOwned::to_owned);
+
+    // Check the avatars before anything is created, so a dangling avatar doesn't leave a half
+    // created room behind
+    validate_room_avatar(&db, avatar.as_deref())?;
+    for event in &body.initial_state {
+        let event =
+            serde_json::from_str::<serde_json::Value>(event.json().get()).map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid initial state event.")
+            })?;
+
+        if event.get("type").and_then(|t| t.as_str()) == Some("m.room.avatar") {
+            validate_room_avatar(
+                &db,
+                event
+                    .get("content")
+                    .and_then(|content| content.get("url"))
+                    .and_then(|url| url.as_str()),
+            )?;
+        }
+    }
 
     let room_id = RoomId::new(db.globals.server_name());
 
+    check_join_limits(&db, sender_user, &room_id)?;
+
     db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
 
     let mutex_state = Arc::clone(
@@ -65,7 +150,8 @@ pub async fn create_room_route(
         body.room_alias_name
             .as_ref()
             .map_or(Ok(None), |localpart| {
-                // TODO: Check for invalid characters and maximum length
+                validate_alias_localpart(localpart, db.globals.server_name())?;
+
                 let alias =
                     RoomAliasId::try_from(format!("#{}:{}", localpart, db.globals.server_name()))
                         .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid alias."))?;
@@ -76,16 +162,34 @@ pub async fn create_room_route(
                         "Room alias already exists.",
                     ))
                 } else {
+                    check_confusable_alias(&db, &alias)?;
                     Ok(Some(alias))
                 }
             })?;
 
+    // Admins can define templates with defaults for new rooms in the config
+    let template = body
+        .json_body
+        .as_ref()
+        .and_then(|json| json.as_object())
+        .and_then(|json| json.get("creation_content"))
+        .and_then(|content| content.as_object())
+        .and_then(|content| content.get("template"))
+        .and_then(|template| template.as_str())
+        .and_then(|name| {
+            let template = db.globals.room_template(name);
+            if template.is_none() {
+                warn!("Unknown room template {}, ignoring it", name);
+            }
+            template
+        });
+
     let mut content = ruma::events::room::create::CreateEventContent::new(sender_user.clone());
     content.federate = body.creation_content.federate;
     content.predecessor = body.creation_content.predecessor.clone();
     content.room_version = match body.room_version.clone() {
         Some(room_version) => {
-            if room_version == RoomVersionId::Version5 || room_version == RoomVersionId::Version6 {
+            if db.globals.supports_room_version(&room_version) {
                 room_version
             } else {
                 return Err(Error::BadRequest(
@@ -97,11 +201,41 @@ pub async fn create_room_route(
         None => RoomVersionId::Version6,
     };
 
+    let mut content = serde_json::to_value(content).expect("event is valid, we just created it");
+
+    // Ruma only knows some fields of creation_content. The others, like the room type of spaces,
+    // are passed on as they are
+    if let Some(creation_content) = body
+        .json_body
+        .as_ref()
+        .and_then(|json| json.as_object())
+        .and_then(|json| json.get("creation_content"))
+        .and_then(|content| content.as_object())
+    {
+        for (key, value) in creation_content {
+            if matches!(key.as_str(), "creator" | "room_version" | "template") {
+                continue;
+            }
+
+            content[key] = serde_json::to_value(value).expect("canonical json is valid json");
+        }
+    }
+
+    if content
+        .get("type")
+        .map_or(false, |room_type| !room_type.is_string())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::BadJson,
+            "The room type has to be a string.",
+        ));
+    }
+
     // 1. The room create event
     db.rooms.build_and_append_pdu(
         PduBuilder {
             event_type: EventType::RoomCreate,
-            content: serde_json::to_value(content).expect("event is valid, we just created it"),
+            content,
             unsigned: None,
             state_key: Some("".to_owned()),
             redacts: None,
@@ -142,6 +276,7 @@ pub async fn create_room_route(
     let preset = body
         .preset
         .clone()
+        .or_else(|| template.and_then(|template| template.preset.clone()))
         .unwrap_or_else(|| match &body.visibility {
             room::Visibility::Private => create_room::RoomPreset::PrivateChat,
             room::Visibility::Public => create_room::RoomPreset::PublicChat,
@@ -157,6 +292,16 @@ pub async fn create_room_route(
         }
     }
 
+    // Bridges expect their users to be able to manage the rooms they are invited to. Like with
+    // invites to existing rooms, nobody gets more power than the creator
+    let creator_level = users[sender_user];
+    for invite_ in &body.invite {
+        if let Some(level) = db.appservice.default_power_level(invite_, &db.globals)? {
+            let current = users.get(invite_).copied().unwrap_or_default();
+            users.insert(invite_.clone(), max(current, min(level, creator_level)));
+        }
+    }
+
     let mut power_levels_content =
         serde_json::to_value(ruma::events::room::power_levels::PowerLevelsEventContent {
             users,
@@ -164,6 +309,18 @@ pub async fn create_room_route(
         })
         .expect("event is valid, we just created it");
 
+    // The defaults were checked when the server started
+    db.globals
+        .apply_default_power_levels(&mut power_levels_content);
+
+    if let Some(template_override) =
+        template.and_then(|template| template.power_level_content_override.as_ref())
+    {
+        for (key, value) in template_override {
+            power_levels_content[key.as_str()] = value.clone();
+        }
+    }
+
     if let Some(power_level_content_override) = &body.power_level_content_override {
         let json = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(
             power_level_content_override.json().get(),
@@ -285,23 +442,71 @@ pub async fn create_room_route(
         &state_lock,
     )?;
 
-    // 6. Events listed in initial_state
+    // 6. Events from the template and listed in initial_state. Later events win, so the request
+    // can replace events of the template
+    if let Some(template) = template {
+        let mut template_events = template
+            .initial_state
+            .iter()
+            .map(|event| PduBuilder {
+                event_type: EventType::from(&*event.event_type),
+                content: event.content.clone(),
+                unsigned: None,
+                state_key: Some(event.state_key.clone()),
+                redacts: None,
+            })
+            .collect::<Vec<_>>();
+
+        if template.encrypted {
+            template_events.insert(
+                0,
+                PduBuilder {
+                    event_type: EventType::RoomEncryption,
+                    content: serde_json::to_value(encryption::EncryptionEventContent::new(
+                        EventEncryptionAlgorithm::MegolmV1AesSha2,
+                    ))
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+            );
+        }
+
+        for mut pdu_builder in template_events {
+            if pdu_builder.event_type == EventType::RoomEncryption {
+                if !db.globals.allow_encryption() {
+                    continue;
+                }
+
+                pdu_builder.content = super::validate_encryption_content(&pdu_builder.content)?;
+            }
+
+            db.rooms
+                .build_and_append_pdu(pdu_builder, &sender_user, &room_id, &db, &state_lock)?;
+        }
+    }
+
     for event in &body.initial_state {
-        let pdu_builder = PduBuilder::from(event.deserialize().map_err(|e| {
+        let mut pdu_builder = PduBuilder::from(event.deserialize().map_err(|e| {
             warn!("Invalid initial state event: {:?}", e);
             Error::BadRequest(ErrorKind::InvalidParam, "Invalid initial state event.")
         })?);
 
-        // Silently skip encryption events if they are not allowed
-        if pdu_builder.event_type == EventType::RoomEncryption && !db.globals.allow_encryption() {
-            continue;
+        if pdu_builder.event_type == EventType::RoomEncryption {
+            // Silently skip encryption events if they are not allowed
+            if !db.globals.allow_encryption() {
+                continue;
+            }
+
+            pdu_builder.content = super::validate_encryption_content(&pdu_builder.content)?;
         }
 
         db.rooms
             .build_and_append_pdu(pdu_builder, &sender_user, &room_id, &db, &state_lock)?;
     }
 
-    // 7. Events implied by name and topic
+    // 7. Events implied by name, topic and avatar
     if let Some(name) = &body.name {
         db.rooms.build_and_append_pdu(
             PduBuilder {
@@ -338,6 +543,22 @@ pub async fn create_room_route(
         )?;
     }
 
+    if let Some(avatar) = avatar {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: EventType::RoomAvatar,
+                content: serde_json::json!({ "url": avatar }),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            &sender_user,
+            &room_id,
+            &db,
+            &state_lock,
+        )?;
+    }
+
     // 8. Events implied by invite (and TODO: invite_3pid)
     drop(state_lock);
     for user_id in &body.invite {
@@ -353,6 +574,15 @@ pub async fn create_room_route(
         db.rooms.set_public(&room_id, true)?;
     }
 
+    if let Some(txn_id) = &txn_id {
+        db.transaction_ids.add_create_room_txnid(
+            sender_user,
+            body.sender_device.as_deref(),
+            txn_id,
+            &room_id,
+        )?;
+    }
+
     info!("{} created a room", sender_user);
 
     db.flush()?;
@@ -360,11 +590,57 @@ pub async fn create_room_route(
     Ok(create_room::Response::new(room_id).into())
 }
 
+/// Makes sure that a room avatar on this server exists in the media repository. Avatars on other
+/// servers can't be checked without downloading them.
+fn validate_room_avatar(db: &Database, url: Option<&str>) -> Result<()> {
+    let url = match url {
+        Some(url) => url,
+        // An avatar event without url means no avatar
+        None => return Ok(()),
+    };
+
+    let server_name = url
+        .strip_prefix("mxc://")
+        .and_then(|rest| rest.split('/').next())
+        .filter(|server_name| !server_name.is_empty())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room avatar is not an mxc URI.",
+        ))?;
+
+    if server_name == db.globals.server_name().as_str() && !db.media.exists(url)? {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room avatar does not exist in the media repository.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// The `Idempotency-Key` header of a request, for clients that can't add fields to the body.
+#[derive(Debug)]
+pub struct IdempotencyKey(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(IdempotencyKey(
+            req.headers()
+                .get_one("Idempotency-Key")
+                .map(ToOwned::to_owned),
+        ))
+    }
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomId}/event/{eventId}`
 ///
 /// Gets a single event.
 ///
-/// - You have to currently be joined to the room (TODO: Respect history visibility)
+/// - You have to currently be joined to the room or the event has to be world readable (TODO:
+/// Respect history visibility for members)
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/event/<_>", data = "<body>")
@@ -374,21 +650,29 @@ pub async fn get_room_event_route(
     db: DatabaseGuard,
     body: Ruma<get_room_event::Request<'_>>,
 ) -> ConduitResult<get_room_event::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+    let world_readable_only = !db.rooms.is_joined(sender_user, &body.room_id)?;
+    if world_readable_only && !db.rooms.is_world_readable(&body.room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
         ));
     }
 
+    // Events of other rooms are not covered by the check above
+    let event = match db.rooms.get_pdu(&body.event_id)? {
+        Some(pdu)
+            if pdu.room_id == *body.room_id
+                && (!world_readable_only || db.rooms.is_world_readable_at(&pdu.event_id)?) =>
+        {
+            pdu
+        }
+        _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
+    };
+
     Ok(get_room_event::Response {
-        event: db
-            .rooms
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
-            .to_room_event(),
+        event: event.to_room_event(),
     }
     .into())
 }
@@ -397,7 +681,8 @@ pub async fn get_room_event_route(
 ///
 /// Lists all aliases of the room.
 ///
-/// - Only users joined to the room are allowed to call this TODO: Allow any user to call it if history_visibility is world readable
+/// - Only users joined to the room are allowed to call this, or anyone if the room is world
+/// readable
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/aliases", data = "<body>")
@@ -407,9 +692,11 @@ pub async fn get_room_aliases_route(
     db: DatabaseGuard,
     body: Ruma<aliases::Request<'_>>,
 ) -> ConduitResult<aliases::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+    if !db.rooms.is_joined(sender_user, &body.room_id)?
+        && !db.rooms.is_world_readable(&body.room_id)?
+    {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
@@ -433,9 +720,12 @@ pub async fn get_room_aliases_route(
 /// - Creates a replacement room
 /// - Sends a tombstone event into the current room
 /// - Sender user joins the room
-/// - Transfers some state events
+/// - Transfers some state events, bans, pinned events and the space children and parents
 /// - Moves local aliases
 /// - Modifies old room power levels to prevent users from speaking
+/// - Invites the users who were invited to the old room
+/// - Spaces and rooms of the sender that point at the old room with `m.space.child` or
+/// `m.space.parent` point at the new room
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/upgrade", data = "<body>")
@@ -445,12 +735,9 @@ pub async fn upgrade_room_route(
     db: DatabaseGuard,
     body: Ruma<upgrade_room::Request<'_>>,
 ) -> ConduitResult<upgrade_room::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    if !matches!(
-        body.new_version,
-        RoomVersionId::Version5 | RoomVersionId::Version6
-    ) {
+    if !db.globals.supports_room_version(&body.new_version) {
         return Err(Error::BadRequest(
             ErrorKind::UnsupportedRoomVersion,
             "This server does not support that room version.",
@@ -505,12 +792,14 @@ pub async fn upgrade_room_route(
     let state_lock = mutex_state.lock().await;
 
     // Get the old room federations status
+    let old_create_content = db
+        .rooms
+        .room_state_get(&body.room_id, &EventType::RoomCreate, "")?
+        .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
+        .content
+        .clone();
     let federate = serde_json::from_value::<Raw<ruma::events::room::create::CreateEventContent>>(
-        db.rooms
-            .room_state_get(&body.room_id, &EventType::RoomCreate, "")?
-            .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
-            .content
-            .clone(),
+        old_create_content.clone(),
     )
     .expect("Raw::from_value always works")
     .deserialize()
@@ -530,11 +819,17 @@ pub async fn upgrade_room_route(
     create_event_content.room_version = body.new_version.clone();
     create_event_content.predecessor = predecessor;
 
+    let mut create_event_content =
+        serde_json::to_value(create_event_content).expect("event is valid, we just created it");
+    // Ruma doesn't know the room type yet. Spaces stay spaces
+    if let Some(room_type) = old_create_content.get("type") {
+        create_event_content["type"] = room_type.clone();
+    }
+
     db.rooms.build_and_append_pdu(
         PduBuilder {
             event_type: EventType::RoomCreate,
-            content: serde_json::to_value(create_event_content)
-                .expect("event is valid, we just created it"),
+            content: create_event_content,
             unsigned: None,
             state_key: Some("".to_owned()),
             redacts: None,
@@ -569,6 +864,46 @@ pub async fn upgrade_room_route(
         &state_lock,
     )?;
 
+    let old_state = db.rooms.room_state_full(&body.room_id)?;
+
+    // Bans and the space children and parents of the room are transferred before the power
+    // levels, so the sender can still send them
+    let mut invited = Vec::new();
+    for ((event_type, state_key), pdu) in &old_state {
+        let transfer = match event_type {
+            EventType::RoomMember => match pdu.content.get("membership").and_then(|m| m.as_str()) {
+                Some("ban") => true,
+                Some("invite") => {
+                    if let Ok(user_id) = UserId::try_from(state_key.as_str()) {
+                        invited.push(user_id);
+                    }
+                    false
+                }
+                _ => false,
+            },
+            EventType::SpaceChild | EventType::SpaceParent => true,
+            _ => false,
+        };
+
+        if !transfer {
+            continue;
+        }
+
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: event_type.clone(),
+                content: pdu.content.clone(),
+                unsigned: None,
+                state_key: Some(state_key.clone()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room,
+            &db,
+            &state_lock,
+        )?;
+    }
+
     // Recommended transferable state events list from the specs
     let transferable_state_events = vec![
         EventType::RoomServerAcl,
@@ -579,12 +914,13 @@ pub async fn upgrade_room_route(
         EventType::RoomGuestAccess,
         EventType::RoomHistoryVisibility,
         EventType::RoomJoinRules,
+        EventType::from("m.room.pinned_events"),
         EventType::RoomPowerLevels,
     ];
 
     // Replicate transferable state events to the new room
     for event_type in transferable_state_events {
-        let event_content = match db.rooms.room_state_get(&body.room_id, &event_type, "")? {
+        let event_content = match old_state.get(&(event_type.clone(), "".to_owned())) {
             Some(v) => v.content.clone(),
             None => continue, // Skipping missing events.
         };
@@ -610,6 +946,18 @@ pub async fn upgrade_room_route(
             .set_alias(&alias, Some(&replacement_room), &db.globals)?;
     }
 
+    // Change lock back to the old room
+    drop(state_lock);
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
     // Get the old room power levels
     let mut power_levels_event_content =
         serde_json::from_value::<Raw<ruma::events::room::power_levels::PowerLevelsEventContent>>(
@@ -649,8 +997,112 @@ pub async fn upgrade_room_route(
 
     drop(state_lock);
 
+    // Users who were invited to the old room are invited to the new one
+    for user_id in invited {
+        if let Err(e) = invite_helper(sender_user, &user_id, &replacement_room, &db, false).await {
+            warn!(
+                "Failed to invite {} to the upgraded room {}: {}",
+                user_id, replacement_room, e
+            );
+        }
+    }
+
+    // Spaces of the sender that contain the old room contain the new room now, and rooms of the
+    // space point at the new space
+    for room_id in db.rooms.rooms_joined(sender_user).filter_map(|r| r.ok()) {
+        if room_id == body.room_id || room_id == replacement_room {
+            continue;
+        }
+
+        for event_type in &[EventType::SpaceChild, EventType::SpaceParent] {
+            if let Err(e) = replace_space_pointer(
+                &db,
+                sender_user,
+                &room_id,
+                event_type.clone(),
+                &body.room_id,
+                &replacement_room,
+            )
+            .await
+            {
+                warn!(
+                    "Failed to point {} at the upgraded room {}: {}",
+                    room_id, replacement_room, e
+                );
+            }
+        }
+    }
+
     db.flush()?;
 
     // Return the replacement room id
     Ok(upgrade_room::Response { replacement_room }.into())
 }
+
+/// Replaces the `m.space.child` or `m.space.parent` event that points at the old room of an
+/// upgrade with one that points at the new room.
+async fn replace_space_pointer(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    event_type: EventType,
+    old_room: &RoomId,
+    new_room: &RoomId,
+) -> Result<()> {
+    let content = match db
+        .rooms
+        .room_state_get(room_id, &event_type, old_room.as_str())?
+    {
+        // Events without via servers were removed already
+        Some(pdu)
+            if pdu
+                .content
+                .get("via")
+                .and_then(|via| via.as_array())
+                .map_or(false, |via| !via.is_empty()) =>
+        {
+            pdu.content.clone()
+        }
+        _ => return Ok(()),
+    };
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: event_type.clone(),
+            content,
+            unsigned: None,
+            state_key: Some(new_room.to_string()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        db,
+        &state_lock,
+    )?;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type,
+            content: serde_json::json!({}),
+            unsigned: None,
+            state_key: Some(old_room.to_string()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        db,
+        &state_lock,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use crate::{
+    database::{audit::AuditAction, users::TokenScope, DatabaseGuard},
+    utils, ConduitResult, Error, Ruma,
+};
+use ruma::{api::client::error::ErrorKind, DeviceId};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
+#[cfg(feature = "conduit_bin")]
+use rocket::{delete, get, post};
+
+/// A scoped token as the list shows it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScopedToken {
+    pub device_id: Box<DeviceId>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+
+    pub scope: TokenScope,
+}
+
+/// Custom endpoint to create access tokens with a limited scope.
+pub mod create_scoped_token {
+    use crate::database::users::TokenScope;
+    use ruma::{api::ruma_api, DeviceId};
+
+    ruma_api! {
+        metadata: {
+            description: "Create an access token with a limited scope.",
+            method: POST,
+            name: "create_scoped_token",
+            path: "/_conduit/client/v1/scoped_tokens",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The display name of the device the token belongs to.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub display_name: Option<String>,
+
+            pub scope: TokenScope,
+        }
+
+        response: {
+            pub access_token: String,
+            pub device_id: Box<DeviceId>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// Custom endpoint to list the access tokens with a limited scope.
+pub mod list_scoped_tokens {
+    use super::ScopedToken;
+    use ruma::api::ruma_api;
+
+    ruma_api! {
+        metadata: {
+            description: "List the access tokens with a limited scope.",
+            method: GET,
+            name: "list_scoped_tokens",
+            path: "/_conduit/client/v1/scoped_tokens",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {}
+
+        response: {
+            pub tokens: Vec<ScopedToken>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// Custom endpoint to revoke access tokens with a limited scope.
+pub mod revoke_scoped_token {
+    use ruma::{api::ruma_api, DeviceId};
+
+    ruma_api! {
+        metadata: {
+            description: "Revoke an access token with a limited scope.",
+            method: DELETE,
+            name: "revoke_scoped_token",
+            path: "/_conduit/client/v1/scoped_tokens/:device_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub device_id: Box<DeviceId>,
+        }
+
+        response: {}
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `POST /_conduit/client/v1/scoped_tokens`
+///
+/// Creates an access token for the sender user that can only do what the scope allows, e.g. to
+/// hand it to a bot.
+///
+/// - The token belongs to a new device, so it shows up in the device list. Deleting the device
+/// revokes the token
+/// - `read_only` only allows GET requests
+/// - `rooms` refuses requests about other rooms and limits sync to these rooms. Endpoints that
+/// act on the whole account are refused too, only sync and profile reads are left
+/// - `e2ee` allows the key endpoints and to-device messages
+/// - Scoped tokens can't manage scoped tokens
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_conduit/client/v1/scoped_tokens", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn create_scoped_token_route(
+    db: DatabaseGuard,
+    body: Ruma<create_scoped_token::Request>,
+) -> ConduitResult<create_scoped_token::Response> {
+    let sender_user = body.authenticated_user()?;
+    body.authenticated_device()?;
+
+    if body.scope.rooms.as_ref().map_or(false, Vec::is_empty) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "A token for no rooms can't do anything.",
+        ));
+    }
+
+    let device_id: Box<DeviceId> = utils::random_string(DEVICE_ID_LENGTH).into();
+    let token = utils::random_string(TOKEN_LENGTH);
+
+    db.users.create_device(
+        sender_user,
+        &device_id,
+        &token,
+        Some(
+            body.display_name
+                .clone()
+                .unwrap_or_else(|| "Scoped token".to_owned()),
+        ),
+    )?;
+    db.users
+        .set_token_scope(sender_user, &device_id, &body.scope)?;
+    db.audit.record(
+        &db.globals,
+        AuditAction::ScopedTokenCreated {
+            user_id: sender_user.clone(),
+            device_id: device_id.clone(),
+        },
+        body.client_ip,
+    )?;
+
+    info!("{} created a scoped token for {}", sender_user, device_id);
+
+    db.flush()?;
+
+    Ok(create_scoped_token::Response {
+        access_token: token,
+        device_id,
+    }
+    .into())
+}
+
+/// # `GET /_conduit/client/v1/scoped_tokens`
+///
+/// Lists the scoped tokens of the sender user. The tokens themselves are not returned.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/v1/scoped_tokens", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn list_scoped_tokens_route(
+    db: DatabaseGuard,
+    body: Ruma<list_scoped_tokens::Request>,
+) -> ConduitResult<list_scoped_tokens::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let devices = db
+        .users
+        .all_devices_metadata(sender_user)
+        .filter_map(|r| r.ok()); // Filter out buggy devices
+
+    let mut tokens = Vec::new();
+    for device in devices {
+        if let Some(scope) = db.users.token_scope(sender_user, &device.device_id)? {
+            tokens.push(ScopedToken {
+                device_id: device.device_id,
+                display_name: device.display_name,
+                scope,
+            });
+        }
+    }
+
+    Ok(list_scoped_tokens::Response { tokens }.into())
+}
+
+/// # `DELETE /_conduit/client/v1/scoped_tokens/{deviceId}`
+///
+/// Revokes a scoped token by removing its device.
+///
+/// - Fails for devices without a scope, those have to be deleted with the UIAA protected device
+/// endpoints
+#[cfg_attr(
+    feature = "conduit_bin",
+    delete("/_conduit/client/v1/scoped_tokens/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn revoke_scoped_token_route(
+    db: DatabaseGuard,
+    body: Ruma<revoke_scoped_token::Request>,
+) -> ConduitResult<revoke_scoped_token::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    if db
+        .users
+        .token_scope(sender_user, &body.device_id)?
+        .is_none()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Scoped token not found.",
+        ));
+    }
+
+    db.users.remove_device(sender_user, &body.device_id)?;
+
+    info!(
+        "{} revoked the scoped token of {}",
+        sender_user, body.device_id
+    );
+
+    db.flush()?;
+
+    Ok(revoke_scoped_token::Response {}.into())
+}
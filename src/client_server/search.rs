@@ -20,7 +20,7 @@ pub async fn search_events_route(
     db: DatabaseGuard,
     body: Ruma<search_events::Request<'_>>,
 ) -> ConduitResult<search_events::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     let search_criteria = body.search_categories.room_events.as_ref().unwrap();
     let filter = search_criteria.filter.clone().unwrap_or_default();
@@ -1,5 +1,8 @@
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{database::DatabaseGuard, utils, ConduitResult, Error, Ruma};
+use crate::{
+    database::{admin::AdminCommand, audit::AuditAction, DatabaseGuard},
+    utils, ConduitResult, Database, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -8,10 +11,12 @@ use ruma::{
             uiaa::IncomingUserIdentifier,
         },
     },
+    events::room::message,
     UserId,
 };
 use serde::Deserialize;
-use tracing::info;
+use std::net::IpAddr;
+use tracing::{info, warn};
 
 #[derive(Debug, Deserialize)]
 struct Claims {
@@ -69,15 +74,46 @@ pub async fn login_route(
             } else {
                 return Err(Error::BadRequest(ErrorKind::Forbidden, "Bad login type."));
             };
-            let user_id =
+            let mut user_id =
                 UserId::parse_with_server_name(username.to_owned(), db.globals.server_name())
                     .map_err(|_| {
                         Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
                     })?;
-            let hash = db.users.password_hash(&user_id)?.ok_or(Error::BadRequest(
-                ErrorKind::Forbidden,
-                "Wrong username or password.",
-            ))?;
+
+            // Users registered after the localpart rules only have lowercase usernames, so accept
+            // other cases for them. Historical user ids still have to match exactly.
+            if !db.users.exists(&user_id)? {
+                user_id = UserId::parse_with_server_name(
+                    user_id.localpart().to_lowercase(),
+                    user_id.server_name(),
+                )
+                .ok()
+                .filter(|normalized| super::account::is_valid_localpart(normalized.localpart()))
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidUsername,
+                    "Username is invalid.",
+                ))?;
+            }
+
+            let failure_keys = login_failure_keys(Some(&user_id), body.client_ip);
+            check_login_lockout(&db, &failure_keys)?;
+
+            let hash = match db.users.password_hash(&user_id)? {
+                Some(hash) => hash,
+                None => {
+                    // Only count the address, we don't want to store unknown user ids
+                    record_login_failure(&db, &login_failure_keys(None, body.client_ip))?;
+                    db.audit.record(
+                        &db.globals,
+                        AuditAction::LoginFailed { user_id: None },
+                        body.client_ip,
+                    )?;
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Wrong username or password.",
+                    ));
+                }
+            };
 
             if hash.is_empty() {
                 return Err(Error::BadRequest(
@@ -86,15 +122,32 @@ pub async fn login_route(
                 ));
             }
 
-            let hash_matches = argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
-
-            if !hash_matches {
+            if !utils::verify_password(&hash, password) {
+                record_login_failure(&db, &failure_keys)?;
+                db.audit.record(
+                    &db.globals,
+                    AuditAction::LoginFailed {
+                        user_id: Some(user_id.clone()),
+                    },
+                    body.client_ip,
+                )?;
                 return Err(Error::BadRequest(
                     ErrorKind::Forbidden,
                     "Wrong username or password.",
                 ));
             }
 
+            for (key, _) in &failure_keys {
+                db.users.reset_login_failures(key)?;
+            }
+
+            // Upgrade old hashes now that we know the password
+            if db.users.password_needs_rehash(&hash) {
+                if let Err(e) = db.users.set_password(&user_id, Some(password.as_str())) {
+                    warn!("Failed to rehash the password of {}: {}", user_id, e);
+                }
+            }
+
             user_id
         }
         login::IncomingLoginInfo::Token { token } => {
@@ -145,6 +198,15 @@ pub async fn login_route(
         )?;
     }
 
+    db.audit.record(
+        &db.globals,
+        AuditAction::Login {
+            user_id: user_id.clone(),
+            device_id: device_id.clone(),
+        },
+        body.client_ip,
+    )?;
+
     info!("{} logged in", user_id);
 
     db.flush()?;
@@ -159,6 +221,83 @@ pub async fn login_route(
     .into())
 }
 
+/// The keys under which failed logins are counted, the address of the client and the user from
+/// that address, together with a description for admins. Failures are never counted for the
+/// user alone, otherwise anyone could lock others out of their accounts.
+pub(crate) fn login_failure_keys(
+    user_id: Option<&UserId>,
+    client_ip: Option<IpAddr>,
+) -> Vec<(Vec<u8>, String)> {
+    let client_ip = client_ip.map(|ip| ip.to_string());
+    let mut keys = Vec::new();
+
+    if let Some(client_ip) = &client_ip {
+        let mut ip_key = b"ip".to_vec();
+        ip_key.push(0xff);
+        ip_key.extend_from_slice(client_ip.as_bytes());
+        keys.push((ip_key, client_ip.clone()));
+    }
+
+    if let Some(user_id) = user_id {
+        let client_ip = client_ip.as_deref().unwrap_or("an unknown address");
+
+        let mut user_key = b"userip".to_vec();
+        user_key.push(0xff);
+        user_key.extend_from_slice(user_id.as_bytes());
+        user_key.push(0xff);
+        user_key.extend_from_slice(client_ip.as_bytes());
+        keys.push((user_key, format!("{} from {}", user_id, client_ip)));
+    }
+
+    keys
+}
+
+/// Fails with M_LIMIT_EXCEEDED if the address, or the user from this address, failed to log in
+/// too often.
+pub(crate) fn check_login_lockout(db: &Database, keys: &[(Vec<u8>, String)]) -> Result<()> {
+    for (key, _) in keys {
+        if let Some(remaining) = db.users.login_lockout(
+            key,
+            db.globals.login_failures_before_lockout(),
+            db.globals.login_lockout(),
+        )? {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(remaining),
+                },
+                "Too many failed login attempts, try again later.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts a failed login and tells the admins when the failures don't stop.
+pub(crate) fn record_login_failure(db: &Database, keys: &[(Vec<u8>, String)]) -> Result<()> {
+    let notice_after = u64::from(db.globals.login_failures_before_lockout()) * 10;
+
+    for (key, description) in keys {
+        // The failures in a row start again after every lockout, so look at all failures since
+        // the last successful login
+        let (_, failures) = db
+            .users
+            .record_login_failure(key, db.globals.login_lockout())?;
+
+        if failures == notice_after {
+            warn!("{} failed logins for {}", failures, description);
+            db.admin.send(AdminCommand::SendMessage(
+                message::MessageEventContent::text_plain(format!(
+                    "Possible brute-force attack: {} failed logins for {}.",
+                    failures, description
+                )),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// # `POST /_matrix/client/r0/logout`
 ///
 /// Log out the current device.
@@ -176,8 +315,8 @@ pub async fn logout_route(
     db: DatabaseGuard,
     body: Ruma<logout::Request>,
 ) -> ConduitResult<logout::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+    let sender_device = body.authenticated_device()?;
 
     db.users.remove_device(&sender_user, sender_device)?;
 
@@ -206,7 +345,7 @@ pub async fn logout_all_route(
     db: DatabaseGuard,
     body: Ruma<logout_all::Request>,
 ) -> ConduitResult<logout_all::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
     for device_id in db.users.all_device_ids(sender_user).flatten() {
         db.users.remove_device(&sender_user, &device_id)?;
@@ -0,0 +1,348 @@
+use crate::{
+    client_server, database::DatabaseGuard, server_server, ConduitResult, Database, Error,
+    PduEvent, Result, Ruma,
+};
+use ruma::{api::client::error::ErrorKind, events::EventType, RoomId, ServerName, UserId};
+use std::{collections::HashSet, iter, sync::Arc};
+use tracing::warn;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// How many rooms /hierarchy returns if the client doesn't set a limit.
+const DEFAULT_HIERARCHY_LIMIT: u64 = 50;
+/// Clients can't ask for more rooms per /hierarchy request than this.
+const MAX_HIERARCHY_LIMIT: u64 = 100;
+
+/// `GET /_matrix/client/v1/rooms/{roomId}/hierarchy` of MSC2946, which ruma doesn't know yet.
+pub mod get_hierarchy {
+    use ruma::{api::ruma_api, RoomId, UInt};
+    use serde::{Deserialize, Serialize};
+
+    use super::super::get_public_rooms_filtered_with_types::PublicRoomsChunkWithType;
+
+    /// A room of a space hierarchy, as clients and other servers see it.
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct SpaceHierarchyRoomsChunk {
+        #[serde(flatten)]
+        pub chunk: PublicRoomsChunkWithType,
+
+        pub join_rule: String,
+
+        /// The `m.space.child` events of the room as stripped state with `origin_server_ts`.
+        #[serde(default)]
+        pub children_state: Vec<serde_json::Value>,
+
+        /// The rooms whose members may join the room, if it is restricted. Only used over
+        /// federation.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub allowed_room_ids: Vec<RoomId>,
+    }
+
+    ruma_api! {
+        metadata: {
+            description: "Get the rooms of a space.",
+            method: GET,
+            name: "get_hierarchy",
+            path: "/_matrix/client/v1/rooms/:room_id/hierarchy",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The space to walk.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// The next_batch of a previous response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            /// The maximum number of rooms in the response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            /// How deep to walk into subspaces. The space itself has depth 0.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub max_depth: Option<UInt>,
+
+            /// Only follow children that are marked as suggested.
+            #[ruma_api(query)]
+            #[serde(default)]
+            pub suggested_only: bool,
+        }
+
+        response: {
+            pub rooms: Vec<SpaceHierarchyRoomsChunk>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+use get_hierarchy::SpaceHierarchyRoomsChunk;
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/hierarchy`
+///
+/// Walks the `m.space.child` events of a space and returns the rooms in it, breadth-first.
+///
+/// - Only returns rooms the user is in, was invited to, or could join or peek into
+/// - Asks the servers in the `via` of the child event about rooms this server isn't in
+/// - `suggested_only` only follows children that are marked as suggested
+/// - The `next_batch` token only works with the same `suggested_only` and `max_depth`
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/rooms/<_>/hierarchy", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_hierarchy_route(
+    db: DatabaseGuard,
+    body: Ruma<get_hierarchy::Request>,
+) -> ConduitResult<get_hierarchy::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let limit = body
+        .limit
+        .map_or(DEFAULT_HIERARCHY_LIMIT, u64::from)
+        .min(MAX_HIERARCHY_LIMIT)
+        .max(1) as usize;
+    let max_depth = body.max_depth.map(u64::from);
+
+    let skip = match &body.from {
+        Some(token) => parse_hierarchy_token(token, body.suggested_only, max_depth)?,
+        None => 0,
+    };
+
+    // Spaces hosted on other servers are asked for as a whole
+    if db.rooms.current_shortstatehash(&body.room_id)?.is_none() {
+        if skip > 0 {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid pagination token.",
+            ));
+        }
+
+        let via = vec![body.room_id.server_name().to_owned()];
+        let (room, mut children) = remote_hierarchy(&db, &body.room_id, &via, body.suggested_only)
+            .await
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Space not found."))?;
+
+        if max_depth == Some(0) {
+            children.clear();
+        }
+
+        let mut rooms = Vec::new();
+        for summary in iter::once(room).chain(children) {
+            if rooms.len() < limit && summary_visible_to(&db, sender_user, &summary)? {
+                rooms.push(summary);
+            }
+        }
+
+        return Ok(get_hierarchy::Response {
+            rooms,
+            next_batch: None,
+        }
+        .into());
+    }
+
+    if !summary_visible_to(&db, sender_user, &local_summary(&db, &body.room_id, &[])?)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this space.",
+        ));
+    }
+
+    let hierarchy = db
+        .rooms
+        .space_hierarchy(&body.room_id, body.suggested_only, max_depth)?;
+
+    // Rooms we have the state of come first, then the rooms we have to ask other servers about
+    let total = hierarchy.rooms.len() + hierarchy.inaccessible.len();
+    let mut rooms = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = skip;
+
+    while next < total && rooms.len() < limit {
+        let summaries = match hierarchy.rooms.get(next) {
+            Some(room) => vec![local_summary(&db, &room.room_id, &room.children_state)?],
+            None => {
+                let (room_id, via) = &hierarchy.inaccessible[next - hierarchy.rooms.len()];
+                match remote_hierarchy(&db, room_id, via, body.suggested_only).await {
+                    // Children that are part of the hierarchy anyway come in their own turn
+                    Some((room, children)) => iter::once(room)
+                        .chain(
+                            children
+                                .into_iter()
+                                .filter(|child| !hierarchy.contains(&child.chunk.chunk.room_id)),
+                        )
+                        .collect(),
+                    None => Vec::new(),
+                }
+            }
+        };
+        next += 1;
+
+        for summary in summaries {
+            if seen.insert(summary.chunk.chunk.room_id.clone())
+                && summary_visible_to(&db, sender_user, &summary)?
+            {
+                rooms.push(summary);
+            }
+        }
+    }
+
+    Ok(get_hierarchy::Response {
+        rooms,
+        next_batch: (next < total).then(|| hierarchy_token(next, body.suggested_only, max_depth)),
+    }
+    .into())
+}
+
+/// Pagination tokens contain how many rooms of the hierarchy were handled already and the
+/// parameters they belong to.
+fn hierarchy_token(skip: usize, suggested_only: bool, max_depth: Option<u64>) -> String {
+    format!(
+        "{}_{}_{}",
+        skip,
+        suggested_only as u8,
+        max_depth.map_or_else(|| "-".to_owned(), |d| d.to_string())
+    )
+}
+
+fn parse_hierarchy_token(
+    token: &str,
+    suggested_only: bool,
+    max_depth: Option<u64>,
+) -> Result<usize> {
+    let skip = token
+        .split('_')
+        .next()
+        .and_then(|skip| skip.parse::<usize>().ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid pagination token.",
+        ))?;
+
+    if hierarchy_token(skip, suggested_only, max_depth) != token {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "suggested_only and max_depth can't change between pages.",
+        ));
+    }
+
+    Ok(skip)
+}
+
+/// Returns the summary of a room we have the state of.
+pub(crate) fn local_summary(
+    db: &Database,
+    room_id: &RoomId,
+    children_state: &[Arc<PduEvent>],
+) -> Result<SpaceHierarchyRoomsChunk> {
+    let join_rule = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomJoinRules, "")?
+        .and_then(|pdu| {
+            pdu.content
+                .get("join_rule")
+                .and_then(|j| j.as_str())
+                .map(ToOwned::to_owned)
+        })
+        .unwrap_or_else(|| "invite".to_owned());
+
+    let allowed_room_ids = if join_rule == "restricted" {
+        db.rooms
+            .restricted_allow_rooms(room_id)
+            .collect::<Result<_>>()?
+    } else {
+        Vec::new()
+    };
+
+    Ok(SpaceHierarchyRoomsChunk {
+        chunk: client_server::public_rooms_chunk(db, room_id)?,
+        join_rule,
+        children_state: children_state
+            .iter()
+            .map(|pdu| stripped_child_event(pdu))
+            .collect(),
+        allowed_room_ids,
+    })
+}
+
+/// Children are sent as stripped state events, but with their timestamp for ordering.
+fn stripped_child_event(pdu: &PduEvent) -> serde_json::Value {
+    serde_json::json!({
+        "type": pdu.kind,
+        "state_key": pdu.state_key,
+        "content": pdu.content,
+        "sender": pdu.sender,
+        "origin_server_ts": pdu.origin_server_ts,
+    })
+}
+
+/// Users can see rooms they are in or were invited to, and rooms they could join or peek into.
+fn summary_visible_to(
+    db: &Database,
+    user_id: &UserId,
+    summary: &SpaceHierarchyRoomsChunk,
+) -> Result<bool> {
+    let room_id = &summary.chunk.chunk.room_id;
+
+    if summary.chunk.chunk.world_readable
+        || matches!(&*summary.join_rule, "public" | "knock")
+        || db.rooms.is_joined(user_id, room_id)?
+        || db.rooms.is_invited(user_id, room_id)?
+    {
+        return Ok(true);
+    }
+
+    if summary.join_rule == "restricted" {
+        for allowed in &summary.allowed_room_ids {
+            if db.rooms.is_joined(user_id, allowed)? {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Asks the `via` servers about a room this server isn't in. Returns the room and its children,
+/// or None if no server knew the room.
+async fn remote_hierarchy(
+    db: &Database,
+    room_id: &RoomId,
+    via: &[Box<ServerName>],
+    suggested_only: bool,
+) -> Option<(SpaceHierarchyRoomsChunk, Vec<SpaceHierarchyRoomsChunk>)> {
+    for server in via {
+        if server == db.globals.server_name() {
+            continue;
+        }
+
+        match server_server::send_request(
+            &db.globals,
+            server,
+            server_server::get_hierarchy::Request {
+                room_id: room_id.clone(),
+                suggested_only,
+            },
+        )
+        .await
+        {
+            Ok(response) => return Some((response.room, response.children)),
+            Err(e) => warn!(
+                "Failed to fetch the hierarchy of {} from {}: {}",
+                room_id, server, e
+            ),
+        }
+    }
+
+    None
+}
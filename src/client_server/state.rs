@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{convert::TryFrom, sync::Arc};
 
 use crate::{
     database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Database, Error, Result, Ruma,
@@ -6,17 +6,14 @@ use crate::{
 use ruma::{
     api::client::{
         error::ErrorKind,
-        r0::state::{get_state_events, get_state_events_for_key, send_state_event},
+        r0::state::{get_state_events_for_key, send_state_event},
     },
     events::{
-        room::{
-            canonical_alias::CanonicalAliasEventContent,
-            history_visibility::{HistoryVisibility, HistoryVisibilityEventContent},
-        },
+        room::{canonical_alias::CanonicalAliasEventContent, encryption::EncryptionEventContent},
         AnyStateEventContent, EventType,
     },
     serde::Raw,
-    EventId, RoomId, UserId,
+    uint, EventEncryptionAlgorithm, EventId, RoomId, UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -29,6 +26,7 @@ use rocket::{get, put};
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
 /// - If event is new canonical_alias: Rejects if alias is incorrect
+/// - Fails with M_LIMIT_EXCEEDED if the user exhausted their message budget
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/state/<_>/<_>", data = "<body>")
@@ -38,7 +36,13 @@ pub async fn send_state_event_for_key_route(
     db: DatabaseGuard,
     body: Ruma<send_state_event::Request<'_>>,
 ) -> ConduitResult<send_state_event::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    db.globals.check_message_limit(
+        sender_user,
+        body.from_appservice,
+        db.reports.is_under_review(sender_user)?,
+    )?;
 
     let event_id = send_state_event_for_key_helper(
         &db,
@@ -62,6 +66,7 @@ pub async fn send_state_event_for_key_route(
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
 /// - If event is new canonical_alias: Rejects if alias is incorrect
+/// - Fails with M_LIMIT_EXCEEDED if the user exhausted their message budget
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/state/<_>", data = "<body>")
@@ -71,7 +76,13 @@ pub async fn send_state_event_for_empty_key_route(
     db: DatabaseGuard,
     body: Ruma<send_state_event::Request<'_>>,
 ) -> ConduitResult<send_state_event::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
+
+    db.globals.check_message_limit(
+        sender_user,
+        body.from_appservice,
+        db.reports.is_under_review(sender_user)?,
+    )?;
 
     let event_id = send_state_event_for_key_helper(
         &db,
@@ -88,11 +99,48 @@ pub async fn send_state_event_for_empty_key_route(
     Ok(send_state_event::Response { event_id }.into())
 }
 
+/// `GET /_matrix/client/r0/rooms/{roomid}/state` with an additional `at` query parameter.
+pub mod get_state_events_at {
+    use ruma::{api::ruma_api, events::AnyStateEvent, serde::Raw, RoomId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the state events of a room, optionally at a point in the past.",
+            method: GET,
+            name: "get_state_events_at",
+            path: "/_matrix/client/r0/rooms/:room_id/state",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room to look up the state for.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// An event id or a pagination or sync token.
+            #[ruma_api(query)]
+            pub at: Option<String>,
+        }
+
+        response: {
+            /// The state events of the room.
+            #[ruma_api(body)]
+            pub room_state: Vec<Raw<AnyStateEvent>>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomid}/state`
 ///
 /// Get all state events for a room.
 ///
-/// - If not joined: Only works if current room history visibility is world readable
+/// - If `at` is an event id: Returns the state before that event
+/// - If `at` is a pagination or sync token: Returns the state at that point of the timeline
+/// - If not joined: Only works if current room history visibility is world readable or if the
+/// user was joined at that point
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/state", data = "<body>")
@@ -100,28 +148,30 @@ pub async fn send_state_event_for_empty_key_route(
 #[tracing::instrument(skip(db, body))]
 pub async fn get_state_events_route(
     db: DatabaseGuard,
-    body: Ruma<get_state_events::Request<'_>>,
-) -> ConduitResult<get_state_events::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    body: Ruma<get_state_events_at::Request>,
+) -> ConduitResult<get_state_events_at::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let shortstatehash = match &body.at {
+        Some(at) => Some(state_at(&db, &body.room_id, sender_user, at)?),
+        None => db.rooms.current_shortstatehash(&body.room_id)?,
+    };
+
+    let joined_then = match shortstatehash {
+        Some(shortstatehash) => db
+            .rooms
+            .state_get(shortstatehash, &EventType::RoomMember, sender_user.as_str())?
+            .map_or(false, |member| {
+                member.content.get("membership").and_then(|m| m.as_str()) == Some("join")
+            }),
+        None => false,
+    };
 
-    #[allow(clippy::blocks_in_if_conditions)]
     // Users not in the room should not be able to access the state unless history_visibility is
     // WorldReadable
     if !db.rooms.is_joined(sender_user, &body.room_id)?
-        && !matches!(
-            db.rooms
-                .room_state_get(&body.room_id, &EventType::RoomHistoryVisibility, "")?
-                .map(|event| {
-                    serde_json::from_value::<HistoryVisibilityEventContent>(event.content.clone())
-                        .map_err(|_| {
-                            Error::bad_database(
-                                "Invalid room history visibility event in database.",
-                            )
-                        })
-                        .map(|e| e.history_visibility)
-                }),
-            Some(Ok(HistoryVisibility::WorldReadable))
-        )
+        && !joined_then
+        && !db.rooms.is_world_readable(&body.room_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -129,15 +179,62 @@ pub async fn get_state_events_route(
         ));
     }
 
-    Ok(get_state_events::Response {
-        room_state: db
+    let room_state = match shortstatehash {
+        Some(shortstatehash) => db
             .rooms
-            .room_state_full(&body.room_id)?
+            .state_full(shortstatehash)?
             .values()
             .map(|pdu| pdu.to_state_event())
             .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(get_state_events_at::Response { room_state }.into())
+}
+
+/// Finds the state hash of a room at an event or token.
+fn state_at(db: &Database, room_id: &RoomId, sender_user: &UserId, at: &str) -> Result<u64> {
+    if !db.rooms.exists(room_id)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
     }
-    .into())
+
+    let event_id = if at.starts_with('$') {
+        EventId::try_from(at)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event id."))?
+    } else {
+        let count = match at.parse::<u64>() {
+            Ok(count) => count,
+            Err(_) => db.globals.parse_sync_token(at)?,
+        };
+
+        // The state at a token is the state before the first event after it
+        match db.rooms.pdus_after(sender_user, room_id, count)?.next() {
+            Some(pdu) => pdu?.1.event_id,
+            None => {
+                return db
+                    .rooms
+                    .current_shortstatehash(room_id)?
+                    .ok_or(Error::BadRequest(ErrorKind::NotFound, "Room not found."))
+            }
+        }
+    };
+
+    match db.rooms.get_pdu(&event_id)? {
+        Some(pdu) if &pdu.room_id == room_id => {}
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Event not found in this room.",
+            ))
+        }
+    }
+
+    db.rooms
+        .pdu_shortstatehash(&event_id)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "The state at this event is unknown.",
+        ))
 }
 
 /// # `GET /_matrix/client/r0/rooms/{roomid}/state/{eventType}/{stateKey}`
@@ -154,26 +251,12 @@ pub async fn get_state_events_for_key_route(
     db: DatabaseGuard,
     body: Ruma<get_state_events_for_key::Request<'_>>,
 ) -> ConduitResult<get_state_events_for_key::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    #[allow(clippy::blocks_in_if_conditions)]
     // Users not in the room should not be able to access the state unless history_visibility is
     // WorldReadable
     if !db.rooms.is_joined(sender_user, &body.room_id)?
-        && !matches!(
-            db.rooms
-                .room_state_get(&body.room_id, &EventType::RoomHistoryVisibility, "")?
-                .map(|event| {
-                    serde_json::from_value::<HistoryVisibilityEventContent>(event.content.clone())
-                        .map_err(|_| {
-                            Error::bad_database(
-                                "Invalid room history visibility event in database.",
-                            )
-                        })
-                        .map(|e| e.history_visibility)
-                }),
-            Some(Ok(HistoryVisibility::WorldReadable))
-        )
+        && !db.rooms.is_world_readable(&body.room_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -210,26 +293,12 @@ pub async fn get_state_events_for_empty_key_route(
     db: DatabaseGuard,
     body: Ruma<get_state_events_for_key::Request<'_>>,
 ) -> ConduitResult<get_state_events_for_key::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
 
-    #[allow(clippy::blocks_in_if_conditions)]
     // Users not in the room should not be able to access the state unless history_visibility is
     // WorldReadable
     if !db.rooms.is_joined(sender_user, &body.room_id)?
-        && !matches!(
-            db.rooms
-                .room_state_get(&body.room_id, &EventType::RoomHistoryVisibility, "")?
-                .map(|event| {
-                    serde_json::from_value::<HistoryVisibilityEventContent>(event.content.clone())
-                        .map_err(|_| {
-                            Error::bad_database(
-                                "Invalid room history visibility event in database.",
-                            )
-                        })
-                        .map(|e| e.history_visibility)
-                }),
-            Some(Ok(HistoryVisibility::WorldReadable))
-        )
+        && !db.rooms.is_world_readable(&body.room_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -290,6 +359,12 @@ async fn send_state_event_for_key_helper(
         }
     }
 
+    let mut content = serde_json::from_str(json.json().get()).expect("content is valid json");
+
+    if event_type == EventType::RoomEncryption {
+        content = validate_encryption_content(&content)?;
+    }
+
     let mutex_state = Arc::clone(
         db.globals
             .roomid_mutex_state
@@ -303,7 +378,7 @@ async fn send_state_event_for_key_helper(
     let event_id = db.rooms.build_and_append_pdu(
         PduBuilder {
             event_type,
-            content: serde_json::from_str(json.json().get()).expect("content is valid json"),
+            content,
             unsigned: None,
             state_key: Some(state_key),
             redacts: None,
@@ -316,3 +391,44 @@ async fn send_state_event_for_key_helper(
 
     Ok(event_id)
 }
+
+/// Checks the content of an m.room.encryption event and returns it with the rotation settings
+/// filled in, so every client and appservice sees the same values.
+///
+/// - Only m.megolm.v1.aes-sha2 is allowed
+/// - The rotation period has to be between a minute and a year
+/// - The rotation has to happen after 1 to 10000 messages
+pub fn validate_encryption_content(content: &serde_json::Value) -> Result<serde_json::Value> {
+    let mut content = serde_json::from_value::<EncryptionEventContent>(content.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid m.room.encryption event."))?;
+
+    if content.algorithm != EventEncryptionAlgorithm::MegolmV1AesSha2 {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only the m.megolm.v1.aes-sha2 algorithm is supported.",
+        ));
+    }
+
+    let rotation_period_ms = content
+        .rotation_period_ms
+        .unwrap_or_else(|| uint!(604_800_000)); // One week
+    if rotation_period_ms < uint!(60_000) || rotation_period_ms > uint!(31_536_000_000) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "rotation_period_ms has to be between a minute and a year.",
+        ));
+    }
+
+    let rotation_period_msgs = content.rotation_period_msgs.unwrap_or_else(|| uint!(100));
+    if rotation_period_msgs < uint!(1) || rotation_period_msgs > uint!(10_000) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "rotation_period_msgs has to be between 1 and 10000.",
+        ));
+    }
+
+    content.rotation_period_ms = Some(rotation_period_ms);
+    content.rotation_period_msgs = Some(rotation_period_msgs);
+
+    Ok(serde_json::to_value(content).expect("event is valid, we just created it"))
+}
@@ -1,18 +1,40 @@
-use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma, RumaResponse};
+use crate::{
+    client_server,
+    database::DatabaseGuard,
+    ruma_wrapper::{ExtendedResponse, RequestTimes},
+    Database, Error, PduEvent, Result, Ruma, RumaResponse,
+};
+use rocket::{
+    futures::{
+        stream::{self, BoxStream, StreamExt},
+        FutureExt,
+    },
+    response::stream::{Event, EventStream},
+};
 use ruma::{
-    api::client::r0::{sync::sync_events, uiaa::UiaaResponse},
-    events::{room::member::MembershipState, AnySyncEphemeralRoomEvent, EventType},
+    api::{
+        client::{
+            error::ErrorKind,
+            r0::{filter::IncomingFilterDefinition, sync::sync_events, uiaa::UiaaResponse},
+        },
+        OutgoingResponse,
+    },
+    events::{
+        presence::PresenceEvent, room::member::MembershipState, AnyRoomAccountDataEvent,
+        AnySyncEphemeralRoomEvent, EventType,
+    },
     serde::Raw,
     DeviceId, RoomId, UserId,
 };
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
+    future::Future,
+    pin::Pin,
     sync::Arc,
     time::Duration,
 };
-use tokio::sync::watch::Sender;
-use tracing::error;
+use tracing::{error, warn};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, tokio};
@@ -23,6 +45,8 @@ use rocket::{get, tokio};
 ///
 /// - This endpoint takes a `since` parameter which should be the `next_batch` value from a
 /// previous request for incremental syncs.
+/// - Fails with M_UNKNOWN_TOKEN if `since` was not created by this server or was invalidated,
+/// for example by a database migration
 ///
 /// Calling this endpoint without a `since` parameter returns:
 /// - Some of the most recent events of each timeline
@@ -44,11 +68,20 @@ use rocket::{get, tokio};
 /// For invited rooms:
 /// - If the user was invited after `since`: A subset of the state of the room at the point of the invite
 ///
+/// For knocked rooms:
+/// - If the user knocked after `since`: A subset of the state of the room at the point of the knock
+///
 /// For left rooms:
-/// - If the user left after `since`: prev_batch token, empty state (TODO: subset of the state at the point of the leave)
+/// - If the user left after `since`: The room account data that changed and the timeline up to the
+/// leave event, empty state (TODO: subset of the state at the point of the leave)
 ///
-/// - Sync is handled in an async task, multiple requests from the same device with the same
-/// `since` will be cached
+/// - The filter (an inline definition or the id of a stored filter) limits the rooms, the number
+/// of timeline events and the types and senders of events in the response
+/// - `set_presence` marks the user as online (the default) or unavailable, offline leaves the
+/// presence alone
+/// - `device_unused_fallback_key_types` lists the fallback keys of the device nobody claimed yet
+/// - Waits at most `max_sync_timeout_secs` for news. If more than `max_concurrent_syncs_per_user`
+/// syncs of the user wait, the oldest ones return early
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/sync", data = "<body>")
@@ -57,118 +90,346 @@ use rocket::{get, tokio};
 pub async fn sync_events_route(
     db: DatabaseGuard,
     body: Ruma<sync_events::Request<'_>>,
-) -> std::result::Result<RumaResponse<sync_events::Response>, RumaResponse<UiaaResponse>> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
-
-    let arc_db = Arc::new(db);
-
-    let mut rx = match arc_db
-        .globals
-        .sync_receivers
-        .write()
-        .unwrap()
-        .entry((sender_user.clone(), sender_device.clone()))
+) -> std::result::Result<ExtendedResponse<sync_events::Response>, RumaResponse<UiaaResponse>> {
+    let sender_user = body.authenticated_user()?;
+    let sender_device = Box::<DeviceId>::from(body.authenticated_device()?.as_str());
+
+    let filter = Arc::new(load_filter(
+        &db,
+        sender_user,
+        &sender_device,
+        body.filter.as_ref(),
+    )?);
+    // Replicas can't write presence. Setting it with the presence endpoint is forwarded instead
+    if !db.globals.is_replica() {
+        client_server::update_presence_from_sync(&db, sender_user, &body.set_presence)?;
+    }
+
+    let db = Arc::new(db);
+
+    // The sync runs in the request itself. While it waits for new events, it only holds the
+    // shared room wakers and the tree watchers of the user
+    let response = sync_helper(
+        Arc::clone(&db),
+        sender_user.clone(),
+        sender_device.clone(),
+        body.since.clone(),
+        body.full_state,
+        filter,
+        body.timeout,
+    )
+    .await?;
+
+    Ok(ExtendedResponse {
+        response,
+        extra_fields: fallback_key_fields(&db, sender_user, &sender_device)?,
+        overrides: serde_json::Map::new(),
+    })
+}
+
+/// Loads the filter of a sync. Access tokens that are limited to some rooms only get those rooms.
+fn load_filter(
+    db: &Database,
+    sender_user: &UserId,
+    sender_device: &DeviceId,
+    filter: Option<&sync_events::IncomingFilter>,
+) -> Result<IncomingFilterDefinition> {
+    let mut filter = client_server::load_sync_filter(db, sender_user, filter)?;
+
+    if let Some(rooms) = db
+        .users
+        .token_scope(sender_user, sender_device)?
+        .and_then(|scope| scope.rooms)
     {
-        Entry::Vacant(v) => {
-            let (tx, rx) = tokio::sync::watch::channel(None);
-
-            tokio::spawn(sync_helper_wrapper(
-                Arc::clone(&arc_db),
-                sender_user.clone(),
-                sender_device.clone(),
-                body.since.clone(),
-                body.full_state,
-                body.timeout,
-                tx,
-            ));
-
-            v.insert((body.since.clone(), rx)).1.clone()
-        }
-        Entry::Occupied(mut o) => {
-            if o.get().0 != body.since {
-                let (tx, rx) = tokio::sync::watch::channel(None);
-
-                tokio::spawn(sync_helper_wrapper(
-                    Arc::clone(&arc_db),
-                    sender_user.clone(),
-                    sender_device.clone(),
-                    body.since.clone(),
-                    body.full_state,
-                    body.timeout,
-                    tx,
-                ));
-
-                o.insert((body.since.clone(), rx.clone()));
-
-                rx
-            } else {
-                o.get().1.clone()
-            }
+        filter.room.rooms = Some(match filter.room.rooms.take() {
+            Some(requested) => requested
+                .into_iter()
+                .filter(|room_id| rooms.contains(room_id))
+                .collect(),
+            None => rooms,
+        });
+    }
+
+    Ok(filter)
+}
+
+/// Ruma doesn't know fallback keys yet. The field is always there, so clients know that the
+/// server supports them
+fn fallback_key_fields(
+    db: &Database,
+    sender_user: &UserId,
+    sender_device: &DeviceId,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let unused_fallback_key_types = serde_json::to_value(
+        db.users
+            .unused_fallback_key_types(sender_user, sender_device)?,
+    )
+    .expect("algorithms can be serialized");
+
+    let mut extra_fields = serde_json::Map::new();
+    extra_fields.insert(
+        "device_unused_fallback_key_types".to_owned(),
+        unused_fallback_key_types.clone(),
+    );
+    extra_fields.insert(
+        "org.matrix.msc2732.device_unused_fallback_key_types".to_owned(),
+        unused_fallback_key_types,
+    );
+
+    Ok(extra_fields)
+}
+
+/// Experimental streaming alternative to long-polling /sync.
+pub mod sync_stream {
+    use ruma::{api::ruma_api, presence::PresenceState};
+
+    ruma_api! {
+        metadata: {
+            description: "Stream incremental syncs as server-sent events.",
+            method: GET,
+            name: "sync_stream",
+            path: "/_conduit/client/v1/sync/stream",
+            rate_limited: false,
+            authentication: AccessToken,
         }
-    };
 
-    let we_have_to_wait = rx.borrow().is_none();
-    if we_have_to_wait {
-        if let Err(e) = rx.changed().await {
-            error!("Error waiting for sync: {}", e);
+        request: {
+            /// The id of a stored filter or an inline filter definition, like for /sync.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub filter: Option<String>,
+
+            /// The `next_batch` of a previous sync to continue from.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub since: Option<String>,
+
+            /// Only applies to the first sync of the stream.
+            #[ruma_api(query)]
+            #[serde(default)]
+            pub full_state: bool,
+
+            #[ruma_api(query)]
+            #[serde(default)]
+            pub set_presence: PresenceState,
         }
-    }
 
-    let result = match rx
-        .borrow()
-        .as_ref()
-        .expect("When sync channel changes it's always set to some")
-    {
-        Ok(response) => Ok(response.clone()),
-        Err(error) => Err(error.to_response()),
-    };
+        response: {}
 
-    result
+        error: ruma::api::client::Error
+    }
 }
 
-async fn sync_helper_wrapper(
+/// What a sync stream needs to compute the next delta.
+struct SyncStream {
     db: Arc<DatabaseGuard>,
     sender_user: UserId,
     sender_device: Box<DeviceId>,
+    set_presence: ruma::presence::PresenceState,
     since: Option<String>,
     full_state: bool,
-    timeout: Option<Duration>,
-    tx: Sender<Option<ConduitResult<sync_events::Response>>>,
-) {
-    let r = sync_helper(
-        Arc::clone(&db),
-        sender_user.clone(),
-        sender_device.clone(),
-        since.clone(),
-        full_state,
-        timeout,
-    )
-    .await;
+    filter: Arc<IncomingFilterDefinition>,
+    /// Resolves when something changed since the last delta. None before the first one.
+    watcher: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// Streams count as waiting syncs of the user. Notified when newer syncs push this one out.
+    closed: Arc<tokio::sync::Notify>,
+}
 
-    if let Ok((_, caching_allowed)) = r {
-        if !caching_allowed {
-            match db
-                .globals
-                .sync_receivers
-                .write()
-                .unwrap()
-                .entry((sender_user, sender_device))
-            {
-                Entry::Occupied(o) => {
-                    // Only remove if the device didn't start a different /sync already
-                    if o.get().0 == since {
-                        o.remove();
-                    }
-                }
-                Entry::Vacant(_) => {}
+impl Drop for SyncStream {
+    fn drop(&mut self) {
+        self.db
+            .globals
+            .remove_sync_waiter(&self.sender_user, &self.closed);
+    }
+}
+
+/// # `GET /_conduit/client/v1/sync/stream`
+///
+/// Experimental: Keeps the connection open and sends incremental syncs as server-sent events
+/// when they happen, instead of the client repeating /sync. The server only computes a delta when
+/// something changed for the user.
+///
+/// - Takes the same `filter`, `since`, `full_state` and `set_presence` parameters as /sync
+/// - Every `sync` event contains a /sync response. Its `next_batch` can be used with /sync or to
+/// reconnect the stream later
+/// - The first event is always sent, later events only if the delta is not empty
+/// - Comments are sent as a heartbeat while nothing happens
+/// - The stream ends when the server shuts down or a sync fails
+/// - Streams count against `max_concurrent_syncs_per_user` like waiting syncs, newer syncs of the
+/// user end the oldest stream
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/v1/sync/stream", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn sync_stream_route(
+    db: DatabaseGuard,
+    body: Ruma<sync_stream::Request>,
+) -> Result<EventStream<BoxStream<'static, Event>>> {
+    let sender_user = body.authenticated_user()?;
+    let sender_device = Box::<DeviceId>::from(body.authenticated_device()?.as_str());
+
+    let filter = body
+        .filter
+        .as_deref()
+        .map(parse_stream_filter)
+        .transpose()?;
+    let filter = Arc::new(load_filter(
+        &db,
+        sender_user,
+        &sender_device,
+        filter.as_ref(),
+    )?);
+
+    let closed = db.globals.add_sync_waiter(sender_user);
+    let state = SyncStream {
+        db: Arc::new(db),
+        sender_user: sender_user.clone(),
+        sender_device,
+        set_presence: body.set_presence.clone(),
+        since: body.since.clone(),
+        full_state: body.full_state,
+        filter,
+        watcher: None,
+        closed,
+    };
+
+    Ok(EventStream::from(
+        stream::unfold(state, next_sync_event).boxed(),
+    ))
+}
+
+/// Filters in the query string are either json definitions or filter ids.
+fn parse_stream_filter(filter: &str) -> Result<sync_events::IncomingFilter> {
+    if filter.starts_with('{') {
+        serde_json::from_str(filter)
+            .map(sync_events::IncomingFilter::FilterDefinition)
+            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid filter definition."))
+    } else {
+        Ok(sync_events::IncomingFilter::FilterId(filter.to_owned()))
+    }
+}
+
+/// Returns a watcher that already registered itself, so changes during the next sync wake it up.
+fn registered_watcher(
+    db: &Arc<DatabaseGuard>,
+    sender_user: &UserId,
+    sender_device: &DeviceId,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    let db = Arc::clone(db);
+    let sender_user = sender_user.clone();
+    let sender_device = sender_device.to_owned();
+
+    let mut watcher: Pin<Box<dyn Future<Output = ()> + Send>> =
+        Box::pin(async move { db.watch(&sender_user, &sender_device).await });
+
+    // Polling once sets up the watchers
+    if (&mut watcher).now_or_never().is_some() {
+        return Box::pin(async {});
+    }
+
+    watcher
+}
+
+/// Waits until something changes for the user and returns the next non-empty delta.
+async fn next_sync_event(mut state: SyncStream) -> Option<(Event, SyncStream)> {
+    let mut first = state.watcher.is_none();
+
+    loop {
+        if let Some(watcher) = state.watcher.take() {
+            let shutdown = state.db.globals.rotate.watch();
+            tokio::select! {
+                _ = watcher => {}
+                _ = shutdown => return None,
+                _ = state.closed.notified() => return None,
+            }
+        }
+
+        state.watcher = Some(registered_watcher(
+            &state.db,
+            &state.sender_user,
+            &state.sender_device,
+        ));
+
+        // Replicas can't write presence
+        if !state.db.globals.is_replica() {
+            if let Err(e) = client_server::update_presence_from_sync(
+                &state.db,
+                &state.sender_user,
+                &state.set_presence,
+            ) {
+                warn!("Failed to update presence from the sync stream: {}", e);
+            }
+        }
+
+        let response = match sync_helper(
+            Arc::clone(&state.db),
+            state.sender_user.clone(),
+            state.sender_device.clone(),
+            state.since.clone(),
+            state.full_state,
+            Arc::clone(&state.filter),
+            Some(Duration::from_secs(0)),
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Sync stream of {} failed: {}", state.sender_user, e);
+                return None;
+            }
+        };
+
+        state.since = Some(response.next_batch.clone());
+        state.full_state = false;
+
+        if !first && is_empty(&response) {
+            continue;
+        }
+        first = false;
+
+        match sync_event_data(
+            &state.db,
+            &state.sender_user,
+            &state.sender_device,
+            response,
+        ) {
+            Ok(data) => return Some((Event::data(data).event("sync"), state)),
+            Err(e) => {
+                warn!("Failed to serialize a sync for the stream: {}", e);
+                return None;
             }
         }
     }
+}
 
-    drop(db);
+/// The json of a /sync response, including the fields ruma doesn't know.
+fn sync_event_data(
+    db: &Database,
+    sender_user: &UserId,
+    sender_device: &DeviceId,
+    response: sync_events::Response,
+) -> Result<String> {
+    let http_response = response
+        .try_into_http_response::<Vec<u8>>()
+        .map_err(|_| Error::BadServerResponse("Failed to serialize the sync response."))?;
+
+    let mut body =
+        serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(http_response.body())
+            .map_err(|_| Error::BadServerResponse("Failed to serialize the sync response."))?;
+    body.extend(fallback_key_fields(db, sender_user, sender_device)?);
+
+    Ok(serde_json::to_string(&body).expect("json map can be serialized"))
+}
 
-    let _ = tx.send(Some(r.map(|(r, _)| r.into())));
+/// True if a sync response contains nothing new.
+fn is_empty(response: &sync_events::Response) -> bool {
+    response.rooms.is_empty()
+        && response.presence.is_empty()
+        && response.account_data.is_empty()
+        && response.device_lists.is_empty()
+        && response.device_one_time_keys_count.is_empty()
+        && response.to_device.is_empty()
 }
 
 async fn sync_helper(
@@ -177,12 +438,9 @@ async fn sync_helper(
     sender_device: Box<DeviceId>,
     since: Option<String>,
     full_state: bool,
+    filter: Arc<IncomingFilterDefinition>,
     timeout: Option<Duration>,
-    // bool = caching allowed
-) -> std::result::Result<(sync_events::Response, bool), Error> {
-    // TODO: match body.set_presence {
-    db.rooms.edus.ping_presence(&sender_user)?;
-
+) -> Result<sync_events::Response> {
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = db.watch(&sender_user, &sender_device);
 
@@ -191,8 +449,9 @@ async fn sync_helper(
 
     let mut joined_rooms = BTreeMap::new();
     let since = since
-        .clone()
-        .and_then(|string| string.parse().ok())
+        .as_deref()
+        .map(|token| db.globals.parse_sync_token(token))
+        .transpose()?
         .unwrap_or(0);
 
     let mut presence_updates = HashMap::new();
@@ -207,12 +466,203 @@ async fn sync_helper(
             .filter_map(|r| r.ok()),
     );
 
+    // Tokens limited to some rooms get no global account data and only presence from those rooms
+    let room_scoped = db
+        .users
+        .token_scope(&sender_user, &sender_device)?
+        .map_or(false, |scope| scope.rooms.is_some());
+
     let all_joined_rooms = db.rooms.rooms_joined(&sender_user).collect::<Vec<_>>();
-    for room_id in all_joined_rooms {
-        let room_id = room_id?;
+
+    // Rooms are independent of each other, so they are handled in parallel. The database calls
+    // block, so each room gets a blocking task. `buffered` keeps the order of the rooms, so
+    // presence updates are merged the same way every time
+    let room_results = stream::iter(all_joined_rooms)
+        .map(|room_id| {
+            let db = Arc::clone(&db);
+            let sender_user = sender_user.clone();
+            let filter = Arc::clone(&filter);
+
+            async move {
+                let room_id = room_id?;
+
+                tokio::task::spawn_blocking(move || {
+                    sync_joined_room(
+                        &db,
+                        &sender_user,
+                        &room_id,
+                        since,
+                        next_batch,
+                        full_state,
+                        &filter,
+                    )
+                    .map(|result| (room_id, result))
+                })
+                .await
+                .map_err(|e| {
+                    error!("Sync task for a room panicked: {}", e);
+                    Error::BadServerResponse("Sync task for a room panicked.")
+                })?
+            }
+        })
+        .buffered(db.globals.sync_room_concurrency())
+        .collect::<Vec<_>>()
+        .await;
+
+    for room_result in room_results {
+        let (room_id, room_result) = room_result?;
+
+        device_list_updates.extend(room_result.device_list_updates);
+        left_encrypted_users.extend(room_result.left_encrypted_users);
+
+        let room_presence_updates =
+            if room_scoped && !client_server::room_filter_matches(&filter.room, &room_id) {
+                HashMap::new()
+            } else {
+                room_result.presence_updates
+            };
+
+        // Every room has its own copy of a presence update, the most recent one wins. Expired
+        // status messages are already removed
+        for (user_id, (count, presence)) in room_presence_updates {
+            match presence_updates.entry(user_id) {
+                Entry::Vacant(v) => {
+                    v.insert((count, presence));
+                }
+                Entry::Occupied(mut o) => {
+                    let (last_count, p) = o.get_mut();
+                    if count <= *last_count {
+                        continue;
+                    }
+
+                    // Keep the profile from older events if the newer one doesn't have it
+                    let displayname = p.content.displayname.take();
+                    let avatar_url = p.content.avatar_url.take();
+
+                    *last_count = count;
+                    *p = presence;
+                    if p.content.displayname.is_none() {
+                        p.content.displayname = displayname;
+                    }
+                    if p.content.avatar_url.is_none() {
+                        p.content.avatar_url = avatar_url;
+                    }
+                }
+            }
+        }
+
+        if let Some(joined_room) = room_result.joined_room {
+            joined_rooms.insert(room_id, joined_room);
+        }
+    }
+
+    let mut left_rooms = BTreeMap::new();
+    let all_left_rooms = db.rooms.rooms_left(&sender_user).collect::<Vec<_>>();
+    for result in all_left_rooms {
+        let (room_id, left_state_events) = result?;
+
+        // Get and drop the lock to wait for remaining operations to finish
+        let mutex_insert = Arc::clone(
+            db.globals
+                .roomid_mutex_insert
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let insert_lock = mutex_insert.lock().unwrap();
+        drop(insert_lock);
+
+        if !client_server::room_filter_matches(&filter.room, &room_id) {
+            continue;
+        }
+
+        // Rooms the user left are only part of an initial sync if the filter asks for them
+        if since == 0 && !filter.room.include_leave {
+            continue;
+        }
+
+        let left_count = db.rooms.get_left_count(&room_id, &sender_user)?;
+
+        // Left before last sync
+        if Some(since) >= left_count {
+            continue;
+        }
+
+        let left_count = left_count.expect("since is smaller than left_count");
+        let (timeline_pdus, limited) =
+            left_room_timeline(&db, &sender_user, &room_id, since, left_count, &filter)?;
+
+        let prev_batch = match timeline_pdus.first() {
+            Some((pdu_id, _)) => db.rooms.pdu_count(pdu_id)?.to_string(),
+            None => next_batch_string.clone(),
+        };
+
+        left_rooms.insert(
+            room_id.clone(),
+            sync_events::LeftRoom {
+                account_data: sync_events::RoomAccountData {
+                    events: room_account_data(&db, &sender_user, &room_id, since, &filter)?,
+                },
+                timeline: sync_events::Timeline {
+                    limited,
+                    prev_batch: Some(prev_batch),
+                    events: timeline_pdus
+                        .iter()
+                        .map(|(_, pdu)| pdu.to_sync_room_event())
+                        .collect(),
+                },
+                state: sync_events::State {
+                    events: left_state_events,
+                },
+            },
+        );
+    }
+
+    let mut invited_rooms = BTreeMap::new();
+    let all_invited_rooms = db.rooms.rooms_invited(&sender_user).collect::<Vec<_>>();
+    for result in all_invited_rooms {
+        let (room_id, invite_state_events) = result?;
+
+        // Get and drop the lock to wait for remaining operations to finish
+        let mutex_insert = Arc::clone(
+            db.globals
+                .roomid_mutex_insert
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let insert_lock = mutex_insert.lock().unwrap();
+        drop(insert_lock);
+
+        if !client_server::room_filter_matches(&filter.room, &room_id) {
+            continue;
+        }
+
+        let invite_count = db.rooms.get_invite_count(&room_id, &sender_user)?;
+
+        // Invited before last sync
+        if Some(since) >= invite_count {
+            continue;
+        }
+
+        invited_rooms.insert(
+            room_id.clone(),
+            sync_events::InvitedRoom {
+                invite_state: sync_events::InviteState {
+                    events: invite_state_events,
+                },
+            },
+        );
+    }
+
+    let mut knocked_rooms = BTreeMap::new();
+    let all_knocked_rooms = db.rooms.rooms_knocked(&sender_user).collect::<Vec<_>>();
+    for result in all_knocked_rooms {
+        let (room_id, knock_state_events) = result?;
 
         // Get and drop the lock to wait for remaining operations to finish
-        // This will make sure the we have all events until next_batch
         let mutex_insert = Arc::clone(
             db.globals
                 .roomid_mutex_insert
@@ -224,130 +674,366 @@ async fn sync_helper(
         let insert_lock = mutex_insert.lock().unwrap();
         drop(insert_lock);
 
-        let mut non_timeline_pdus = db
+        if !client_server::room_filter_matches(&filter.room, &room_id) {
+            continue;
+        }
+
+        let knock_count = db.rooms.get_knock_count(&room_id, &sender_user)?;
+
+        // Knocked before last sync
+        if Some(since) >= knock_count {
+            continue;
+        }
+
+        knocked_rooms.insert(
+            room_id.clone(),
+            sync_events::KnockedRoom {
+                knock_state: sync_events::KnockState {
+                    events: knock_state_events,
+                },
+            },
+        );
+    }
+
+    for user_id in left_encrypted_users {
+        let still_share_encrypted_room = db
             .rooms
-            .pdus_until(&sender_user, &room_id, u64::MAX)?
-            .filter_map(|r| {
-                // Filter out buggy events
-                if r.is_err() {
-                    error!("Bad pdu in pdus_since: {:?}", r);
-                }
-                r.ok()
+            .get_shared_rooms(vec![sender_user.clone(), user_id.clone()])?
+            .filter_map(|r| r.ok())
+            .filter_map(|other_room_id| {
+                Some(
+                    db.rooms
+                        .room_state_get(&other_room_id, &EventType::RoomEncryption, "")
+                        .ok()?
+                        .is_some(),
+                )
             })
-            .take_while(|(pduid, _)| {
-                db.rooms
-                    .pdu_count(pduid)
-                    .map_or(false, |count| count > since)
-            });
-
-        // Take the last 10 events for the timeline
-        let timeline_pdus = non_timeline_pdus
-            .by_ref()
-            .take(10)
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect::<Vec<_>>();
-
-        let send_notification_counts = !timeline_pdus.is_empty()
-            || db
-                .rooms
-                .edus
-                .last_privateread_update(&sender_user, &room_id)?
-                > since;
+            .all(|encrypted| !encrypted);
+        // If the user doesn't share an encrypted room with the target anymore, we need to tell
+        // them
+        if still_share_encrypted_room {
+            device_list_left.insert(user_id);
+        }
+    }
 
-        // They /sync response doesn't always return all messages, so we say the output is
-        // limited unless there are events in non_timeline_pdus
-        let limited = non_timeline_pdus.next().is_some();
+    // Remove the to-device events the device confirmed to have received by syncing with `since`.
+    // Replicas can't delete them, the events up to `since` are skipped below instead
+    if !db.globals.is_replica() {
+        db.users
+            .acknowledge_to_device_events(&sender_user, &sender_device, since)?;
+    }
 
-        // Database queries:
+    let response = sync_events::Response {
+        next_batch: db.globals.sync_token(next_batch)?,
+        rooms: sync_events::Rooms {
+            leave: left_rooms,
+            join: joined_rooms,
+            invite: invited_rooms,
+            knock: knocked_rooms,
+        },
+        presence: sync_events::Presence {
+            events: presence_updates
+                .into_iter()
+                .filter(|(user_id, _)| {
+                    client_server::event_filter_matches(
+                        &filter.presence,
+                        Some(user_id),
+                        "m.presence",
+                    )
+                })
+                .map(|(_, (_, v))| Raw::from(v))
+                .collect(),
+        },
+        account_data: sync_events::GlobalAccountData {
+            events: if room_scoped {
+                Vec::new()
+            } else {
+                db.account_data
+                    .changes_since(None, &sender_user, since)?
+                    .into_iter()
+                    .filter(|(kind, _)| {
+                        client_server::event_filter_matches(
+                            &filter.account_data,
+                            None,
+                            kind.as_ref(),
+                        )
+                    })
+                    .filter_map(|(_, v)| {
+                        serde_json::from_str(v.json().get())
+                            .map_err(|_| Error::bad_database("Invalid account event in database."))
+                            .ok()
+                    })
+                    .collect::<Vec<_>>()
+            },
+        },
+        device_lists: sync_events::DeviceLists {
+            changed: device_list_updates.into_iter().collect(),
+            left: device_list_left.into_iter().collect(),
+        },
+        device_one_time_keys_count: if db.users.last_one_time_keys_update(&sender_user)? > since
+            || since == 0
+        {
+            db.users.count_one_time_keys(&sender_user, &sender_device)?
+        } else {
+            BTreeMap::new()
+        },
+        to_device: sync_events::ToDevice {
+            events: db.users.get_to_device_events(
+                &sender_user,
+                &sender_device,
+                since,
+                next_batch,
+            )?,
+        },
+    };
+
+    // TODO: Retry the endpoint instead of returning (waiting for #118)
+    if !full_state && is_empty(&response) {
+        // Hang a few seconds so requests are not spammed
+        // Stop hanging if new info arrives or newer syncs of the user push this one out
+        let duration = timeout
+            .unwrap_or_default()
+            .min(db.globals.max_sync_timeout());
+        let waiter = db.globals.register_sync_waiter(&sender_user);
+        RequestTimes::wait(async {
+            tokio::select! {
+                _ = tokio::time::timeout(duration, watcher) => {}
+                _ = waiter.closed() => {}
+            }
+        })
+        .await;
+    }
+
+    Ok(response)
+}
+
+/// What sync found in one joined room.
+#[derive(Default)]
+struct JoinedRoomSync {
+    /// None if nothing changed in the room or the filter doesn't let it through
+    joined_room: Option<sync_events::JoinedRoom>,
+    presence_updates: HashMap<UserId, (u64, PresenceEvent)>, // With the count of the update
+    device_list_updates: HashSet<UserId>,
+    left_encrypted_users: HashSet<UserId>, // Users that have left this encrypted room
+}
+
+/// Computes the /sync response for one joined room.
+#[allow(clippy::too_many_arguments)]
+fn sync_joined_room(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    since: u64,
+    next_batch: u64,
+    full_state: bool,
+    filter: &IncomingFilterDefinition,
+) -> Result<JoinedRoomSync> {
+    let mut result = JoinedRoomSync::default();
+
+    // Get and drop the lock to wait for remaining operations to finish
+    // This will make sure the we have all events until next_batch
+    let mutex_insert = Arc::clone(
+        db.globals
+            .roomid_mutex_insert
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let insert_lock = mutex_insert.lock().unwrap();
+    drop(insert_lock);
+
+    // Rooms without any activity since the last sync have nothing new for the client, not even
+    // presence or device list updates. Typing notifications time out without activity, so they
+    // are checked separately
+    if since != 0
+        && !full_state
+        && db
+            .activity
+            .last_activity(room_id)?
+            .map_or(false, |activity| activity <= since)
+        && db.rooms.edus.last_typing_update(room_id, &db.globals)? <= since
+    {
+        // The next sync needs the state at this token
+        if let Some(current_shortstatehash) = db.rooms.current_shortstatehash(room_id)? {
+            if !db.globals.is_replica() {
+                db.rooms.associate_token_shortstatehash(
+                    room_id,
+                    next_batch,
+                    current_shortstatehash,
+                )?;
+            }
+        }
+        return Ok(result);
+    }
+
+    // Look for device list updates in this room
+    result.device_list_updates.extend(
+        db.users
+            .keys_changed(&room_id.to_string(), since, None)
+            .filter_map(|r| r.ok()),
+    );
+
+    // Take presence updates from this room
+    result.presence_updates =
+        db.rooms
+            .edus
+            .presence_since(room_id, since, &db.rooms, &db.globals)?;
+
+    if !client_server::room_filter_matches(&filter.room, room_id) {
+        return Ok(result);
+    }
+
+    if !db.globals.is_replica() && db.activity.last_activity(room_id)?.is_none() {
+        // Rooms from before we tracked the activity can be skipped from now on
+        db.activity.bump(room_id, next_batch)?;
+    }
+
+    // Take the last events for the timeline. The /sync response doesn't always return all
+    // messages, so we say the output is limited unless we reached the last sync
+    let timeline_limit = client_server::timeline_limit(&filter.room.timeline);
+    let mut timeline_pdus = Vec::new();
+    let mut limited = false;
+    // Where the client continues with /messages, the oldest event we looked at
+    let mut oldest_scanned_count = None;
 
-        let current_shortstatehash = db
+    for (scanned, (pdu_id, pdu)) in db
+        .rooms
+        .pdus_until(sender_user, room_id, u64::MAX)?
+        .filter_map(|r| {
+            // Filter out buggy events
+            if r.is_err() {
+                error!("Bad pdu in pdus_since: {:?}", r);
+            }
+            r.ok()
+        })
+        .enumerate()
+    {
+        let count = match db.rooms.pdu_count(&pdu_id) {
+            Ok(count) if count > since => count,
+            _ => break,
+        };
+
+        if timeline_pdus.len() == timeline_limit || scanned == client_server::MAX_SCANNED_EVENTS {
+            limited = true;
+            break;
+        }
+
+        oldest_scanned_count = Some(count);
+
+        if !pdu.is_dummy()
+            && client_server::room_event_filter_matches(
+                &filter.room.timeline,
+                room_id,
+                Some(&pdu.sender),
+                pdu.kind.as_ref(),
+            )
+        {
+            timeline_pdus.push((pdu_id, pdu));
+        }
+    }
+
+    timeline_pdus.reverse();
+
+    let send_notification_counts = !timeline_pdus.is_empty()
+        || db
             .rooms
-            .current_shortstatehash(&room_id)?
-            .expect("All rooms have state");
-
-        let since_shortstatehash = db.rooms.get_token_shortstatehash(&room_id, since)?;
-
-        // Calculates joined_member_count, invited_member_count and heroes
-        let calculate_counts = || {
-            let joined_member_count = db.rooms.room_joined_count(&room_id)?.unwrap_or(0);
-            let invited_member_count = db.rooms.room_invited_count(&room_id)?.unwrap_or(0);
-
-            // Recalculate heroes (first 5 members)
-            let mut heroes = Vec::new();
-
-            if joined_member_count + invited_member_count <= 5 {
-                // Go through all PDUs and for each member event, check if the user is still joined or
-                // invited until we have 5 or we reach the end
-
-                for hero in db
-                    .rooms
-                    .all_pdus(&sender_user, &room_id)?
-                    .filter_map(|pdu| pdu.ok()) // Ignore all broken pdus
-                    .filter(|(_, pdu)| pdu.kind == EventType::RoomMember)
-                    .map(|(_, pdu)| {
-                        let content = serde_json::from_value::<
-                            ruma::events::room::member::MemberEventContent,
-                        >(pdu.content.clone())
-                        .map_err(|_| Error::bad_database("Invalid member event in database."))?;
-
-                        if let Some(state_key) = &pdu.state_key {
-                            let user_id = UserId::try_from(state_key.clone()).map_err(|_| {
-                                Error::bad_database("Invalid UserId in member PDU.")
-                            })?;
-
-                            // The membership was and still is invite or join
-                            if matches!(
-                                content.membership,
-                                MembershipState::Join | MembershipState::Invite
-                            ) && (db.rooms.is_joined(&user_id, &room_id)?
-                                || db.rooms.is_invited(&user_id, &room_id)?)
-                            {
-                                Ok::<_, Error>(Some(state_key.clone()))
-                            } else {
-                                Ok(None)
-                            }
+            .edus
+            .last_privateread_update(sender_user, room_id)?
+            > since;
+
+    // Database queries:
+
+    let current_shortstatehash = db
+        .rooms
+        .current_shortstatehash(room_id)?
+        .expect("All rooms have state");
+
+    let since_shortstatehash = match db.rooms.get_token_shortstatehash(room_id, since)? {
+        // Replicas don't save the state of their syncs. The state before the first event after
+        // `since` is the state at `since`
+        None if since > 0 && db.globals.is_replica() => {
+            match db
+                .rooms
+                .pdus_after(sender_user, room_id, since)?
+                .filter_map(|r| r.ok())
+                .next()
+            {
+                Some((_, pdu)) => db.rooms.pdu_shortstatehash(&pdu.event_id)?,
+                None => Some(current_shortstatehash),
+            }
+        }
+        shortstatehash => shortstatehash,
+    };
+
+    // Calculates joined_member_count, invited_member_count and heroes
+    let calculate_counts = || {
+        let joined_member_count = db.rooms.room_joined_count(room_id)?.unwrap_or(0);
+        let invited_member_count = db.rooms.room_invited_count(room_id)?.unwrap_or(0);
+
+        // Recalculate heroes (first 5 members)
+        let mut heroes = Vec::new();
+
+        if joined_member_count + invited_member_count <= 5 {
+            // Go through all PDUs and for each member event, check if the user is still joined or
+            // invited until we have 5 or we reach the end
+
+            for hero in db
+                .rooms
+                .all_pdus(sender_user, room_id)?
+                .filter_map(|pdu| pdu.ok()) // Ignore all broken pdus
+                .filter(|(_, pdu)| pdu.kind == EventType::RoomMember)
+                .map(|(_, pdu)| {
+                    let content = serde_json::from_value::<
+                        ruma::events::room::member::MemberEventContent,
+                    >(pdu.content.clone())
+                    .map_err(|_| Error::bad_database("Invalid member event in database."))?;
+
+                    if let Some(state_key) = &pdu.state_key {
+                        let user_id = UserId::try_from(state_key.clone())
+                            .map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
+
+                        // The membership was and still is invite or join
+                        if matches!(
+                            content.membership,
+                            MembershipState::Join | MembershipState::Invite
+                        ) && (db.rooms.is_joined(&user_id, room_id)?
+                            || db.rooms.is_invited(&user_id, room_id)?)
+                        {
+                            Ok::<_, Error>(Some(state_key.clone()))
                         } else {
                             Ok(None)
                         }
-                    })
-                    // Filter out buggy users
-                    .filter_map(|u| u.ok())
-                    // Filter for possible heroes
-                    .flatten()
-                {
-                    if heroes.contains(&hero) || hero == sender_user.as_str() {
-                        continue;
+                    } else {
+                        Ok(None)
                     }
-
-                    heroes.push(hero);
+                })
+                // Filter out buggy users
+                .filter_map(|u| u.ok())
+                // Filter for possible heroes
+                .flatten()
+            {
+                if heroes.contains(&hero) || hero == sender_user.as_str() {
+                    continue;
                 }
-            }
 
-            Ok::<_, Error>((
-                Some(joined_member_count),
-                Some(invited_member_count),
-                heroes,
-            ))
-        };
+                heroes.push(hero);
+            }
+        }
 
-        let (
+        Ok::<_, Error>((
+            Some(joined_member_count),
+            Some(invited_member_count),
             heroes,
-            joined_member_count,
-            invited_member_count,
-            joined_since_last_sync,
-            state_events,
-        ) = if since_shortstatehash.is_none() {
+        ))
+    };
+
+    let (heroes, joined_member_count, invited_member_count, joined_since_last_sync, state_events) =
+        if since_shortstatehash.is_none() {
             // Probably since = 0, we will do an initial sync
             let (joined_member_count, invited_member_count, heroes) = calculate_counts()?;
 
-            let current_state_ids = db.rooms.state_full_ids(current_shortstatehash)?;
-            let state_events = current_state_ids
-                .iter()
-                .map(|(_, id)| db.rooms.get_pdu(id))
-                .filter_map(|r| r.ok().flatten())
-                .collect::<Vec<_>>();
+            let state_events = db.rooms.state_full_pdus(current_shortstatehash)?.to_vec();
 
             (
                 heroes,
@@ -388,11 +1074,7 @@ async fn sync_helper(
             let since_state_ids = db.rooms.state_full_ids(since_shortstatehash)?;
 
             let state_events = if joined_since_last_sync {
-                current_state_ids
-                    .iter()
-                    .map(|(_, id)| db.rooms.get_pdu(id))
-                    .filter_map(|r| r.ok().flatten())
-                    .collect::<Vec<_>>()
+                db.rooms.state_full_pdus(current_shortstatehash)?.to_vec()
             } else {
                 current_state_ids
                     .iter()
@@ -419,57 +1101,50 @@ async fn sync_helper(
                 .any(|event| event.kind == EventType::RoomMember);
 
             if encrypted_room {
-                for state_event in &state_events {
-                    if state_event.kind != EventType::RoomMember {
+                let membership_changes =
+                    match db
+                        .rooms
+                        .membership_changes_since(room_id, since, &db.globals)?
+                    {
+                        Some(changes) => changes,
+                        // The log doesn't go back that far, look at the member events instead
+                        None => member_event_changes(&state_events)?,
+                    };
+
+                for (user_id, membership) in membership_changes {
+                    if &user_id == sender_user {
                         continue;
                     }
 
-                    if let Some(state_key) = &state_event.state_key {
-                        let user_id = UserId::try_from(state_key.clone())
-                            .map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
-
-                        if user_id == sender_user {
-                            continue;
-                        }
-
-                        let new_membership = serde_json::from_value::<
-                            Raw<ruma::events::room::member::MemberEventContent>,
-                        >(state_event.content.clone())
-                        .expect("Raw::from_value always works")
-                        .deserialize()
-                        .map_err(|_| Error::bad_database("Invalid PDU in database."))?
-                        .membership;
-
-                        match new_membership {
-                            MembershipState::Join => {
-                                // A new user joined an encrypted room
-                                if !share_encrypted_room(&db, &sender_user, &user_id, &room_id)? {
-                                    device_list_updates.insert(user_id);
-                                }
+                    match membership {
+                        MembershipState::Join => {
+                            // A new user joined an encrypted room
+                            if !share_encrypted_room(db, sender_user, &user_id, room_id)? {
+                                result.device_list_updates.insert(user_id);
                             }
-                            MembershipState::Leave => {
-                                // Write down users that have left encrypted rooms we are in
-                                left_encrypted_users.insert(user_id);
-                            }
-                            _ => {}
                         }
+                        MembershipState::Leave => {
+                            // Write down users that have left encrypted rooms we are in
+                            result.left_encrypted_users.insert(user_id);
+                        }
+                        _ => {}
                     }
                 }
             }
 
             if joined_since_last_sync && encrypted_room || new_encrypted_room {
                 // If the user is in a new encrypted room, give them all joined users
-                device_list_updates.extend(
+                result.device_list_updates.extend(
                     db.rooms
-                        .room_members(&room_id)
+                        .room_members(room_id)
                         .flatten()
                         .filter(|user_id| {
                             // Don't send key updates from the sender to the sender
-                            &sender_user != user_id
+                            sender_user != user_id
                         })
                         .filter(|user_id| {
                             // Only send keys if the sender doesn't share an encrypted room with the target already
-                            !share_encrypted_room(&db, &sender_user, user_id, &room_id)
+                            !share_encrypted_room(db, sender_user, user_id, room_id)
                                 .unwrap_or(false)
                         }),
                 );
@@ -490,308 +1165,327 @@ async fn sync_helper(
             )
         };
 
-        // Look for device list updates in this room
-        device_list_updates.extend(
-            db.users
-                .keys_changed(&room_id.to_string(), since, None)
-                .filter_map(|r| r.ok()),
-        );
+    // The client wants the complete state, even if nothing changed since the last sync
+    let state_events = if full_state && !joined_since_last_sync {
+        db.rooms.state_full_pdus(current_shortstatehash)?.to_vec()
+    } else {
+        state_events
+    };
 
-        let notification_count = if send_notification_counts {
-            Some(
-                db.rooms
-                    .notification_count(&sender_user, &room_id)?
-                    .try_into()
-                    .expect("notification count can't go that high"),
-            )
-        } else {
-            None
-        };
+    // With lazy loading, the client only wants the members of the timeline senders
+    let state_events = if client_server::lazy_load_members(&filter.room.state) {
+        lazy_loaded_state(db, sender_user, room_id, state_events, &timeline_pdus)?
+    } else {
+        state_events
+    };
 
-        let highlight_count = if send_notification_counts {
-            Some(
-                db.rooms
-                    .highlight_count(&sender_user, &room_id)?
-                    .try_into()
-                    .expect("highlight count can't go that high"),
-            )
-        } else {
-            None
-        };
+    let notification_count = if send_notification_counts {
+        Some(
+            db.rooms
+                .notification_count(sender_user, room_id)?
+                .try_into()
+                .expect("notification count can't go that high"),
+        )
+    } else {
+        None
+    };
+
+    let highlight_count = if send_notification_counts {
+        Some(
+            db.rooms
+                .highlight_count(sender_user, room_id)?
+                .try_into()
+                .expect("highlight count can't go that high"),
+        )
+    } else {
+        None
+    };
 
-        let prev_batch = timeline_pdus
-            .first()
-            .map_or(Ok::<_, Error>(None), |(pdu_id, _)| {
-                Ok(Some(db.rooms.pdu_count(pdu_id)?.to_string()))
-            })?;
+    let prev_batch = oldest_scanned_count.map(|count| count.to_string());
 
-        let room_events = timeline_pdus
-            .iter()
-            .map(|(_, pdu)| pdu.to_sync_room_event())
-            .collect::<Vec<_>>();
+    let room_events = timeline_pdus
+        .iter()
+        .map(|(_, pdu)| pdu.to_sync_room_event())
+        .collect::<Vec<_>>();
 
-        let mut edus = db
+    // Send all receipts in one event instead of one event per user
+    let mut receipts = serde_json::Map::new();
+    if db.rooms.edus.last_readreceipt_update(room_id)? > since {
+        for receipt in db
             .rooms
             .edus
-            .readreceipts_since(&room_id, since)
+            .readreceipts_since(room_id, since)
             .filter_map(|r| r.ok()) // Filter out buggy events
             .map(|(_, _, v)| v)
-            .collect::<Vec<_>>();
-
-        if db.rooms.edus.last_typing_update(&room_id, &db.globals)? > since {
-            edus.push(
-                serde_json::from_str(
-                    &serde_json::to_string(&AnySyncEphemeralRoomEvent::Typing(
-                        db.rooms.edus.typings_all(&room_id)?,
-                    ))
-                    .expect("event is valid, we just created it"),
-                )
-                .expect("event is valid, we just created it"),
-            );
-        }
-
-        // Save the state after this sync so we can send the correct state diff next sync
-        db.rooms
-            .associate_token_shortstatehash(&room_id, next_batch, current_shortstatehash)?;
-
-        let joined_room = sync_events::JoinedRoom {
-            account_data: sync_events::RoomAccountData {
-                events: db
-                    .account_data
-                    .changes_since(Some(&room_id), &sender_user, since)?
-                    .into_iter()
-                    .filter_map(|(_, v)| {
-                        serde_json::from_str(v.json().get())
-                            .map_err(|_| Error::bad_database("Invalid account event in database."))
-                            .ok()
-                    })
-                    .collect::<Vec<_>>(),
-            },
-            summary: sync_events::RoomSummary {
-                heroes,
-                joined_member_count: joined_member_count.map(|n| (n as u32).into()),
-                invited_member_count: invited_member_count.map(|n| (n as u32).into()),
-            },
-            unread_notifications: sync_events::UnreadNotificationsCount {
-                highlight_count,
-                notification_count,
-            },
-            timeline: sync_events::Timeline {
-                limited: limited || joined_since_last_sync,
-                prev_batch,
-                events: room_events,
-            },
-            state: sync_events::State {
-                events: state_events
-                    .iter()
-                    .map(|pdu| pdu.to_sync_state_event())
-                    .collect(),
-            },
-            ephemeral: sync_events::Ephemeral { events: edus },
-        };
-
-        if !joined_room.is_empty() {
-            joined_rooms.insert(room_id.clone(), joined_room);
-        }
-
-        // Take presence updates from this room
-        for (user_id, presence) in
-            db.rooms
-                .edus
-                .presence_since(&room_id, since, &db.rooms, &db.globals)?
         {
-            match presence_updates.entry(user_id) {
-                Entry::Vacant(v) => {
-                    v.insert(presence);
-                }
-                Entry::Occupied(mut o) => {
-                    let p = o.get_mut();
-
-                    // Update existing presence event with more info
-                    p.content.presence = presence.content.presence;
-                    if let Some(status_msg) = presence.content.status_msg {
-                        p.content.status_msg = Some(status_msg);
-                    }
-                    if let Some(last_active_ago) = presence.content.last_active_ago {
-                        p.content.last_active_ago = Some(last_active_ago);
-                    }
-                    if let Some(displayname) = presence.content.displayname {
-                        p.content.displayname = Some(displayname);
-                    }
-                    if let Some(avatar_url) = presence.content.avatar_url {
-                        p.content.avatar_url = Some(avatar_url);
-                    }
-                    if let Some(currently_active) = presence.content.currently_active {
-                        p.content.currently_active = Some(currently_active);
-                    }
+            if let Ok(serde_json::Value::Object(mut event)) =
+                serde_json::from_str::<serde_json::Value>(receipt.json().get())
+            {
+                if let Some(serde_json::Value::Object(content)) = event.remove("content") {
+                    merge_json_objects(&mut receipts, content);
                 }
             }
         }
     }
 
-    let mut left_rooms = BTreeMap::new();
-    let all_left_rooms = db.rooms.rooms_left(&sender_user).collect::<Vec<_>>();
-    for result in all_left_rooms {
-        let (room_id, left_state_events) = result?;
+    let ephemeral_matches = |kind: &str| {
+        client_server::room_event_filter_matches(&filter.room.ephemeral, room_id, None, kind)
+    };
 
-        // Get and drop the lock to wait for remaining operations to finish
-        let mutex_insert = Arc::clone(
-            db.globals
-                .roomid_mutex_insert
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
+    let mut edus = Vec::<Raw<AnySyncEphemeralRoomEvent>>::new();
+    if !receipts.is_empty() && ephemeral_matches("m.receipt") {
+        edus.push(Raw::from_json(
+            serde_json::value::to_raw_value(&serde_json::json!({
+                "type": "m.receipt",
+                "content": receipts,
+            }))
+            .expect("json is valid raw value"),
+        ));
+    }
+
+    if db.rooms.edus.last_typing_update(room_id, &db.globals)? > since
+        && ephemeral_matches("m.typing")
+    {
+        edus.push(
+            serde_json::from_str(
+                &serde_json::to_string(&AnySyncEphemeralRoomEvent::Typing(
+                    db.rooms.edus.typings_all(room_id)?,
+                ))
+                .expect("event is valid, we just created it"),
+            )
+            .expect("event is valid, we just created it"),
         );
-        let insert_lock = mutex_insert.lock().unwrap();
-        drop(insert_lock);
+    }
 
-        let left_count = db.rooms.get_left_count(&room_id, &sender_user)?;
+    // Save the state after this sync so we can send the correct state diff next sync
+    if !db.globals.is_replica() {
+        db.rooms
+            .associate_token_shortstatehash(room_id, next_batch, current_shortstatehash)?;
+    }
 
-        // Left before last sync
-        if Some(since) >= left_count {
-            continue;
-        }
+    let joined_room = sync_events::JoinedRoom {
+        account_data: sync_events::RoomAccountData {
+            events: room_account_data(db, sender_user, room_id, since, filter)?,
+        },
+        summary: sync_events::RoomSummary {
+            heroes,
+            joined_member_count: joined_member_count.map(|n| (n as u32).into()),
+            invited_member_count: invited_member_count.map(|n| (n as u32).into()),
+        },
+        unread_notifications: sync_events::UnreadNotificationsCount {
+            highlight_count,
+            notification_count,
+        },
+        timeline: sync_events::Timeline {
+            limited: limited || joined_since_last_sync,
+            prev_batch,
+            events: room_events,
+        },
+        state: sync_events::State {
+            events: state_events
+                .iter()
+                .filter(|pdu| {
+                    client_server::room_event_filter_matches(
+                        &filter.room.state,
+                        room_id,
+                        Some(&pdu.sender),
+                        pdu.kind.as_ref(),
+                    )
+                })
+                .map(|pdu| pdu.to_sync_state_event())
+                .collect(),
+        },
+        ephemeral: sync_events::Ephemeral { events: edus },
+    };
 
-        left_rooms.insert(
-            room_id.clone(),
-            sync_events::LeftRoom {
-                account_data: sync_events::RoomAccountData { events: Vec::new() },
-                timeline: sync_events::Timeline {
-                    limited: false,
-                    prev_batch: Some(next_batch_string.clone()),
-                    events: Vec::new(),
-                },
-                state: sync_events::State {
-                    events: left_state_events,
-                },
-            },
-        );
+    if !joined_room.is_empty() {
+        result.joined_room = Some(joined_room);
     }
 
-    let mut invited_rooms = BTreeMap::new();
-    let all_invited_rooms = db.rooms.rooms_invited(&sender_user).collect::<Vec<_>>();
-    for result in all_invited_rooms {
-        let (room_id, invite_state_events) = result?;
+    Ok(result)
+}
 
-        // Get and drop the lock to wait for remaining operations to finish
-        let mutex_insert = Arc::clone(
-            db.globals
-                .roomid_mutex_insert
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let insert_lock = mutex_insert.lock().unwrap();
-        drop(insert_lock);
+/// Drops the member events of users other than the timeline senders and the user from the state
+/// events. The current member events of timeline senders are added if they are missing, even if
+/// the client already got them in an earlier sync.
+fn lazy_loaded_state(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    state_events: Vec<Arc<PduEvent>>,
+    timeline_pdus: &[(Vec<u8>, PduEvent)],
+) -> Result<Vec<Arc<PduEvent>>> {
+    let mut missing = timeline_pdus
+        .iter()
+        .map(|(_, pdu)| pdu.sender.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut events = state_events
+        .into_iter()
+        .filter(|pdu| {
+            pdu.kind != EventType::RoomMember
+                || pdu.state_key.as_deref().map_or(false, |state_key| {
+                    missing.remove(state_key) || state_key == sender_user.as_str()
+                })
+        })
+        .collect::<Vec<_>>();
 
-        let invite_count = db.rooms.get_invite_count(&room_id, &sender_user)?;
+    for user_id in missing {
+        if let Some(member) = db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomMember, user_id)?
+        {
+            events.push(member);
+        }
+    }
 
-        // Invited before last sync
-        if Some(since) >= invite_count {
+    Ok(events)
+}
+
+/// Returns the memberships of the member events in the state events.
+fn member_event_changes(
+    state_events: &[Arc<PduEvent>],
+) -> Result<HashMap<UserId, MembershipState>> {
+    let mut changes = HashMap::new();
+
+    for state_event in state_events {
+        if state_event.kind != EventType::RoomMember {
             continue;
         }
 
-        invited_rooms.insert(
-            room_id.clone(),
-            sync_events::InvitedRoom {
-                invite_state: sync_events::InviteState {
-                    events: invite_state_events,
-                },
-            },
-        );
-    }
+        if let Some(state_key) = &state_event.state_key {
+            let user_id = UserId::try_from(state_key.clone())
+                .map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
 
-    for user_id in left_encrypted_users {
-        let still_share_encrypted_room = db
-            .rooms
-            .get_shared_rooms(vec![sender_user.clone(), user_id.clone()])?
-            .filter_map(|r| r.ok())
-            .filter_map(|other_room_id| {
-                Some(
-                    db.rooms
-                        .room_state_get(&other_room_id, &EventType::RoomEncryption, "")
-                        .ok()?
-                        .is_some(),
-                )
-            })
-            .all(|encrypted| !encrypted);
-        // If the user doesn't share an encrypted room with the target anymore, we need to tell
-        // them
-        if still_share_encrypted_room {
-            device_list_left.insert(user_id);
+            let membership = serde_json::from_value::<
+                Raw<ruma::events::room::member::MemberEventContent>,
+            >(state_event.content.clone())
+            .expect("Raw::from_value always works")
+            .deserialize()
+            .map_err(|_| Error::bad_database("Invalid PDU in database."))?
+            .membership;
+
+            changes.insert(user_id, membership);
         }
     }
 
-    // Remove all to-device events the device received *last time*
-    db.users
-        .remove_to_device_events(&sender_user, &sender_device, since)?;
+    Ok(changes)
+}
 
-    let response = sync_events::Response {
-        next_batch: next_batch_string,
-        rooms: sync_events::Rooms {
-            leave: left_rooms,
-            join: joined_rooms,
-            invite: invited_rooms,
-            knock: BTreeMap::new(), // TODO
-        },
-        presence: sync_events::Presence {
-            events: presence_updates
-                .into_iter()
-                .map(|(_, v)| Raw::from(v))
-                .collect(),
-        },
-        account_data: sync_events::GlobalAccountData {
-            events: db
-                .account_data
-                .changes_since(None, &sender_user, since)?
-                .into_iter()
-                .filter_map(|(_, v)| {
-                    serde_json::from_str(v.json().get())
-                        .map_err(|_| Error::bad_database("Invalid account event in database."))
-                        .ok()
-                })
-                .collect::<Vec<_>>(),
-        },
-        device_lists: sync_events::DeviceLists {
-            changed: device_list_updates.into_iter().collect(),
-            left: device_list_left.into_iter().collect(),
-        },
-        device_one_time_keys_count: if db.users.last_one_time_keys_update(&sender_user)? > since
-            || since == 0
+/// Returns the room account data that changed since the last sync.
+fn room_account_data(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    since: u64,
+    filter: &IncomingFilterDefinition,
+) -> Result<Vec<Raw<AnyRoomAccountDataEvent>>> {
+    Ok(db
+        .account_data
+        .changes_since(Some(room_id), sender_user, since)?
+        .into_iter()
+        .filter(|(kind, _)| {
+            client_server::room_event_filter_matches(
+                &filter.room.account_data,
+                room_id,
+                None,
+                kind.as_ref(),
+            )
+        })
+        .filter_map(|(_, v)| {
+            serde_json::from_str(v.json().get())
+                .map_err(|_| Error::bad_database("Invalid account event in database."))
+                .ok()
+        })
+        .collect())
+}
+
+/// Returns the timeline of a room the user left since the last sync, up to and including the
+/// leave event, and whether it was limited. Events from before the user joined are left out, so
+/// users who rejected an invite only see their leave event.
+fn left_room_timeline(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    since: u64,
+    left_count: u64,
+    filter: &IncomingFilterDefinition,
+) -> Result<(Vec<(Vec<u8>, PduEvent)>, bool)> {
+    let limit = client_server::timeline_limit(&filter.room.timeline);
+    let mut timeline_pdus = Vec::new();
+    let mut limited = false;
+    let mut seen_leave = false;
+
+    for (scanned, (pdu_id, pdu)) in db
+        .rooms
+        .pdus_until(sender_user, room_id, left_count)?
+        .filter_map(|r| {
+            // Filter out buggy events
+            if r.is_err() {
+                error!("Bad pdu in pdus_until: {:?}", r);
+            }
+            r.ok()
+        })
+        .take_while(|(pduid, _)| {
+            db.rooms
+                .pdu_count(pduid)
+                .map_or(false, |count| count > since)
+        })
+        .enumerate()
+    {
+        if scanned == client_server::MAX_SCANNED_EVENTS {
+            limited = true;
+            break;
+        }
+
+        let own_membership = if pdu.kind == EventType::RoomMember
+            && pdu.state_key.as_deref() == Some(sender_user.as_str())
         {
-            db.users.count_one_time_keys(&sender_user, &sender_device)?
+            pdu.content
+                .get("membership")
+                .and_then(|membership| membership.as_str())
+                .map(ToOwned::to_owned)
         } else {
-            BTreeMap::new()
-        },
-        to_device: sync_events::ToDevice {
-            events: db
-                .users
-                .get_to_device_events(&sender_user, &sender_device)?,
-        },
-    };
+            None
+        };
 
-    // TODO: Retry the endpoint instead of returning (waiting for #118)
-    if !full_state
-        && response.rooms.is_empty()
-        && response.presence.is_empty()
-        && response.account_data.is_empty()
-        && response.device_lists.is_empty()
-        && response.device_one_time_keys_count.is_empty()
-        && response.to_device.is_empty()
-    {
-        // Hang a few seconds so requests are not spammed
-        // Stop hanging if new info arrives
-        let mut duration = timeout.unwrap_or_default();
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
+        // The user could only see events while they were joined
+        let stop = match own_membership.as_deref() {
+            Some("join") => true,
+            Some(_) if !seen_leave => {
+                seen_leave = true;
+                pdu.unsigned
+                    .get("prev_content")
+                    .and_then(|prev_content| prev_content.get("membership"))
+                    .and_then(|membership| membership.as_str())
+                    != Some("join")
+            }
+            _ => false,
+        };
+
+        if !pdu.is_dummy()
+            && client_server::room_event_filter_matches(
+                &filter.room.timeline,
+                room_id,
+                Some(&pdu.sender),
+                pdu.kind.as_ref(),
+            )
+        {
+            if timeline_pdus.len() == limit {
+                limited = true;
+                break;
+            }
+            timeline_pdus.push((pdu_id, pdu));
+        }
+
+        if stop {
+            break;
         }
-        let _ = tokio::time::timeout(duration, watcher).await;
-        Ok((response, false))
-    } else {
-        Ok((response, since != next_batch)) // Only cache if we made progress
     }
+
+    timeline_pdus.reverse();
+
+    Ok((timeline_pdus, limited))
 }
 
 #[tracing::instrument(skip(db))]
@@ -816,3 +1510,21 @@ fn share_encrypted_room(
         })
         .any(|encrypted| encrypted))
 }
+
+/// Merges `other` into `object`, combining nested objects instead of replacing them.
+fn merge_json_objects(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    other: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in other {
+        if let serde_json::Value::Object(value) = value {
+            if let Some(serde_json::Value::Object(existing)) = object.get_mut(&key) {
+                merge_json_objects(existing, value);
+                continue;
+            }
+            object.insert(key, serde_json::Value::Object(value));
+        } else {
+            object.insert(key, value);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
+use ruma::api::client::error::ErrorKind;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// How many threads /threads returns if the client doesn't set a limit.
+const DEFAULT_THREADS_LIMIT: u64 = 10;
+/// Clients can't ask for more threads per /threads request than this.
+const MAX_THREADS_LIMIT: u64 = 100;
+
+/// `GET /_matrix/client/v1/rooms/{roomId}/threads` of MSC3440, which ruma doesn't know yet.
+pub mod get_threads {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the thread roots of a room.",
+            method: GET,
+            name: "get_threads",
+            path: "/_matrix/client/v1/rooms/:room_id/threads",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// `all` or `participated`, only the threads the user started or replied to.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub include: Option<String>,
+
+            /// The next_batch of a previous response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+
+            /// The maximum number of threads in the response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/threads`
+///
+/// Lists the roots of the threads in a room, the thread with the latest reply first.
+///
+/// - Only works if the user is joined or the room is world readable
+/// - The roots have the latest reply, the number of replies and whether the user participated
+/// in their bundled `m.thread` aggregation
+/// - `include=participated` only lists threads the user started or replied to
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/rooms/<_>/threads", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_threads_route(
+    db: DatabaseGuard,
+    body: Ruma<get_threads::Request>,
+) -> ConduitResult<get_threads::Response> {
+    let sender_user = body.authenticated_user()?;
+
+    let world_readable_only = !db.rooms.is_joined(sender_user, &body.room_id)?;
+    if world_readable_only && !db.rooms.is_world_readable(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    let participant = match body.include.as_deref() {
+        None | Some("all") => None,
+        Some("participated") => Some(sender_user),
+        Some(_) => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "include has to be all or participated.",
+            ))
+        }
+    };
+
+    let from = body
+        .from
+        .as_ref()
+        .map(|from| from.parse())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
+
+    let limit = body
+        .limit
+        .map_or(DEFAULT_THREADS_LIMIT, u64::from)
+        .min(MAX_THREADS_LIMIT)
+        .max(1) as usize;
+
+    let threads = db
+        .rooms
+        .threads_until(sender_user, &body.room_id, from, participant)?
+        .filter_map(|r| r.ok()) // Filter out buggy threads
+        .filter(|(_, pdu)| {
+            !world_readable_only
+                || db
+                    .rooms
+                    .is_world_readable_at(&pdu.event_id)
+                    .unwrap_or(false)
+        })
+        .take(limit)
+        .collect::<Vec<_>>();
+
+    let next_batch = if threads.len() == limit {
+        threads.last().map(|(count, _)| count.to_string())
+    } else {
+        None
+    };
+
+    Ok(get_threads::Response {
+        chunk: threads
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect(),
+        next_batch,
+    }
+    .into())
+}
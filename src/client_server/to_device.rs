@@ -25,7 +25,7 @@ pub async fn send_event_to_device_route(
     db: DatabaseGuard,
     body: Ruma<send_event_to_device::Request<'_>>,
 ) -> ConduitResult<send_event_to_device::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.authenticated_user()?;
     let sender_device = body.sender_device.as_deref();
 
     // TODO: uncomment when https://github.com/vector-im/element-android/issues/3589 is solved
@@ -22,6 +22,9 @@ pub async fn get_supported_versions_route() -> ConduitResult<get_supported_versi
 
     resp.unstable_features
         .insert("org.matrix.e2e_cross_signing".to_owned(), true);
+    // Threads, with the /relations and /threads endpoints under /v1
+    resp.unstable_features
+        .insert("org.matrix.msc3440.stable".to_owned(), true);
 
     Ok(resp.into())
 }
@@ -8,6 +8,7 @@ use rocket::post;
 ///
 /// Searches all known users for a match.
 ///
+/// - Searches local users and the remote users we share a room with
 /// - TODO: Hide users that are not in any public rooms?
 #[cfg_attr(
     feature = "conduit_bin",
@@ -20,7 +21,7 @@ pub async fn search_users_route(
 ) -> ConduitResult<search_users::Response> {
     let limit = u64::from(body.limit) as usize;
 
-    let mut users = db.users.iter().filter_map(|user_id| {
+    let local_users = db.users.iter().filter_map(|user_id| {
         // Filter out buggy users (they should not exist, but you never know...)
         let user_id = user_id.ok()?;
 
@@ -52,6 +53,32 @@ pub async fn search_users_route(
         Some(user)
     });
 
+    let remote_users = db.users.directory_entries().filter_map(|entry| {
+        let (user_id, display_name) = entry.ok()?;
+
+        let user_id_matches = user_id
+            .as_str()
+            .to_lowercase()
+            .contains(&body.search_term.to_lowercase());
+
+        let user_displayname_matches = display_name.as_ref().map_or(false, |name| {
+            name.to_lowercase()
+                .contains(&body.search_term.to_lowercase())
+        });
+
+        if !user_id_matches && !user_displayname_matches {
+            return None;
+        }
+
+        Some(search_users::User {
+            user_id,
+            display_name,
+            avatar_url: None,
+        })
+    });
+
+    let mut users = local_users.chain(remote_users);
+
     let results = users.by_ref().take(limit).collect();
     let limited = users.next().is_some();
 
@@ -0,0 +1,174 @@
+use crate::database::Config;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Status},
+    Request, Response,
+};
+use std::io::{Cursor, Write};
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compresses big JSON responses (like /sync, /messages and /state) with gzip or zstd, depending on
+/// the Accept-Encoding header of the request.
+pub struct Compression {
+    min_size: usize,
+    gzip_level: u32,
+    zstd_level: i32,
+}
+
+impl Compression {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            min_size: config.compression_min_size as usize,
+            gzip_level: config.gzip_level.min(9),
+            zstd_level: config.zstd_level.max(1).min(21),
+        }
+    }
+}
+
+/// Picks the encoding with the highest q-value the client accepts. zstd wins ties.
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let mut wildcard = None;
+    let mut gzip_listed = false;
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|value| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let encoding = match &*name {
+            "zstd" => Encoding::Zstd,
+            "gzip" | "x-gzip" => {
+                gzip_listed = true;
+                Encoding::Gzip
+            }
+            "*" => {
+                wildcard = Some(q);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if q > 0.0
+            && best.map_or(true, |(_, best_q)| {
+                q > best_q || (q >= best_q && encoding == Encoding::Zstd)
+            })
+        {
+            best = Some((encoding, q));
+        }
+    }
+
+    // A wildcard only counts for gzip, because not every client that sends "*" can decode zstd.
+    // It doesn't override an explicit q-value for gzip, e.g. `gzip;q=0, *`.
+    match (best, wildcard) {
+        (Some((encoding, _)), _) => Some(encoding),
+        (None, Some(q)) if q > 0.0 && !gzip_listed => Some(Encoding::Gzip),
+        _ => None,
+    }
+}
+
+fn compress(
+    body: &[u8],
+    encoding: Encoding,
+    gzip_level: u32,
+    zstd_level: i32,
+) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Zstd => zstd::stream::encode_all(body, zstd_level),
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::with_capacity(body.len() / 4),
+                flate2::Compression::new(gzip_level),
+            );
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if res.status() == Status::PartialContent
+            || res.status() == Status::NotModified
+            || res.headers().contains("Content-Encoding")
+            || !res.content_type().map_or(false, |c| c.is_json())
+        {
+            return;
+        }
+
+        let encoding = match req
+            .headers()
+            .get("Accept-Encoding")
+            .find_map(preferred_encoding)
+        {
+            Some(e) => e,
+            None => return,
+        };
+
+        let body = match res.body_mut().to_bytes().await {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("Failed to read response body for compression: {}", e);
+                return;
+            }
+        };
+
+        // Caches have to know that the response depends on the Accept-Encoding header
+        res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+        if body.len() < self.min_size {
+            res.set_sized_body(body.len(), Cursor::new(body));
+            return;
+        }
+
+        let (gzip_level, zstd_level) = (self.gzip_level, self.zstd_level);
+        let result = tokio::task::spawn_blocking(move || {
+            let compressed = compress(&body, encoding, gzip_level, zstd_level);
+            (body, compressed)
+        })
+        .await;
+
+        match result {
+            Ok((_, Ok(compressed))) => {
+                res.set_raw_header("Content-Encoding", encoding.as_str());
+                res.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Ok((body, Err(e))) => {
+                warn!("Failed to compress response: {}", e);
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+            Err(e) => {
+                // The body is gone, so the best we can do is an error
+                warn!("Compression task panicked: {}", e);
+                res.set_status(Status::InternalServerError);
+                res.set_sized_body(0, Cursor::new(Vec::new()));
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::database::Config;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Method},
+    Request, Response,
+};
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+const ALLOWED_HEADERS: &str =
+    "Origin, X-Requested-With, Content-Type, Accept, Authorization, If-None-Match, Idempotency-Key";
+
+/// Adds the Access-Control-Allow-* headers the spec requires to every response, so web clients
+/// can use the API. OPTIONS preflights are answered by `client_server::options_route`.
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    max_age: String,
+}
+
+impl Cors {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            allowed_origins: config.cors_allowed_origins.clone(),
+            max_age: config.cors_max_age_secs.to_string(),
+        }
+    }
+
+    /// Returns the value for Access-Control-Allow-Origin, or None if the origin is not allowed.
+    fn allow_origin<'a>(&self, origin: Option<&'a str>) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some("*");
+        }
+
+        origin.filter(|origin| self.allowed_origins.iter().any(|o| o == origin))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if self.allowed_origins.is_empty() {
+            return;
+        }
+
+        let allow_origin = self.allow_origin(req.headers().get_one("Origin"));
+
+        if allow_origin != Some("*") {
+            // The answer depends on the origin, so caches must not share it
+            res.adjoin_header(Header::new("Vary", "Origin"));
+        }
+
+        let allow_origin = match allow_origin {
+            Some(o) => o.to_owned(),
+            None => return,
+        };
+
+        res.set_raw_header("Access-Control-Allow-Origin", allow_origin);
+        res.set_raw_header("Access-Control-Allow-Methods", ALLOWED_METHODS);
+        res.set_raw_header("Access-Control-Allow-Headers", ALLOWED_HEADERS);
+        // Let web clients revalidate media with the ETag
+        res.set_raw_header("Access-Control-Expose-Headers", "ETag");
+
+        if req.method() == Method::Options {
+            res.set_raw_header("Access-Control-Max-Age", self.max_age.clone());
+        }
+    }
+}
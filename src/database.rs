@@ -1,20 +1,25 @@
 pub mod abstraction;
 
 pub mod account_data;
+pub mod activity;
 pub mod admin;
 pub mod appservice;
+pub mod audit;
 pub mod globals;
+pub mod jobs;
 pub mod key_backups;
 pub mod media;
 pub mod proxy;
 pub mod pusher;
+pub mod reports;
 pub mod rooms;
 pub mod sending;
+pub mod shortids;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
 
-use crate::{utils, Error, Result};
+use crate::{utils, Error, PduEvent, Result};
 use abstraction::DatabaseEngine;
 use directories::ProjectDirs;
 use lru_cache::LruCache;
@@ -24,20 +29,28 @@ use rocket::{
     request::{FromRequest, Request},
     Shutdown, State,
 };
-use ruma::{DeviceId, EventId, RoomId, ServerName, UserId};
+use ruma::{
+    api::client::r0::room::create_room, DeviceId, EventId, Int, RoomId, RoomIdOrAliasId,
+    ServerName, UserId,
+};
 use serde::{de::IgnoredAny, Deserialize};
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     fs::{self, remove_dir_all},
+    future::Future,
     io::Write,
     mem::size_of,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     ops::Deref,
     path::Path,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
 };
-use tokio::sync::{OwnedRwLockReadGuard, RwLock as TokioRwLock, Semaphore};
-use tracing::{debug, error, warn};
+use tokio::sync::{Notify, OwnedRwLockReadGuard, RwLock as TokioRwLock, Semaphore};
+use tracing::{debug, error, info, warn};
 
 use self::proxy::ProxyConfig;
 
@@ -70,16 +83,197 @@ pub struct Config {
     jwt_secret: Option<String>,
     #[serde(default = "Vec::new")]
     trusted_servers: Vec<Box<ServerName>>,
+    #[serde(default)]
+    signing_key_lifetime_days: u32,
+    #[serde(default)]
+    old_verify_key_retention_days: u32,
     #[serde(default = "default_log")]
     pub log: String,
+    admin_contact: Option<String>,
+    max_joined_rooms_per_user: Option<u32>,
+    max_local_members_per_room: Option<u32>,
+    max_pending_invites_per_user: Option<u32>,
+    #[serde(default = "false_fn")]
+    block_key_requests_to_untrusted_devices: bool,
+    #[serde(default = "false_fn")]
+    require_verified_key_backups: bool,
+    #[serde(default = "default_max_forward_extremities")]
+    max_forward_extremities: u32,
+    messages_per_second: Option<f64>,
+    #[serde(default = "default_message_burst_count")]
+    message_burst_count: u32,
+    #[serde(default = "true_fn")]
+    exempt_appservices_from_message_limit: bool,
+    appservice_default_power_level: Option<Int>,
+    #[serde(default = "default_public_rooms_cache_ttl_secs")]
+    public_rooms_cache_ttl_secs: u32,
+    #[serde(default = "Vec::new")]
+    federated_directory_peers: Vec<Box<ServerName>>,
+    #[serde(default = "default_federated_directory_timeout_ms")]
+    federated_directory_timeout_ms: u64,
+    #[serde(default = "default_max_concurrent_federation_rooms")]
+    max_concurrent_federation_rooms: u16,
+    #[serde(default = "default_invalid_event_ttl_secs")]
+    invalid_event_ttl_secs: u32,
+    #[serde(default = "default_argon2_memory_kib")]
+    argon2_memory_kib: u32,
+    #[serde(default = "default_argon2_iterations")]
+    argon2_iterations: u32,
+    #[serde(default = "default_argon2_parallelism")]
+    argon2_parallelism: u32,
+    #[serde(default = "default_login_failures_before_lockout")]
+    login_failures_before_lockout: u32,
+    #[serde(default = "default_login_lockout_secs")]
+    login_lockout_secs: u32,
+    #[serde(default = "default_trusted_proxies")]
+    trusted_proxies: Vec<IpAddr>,
+    registrations_per_ip: Option<u32>,
+    #[serde(default = "default_registration_ip_window_secs")]
+    registration_ip_window_secs: u32,
+    recaptcha_public_key: Option<String>,
+    recaptcha_private_key: Option<String>,
+    #[serde(default = "default_invite_policy")]
+    default_invite_policy: users::InvitePolicy,
+    mentions_only_room_size: Option<u32>,
+    #[serde(default = "false_fn")]
+    purge_media_of_deactivated_users: bool,
+    #[serde(default = "default_max_reaction_keys_per_event")]
+    max_reaction_keys_per_event: u32,
+    #[serde(default = "default_max_to_device_events_per_device")]
+    max_to_device_events_per_device: u32,
+    #[serde(default = "default_removed_device_queue_retention_secs")]
+    removed_device_queue_retention_secs: u32,
+    #[serde(default = "false_fn")]
+    reject_confusable_aliases: bool,
+    #[serde(default = "true_fn")]
+    pub allow_compression: bool,
+    #[serde(default = "default_compression_min_size")]
+    pub compression_min_size: u32,
+    #[serde(default = "default_gzip_level")]
+    pub gzip_level: u32,
+    #[serde(default = "default_zstd_level")]
+    pub zstd_level: i32,
+    #[serde(default = "default_cors_allowed_origins")]
+    pub cors_allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_max_age_secs")]
+    pub cors_max_age_secs: u32,
+    #[serde(default = "default_slow_request_threshold_ms")]
+    pub slow_request_threshold_ms: u64,
+    #[serde(default = "default_sync_room_concurrency")]
+    sync_room_concurrency: u32,
+    #[serde(default = "default_max_sync_timeout_secs")]
+    max_sync_timeout_secs: u32,
+    #[serde(default = "default_max_concurrent_syncs_per_user")]
+    max_concurrent_syncs_per_user: u32,
+    #[serde(default = "default_create_room_txn_id_ttl_secs")]
+    create_room_txn_id_ttl_secs: u32,
+    #[serde(default = "Vec::new")]
+    auto_join_rooms: Vec<RoomIdOrAliasId>,
+    welcome_message: Option<String>,
+    #[serde(default = "default_reserved_usernames")]
+    reserved_usernames: Vec<String>,
+    #[serde(default = "BTreeMap::new")]
+    room_templates: BTreeMap<String, RoomTemplate>,
+    pub replica_of: Option<String>,
+    replication_secret: Option<String>,
+    #[serde(default = "default_replica_poll_interval_ms")]
+    replica_poll_interval_ms: u64,
+    #[serde(default = "false_fn")]
+    audit_log: bool,
+    #[serde(default)]
+    audit_log_retention_days: u32,
+    #[serde(default)]
+    default_power_levels: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    status_msg_ttl_secs: u32,
+    report_escalation_threshold: Option<u32>,
+    #[serde(default = "false_fn")]
+    quarantine_reported_media: bool,
+    #[serde(default = "default_messages_per_second_under_review")]
+    messages_per_second_under_review: f64,
 
     #[serde(flatten)]
     catchall: BTreeMap<String, IgnoredAny>,
 }
 
+/// Defaults for createRoom that clients can pick with `template` in `creation_content`. The
+/// values of the request win over the template.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomTemplate {
+    pub preset: Option<create_room::RoomPreset>,
+    /// Sent before the initial_state of the request
+    #[serde(default = "Vec::new")]
+    pub initial_state: Vec<RoomTemplateStateEvent>,
+    /// Applied before the power_level_content_override of the request
+    pub power_level_content_override: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default = "false_fn")]
+    pub encrypted: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomTemplateStateEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(default = "String::new")]
+    pub state_key: String,
+    pub content: serde_json::Value,
+}
+
 const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
 
+/// The database version after all migrations ran.
+const DATABASE_VERSION: u64 = 21;
+
+/// How often `StartupStatus::item_done` logs the progress of a step.
+const STARTUP_PROGRESS_INTERVAL: usize = 100_000;
+
+/// What the server is doing while it starts, e.g. which migration is running. Migrations of large
+/// databases can take a long time, so this is logged and shown by the `/ready` endpoint.
+#[derive(Default)]
+pub struct StartupStatus {
+    ready: AtomicBool,
+    step: Mutex<String>,
+    done: AtomicUsize,
+}
+
+impl StartupStatus {
+    pub fn set_step(&self, step: String) {
+        info!("{}", step);
+        *self.step.lock().unwrap() = step;
+        self.done.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts one processed item of the current step and logs the progress every now and then.
+    pub fn item_done(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done % STARTUP_PROGRESS_INTERVAL == 0 {
+            info!("{}: {} items done", self.step.lock().unwrap(), done);
+        }
+    }
+
+    /// Returns a description of the current step, including the progress.
+    pub fn describe(&self) -> String {
+        let step = self.step.lock().unwrap();
+        match self.done.load(Ordering::Relaxed) {
+            0 => step.clone(),
+            done => format!("{}: {} items done", step, done),
+        }
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
 impl Config {
+    /// Config keys that can also be read from a file by setting `<key>_file` to its path.
+    pub const SECRET_KEYS: &'static [&'static str] =
+        &["jwt_secret", "recaptcha_private_key", "replication_secret"];
+
     pub fn warn_deprecated(&self) {
         let mut was_deprecated = false;
         for key in self
@@ -125,17 +319,152 @@ fn default_max_concurrent_requests() -> u16 {
     100
 }
 
+fn default_max_forward_extremities() -> u32 {
+    10
+}
+
+fn default_message_burst_count() -> u32 {
+    10
+}
+
+fn default_public_rooms_cache_ttl_secs() -> u32 {
+    5 * 60
+}
+
+fn default_federated_directory_timeout_ms() -> u64 {
+    3000
+}
+
+fn default_max_concurrent_federation_rooms() -> u16 {
+    16
+}
+
+fn default_invalid_event_ttl_secs() -> u32 {
+    60 * 60 * 24
+}
+
+fn default_argon2_memory_kib() -> u32 {
+    4096
+}
+
+fn default_argon2_iterations() -> u32 {
+    3
+}
+
+fn default_argon2_parallelism() -> u32 {
+    1
+}
+
+fn default_login_failures_before_lockout() -> u32 {
+    5
+}
+
+fn default_login_lockout_secs() -> u32 {
+    5 * 60
+}
+
+fn default_trusted_proxies() -> Vec<IpAddr> {
+    vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()]
+}
+
+fn default_registration_ip_window_secs() -> u32 {
+    60 * 60
+}
+
+fn default_messages_per_second_under_review() -> f64 {
+    0.05
+}
+
+fn default_invite_policy() -> users::InvitePolicy {
+    users::InvitePolicy::Everyone
+}
+
+fn default_max_reaction_keys_per_event() -> u32 {
+    20
+}
+
+fn default_max_to_device_events_per_device() -> u32 {
+    1000
+}
+
+fn default_removed_device_queue_retention_secs() -> u32 {
+    60 * 60 * 24 * 7
+}
+
+fn default_compression_min_size() -> u32 {
+    1024
+}
+
+fn default_gzip_level() -> u32 {
+    6
+}
+
+fn default_zstd_level() -> i32 {
+    3
+}
+
+fn default_reserved_usernames() -> Vec<String> {
+    [
+        "admin",
+        "administrator",
+        "abuse",
+        "conduit",
+        "matrix",
+        "moderator",
+        "postmaster",
+        "root",
+        "security",
+        "support",
+    ]
+    .iter()
+    .map(|&name| name.to_owned())
+    .collect()
+}
+
+fn default_sync_room_concurrency() -> u32 {
+    8
+}
+
+fn default_max_sync_timeout_secs() -> u32 {
+    30
+}
+
+fn default_max_concurrent_syncs_per_user() -> u32 {
+    10
+}
+
+fn default_create_room_txn_id_ttl_secs() -> u32 {
+    60 * 60
+}
+
+fn default_slow_request_threshold_ms() -> u64 {
+    2000
+}
+
+fn default_cors_allowed_origins() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+fn default_cors_max_age_secs() -> u32 {
+    60 * 60 * 24
+}
+
+fn default_replica_poll_interval_ms() -> u64 {
+    500
+}
+
 fn default_log() -> String {
     "info,state_res=warn,rocket=off,_=off,sled=off".to_owned()
 }
 
-#[cfg(feature = "sled")]
-pub type Engine = abstraction::sled::Engine;
-
+// Several backends can be built in to migrate between them, the server uses the first one
 #[cfg(feature = "sqlite")]
 pub type Engine = abstraction::sqlite::Engine;
 
-#[cfg(feature = "heed")]
+#[cfg(all(feature = "sled", not(feature = "sqlite")))]
+pub type Engine = abstraction::sled::Engine;
+
+#[cfg(all(feature = "heed", not(any(feature = "sqlite", feature = "sled"))))]
 pub type Engine = abstraction::heed::Engine;
 
 pub struct Database {
@@ -145,6 +474,8 @@ pub struct Database {
     pub uiaa: uiaa::Uiaa,
     pub rooms: rooms::Rooms,
     pub account_data: account_data::AccountData,
+    pub shortids: Arc<shortids::ShortIds>,
+    pub activity: Arc<activity::RoomActivity>,
     pub media: media::Media,
     pub key_backups: key_backups::KeyBackups,
     pub transaction_ids: transaction_ids::TransactionIds,
@@ -152,6 +483,9 @@ pub struct Database {
     pub admin: admin::Admin,
     pub appservice: appservice::Appservice,
     pub pusher: pusher::PushData,
+    pub jobs: jobs::Jobs,
+    pub audit: audit::Audit,
+    pub reports: reports::Reports,
 }
 
 impl Database {
@@ -195,9 +529,33 @@ impl Database {
     }
 
     /// Load an existing database or create a new one.
-    pub async fn load_or_create(config: &Config) -> Result<Arc<TokioRwLock<Self>>> {
+    /// Opens the database and runs all migrations. What is being done is reported to `status`,
+    /// which is shown by the `/ready` endpoint while the server starts.
+    pub async fn load_or_create(
+        config: &Config,
+        status: &StartupStatus,
+    ) -> Result<Arc<TokioRwLock<Self>>> {
         Self::check_sled_or_sqlite_db(&config)?;
 
+        let is_replica = config.replica_of.is_some();
+        if is_replica && !cfg!(feature = "sqlite") {
+            return Err(Error::bad_config(
+                "Replicas need the sqlite backend, the other backends can't be opened by two processes.",
+            ));
+        }
+
+        // New events need at least one prev_event
+        if config.max_forward_extremities < 1 {
+            return Err(Error::bad_config(
+                "max_forward_extremities must be at least 1.",
+            ));
+        }
+
+        // All trees are opened right away. Opening a tree is cheap with every engine, and
+        // transactions downcast trees to the tree type of the engine, so they can't be wrapped to
+        // open on first use. Large databases spend their startup time in the migrations, which
+        // report their progress.
+        status.set_step("Opening the database".to_owned());
         let builder = Engine::open(&config)?;
 
         if config.max_request_size < 1024 {
@@ -207,6 +565,18 @@ impl Database {
         let (admin_sender, admin_receiver) = mpsc::unbounded();
         let (sending_sender, sending_receiver) = mpsc::unbounded();
 
+        let shortids = Arc::new(shortids::ShortIds {
+            userid_shortuserid: builder.open_tree("userid_shortuserid")?,
+            eventtype_shorteventtype: builder.open_tree("eventtype_shorteventtype")?,
+            shorteventtype_eventtype: builder.open_tree("shorteventtype_eventtype")?,
+        });
+
+        let activity = Arc::new(activity::RoomActivity {
+            roomid_lastactivitycount: builder.open_tree("roomid_lastactivitycount")?,
+            update_lock: Mutex::new(()),
+            roomid_waker: Mutex::new(HashMap::new()),
+        });
+
         let db = Arc::new(TokioRwLock::from(Self {
             _db: builder.clone(),
             users: users::Users {
@@ -218,14 +588,39 @@ impl Database {
                 userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
                 userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
                 token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+                userdeviceid_tokencreatedts: builder.open_tree("userdeviceid_tokencreatedts")?,
+                userdeviceid_tokenscope: builder.open_tree("userdeviceid_tokenscope")?,
                 onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
                 userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
+                fallbackkeyid_fallbackkey: builder.open_tree("fallbackkeyid_fallbackkey")?,
                 keychangeid_userid: builder.open_tree("keychangeid_userid")?,
                 keyid_key: builder.open_tree("keyid_key")?,
                 userid_masterkeyid: builder.open_tree("userid_masterkeyid")?,
                 userid_selfsigningkeyid: builder.open_tree("userid_selfsigningkeyid")?,
                 userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
                 todeviceid_events: builder.open_tree("todeviceid_events")?,
+                userdeviceid_todeviceack: builder.open_tree("userdeviceid_todeviceack")?,
+                userdeviceid_todevicecount: builder.open_tree("userdeviceid_todevicecount")?,
+                userdeviceid_removedts: builder.open_tree("userdeviceid_removedts")?,
+                remoteuserid_devicelistid: builder.open_tree("remoteuserid_devicelistid")?,
+                remoteuserdeviceid_devicekeys: builder
+                    .open_tree("remoteuserdeviceid_devicekeys")?,
+                remoteuserid_displayname: builder.open_tree("remoteuserid_displayname")?,
+                loginfailureid_data: builder.open_tree("loginfailureid_data")?,
+                ipregistrationid_userid: builder.open_tree("ipregistrationid_userid")?,
+                userid_registrationip: builder.open_tree("userid_registrationip")?,
+                lowercaselocalpart_userid: builder.open_tree("lowercaselocalpart_userid")?,
+                userfilterid_filter: builder.open_tree("userfilterid_filter")?,
+                exportid_expiryuserid: builder.open_tree("exportid_expiryuserid")?,
+                // The primary may remove tokens at any time, so replicas look them up every time
+                token_cache: Mutex::new(LruCache::new(if is_replica { 0 } else { 10_000 })),
+                todevicecount_lock: Mutex::new(()),
+                password_hash_params: utils::PasswordHashParams {
+                    memory_kib: config.argon2_memory_kib,
+                    iterations: config.argon2_iterations,
+                    parallelism: config.argon2_parallelism,
+                },
+                activity: Arc::clone(&activity),
             },
             uiaa: uiaa::Uiaa {
                 userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
@@ -235,6 +630,9 @@ impl Database {
             rooms: rooms::Rooms {
                 edus: rooms::RoomEdus {
                     readreceiptid_readreceipt: builder.open_tree("readreceiptid_readreceipt")?,
+                    roomid_lastreceiptupdate: builder.open_tree("roomid_lastreceiptupdate")?,
+                    roomuserthreadid_readreceiptid: builder
+                        .open_tree("roomuserthreadid_readreceiptid")?,
                     roomuserid_privateread: builder.open_tree("roomuserid_privateread")?, // "Private" read receipt
                     roomuserid_lastprivatereadupdate: builder
                         .open_tree("roomuserid_lastprivatereadupdate")?,
@@ -242,13 +640,18 @@ impl Database {
                     roomid_lasttypingupdate: builder.open_tree("roomid_lasttypingupdate")?,
                     presenceid_presence: builder.open_tree("presenceid_presence")?,
                     userid_lastpresenceupdate: builder.open_tree("userid_lastpresenceupdate")?,
+                    userid_presence: builder.open_tree("userid_presence")?,
+                    update_count_lock: Mutex::new(()),
+                    activity: Arc::clone(&activity),
                 },
+                activity: Arc::clone(&activity),
                 pduid_pdu: builder.open_tree("pduid_pdu")?,
                 eventid_pduid: builder.open_tree("eventid_pduid")?,
                 roomid_pduleaves: builder.open_tree("roomid_pduleaves")?,
 
                 alias_roomid: builder.open_tree("alias_roomid")?,
                 aliasid_alias: builder.open_tree("aliasid_alias")?,
+                alias_userid: builder.open_tree("alias_userid")?,
                 publicroomids: builder.open_tree("publicroomids")?,
 
                 tokenids: builder.open_tree("tokenids")?,
@@ -262,8 +665,11 @@ impl Database {
                 roomuseroncejoinedids: builder.open_tree("roomuseroncejoinedids")?,
                 userroomid_invitestate: builder.open_tree("userroomid_invitestate")?,
                 roomuserid_invitecount: builder.open_tree("roomuserid_invitecount")?,
+                roomuserid_knockcount: builder.open_tree("roomuserid_knockcount")?,
+                userroomid_knockstate: builder.open_tree("userroomid_knockstate")?,
                 userroomid_leftstate: builder.open_tree("userroomid_leftstate")?,
                 roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
+                roomchangeid_membership: builder.open_tree("roomchangeid_membership")?,
 
                 userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
                 userroomid_highlightcount: builder.open_tree("userroomid_highlightcount")?,
@@ -285,29 +691,66 @@ impl Database {
 
                 eventid_outlierpdu: builder.open_tree("eventid_outlierpdu")?,
                 softfailedeventids: builder.open_tree("softfailedeventids")?,
+                eventid_rejectionreason: builder.open_tree("eventid_rejectionreason")?,
+                origineventid_invalidity: builder.open_tree("origineventid_invalidity")?,
+                restrictedroomid_allowroomids: builder
+                    .open_tree("restrictedroomid_allowroomids")?,
+                allowroomid_restrictedroomids: builder
+                    .open_tree("allowroomid_restrictedroomids")?,
+                roomidday_messagecount: builder.open_tree("roomidday_messagecount")?,
+                roomiddayuserid: builder.open_tree("roomiddayuserid")?,
 
                 referencedevents: builder.open_tree("referencedevents")?,
-                pdu_cache: Mutex::new(LruCache::new(
+                annotationid_reactionid: builder.open_tree("annotationid_reactionid")?,
+                reactionid_annotationid: builder.open_tree("reactionid_annotationid")?,
+                annotationkey_count: builder.open_tree("annotationkey_count")?,
+                relationid_reltype: builder.open_tree("relationid_reltype")?,
+                typedrelationid: builder.open_tree("typedrelationid")?,
+                eventid_sender: builder.open_tree("eventid_sender")?,
+                eventid_threadid: builder.open_tree("eventid_threadid")?,
+                threadid_eventid: builder.open_tree("threadid_eventid")?,
+                eventid_threadcount: builder.open_tree("eventid_threadcount")?,
+                threaduserids: builder.open_tree("threaduserids")?,
+                // Redactions on the primary change pdus, which a replica wouldn't notice
+                pdu_cache: Mutex::new(LruCache::new(if is_replica {
+                    0
+                } else {
                     config
                         .pdu_cache_capacity
                         .try_into()
-                        .expect("pdu cache capacity fits into usize"),
-                )),
+                        .expect("pdu cache capacity fits into usize")
+                })),
                 auth_chain_cache: Mutex::new(LruCache::new(1_000_000)),
                 shorteventid_cache: Mutex::new(LruCache::new(1_000_000)),
                 eventidshort_cache: Mutex::new(LruCache::new(1_000_000)),
                 shortstatekey_cache: Mutex::new(LruCache::new(1_000_000)),
                 statekeyshort_cache: Mutex::new(LruCache::new(1_000_000)),
+                // The caches below are cleared by changes that only the primary sees, so they are
+                // disabled on replicas like pdu_cache. The room member caches are only filled when
+                // appending events, which replicas don't do
                 our_real_users_cache: RwLock::new(HashMap::new()),
                 appservice_in_room_cache: RwLock::new(HashMap::new()),
+                restricted_join_cache: Mutex::new(LruCache::new(if is_replica {
+                    0
+                } else {
+                    100_000
+                })),
+                statepdus_cache: Mutex::new(LruCache::new(if is_replica { 0 } else { 100 })),
+                space_hierarchy_cache: Mutex::new(LruCache::new(if is_replica { 0 } else { 100 })),
                 stateinfo_cache: Mutex::new(LruCache::new(1000)),
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
                 roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
+                shortids: Arc::clone(&shortids),
+                activity: Arc::clone(&activity),
             },
+            shortids,
+            activity,
             media: media::Media {
                 mediaid_file: builder.open_tree("mediaid_file")?,
+                userid_mxc: builder.open_tree("userid_mxc")?,
+                mxc_quarantined: builder.open_tree("mxc_quarantined")?,
             },
             key_backups: key_backups::KeyBackups {
                 backupid_algorithm: builder.open_tree("backupid_algorithm")?,
@@ -316,6 +759,7 @@ impl Database {
             },
             transaction_ids: transaction_ids::TransactionIds {
                 userdevicetxnid_response: builder.open_tree("userdevicetxnid_response")?,
+                userdevicetxnid_createdroom: builder.open_tree("userdevicetxnid_createdroom")?,
             },
             sending: sending::Sending {
                 servername_educount: builder.open_tree("servername_educount")?,
@@ -323,17 +767,33 @@ impl Database {
                 servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
                 maximum_requests: Arc::new(Semaphore::new(config.max_concurrent_requests as usize)),
                 sender: sending_sender,
+                destination_status: Mutex::new(HashMap::new()),
             },
             admin: admin::Admin {
                 sender: admin_sender,
             },
             appservice: appservice::Appservice {
                 cached_registrations: Arc::new(RwLock::new(HashMap::new())),
+                cached_namespaces: RwLock::new(HashMap::new()),
                 id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
             },
             pusher: pusher::PushData {
                 senderkey_pusher: builder.open_tree("senderkey_pusher")?,
             },
+            jobs: jobs::Jobs {
+                jobid_job: builder.open_tree("jobid_job")?,
+                job_queued: Arc::new(Notify::new()),
+            },
+            audit: audit::Audit {
+                auditid_entry: builder.open_tree("auditid_entry")?,
+                userauditid: builder.open_tree("userauditid")?,
+            },
+            reports: reports::Reports {
+                eventidreporter_report: builder.open_tree("eventidreporter_report")?,
+                senderreporter: builder.open_tree("senderreporter")?,
+                userid_escalation: builder.open_tree("userid_escalation")?,
+                userid_lastresolvedts: builder.open_tree("userid_lastresolvedts")?,
+            },
             globals: globals::Globals::load(
                 builder.open_tree("global")?,
                 builder.open_tree("server_signingkeys")?,
@@ -343,9 +803,19 @@ impl Database {
 
         {
             let db = db.read().await;
+
+            // Only the primary runs migrations
+            if is_replica && db.globals.database_version()? < DATABASE_VERSION {
+                return Err(Error::bad_config(
+                    "The primary has to migrate the database before replicas can open it.",
+                ));
+            }
+
             // MIGRATIONS
             // TODO: database versions of new dbs should probably not be 0
             if db.globals.database_version()? < 1 {
+                status.set_step("Migrating the database from version 0 to 1".to_owned());
+
                 for (roomserverid, _) in db.rooms.roomserverids.iter() {
                     let mut parts = roomserverid.split(|&b| b == 0xff);
                     let room_id = parts.next().expect("split always returns one element");
@@ -369,6 +839,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 2 {
+                status.set_step("Migrating the database from version 1 to 2".to_owned());
+
                 // We accidentally inserted hashed versions of "" into the db instead of just ""
                 for (userid, password) in db.users.userid_password.iter() {
                     let password = utils::string_from_bytes(&password);
@@ -388,6 +860,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 3 {
+                status.set_step("Migrating the database from version 2 to 3".to_owned());
+
                 // Move media to filesystem
                 for (key, content) in db.media.mediaid_file.iter() {
                     if content.is_empty() {
@@ -406,6 +880,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 4 {
+                status.set_step("Migrating the database from version 3 to 4".to_owned());
+
                 // Add federated users to db as deactivated
                 for our_user in db.users.iter() {
                     let our_user = our_user?;
@@ -429,6 +905,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 5 {
+                status.set_step("Migrating the database from version 4 to 5".to_owned());
+
                 // Upgrade user data store
                 for (roomuserdataid, _) in db.account_data.roomuserdataid_accountdata.iter() {
                     let mut parts = roomuserdataid.split(|&b| b == 0xff);
@@ -453,8 +931,15 @@ impl Database {
             }
 
             if db.globals.database_version()? < 6 {
+                status.set_step("Migrating the database from version 5 to 6".to_owned());
+
                 // Set room member count
-                for (roomid, _) in db.rooms.roomid_shortstatehash.iter() {
+                for (roomid, _) in db
+                    .rooms
+                    .roomid_shortstatehash
+                    .iter()
+                    .inspect(|_| status.item_done())
+                {
                     let room_id =
                         RoomId::try_from(utils::string_from_bytes(&roomid).unwrap()).unwrap();
 
@@ -467,6 +952,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 7 {
+                status.set_step("Migrating the database from version 6 to 7".to_owned());
+
                 // Upgrade state store
                 let mut last_roomstates: HashMap<RoomId, u64> = HashMap::new();
                 let mut current_sstatehash: Option<u64> = None;
@@ -595,6 +1082,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 8 {
+                status.set_step("Migrating the database from version 7 to 8".to_owned());
+
                 // Generate short room ids for all rooms
                 for (room_id, _) in db.rooms.roomid_shortstatehash.iter() {
                     let shortroomid = db.globals.next_count()?.to_be_bytes();
@@ -602,49 +1091,59 @@ impl Database {
                     println!("Migration: 8");
                 }
                 // Update pduids db layout
-                let mut batch = db.rooms.pduid_pdu.iter().filter_map(|(key, v)| {
-                    if !key.starts_with(b"!") {
-                        return None;
-                    }
-                    let mut parts = key.splitn(2, |&b| b == 0xff);
-                    let room_id = parts.next().unwrap();
-                    let count = parts.next().unwrap();
+                let mut batch = db
+                    .rooms
+                    .pduid_pdu
+                    .iter()
+                    .inspect(|_| status.item_done())
+                    .filter_map(|(key, v)| {
+                        if !key.starts_with(b"!") {
+                            return None;
+                        }
+                        let mut parts = key.splitn(2, |&b| b == 0xff);
+                        let room_id = parts.next().unwrap();
+                        let count = parts.next().unwrap();
 
-                    let short_room_id = db
-                        .rooms
-                        .roomid_shortroomid
-                        .get(&room_id)
-                        .unwrap()
-                        .expect("shortroomid should exist");
+                        let short_room_id = db
+                            .rooms
+                            .roomid_shortroomid
+                            .get(&room_id)
+                            .unwrap()
+                            .expect("shortroomid should exist");
 
-                    let mut new_key = short_room_id;
-                    new_key.extend_from_slice(count);
+                        let mut new_key = short_room_id;
+                        new_key.extend_from_slice(count);
 
-                    Some((new_key, v))
-                });
+                        Some((new_key, v))
+                    });
 
                 db.rooms.pduid_pdu.insert_batch(&mut batch)?;
 
-                let mut batch2 = db.rooms.eventid_pduid.iter().filter_map(|(k, value)| {
-                    if !value.starts_with(b"!") {
-                        return None;
-                    }
-                    let mut parts = value.splitn(2, |&b| b == 0xff);
-                    let room_id = parts.next().unwrap();
-                    let count = parts.next().unwrap();
+                let mut batch2 = db
+                    .rooms
+                    .eventid_pduid
+                    .iter()
+                    .inspect(|_| status.item_done())
+                    .filter_map(|(k, value)| {
+                        if !value.starts_with(b"!") {
+                            return None;
+                        }
+                        let mut parts = value.splitn(2, |&b| b == 0xff);
+                        let room_id = parts.next().unwrap();
+                        let count = parts.next().unwrap();
 
-                    let short_room_id = db
-                        .rooms
-                        .roomid_shortroomid
-                        .get(&room_id)
-                        .unwrap()
-                        .expect("shortroomid should exist");
+                        let short_room_id = db
+                            .rooms
+                            .roomid_shortroomid
+                            .get(&room_id)
+                            .unwrap()
+                            .expect("shortroomid should exist");
 
-                    let mut new_value = short_room_id;
-                    new_value.extend_from_slice(count);
+                        let mut new_value = short_room_id;
+                        new_value.extend_from_slice(count);
 
-                    Some((k, new_value))
-                });
+                        Some((k, new_value))
+                    });
 
                 db.rooms.eventid_pduid.insert_batch(&mut batch2)?;
 
@@ -654,11 +1153,14 @@ impl Database {
             }
 
             if db.globals.database_version()? < 9 {
+                status.set_step("Migrating the database from version 8 to 9".to_owned());
+
                 // Update tokenids db layout
                 let mut iter = db
                     .rooms
                     .tokenids
                     .iter()
+                    .inspect(|_| status.item_done())
                     .filter_map(|(key, _)| {
                         if !key.starts_with(b"!") {
                             return None;
@@ -719,6 +1221,8 @@ impl Database {
             }
 
             if db.globals.database_version()? < 10 {
+                status.set_step("Migrating the database from version 9 to 10".to_owned());
+
                 // Add other direction for shortstatekeys
                 for (statekey, shortstatekey) in db.rooms.statekey_shortstatekey.iter() {
                     db.rooms
@@ -736,17 +1240,472 @@ impl Database {
 
                 println!("Migration: 9 -> 10 finished");
             }
+
+            if db.globals.database_version()? < 11 {
+                status.set_step("Migrating the database from version 10 to 11".to_owned());
+
+                // Use short ids for users and event types in account data keys. Membership and
+                // receipt keys keep the full ids, their readers parse user ids out of the keys
+                let old_keys = db
+                    .account_data
+                    .roomuserdataid_accountdata
+                    .iter()
+                    .inspect(|_| status.item_done())
+                    .filter(|(key, _)| is_old_accountdata_key(key))
+                    .collect::<Vec<_>>();
+
+                for (key, data) in &old_keys {
+                    // Room + 0xff + User + 0xff + Count + 0xff + Type
+                    let mut parts = key.splitn(3, |&b| b == 0xff);
+                    let room_id = parts.next().expect("splitn always returns one element");
+                    let user_id = parts
+                        .next()
+                        .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                        .and_then(|user_id| UserId::try_from(user_id).ok());
+                    let rest = parts.next().unwrap_or_default();
+
+                    let (user_id, count, event_type) = match user_id {
+                        Some(user_id) if rest.len() > size_of::<u64>() + 1 => (
+                            user_id,
+                            &rest[..size_of::<u64>()],
+                            &rest[size_of::<u64>() + 1..],
+                        ),
+                        _ => {
+                            warn!("Skipping invalid account data key {:?}", key);
+                            continue;
+                        }
+                    };
+
+                    let event_type = match utils::string_from_bytes(event_type)
+                        .ok()
+                        .and_then(|e| ruma::events::EventType::try_from(e).ok())
+                    {
+                        Some(event_type) => event_type,
+                        None => {
+                            warn!("Skipping invalid account data key {:?}", key);
+                            continue;
+                        }
+                    };
+
+                    let shortuserid = db
+                        .shortids
+                        .get_or_create_shortuserid(&user_id, &db.globals)?
+                        .to_be_bytes();
+                    let shorteventtype = db
+                        .shortids
+                        .get_or_create_shorteventtype(&event_type, &db.globals)?
+                        .to_be_bytes();
+
+                    let mut prefix = room_id.to_vec();
+                    prefix.push(0xff);
+                    prefix.extend_from_slice(&shortuserid);
+
+                    let mut roomuserdataid = prefix.clone();
+                    roomuserdataid.extend_from_slice(count);
+                    roomuserdataid.extend_from_slice(&shorteventtype);
+
+                    let mut roomusertype = prefix;
+                    roomusertype.extend_from_slice(&shorteventtype);
+
+                    db.account_data
+                        .roomuserdataid_accountdata
+                        .insert(&roomuserdataid, data)?;
+                    db.account_data
+                        .roomusertype_roomuserdataid
+                        .insert(&roomusertype, &roomuserdataid)?;
+                }
+
+                for (key, _) in old_keys {
+                    db.account_data.roomuserdataid_accountdata.remove(&key)?;
+                }
+
+                let old_typekeys = db
+                    .account_data
+                    .roomusertype_roomuserdataid
+                    .iter()
+                    .filter(|(key, _)| is_old_accountdata_key(key))
+                    .map(|(key, _)| key)
+                    .collect::<Vec<_>>();
+
+                for key in old_typekeys {
+                    db.account_data.roomusertype_roomuserdataid.remove(&key)?;
+                }
+
+                db.globals.bump_database_version(11)?;
+
+                println!("Migration: 10 -> 11 finished");
+            }
+
+            if db.globals.database_version()? < 12 {
+                status.set_step("Migrating the database from version 11 to 12".to_owned());
+
+                // Membership changes are logged from now on. Older changes are only in the state
+                db.globals
+                    .set_membership_log_start(db.globals.current_count()?)?;
+
+                db.globals.bump_database_version(12)?;
+
+                println!("Migration: 11 -> 12 finished");
+            }
+
+            if db.globals.database_version()? < 13 {
+                status.set_step("Migrating the database from version 12 to 13".to_owned());
+
+                // Index the relations of existing events
+                for (pdu_id, pdu) in db.rooms.pduid_pdu.iter().inspect(|_| status.item_done()) {
+                    let relation = serde_json::from_slice::<serde_json::Value>(&pdu)
+                        .ok()
+                        .and_then(|pdu| rooms::parse_relation(pdu.get("content")?));
+
+                    if let Some((target, rel_type)) = relation {
+                        db.rooms
+                            .relationid_reltype
+                            .insert(&rooms::relation_id(&target, &pdu_id), rel_type.as_bytes())?;
+                    }
+                }
+
+                db.globals.bump_database_version(13)?;
+
+                println!("Migration: 12 -> 13 finished");
+            }
+
+            if db.globals.database_version()? < 14 {
+                status.set_step("Migrating the database from version 13 to 14".to_owned());
+
+                // Remember the senders of existing events
+                for (_, pdu) in db.rooms.pduid_pdu.iter().inspect(|_| status.item_done()) {
+                    let pdu = match serde_json::from_slice::<serde_json::Value>(&pdu) {
+                        Ok(pdu) => pdu,
+                        Err(_) => continue,
+                    };
+
+                    if let (Some(event_id), Some(sender)) = (
+                        pdu.get("event_id").and_then(|e| e.as_str()),
+                        pdu.get("sender").and_then(|s| s.as_str()),
+                    ) {
+                        db.rooms
+                            .eventid_sender
+                            .insert(event_id.as_bytes(), sender.as_bytes())?;
+                    }
+                }
+
+                db.globals.bump_database_version(14)?;
+
+                println!("Migration: 13 -> 14 finished");
+            }
+
+            if db.globals.database_version()? < 15 {
+                status.set_step("Migrating the database from version 14 to 15".to_owned());
+
+                // Collect the thread replies first, adding them changes their roots
+                let replies = db
+                    .rooms
+                    .pduid_pdu
+                    .iter()
+                    .inspect(|_| status.item_done())
+                    .filter_map(|(pdu_id, pdu)| {
+                        Some((pdu_id, serde_json::from_slice::<PduEvent>(&pdu).ok()?))
+                    })
+                    .filter(|(_, pdu)| {
+                        rooms::parse_relation(&pdu.content)
+                            .map_or(false, |(_, rel_type)| rel_type == "m.thread")
+                    })
+                    .collect::<Vec<_>>();
+
+                for (pdu_id, pdu) in replies {
+                    db.rooms.add_thread_reply(&pdu, &pdu_id)?;
+                }
+
+                db.globals.bump_database_version(15)?;
+
+                println!("Migration: 14 -> 15 finished");
+            }
+
+            if db.globals.database_version()? < 16 {
+                status.set_step("Migrating the database from version 15 to 16".to_owned());
+
+                // Index the allow lists of existing restricted rooms
+                for (room_id, _) in db
+                    .rooms
+                    .roomid_shortroomid
+                    .iter()
+                    .inspect(|_| status.item_done())
+                {
+                    let room_id = match utils::string_from_bytes(&room_id)
+                        .ok()
+                        .and_then(|room_id| RoomId::try_from(room_id).ok())
+                    {
+                        Some(room_id) => room_id,
+                        None => {
+                            warn!("Skipping invalid room id {:?}", room_id);
+                            continue;
+                        }
+                    };
+
+                    if let Some(join_rules) = db.rooms.room_state_get(
+                        &room_id,
+                        &ruma::events::EventType::RoomJoinRules,
+                        "",
+                    )? {
+                        db.rooms
+                            .update_restricted_allow_rooms(&room_id, &join_rules.content)?;
+                    }
+                }
+
+                db.globals.bump_database_version(16)?;
+
+                println!("Migration: 15 -> 16 finished");
+            }
+
+            if db.globals.database_version()? < 17 {
+                status.set_step("Migrating the database from version 16 to 17".to_owned());
+
+                // Index the lowercase localparts of existing users
+                for user_id in db.users.iter().inspect(|_| status.item_done()) {
+                    let user_id = match user_id {
+                        Ok(user_id) => user_id,
+                        Err(e) => {
+                            warn!("Skipping invalid user id: {}", e);
+                            continue;
+                        }
+                    };
+
+                    db.users.index_lowercase_localpart(&user_id)?;
+                }
+
+                db.globals.bump_database_version(17)?;
+
+                println!("Migration: 16 -> 17 finished");
+            }
+
+            if db.globals.database_version()? < 18 {
+                status.set_step("Migrating the database from version 17 to 18".to_owned());
+
+                // Index the audit log entries by user
+                for (key, entry) in db
+                    .audit
+                    .auditid_entry
+                    .iter()
+                    .inspect(|_| status.item_done())
+                {
+                    match serde_json::from_slice(&entry) {
+                        Ok(entry) => db.audit.index_entry(&key, &entry)?,
+                        Err(e) => warn!("Skipping invalid audit log entry: {}", e),
+                    }
+                }
+
+                db.globals.bump_database_version(18)?;
+
+                println!("Migration: 17 -> 18 finished");
+            }
+
+            if db.globals.database_version()? < 19 {
+                status.set_step("Migrating the database from version 18 to 19".to_owned());
+
+                // Remember the newest presence event of each local user, so their presence can be
+                // read before they set it again
+                let mut newest_presence = HashMap::new();
+                for (key, value) in db
+                    .rooms
+                    .edus
+                    .presenceid_presence
+                    .iter()
+                    .inspect(|_| status.item_done())
+                {
+                    // PresenceId = RoomId + 0xff + Count + 0xff + UserId
+                    let count_and_user = match key.splitn(2, |&b| b == 0xff).nth(1) {
+                        Some(count_and_user) if count_and_user.len() > 9 => count_and_user,
+                        _ => continue,
+                    };
+                    let count = utils::u64_from_bytes(&count_and_user[..8]).map_err(|_| {
+                        Error::bad_database("Invalid count in presenceid_presence.")
+                    })?;
+                    let user_id = match utils::string_from_bytes(&count_and_user[9..])
+                        .ok()
+                        .and_then(|user_id| UserId::try_from(user_id).ok())
+                    {
+                        Some(user_id) if user_id.server_name() == db.globals.server_name() => {
+                            user_id
+                        }
+                        _ => continue,
+                    };
+
+                    if newest_presence
+                        .get(&user_id)
+                        .map_or(true, |(newest_count, _)| *newest_count < count)
+                    {
+                        newest_presence.insert(user_id, (count, value));
+                    }
+                }
+
+                let now = utils::millis_since_unix_epoch();
+                for (user_id, (_, value)) in newest_presence {
+                    if db.rooms.edus.user_presence(&user_id)?.is_some() {
+                        continue;
+                    }
+
+                    let presence = match serde_json::from_slice::<
+                        ruma::events::presence::PresenceEvent,
+                    >(&value)
+                    {
+                        Ok(presence) => presence.content,
+                        Err(e) => {
+                            warn!("Skipping invalid presence of {}: {}", user_id, e);
+                            continue;
+                        }
+                    };
+
+                    // Stored presence events hold the time of the update in last_active_ago
+                    db.rooms.edus.set_user_presence(
+                        &user_id,
+                        &rooms::UserPresence {
+                            presence: presence.presence,
+                            status_msg_ts: presence.status_msg.as_ref().map(|_| now),
+                            status_msg: presence.status_msg,
+                            last_active_ts: presence.last_active_ago.map_or(now, u64::from),
+                        },
+                    )?;
+                }
+
+                db.globals.bump_database_version(19)?;
+
+                println!("Migration: 18 -> 19 finished");
+            }
+
+            if db.globals.database_version()? < 20 {
+                status.set_step("Migrating the database from version 19 to 20".to_owned());
+
+                // Index the existing relations by type
+                for (relation_id, rel_type) in db
+                    .rooms
+                    .relationid_reltype
+                    .iter()
+                    .inspect(|_| status.item_done())
+                {
+                    // RelationId = EventId + 0xff + PduId, event ids don't contain 0xff
+                    let mut parts = relation_id.splitn(2, |&b| b == 0xff);
+                    let (target, pdu_id) = match (parts.next(), parts.next()) {
+                        (Some(target), Some(pdu_id)) => (target, pdu_id),
+                        _ => continue,
+                    };
+
+                    let mut typed_relation_id = target.to_vec();
+                    typed_relation_id.push(0xff);
+                    typed_relation_id.extend_from_slice(&rel_type);
+                    typed_relation_id.push(0xff);
+                    typed_relation_id.extend_from_slice(pdu_id);
+
+                    db.rooms.typedrelationid.insert(&typed_relation_id, &[])?;
+                }
+
+                db.globals.bump_database_version(20)?;
+
+                println!("Migration: 19 -> 20 finished");
+            }
+
+            if db.globals.database_version()? < 21 {
+                status.set_step("Migrating the database from version 20 to 21".to_owned());
+
+                // Thread roots used to store their bundled aggregation, move the reply count to
+                // its own tree and remove the rest
+                for (root, _) in db
+                    .rooms
+                    .eventid_threadid
+                    .iter()
+                    .inspect(|_| status.item_done())
+                {
+                    let root = match utils::string_from_bytes(&root)
+                        .ok()
+                        .and_then(|root| EventId::try_from(root).ok())
+                    {
+                        Some(root) => root,
+                        None => continue,
+                    };
+                    let root_pdu_id = match db.rooms.get_pdu_id(&root)? {
+                        Some(root_pdu_id) => root_pdu_id,
+                        None => continue,
+                    };
+                    let mut root_pdu = match db.rooms.get_pdu_from_id(&root_pdu_id)? {
+                        Some(root_pdu) => root_pdu,
+                        None => continue,
+                    };
+
+                    let thread = match root_pdu
+                        .unsigned
+                        .get_mut("m.relations")
+                        .and_then(|relations| relations.as_object_mut())
+                        .and_then(|relations| relations.remove("m.thread"))
+                    {
+                        Some(thread) => thread,
+                        None => continue,
+                    };
+
+                    if let Some(count) = thread.get("count").and_then(|count| count.as_u64()) {
+                        db.rooms
+                            .eventid_threadcount
+                            .insert(root.as_bytes(), &count.to_be_bytes())?;
+                    }
+
+                    if root_pdu
+                        .unsigned
+                        .get("m.relations")
+                        .and_then(|relations| relations.as_object())
+                        .map_or(false, |relations| relations.is_empty())
+                    {
+                        root_pdu.unsigned.remove("m.relations");
+                    }
+
+                    db.rooms.pduid_pdu.insert(
+                        &root_pdu_id,
+                        &serde_json::to_vec(&root_pdu).expect("PduEvent::to_vec always works"),
+                    )?;
+                }
+
+                db.globals.bump_database_version(21)?;
+
+                println!("Migration: 20 -> 21 finished");
+            }
+        }
+
+        // Replicas only answer requests. The primary runs the background tasks, and everything
+        // that would send something to other servers is forwarded to it
+        if is_replica {
+            return Ok(db);
         }
 
         let guard = db.read().await;
 
+        status.set_step("Removing outdated presence and read receipts".to_owned());
+
         // This data is probably outdated
         guard.rooms.edus.presenceid_presence.clear()?;
 
+        let removed_receipts = guard.rooms.edus.compact_readreceipts()?;
+        if removed_receipts > 0 {
+            info!("Removed {} outdated read receipts", removed_receipts);
+        }
+
+        // Everything is forgotten when the cache is disabled
+        let removed_invalid = guard
+            .rooms
+            .prune_invalid_events(guard.globals.invalid_event_ttl().unwrap_or_default())?;
+        if removed_invalid > 0 {
+            info!("Forgot {} events that failed validation", removed_invalid);
+        }
+
+        let removed_exports = admin::prune_data_exports(&guard)?;
+        if removed_exports > 0 {
+            info!("Deleted {} expired user data exports", removed_exports);
+        }
+
         guard.admin.start_handler(Arc::clone(&db), admin_receiver);
         guard
             .sending
             .start_handler(Arc::clone(&db), sending_receiver);
+        guard.jobs.start_handler(Arc::clone(&db));
+        guard.jobs.start_prune_task(Arc::clone(&db));
+        guard.jobs.start_key_rotation_task(Arc::clone(&db));
+        guard.audit.start_prune_task(Arc::clone(&db));
 
         drop(guard);
 
@@ -771,7 +1730,13 @@ impl Database {
         });
     }
 
-    pub async fn watch(&self, user_id: &UserId, device_id: &DeviceId) {
+    /// Returns a future that resolves when something new for the device happened. The watchers
+    /// are set up right away, so changes between this call and polling the future aren't missed.
+    pub fn watch(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> impl Future<Output = ()> + Send + '_ {
         let userid_bytes = user_id.as_bytes().to_vec();
         let mut userid_prefix = userid_bytes.clone();
         userid_prefix.push(0xff);
@@ -796,6 +1761,11 @@ impl Database {
                 .userroomid_invitestate
                 .watch_prefix(&userid_prefix),
         );
+        futures.push(
+            self.rooms
+                .userroomid_knockstate
+                .watch_prefix(&userid_prefix),
+        );
         futures.push(self.rooms.userroomid_leftstate.watch_prefix(&userid_prefix));
         futures.push(
             self.rooms
@@ -808,53 +1778,24 @@ impl Database {
                 .watch_prefix(&userid_prefix),
         );
 
-        // Events for rooms we are in
-        for room_id in self.rooms.rooms_joined(user_id).filter_map(|r| r.ok()) {
-            let roomid_bytes = room_id.as_bytes().to_vec();
-            let mut roomid_prefix = roomid_bytes.clone();
-            roomid_prefix.push(0xff);
-
-            // PDUs
-            futures.push(self.rooms.pduid_pdu.watch_prefix(&roomid_prefix));
-
-            // EDUs
-            futures.push(
-                self.rooms
-                    .edus
-                    .roomid_lasttypingupdate
-                    .watch_prefix(&roomid_bytes),
-            );
-
-            futures.push(
-                self.rooms
-                    .edus
-                    .readreceiptid_readreceipt
-                    .watch_prefix(&roomid_prefix),
-            );
-
-            // Key changes
-            futures.push(self.users.keychangeid_userid.watch_prefix(&roomid_prefix));
+        let shortuserid = self
+            .shortids
+            .get_or_create_shortuserid(user_id, &self.globals)
+            .ok();
 
-            // Room account data
-            let mut roomuser_prefix = roomid_prefix.clone();
-            roomuser_prefix.extend_from_slice(&userid_prefix);
+        // Everything that happens in rooms we are in: pdus, edus, key changes and room account data
+        futures.push(Box::pin(self.activity.watch_rooms(
+            self.rooms.rooms_joined(user_id).filter_map(|r| r.ok()),
+        )));
 
+        if let Some(shortuserid) = shortuserid {
             futures.push(
                 self.account_data
                     .roomusertype_roomuserdataid
-                    .watch_prefix(&roomuser_prefix),
+                    .watch_prefix(&account_data::roomuser_prefix(None, shortuserid)),
             );
         }
 
-        let mut globaluserdata_prefix = vec![0xff];
-        globaluserdata_prefix.extend_from_slice(&userid_prefix);
-
-        futures.push(
-            self.account_data
-                .roomusertype_roomuserdataid
-                .watch_prefix(&globaluserdata_prefix),
-        );
-
         // More key changes (used when user is not joined to any rooms)
         futures.push(self.users.keychangeid_userid.watch_prefix(&userid_prefix));
 
@@ -867,8 +1808,16 @@ impl Database {
 
         futures.push(Box::pin(self.globals.rotate.watch()));
 
+        // The tree watchers only see writes of this process. A replica doesn't know what the
+        // primary changed, so it checks again whenever the primary moved on
+        if self.globals.is_replica() {
+            futures.push(Box::pin(self.globals.replica_advanced.watch()));
+        }
+
         // Wait until one of them finds something
-        futures.next().await;
+        async move {
+            futures.next().await;
+        }
     }
 
     #[tracing::instrument(skip(self))]
@@ -959,3 +1908,10 @@ impl From<OwnedRwLockReadGuard<Database>> for DatabaseGuard {
         Self(val)
     }
 }
+
+/// Account data keys from before database version 11 contain the full user id after the room id.
+fn is_old_accountdata_key(key: &[u8]) -> bool {
+    key.splitn(2, |&b| b == 0xff)
+        .nth(1)
+        .map_or(false, |rest| rest.starts_with(b"@"))
+}
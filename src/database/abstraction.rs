@@ -1,7 +1,7 @@
 use super::Config;
-use crate::Result;
+use crate::{ruma_wrapper::RequestTimes, Result};
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{any::Any, future::Future, pin::Pin, sync::Arc, time::Instant};
 
 #[cfg(feature = "sled")]
 pub mod sled;
@@ -12,9 +12,16 @@ pub mod sqlite;
 #[cfg(feature = "heed")]
 pub mod heed;
 
+pub mod migrate;
+
+#[cfg(test)]
+pub mod memory;
+
 pub trait DatabaseEngine: Sized {
     fn open(config: &Config) -> Result<Arc<Self>>;
     fn open_tree(self: &Arc<Self>, name: &'static str) -> Result<Arc<dyn Tree>>;
+    /// Returns the names of all trees that were ever opened in this database.
+    fn tree_names(self: &Arc<Self>) -> Result<Vec<String>>;
     fn flush(self: &Arc<Self>) -> Result<()>;
 }
 
@@ -44,6 +51,12 @@ pub trait Tree: Send + Sync {
 
     fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
 
+    /// Applies all operations of the transaction at once. All trees of the transaction have to
+    /// belong to the same engine as this tree.
+    fn commit(&self, transaction: Transaction) -> Result<()>;
+
+    fn as_any(&self) -> &dyn Any;
+
     fn clear(&self) -> Result<()> {
         for (key, _) in self.iter() {
             self.remove(&key)?;
@@ -52,3 +65,128 @@ pub trait Tree: Send + Sync {
         Ok(())
     }
 }
+
+/// Wraps the trees of an engine to add the time spent in them to the current request, so slow
+/// requests can be told apart by their database time.
+pub struct TimedTree<T>(pub T);
+
+fn timed<R>(f: impl FnOnce() -> R) -> R {
+    let started = Instant::now();
+    let result = f();
+    RequestTimes::add_db(started.elapsed());
+    result
+}
+
+struct TimedIter<I>(I);
+
+impl<I: Iterator> Iterator for TimedIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        timed(|| self.0.next())
+    }
+}
+
+impl<T: Tree + 'static> Tree for TimedTree<T> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        timed(|| self.0.get(key))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        timed(|| self.0.insert(key, value))
+    }
+
+    fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        timed(|| self.0.insert_batch(iter))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        timed(|| self.0.remove(key))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        Box::new(TimedIter(timed(|| self.0.iter())))
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        Box::new(TimedIter(timed(|| self.0.iter_from(from, backwards))))
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        timed(|| self.0.increment(key))
+    }
+
+    fn increment_batch<'a>(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        timed(|| self.0.increment_batch(iter))
+    }
+
+    fn scan_prefix<'a>(
+        &'a self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        Box::new(TimedIter(timed(|| self.0.scan_prefix(prefix))))
+    }
+
+    fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        // Waiting for changes is not database time
+        self.0.watch_prefix(prefix)
+    }
+
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        timed(|| self.0.commit(transaction))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        // Transactions downcast to the tree type of the engine
+        self.0.as_any()
+    }
+
+    fn clear(&self) -> Result<()> {
+        timed(|| self.0.clear())
+    }
+}
+
+pub enum TransactionOperation {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Increment(Vec<u8>),
+}
+
+/// Changes to one or more trees that are either all written or, if anything fails or the server
+/// crashes, not at all.
+#[derive(Default)]
+pub struct Transaction {
+    pub operations: Vec<(Arc<dyn Tree>, TransactionOperation)>,
+}
+
+impl Transaction {
+    pub fn insert(&mut self, tree: &Arc<dyn Tree>, key: &[u8], value: &[u8]) {
+        self.operations.push((
+            Arc::clone(tree),
+            TransactionOperation::Insert(key.to_vec(), value.to_vec()),
+        ));
+    }
+
+    pub fn remove(&mut self, tree: &Arc<dyn Tree>, key: &[u8]) {
+        self.operations
+            .push((Arc::clone(tree), TransactionOperation::Remove(key.to_vec())));
+    }
+
+    pub fn increment(&mut self, tree: &Arc<dyn Tree>, key: &[u8]) {
+        self.operations.push((
+            Arc::clone(tree),
+            TransactionOperation::Increment(key.to_vec()),
+        ));
+    }
+
+    pub fn commit(self) -> Result<()> {
+        match self.operations.first() {
+            Some((tree, _)) => Arc::clone(tree).commit(self),
+            None => Ok(()),
+        }
+    }
+}
@@ -4,6 +4,7 @@ use threadpool::ThreadPool;
 
 use crate::{Error, Result};
 use std::{
+    any::Any,
     collections::HashMap,
     future::Future,
     pin::Pin,
@@ -11,7 +12,7 @@ use std::{
 };
 use tokio::sync::oneshot::Sender;
 
-use super::{DatabaseEngine, Tree};
+use super::{DatabaseEngine, TimedTree, Transaction, TransactionOperation, Tree};
 
 type TupleOfBytes = (Vec<u8>, Vec<u8>);
 
@@ -53,7 +54,7 @@ impl DatabaseEngine for Engine {
 
     fn open_tree(self: &Arc<Self>, name: &'static str) -> Result<Arc<dyn Tree>> {
         // Creates the db if it doesn't exist already
-        Ok(Arc::new(EngineTree {
+        Ok(Arc::new(TimedTree(EngineTree {
             engine: Arc::clone(self),
             tree: Arc::new(
                 self.env
@@ -61,7 +62,24 @@ impl DatabaseEngine for Engine {
                     .map_err(convert_error)?,
             ),
             watchers: RwLock::new(HashMap::new()),
-        }))
+        })))
+    }
+
+    fn tree_names(self: &Arc<Self>) -> Result<Vec<String>> {
+        // The keys of the unnamed database are the names of all other databases
+        let main = self.env.create_database(None).map_err(convert_error)?;
+        let txn = self.env.read_txn().map_err(convert_error)?;
+
+        let names = main
+            .iter(&txn)
+            .map_err(convert_error)?
+            .map(|r| {
+                r.map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+                    .map_err(convert_error)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(names)
     }
 
     fn flush(self: &Arc<Self>) -> Result<()> {
@@ -71,6 +89,30 @@ impl DatabaseEngine for Engine {
 }
 
 impl EngineTree {
+    fn wake_watchers(&self, key: &[u8]) {
+        let watchers = self.watchers.read().unwrap();
+        let mut triggered = Vec::new();
+
+        for length in 0..=key.len() {
+            if watchers.contains_key(&key[..length]) {
+                triggered.push(&key[..length]);
+            }
+        }
+
+        drop(watchers);
+
+        if !triggered.is_empty() {
+            let mut watchers = self.watchers.write().unwrap();
+            for prefix in triggered {
+                if let Some(txs) = watchers.remove(prefix) {
+                    for tx in txs {
+                        let _ = tx.send(());
+                    }
+                }
+            }
+        };
+    }
+
     #[tracing::instrument(skip(self, tree, from, backwards))]
     fn iter_from_thread(
         &self,
@@ -146,27 +188,7 @@ impl Tree for EngineTree {
             .map_err(convert_error)?;
         txn.commit().map_err(convert_error)?;
 
-        let watchers = self.watchers.read().unwrap();
-        let mut triggered = Vec::new();
-
-        for length in 0..=key.len() {
-            if watchers.contains_key(&key[..length]) {
-                triggered.push(&key[..length]);
-            }
-        }
-
-        drop(watchers);
-
-        if !triggered.is_empty() {
-            let mut watchers = self.watchers.write().unwrap();
-            for prefix in triggered {
-                if let Some(txs) = watchers.remove(prefix) {
-                    for tx in txs {
-                        let _ = tx.send(());
-                    }
-                }
-            }
-        };
+        self.wake_watchers(key);
 
         Ok(())
     }
@@ -237,4 +259,58 @@ impl Tree for EngineTree {
             rx.await.unwrap();
         })
     }
+
+    #[tracing::instrument(skip(self, transaction))]
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        let trees = transaction
+            .operations
+            .iter()
+            .map(|(tree, operation)| {
+                (
+                    tree.as_any()
+                        .downcast_ref::<EngineTree>()
+                        .expect("all trees of a transaction use the same engine"),
+                    operation,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut txn = self.engine.env.write_txn().map_err(convert_error)?;
+
+        for (tree, operation) in &trees {
+            match operation {
+                TransactionOperation::Insert(key, value) => {
+                    tree.tree
+                        .put(&mut txn, &key, &value)
+                        .map_err(convert_error)?;
+                }
+                TransactionOperation::Remove(key) => {
+                    tree.tree.delete(&mut txn, &key).map_err(convert_error)?;
+                }
+                TransactionOperation::Increment(key) => {
+                    let old = tree.tree.get(&txn, &key).map_err(convert_error)?;
+                    let new = crate::utils::increment(old.as_deref())
+                        .expect("utils::increment always returns Some");
+                    tree.tree
+                        .put(&mut txn, &key, &&*new)
+                        .map_err(convert_error)?;
+                }
+            }
+        }
+
+        // Dropping the transaction without committing discards all changes
+        txn.commit().map_err(convert_error)?;
+
+        for (tree, operation) in trees {
+            if let TransactionOperation::Insert(key, _) = operation {
+                tree.wake_watchers(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
@@ -0,0 +1,136 @@
+use crate::{utils, Result};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+
+use super::{Transaction, TransactionOperation, Tree};
+
+/// A tree that only lives in memory, so tests can use the database functions without an engine.
+#[derive(Default)]
+pub struct MemoryTree(RwLock<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl MemoryTree {
+    pub fn open() -> Arc<dyn Tree> {
+        Arc::new(Self::default())
+    }
+
+    fn apply(&self, operation: &TransactionOperation) {
+        let mut map = self.0.write().unwrap();
+        match operation {
+            TransactionOperation::Insert(key, value) => {
+                map.insert(key.clone(), value.clone());
+            }
+            TransactionOperation::Remove(key) => {
+                map.remove(key);
+            }
+            TransactionOperation::Increment(key) => {
+                let new = utils::increment(map.get(key).map(|v| v.as_slice()))
+                    .expect("utils::increment always returns Some");
+                map.insert(key.clone(), new);
+            }
+        }
+    }
+}
+
+impl Tree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.apply(&TransactionOperation::Insert(key.to_vec(), value.to_vec()));
+        Ok(())
+    }
+
+    fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        for (key, value) in iter {
+            self.insert(&key, &value)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.apply(&TransactionOperation::Remove(key.to_vec()));
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        let items = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+
+        Box::new(items.into_iter())
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        let map = self.0.read().unwrap();
+        let items = if backwards {
+            map.range(..=from.to_vec())
+                .rev()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
+        } else {
+            map.range(from.to_vec()..)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        Box::new(items.into_iter())
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.apply(&TransactionOperation::Increment(key.to_vec()));
+        Ok(self.get(key)?.expect("increment always sets a value"))
+    }
+
+    fn increment_batch<'a>(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        for key in iter {
+            self.increment(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn scan_prefix<'a>(
+        &'a self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        Box::new(
+            self.iter_from(&prefix, false)
+                .take_while(move |(key, _)| key.starts_with(&prefix)),
+        )
+    }
+
+    fn watch_prefix<'a>(&'a self, _: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        // Nothing waits for changes in tests
+        Box::pin(std::future::pending())
+    }
+
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        for (tree, operation) in &transaction.operations {
+            tree.as_any()
+                .downcast_ref::<MemoryTree>()
+                .expect("all trees of a transaction use the same engine")
+                .apply(operation);
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
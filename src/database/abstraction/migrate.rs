@@ -0,0 +1,149 @@
+use super::{DatabaseEngine, Tree};
+use crate::{database::Config, Error, Result};
+use std::sync::Arc;
+
+/// How many entries are written at once.
+const BATCH_SIZE: usize = 1000;
+/// Progress is reported after this many entries of a tree.
+const PROGRESS_INTERVAL: usize = 100_000;
+
+/// A database of one of the backends conduit was built with.
+struct OpenedEngine {
+    tree_names: Vec<String>,
+    open_tree: Box<dyn Fn(&'static str) -> Result<Arc<dyn Tree>>>,
+    flush: Box<dyn Fn() -> Result<()>>,
+}
+
+fn open<E: DatabaseEngine + 'static>(config: &Config) -> Result<OpenedEngine> {
+    let engine = E::open(config)?;
+    let tree_names = engine.tree_names()?;
+
+    let tree_engine = Arc::clone(&engine);
+    Ok(OpenedEngine {
+        tree_names,
+        open_tree: Box::new(move |name| tree_engine.open_tree(name)),
+        flush: Box::new(move || engine.flush()),
+    })
+}
+
+fn open_backend(backend: &str, config: &Config) -> Result<OpenedEngine> {
+    match backend {
+        #[cfg(feature = "sqlite")]
+        "sqlite" => open::<super::sqlite::Engine>(config),
+        #[cfg(feature = "sled")]
+        "sled" => open::<super::sled::Engine>(config),
+        #[cfg(feature = "heed")]
+        "heed" => open::<super::heed::Engine>(config),
+        _ => Err(Error::bad_config(
+            "Unknown database backend, or conduit was built without it.",
+        )),
+    }
+}
+
+/// Copies every tree of the database at `database_path` in the config to a new database of
+/// another backend at `target_path`, then reads both databases again to verify the copy.
+///
+/// The server must not run while the database is copied. The target database must be empty.
+pub fn migrate_backend(config: &Config, from: &str, to: &str, target_path: &str) -> Result<()> {
+    if from == to && config.database_path == target_path {
+        return Err(Error::bad_config(
+            "The source and the target database are the same.",
+        ));
+    }
+
+    let source = open_backend(from, config)?;
+
+    let mut target_config = config.clone();
+    target_config.database_path = target_path.to_owned();
+    let target = open_backend(to, &target_config)?;
+
+    if !target.tree_names.is_empty() {
+        return Err(Error::bad_config("The target database is not empty."));
+    }
+
+    let total = source.tree_names.len();
+    for (i, name) in source.tree_names.iter().enumerate() {
+        // Engines want static names, because the server only opens a fixed set of trees
+        let name: &'static str = Box::leak(name.clone().into_boxed_str());
+
+        println!("Copying tree {} ({}/{})", name, i + 1, total);
+
+        let source_tree = (source.open_tree)(name)?;
+        let target_tree = (target.open_tree)(name)?;
+
+        let copied = copy_tree(&*source_tree, &*target_tree)?;
+        (target.flush)()?;
+
+        println!("Verifying tree {}", name);
+        verify_tree(name, &*source_tree, &*target_tree, copied)?;
+    }
+
+    println!(
+        "Copied {} trees from the {} database to the {} database at {}",
+        total, from, to, target_path
+    );
+
+    Ok(())
+}
+
+fn copy_tree(source: &dyn Tree, target: &dyn Tree) -> Result<usize> {
+    let mut copied = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    for entry in source.iter() {
+        batch.push(entry);
+
+        if batch.len() == BATCH_SIZE {
+            target.insert_batch(&mut batch.drain(..))?;
+            copied += BATCH_SIZE;
+
+            if copied % PROGRESS_INTERVAL == 0 {
+                println!("  {} entries", copied);
+            }
+        }
+    }
+
+    copied += batch.len();
+    target.insert_batch(&mut batch.into_iter())?;
+
+    println!("  {} entries", copied);
+
+    Ok(copied)
+}
+
+/// Makes sure both trees contain exactly the same entries. Both iterate in key order.
+fn verify_tree(name: &str, source: &dyn Tree, target: &dyn Tree, copied: usize) -> Result<()> {
+    let mut source_entries = source.iter();
+    let mut target_entries = target.iter();
+    let mut verified = 0;
+
+    loop {
+        match (source_entries.next(), target_entries.next()) {
+            (None, None) => break,
+            (Some(source_entry), Some(target_entry)) if source_entry == target_entry => {
+                verified += 1;
+            }
+            _ => {
+                eprintln!(
+                    "Tree {} differs from the source after {} entries",
+                    name, verified
+                );
+                return Err(Error::bad_database(
+                    "The copied database differs from the source.",
+                ));
+            }
+        }
+    }
+
+    if verified != copied {
+        eprintln!(
+            "Tree {} has {} entries, but {} were copied. Was the server running?",
+            name, verified, copied
+        );
+        return Err(Error::bad_database(
+            "The source database changed while it was copied.",
+        ));
+    }
+
+    Ok(())
+}
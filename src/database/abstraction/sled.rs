@@ -1,9 +1,13 @@
 use super::super::Config;
 use crate::{utils, Result};
-use std::{future::Future, pin::Pin, sync::Arc};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+use std::{any::Any, future::Future, pin::Pin, sync::Arc};
 use tracing::warn;
 
-use super::{DatabaseEngine, Tree};
+use super::{DatabaseEngine, TimedTree, Transaction, TransactionOperation, Tree};
 
 pub struct Engine(sled::Db);
 
@@ -21,7 +25,18 @@ impl DatabaseEngine for Engine {
     }
 
     fn open_tree(self: &Arc<Self>, name: &'static str) -> Result<Arc<dyn Tree>> {
-        Ok(Arc::new(SledEngineTree(self.0.open_tree(name)?)))
+        Ok(Arc::new(TimedTree(SledEngineTree(self.0.open_tree(name)?))))
+    }
+
+    fn tree_names(self: &Arc<Self>) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .tree_names()
+            .into_iter()
+            // Conduit never uses the default tree
+            .filter(|name| &**name != b"__sled__default")
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .collect())
     }
 
     fn flush(self: &Arc<Self>) -> Result<()> {
@@ -125,4 +140,57 @@ impl Tree for SledEngineTree {
             self.0.watch_prefix(prefix).await;
         })
     }
+
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        let mut trees = Vec::<sled::Tree>::new();
+        let operations = transaction
+            .operations
+            .iter()
+            .map(|(tree, operation)| {
+                let tree = &tree
+                    .as_any()
+                    .downcast_ref::<SledEngineTree>()
+                    .expect("all trees of a transaction use the same engine")
+                    .0;
+                let index = trees
+                    .iter()
+                    .position(|t| t.name() == tree.name())
+                    .unwrap_or_else(|| {
+                        trees.push(tree.clone());
+                        trees.len() - 1
+                    });
+                (index, operation)
+            })
+            .collect::<Vec<_>>();
+
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                for (index, operation) in &operations {
+                    let tree = &trees[*index];
+                    match operation {
+                        TransactionOperation::Insert(key, value) => {
+                            tree.insert(key.as_slice(), value.as_slice())?;
+                        }
+                        TransactionOperation::Remove(key) => {
+                            tree.remove(key.as_slice())?;
+                        }
+                        TransactionOperation::Increment(key) => {
+                            let new = utils::increment(tree.get(key.as_slice())?.as_deref())
+                                .expect("utils::increment always returns Some");
+                            tree.insert(key.as_slice(), new)?;
+                        }
+                    }
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(()) => unreachable!("transaction is never aborted"),
+                TransactionError::Storage(e) => e.into(),
+            })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
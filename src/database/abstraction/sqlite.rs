@@ -1,14 +1,16 @@
-use super::{DatabaseEngine, Tree};
+use super::{DatabaseEngine, TimedTree, Transaction, TransactionOperation, Tree};
 use crate::{database::Config, Result};
 use parking_lot::{Mutex, MutexGuard, RwLock};
 use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
 use std::{
+    any::Any,
     cell::RefCell,
     collections::HashMap,
     future::Future,
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 use thread_local::ThreadLocal;
 use tokio::sync::oneshot::Sender;
@@ -57,6 +59,9 @@ impl Engine {
         conn.pragma_update(Some(Main), "synchronous", &"NORMAL")?;
         conn.pragma_update(Some(Main), "cache_size", &(-i64::from(cache_size_kb)))?;
         conn.pragma_update(Some(Main), "wal_autocheckpoint", &2000)?;
+        // Replicas read the same database, so wait for locks held by the other process instead of
+        // failing
+        conn.busy_timeout(Duration::from_secs(5))?;
 
         Ok(conn)
     }
@@ -110,11 +115,21 @@ impl DatabaseEngine for Engine {
     fn open_tree(self: &Arc<Self>, name: &str) -> Result<Arc<dyn Tree>> {
         self.write_lock().execute(&format!("CREATE TABLE IF NOT EXISTS {} ( \"key\" BLOB PRIMARY KEY, \"value\" BLOB NOT NULL )", name), [])?;
 
-        Ok(Arc::new(SqliteTable {
+        Ok(Arc::new(TimedTree(SqliteTable {
             engine: Arc::clone(self),
             name: name.to_owned(),
             watchers: RwLock::new(HashMap::new()),
-        }))
+        })))
+    }
+
+    fn tree_names(self: &Arc<Self>) -> Result<Vec<String>> {
+        let guard = self.write_lock();
+        let mut statement = guard.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(names)
     }
 
     fn flush(self: &Arc<Self>) -> Result<()> {
@@ -155,6 +170,39 @@ impl SqliteTable {
         Ok(())
     }
 
+    #[tracing::instrument(skip(self, guard, key))]
+    fn remove_with_guard(&self, guard: &Connection, key: &[u8]) -> Result<()> {
+        guard.execute(
+            format!("DELETE FROM {} WHERE key = ?", self.name).as_str(),
+            [key],
+        )?;
+        Ok(())
+    }
+
+    fn wake_watchers(&self, key: &[u8]) {
+        let watchers = self.watchers.read();
+        let mut triggered = Vec::new();
+
+        for length in 0..=key.len() {
+            if watchers.contains_key(&key[..length]) {
+                triggered.push(&key[..length]);
+            }
+        }
+
+        drop(watchers);
+
+        if !triggered.is_empty() {
+            let mut watchers = self.watchers.write();
+            for prefix in triggered {
+                if let Some(txs) = watchers.remove(prefix) {
+                    for tx in txs {
+                        let _ = tx.send(());
+                    }
+                }
+            }
+        };
+    }
+
     pub fn iter_with_guard<'a>(
         &'a self,
         guard: &'a Connection,
@@ -201,27 +249,7 @@ impl Tree for SqliteTable {
         self.insert_with_guard(&guard, key, value)?;
         drop(guard);
 
-        let watchers = self.watchers.read();
-        let mut triggered = Vec::new();
-
-        for length in 0..=key.len() {
-            if watchers.contains_key(&key[..length]) {
-                triggered.push(&key[..length]);
-            }
-        }
-
-        drop(watchers);
-
-        if !triggered.is_empty() {
-            let mut watchers = self.watchers.write();
-            for prefix in triggered {
-                if let Some(txs) = watchers.remove(prefix) {
-                    for tx in txs {
-                        let _ = tx.send(());
-                    }
-                }
-            }
-        };
+        self.wake_watchers(key);
 
         Ok(())
     }
@@ -263,12 +291,7 @@ impl Tree for SqliteTable {
     fn remove(&self, key: &[u8]) -> Result<()> {
         let guard = self.engine.write_lock();
 
-        guard.execute(
-            format!("DELETE FROM {} WHERE key = ?", self.name).as_str(),
-            [key],
-        )?;
-
-        Ok(())
+        self.remove_with_guard(&guard, key)
     }
 
     #[tracing::instrument(skip(self))]
@@ -381,6 +404,60 @@ impl Tree for SqliteTable {
         })
     }
 
+    #[tracing::instrument(skip(self, transaction))]
+    fn commit(&self, transaction: Transaction) -> Result<()> {
+        let tables = transaction
+            .operations
+            .iter()
+            .map(|(tree, operation)| {
+                (
+                    tree.as_any()
+                        .downcast_ref::<SqliteTable>()
+                        .expect("all trees of a transaction use the same engine"),
+                    operation,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let guard = self.engine.write_lock();
+
+        guard.execute("BEGIN", [])?;
+        let result = tables
+            .iter()
+            .try_for_each(|(table, operation)| match operation {
+                TransactionOperation::Insert(key, value) => {
+                    table.insert_with_guard(&guard, key, value)
+                }
+                TransactionOperation::Remove(key) => table.remove_with_guard(&guard, key),
+                TransactionOperation::Increment(key) => {
+                    let old = table.get_with_guard(&guard, key)?;
+                    let new = crate::utils::increment(old.as_deref())
+                        .expect("utils::increment always returns Some");
+                    table.insert_with_guard(&guard, key, &new)
+                }
+            });
+
+        if let Err(e) = result {
+            guard.execute("ROLLBACK", [])?;
+            return Err(e);
+        }
+        guard.execute("COMMIT", [])?;
+
+        drop(guard);
+
+        for (table, operation) in tables {
+            if let TransactionOperation::Insert(key, _) = operation {
+                table.wake_watchers(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     #[tracing::instrument(skip(self))]
     fn clear(&self) -> Result<()> {
         debug!("clear: running");
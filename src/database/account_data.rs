@@ -6,13 +6,15 @@ use ruma::{
     RoomId, UserId,
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+use std::{collections::HashMap, mem::size_of, sync::Arc};
 
-use super::abstraction::Tree;
+use super::{abstraction::Tree, activity::RoomActivity, shortids::ShortIds};
 
 pub struct AccountData {
-    pub(super) roomuserdataid_accountdata: Arc<dyn Tree>, // RoomUserDataId = Room + User + Count + Type
-    pub(super) roomusertype_roomuserdataid: Arc<dyn Tree>, // RoomUserType = Room + User + Type
+    pub(super) roomuserdataid_accountdata: Arc<dyn Tree>, // RoomUserDataId = Room + ShortUserId + Count + ShortEventType
+    pub(super) roomusertype_roomuserdataid: Arc<dyn Tree>, // RoomUserType = Room + ShortUserId + ShortEventType
+    pub(super) shortids: Arc<ShortIds>,
+    pub(super) activity: Arc<RoomActivity>,
 }
 
 impl AccountData {
@@ -26,22 +28,23 @@ impl AccountData {
         data: &T,
         globals: &super::globals::Globals,
     ) -> Result<()> {
-        let mut prefix = room_id
-            .map(|r| r.to_string())
-            .unwrap_or_default()
-            .as_bytes()
-            .to_vec();
-        prefix.push(0xff);
-        prefix.extend_from_slice(&user_id.as_bytes());
-        prefix.push(0xff);
-
+        let prefix = roomuser_prefix(
+            room_id,
+            self.shortids.get_or_create_shortuserid(user_id, globals)?,
+        );
+        let shorteventtype = self
+            .shortids
+            .get_or_create_shorteventtype(&event_type, globals)?
+            .to_be_bytes();
+
+        let appending_count = globals.next_appending_count()?;
+        let count = appending_count.count;
         let mut roomuserdataid = prefix.clone();
-        roomuserdataid.extend_from_slice(&globals.next_count()?.to_be_bytes());
-        roomuserdataid.push(0xff);
-        roomuserdataid.extend_from_slice(&event_type.as_bytes());
+        roomuserdataid.extend_from_slice(&count.to_be_bytes());
+        roomuserdataid.extend_from_slice(&shorteventtype);
 
         let mut key = prefix;
-        key.extend_from_slice(event_type.as_bytes());
+        key.extend_from_slice(&shorteventtype);
 
         let json = serde_json::to_value(data).expect("all types here can be serialized"); // TODO: maybe add error handling
         if json.get("type").is_none() || json.get("content").is_none() {
@@ -66,6 +69,10 @@ impl AccountData {
             self.roomuserdataid_accountdata.remove(&prev)?;
         }
 
+        if let Some(room_id) = room_id {
+            self.activity.bump(room_id, count)?;
+        }
+
         Ok(())
     }
 
@@ -77,15 +84,17 @@ impl AccountData {
         user_id: &UserId,
         kind: EventType,
     ) -> Result<Option<T>> {
-        let mut key = room_id
-            .map(|r| r.to_string())
-            .unwrap_or_default()
-            .as_bytes()
-            .to_vec();
-        key.push(0xff);
-        key.extend_from_slice(&user_id.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(kind.as_ref().as_bytes());
+        let shortuserid = match self.shortids.get_shortuserid(user_id)? {
+            Some(shortuserid) => shortuserid,
+            None => return Ok(None),
+        };
+        let shorteventtype = match self.shortids.get_shorteventtype(&kind)? {
+            Some(shorteventtype) => shorteventtype,
+            None => return Ok(None),
+        };
+
+        let mut key = roomuser_prefix(room_id, shortuserid);
+        key.extend_from_slice(&shorteventtype.to_be_bytes());
 
         self.roomusertype_roomuserdataid
             .get(&key)?
@@ -112,14 +121,12 @@ impl AccountData {
     ) -> Result<HashMap<EventType, Raw<AnyEphemeralRoomEvent>>> {
         let mut userdata = HashMap::new();
 
-        let mut prefix = room_id
-            .map(|r| r.to_string())
-            .unwrap_or_default()
-            .as_bytes()
-            .to_vec();
-        prefix.push(0xff);
-        prefix.extend_from_slice(&user_id.as_bytes());
-        prefix.push(0xff);
+        let shortuserid = match self.shortids.get_shortuserid(user_id)? {
+            Some(shortuserid) => shortuserid,
+            None => return Ok(userdata),
+        };
+
+        let prefix = roomuser_prefix(room_id, shortuserid);
 
         // Skip the data that's exactly at since, because we sent that last time
         let mut first_possible = prefix.clone();
@@ -130,14 +137,14 @@ impl AccountData {
             .iter_from(&first_possible, false)
             .take_while(move |(k, _)| k.starts_with(&prefix))
             .map(|(k, v)| {
+                let shorteventtype = k
+                    .len()
+                    .checked_sub(size_of::<u64>())
+                    .and_then(|i| utils::u64_from_bytes(&k[i..]).ok())
+                    .ok_or_else(|| Error::bad_database("RoomUserData ID in db is invalid."))?;
+
                 Ok::<_, Error>((
-                    EventType::try_from(
-                        utils::string_from_bytes(k.rsplit(|&b| b == 0xff).next().ok_or_else(
-                            || Error::bad_database("RoomUserData ID in db is invalid."),
-                        )?)
-                        .map_err(|_| Error::bad_database("RoomUserData ID in db is invalid."))?,
-                    )
-                    .map_err(|_| Error::bad_database("RoomUserData ID in db is invalid."))?,
+                    self.shortids.get_eventtype_from_short(shorteventtype)?,
                     serde_json::from_slice::<Raw<AnyEphemeralRoomEvent>>(&v).map_err(|_| {
                         Error::bad_database("Database contains invalid account data.")
                     })?,
@@ -151,3 +158,12 @@ impl AccountData {
         Ok(userdata)
     }
 }
+
+/// Returns the common prefix of all account data keys of a user in a room (or the global account
+/// data if `room_id` is None).
+pub(super) fn roomuser_prefix(room_id: Option<&RoomId>, shortuserid: u64) -> Vec<u8> {
+    let mut prefix = room_id.map_or_else(Vec::new, |r| r.as_bytes().to_vec());
+    prefix.push(0xff);
+    prefix.extend_from_slice(&shortuserid.to_be_bytes());
+    prefix
+}
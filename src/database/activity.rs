@@ -0,0 +1,110 @@
+use crate::{utils, Error, Result};
+use rocket::futures::{future, stream::FuturesUnordered, StreamExt};
+use ruma::RoomId;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::sync::broadcast;
+
+use super::abstraction::Tree;
+
+/// Wakes up the syncs that are waiting for a change in one room.
+type RoomWaker = broadcast::Sender<()>;
+
+/// Remembers the count of the last change in each room: new pdus, membership and state changes,
+/// read receipts, typing notifications, presence updates, device key changes of members and room
+/// account data. Sync uses this to skip rooms that didn't change since the last sync and to wait
+/// for the next change.
+pub struct RoomActivity {
+    pub(super) roomid_lastactivitycount: Arc<dyn Tree>, // LastActivityCount = Count
+    /// Makes sure the count never goes backwards when changes happen concurrently.
+    pub(super) update_lock: Mutex<()>,
+    /// All syncs waiting for a room share one waker. The waiting syncs own it, so it goes away
+    /// when the last of them returns.
+    pub(super) roomid_waker: Mutex<HashMap<RoomId, Weak<RoomWaker>>>,
+}
+
+impl RoomActivity {
+    /// Marks the room as changed at `count` and wakes up the syncs waiting for it.
+    #[tracing::instrument(skip(self))]
+    pub fn bump(&self, room_id: &RoomId, count: u64) -> Result<()> {
+        {
+            let _lock = self.update_lock.lock().unwrap();
+
+            if self
+                .last_activity(room_id)?
+                .map_or(true, |current| current < count)
+            {
+                self.roomid_lastactivitycount
+                    .insert(room_id.as_bytes(), &count.to_be_bytes())?;
+            }
+        }
+
+        let mut wakers = self.roomid_waker.lock().unwrap();
+        match wakers.get(room_id).map(Weak::upgrade) {
+            Some(Some(waker)) => {
+                // Fails if nobody is waiting right now, which is fine
+                let _ = waker.send(());
+            }
+            Some(None) => {
+                // Nobody waits for the room anymore
+                wakers.remove(room_id);
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Returns the count of the last change in the room, or None if no change was recorded yet.
+    #[tracing::instrument(skip(self))]
+    pub fn last_activity(&self, room_id: &RoomId) -> Result<Option<u64>> {
+        self.roomid_lastactivitycount
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid count in roomid_lastactivitycount."))
+            })
+            .transpose()
+    }
+
+    /// Returns a future that resolves when any of the rooms changes. Changes after this call count
+    /// even if the future wasn't polled yet. Never resolves if there are no rooms.
+    pub fn watch_rooms(
+        &self,
+        room_ids: impl IntoIterator<Item = RoomId>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let mut wakers = self.roomid_waker.lock().unwrap();
+
+        let mut receivers = room_ids
+            .into_iter()
+            .map(|room_id| {
+                let waker = match wakers.get(&room_id).and_then(Weak::upgrade) {
+                    Some(waker) => waker,
+                    None => {
+                        let waker = Arc::new(broadcast::channel(1).0);
+                        wakers.insert(room_id, Arc::downgrade(&waker));
+                        waker
+                    }
+                };
+                let mut receiver = waker.subscribe();
+
+                async move {
+                    // Lagging behind also means that something changed
+                    let _ = receiver.recv().await;
+                    drop(waker);
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        async move {
+            if receivers.is_empty() {
+                future::pending::<()>().await;
+            }
+
+            receivers.next().await;
+        }
+    }
+}
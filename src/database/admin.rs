@@ -1,13 +1,26 @@
 use std::{
+    collections::{BTreeSet, HashMap, HashSet},
     convert::{TryFrom, TryInto},
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
-use crate::{pdu::PduBuilder, Database};
+use super::pusher;
+use crate::{pdu::PduBuilder, utils, Database, Error, PduEvent, Result};
 use rocket::futures::{channel::mpsc, stream::StreamExt};
 use ruma::{
-    events::{room::message, EventType},
-    UserId,
+    api::client::error::ErrorKind,
+    events::{
+        push_rules,
+        room::{message, power_levels::PowerLevelsEventContent},
+        EventType,
+    },
+    push::{Action, Ruleset},
+    serde::{CanonicalJsonObject, CanonicalJsonValue, Raw},
+    EventId, RoomId, UserId,
 };
 use tokio::sync::{MutexGuard, RwLock, RwLockReadGuard};
 use tracing::warn;
@@ -16,6 +29,15 @@ pub enum AdminCommand {
     RegisterAppservice(serde_yaml::Value),
     ListAppservices,
     SendMessage(message::MessageEventContent),
+    RedactUserEvents {
+        user_id: UserId,
+        room_id: Option<RoomId>,
+        since: Option<u64>, // Timestamp in milliseconds
+    },
+    ExportRoom(RoomId),
+    ImportRoom(PathBuf),
+    ExportUserData(UserId),
+    DebugPush(UserId),
 }
 
 #[derive(Clone)]
@@ -114,6 +136,49 @@ impl Admin {
                             AdminCommand::SendMessage(message) => {
                                 send_message(message, guard, &state_lock);
                             }
+                            AdminCommand::RedactUserEvents { user_id, room_id, since } => {
+                                let output = match redact_user_events(&guard, &conduit_user, &conduit_room, &user_id, room_id, since).await {
+                                    Ok(output) => output,
+                                    Err(e) => format!("Failed to redact events: {}", e),
+                                };
+                                send_message(message::MessageEventContent::text_plain(output), guard, &state_lock);
+                            }
+                            AdminCommand::ExportRoom(room_id) => {
+                                let output = match export_room(&guard, &conduit_user, &room_id) {
+                                    Ok(path) => format!("Exported {} to {}", room_id, path.display()),
+                                    Err(e) => format!("Failed to export room: {}", e),
+                                };
+                                send_message(message::MessageEventContent::text_plain(output), guard, &state_lock);
+                            }
+                            AdminCommand::ExportUserData(user_id) => {
+                                // Collecting all events of a user can take a while, so this runs
+                                // in the background and reports back when it's done
+                                let db = Arc::clone(&db);
+                                let export_user = user_id.clone();
+                                tokio::spawn(async move {
+                                    let guard = db.read().await;
+                                    let output = match export_user_data(&guard, &export_user) {
+                                        Ok(output) => output,
+                                        Err(e) => format!("Failed to export the data of {}: {}", export_user, e),
+                                    };
+                                    guard.admin.send(AdminCommand::SendMessage(message::MessageEventContent::text_plain(output)));
+                                });
+                                send_message(message::MessageEventContent::text_plain(format!("Exporting the data of {}, a download link will be posted here when it's done.", user_id)), guard, &state_lock);
+                            }
+                            AdminCommand::DebugPush(user_id) => {
+                                let output = match debug_push(&guard, &conduit_user, &conduit_room, &user_id).await {
+                                    Ok(output) => output,
+                                    Err(e) => format!("Failed to debug the push setup of {}: {}", user_id, e),
+                                };
+                                send_message(message::MessageEventContent::text_plain(output), guard, &state_lock);
+                            }
+                            AdminCommand::ImportRoom(path) => {
+                                let output = match import_room(&guard, &path).await {
+                                    Ok(output) => output,
+                                    Err(e) => format!("Failed to import room: {}", e),
+                                };
+                                send_message(message::MessageEventContent::text_plain(output), guard, &state_lock);
+                            }
                         }
 
                         drop(state_lock);
@@ -127,3 +192,720 @@ impl Admin {
         self.sender.unbounded_send(command).unwrap();
     }
 }
+
+/// Lists the pushers and push rules of a local user, evaluates a sample message against the rules
+/// and sends a test notification through every pusher.
+async fn debug_push(
+    db: &Database,
+    conduit_user: &UserId,
+    conduit_room: &RoomId,
+    user_id: &UserId,
+) -> Result<String> {
+    if user_id.server_name() != db.globals.server_name() || !db.users.exists(user_id)? {
+        return Ok(format!("{} is not a local user.", user_id));
+    }
+
+    let pushers = db.pusher.get_pushers(user_id)?;
+
+    let mut output = format!("Pushers of {} ({}):", user_id, pushers.len());
+    for pusher in &pushers {
+        output += &format!(
+            "\n- {} ({}), kind {:?}, pushkey {}, url {}",
+            pusher.app_display_name,
+            pusher.app_id,
+            pusher.kind,
+            pusher.pushkey,
+            pusher.data.url.as_deref().unwrap_or("none"),
+        );
+    }
+
+    let ruleset = db
+        .account_data
+        .get::<push_rules::PushRulesEvent>(None, user_id, EventType::PushRules)?
+        .map(|ev| ev.content.global)
+        .unwrap_or_else(|| Ruleset::server_default(user_id));
+
+    // Go through json, so all kinds of rules can be listed the same way
+    let rules = serde_json::to_value(&ruleset).expect("rulesets can be serialized");
+    for kind in &["override", "content", "room", "sender", "underride"] {
+        output += &format!("\n\n{} rules:", kind);
+        for rule in rules
+            .get(kind)
+            .and_then(|rules| rules.as_array())
+            .into_iter()
+            .flatten()
+        {
+            output += &format!(
+                "\n- {}{}: {}",
+                rule.get("rule_id")
+                    .and_then(|id| id.as_str())
+                    .unwrap_or("?"),
+                if rule.get("enabled").and_then(|e| e.as_bool()) == Some(false) {
+                    " (disabled)"
+                } else {
+                    ""
+                },
+                rule.get("actions").unwrap_or(&serde_json::Value::Null),
+            );
+        }
+    }
+
+    let sample = serde_json::from_value::<PduEvent>(serde_json::json!({
+        "event_id": "$conduit_debug_push",
+        "room_id": conduit_room,
+        "sender": conduit_user,
+        "origin_server_ts": utils::millis_since_unix_epoch(),
+        "type": "m.room.message",
+        "content": {
+            "msgtype": "m.text",
+            "body": format!("Test notification for {}", user_id),
+        },
+        "prev_events": [],
+        "depth": 0,
+        "auth_events": [],
+        "hashes": { "sha256": "" },
+        "signatures": {},
+    }))
+    .expect("sample event is valid");
+
+    let power_levels = db
+        .rooms
+        .room_state_get(conduit_room, &EventType::RoomPowerLevels, "")?
+        .map(|pdu| {
+            serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let actions = pusher::get_actions(
+        user_id,
+        &ruleset,
+        &power_levels,
+        &sample.to_sync_room_event(),
+        conduit_room,
+        db,
+    )?;
+
+    output += &format!(
+        "\n\nActions for a message from {} that mentions the user: {}",
+        conduit_user,
+        serde_json::to_string(actions).expect("actions can be serialized"),
+    );
+
+    let tweaks = actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::SetTweak(tweak) => Some(tweak.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    output += "\n\nTest notifications:";
+    for pusher in &pushers {
+        let result =
+            match pusher::send_notice(0_u32.into(), pusher, tweaks.clone(), &sample, db).await {
+                Ok(()) => "sent".to_owned(),
+                Err(e) => format!("failed: {}", e),
+            };
+        output += &format!("\n- {}: {}", pusher.pushkey, result);
+    }
+
+    Ok(output)
+}
+
+/// Redacts all events of a user in one or all of their rooms, optionally only the ones sent after
+/// `since`.
+///
+/// The redactions are sent by the first user out of the conduit user, the local members of the
+/// room with enough power and the user themself who is allowed to redact in the room.
+async fn redact_user_events(
+    db: &Database,
+    conduit_user: &UserId,
+    conduit_room: &RoomId,
+    user_id: &UserId,
+    room_id: Option<RoomId>,
+    since: Option<u64>,
+) -> Result<String> {
+    let room_ids = match room_id {
+        Some(room_id) => vec![room_id],
+        None => db
+            .rooms
+            .rooms_joined(user_id)
+            .chain(
+                db.rooms
+                    .rooms_left(user_id)
+                    .map(|r| r.map(|(room_id, _)| room_id)),
+            )
+            .collect::<Result<Vec<_>>>()?,
+    };
+
+    let mut redacted = 0;
+    let mut skipped_rooms = Vec::new();
+
+    for room_id in room_ids {
+        // The admin room is already locked while admin commands are handled
+        if &room_id == conduit_room || !db.rooms.exists(&room_id)? {
+            skipped_rooms.push(room_id);
+            continue;
+        }
+
+        let mutex_state = Arc::clone(
+            db.globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let redactor = match find_redactor(db, conduit_user, &room_id, user_id)? {
+            Some(redactor) => redactor,
+            None => {
+                skipped_rooms.push(room_id);
+                continue;
+            }
+        };
+
+        let events = db
+            .rooms
+            .pdus_until(&redactor, &room_id, u64::MAX)?
+            .filter_map(|r| r.ok())
+            .map(|(_, pdu)| pdu)
+            .take_while(|pdu| since.map_or(true, |since| u64::from(pdu.origin_server_ts) >= since))
+            .filter(|pdu| {
+                &pdu.sender == user_id
+                    && pdu.kind != EventType::RoomRedaction
+                    && pdu.kind != EventType::RoomCreate
+                    && !pdu.unsigned.contains_key("redacted_because")
+            })
+            .map(|pdu| pdu.event_id)
+            .collect::<Vec<_>>();
+
+        for event_id in events {
+            db.rooms.build_and_append_pdu(
+                PduBuilder {
+                    event_type: EventType::RoomRedaction,
+                    content: serde_json::json!({ "reason": "Removed by the server admin" }),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: Some(event_id),
+                },
+                &redactor,
+                &room_id,
+                db,
+                &state_lock,
+            )?;
+            redacted += 1;
+        }
+
+        drop(state_lock);
+    }
+
+    let mut output = format!("Redacted {} events of {}.", redacted, user_id);
+    if !skipped_rooms.is_empty() {
+        output += &format!(
+            "\nSkipped the admin room and rooms where no local user is allowed to redact: {}",
+            skipped_rooms
+                .iter()
+                .map(|room_id| room_id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(output)
+}
+
+fn find_redactor(
+    db: &Database,
+    conduit_user: &UserId,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<Option<UserId>> {
+    let power_levels = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomPowerLevels, "")?
+        .map(|pdu| {
+            serde_json::from_value::<Raw<PowerLevelsEventContent>>(pdu.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let can_redact = |candidate: &UserId| {
+        power_levels
+            .users
+            .get(candidate)
+            .unwrap_or(&power_levels.users_default)
+            >= &power_levels.redact
+    };
+
+    if db.rooms.is_joined(conduit_user, room_id)? && can_redact(conduit_user) {
+        return Ok(Some(conduit_user.clone()));
+    }
+
+    // room_members only returns joined members
+    if let Some(member) = db
+        .rooms
+        .room_members(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|member| member.server_name() == db.globals.server_name())
+        .find(|member| can_redact(member))
+    {
+        return Ok(Some(member));
+    }
+
+    // Everyone can redact their own events
+    if user_id.server_name() == db.globals.server_name() && db.rooms.is_joined(user_id, room_id)? {
+        return Ok(Some(user_id.clone()));
+    }
+
+    Ok(None)
+}
+
+/// Version of the archive format written by `export_room`.
+const ROOM_ARCHIVE_VERSION: u64 = 1;
+
+/// Writes a room into a newline delimited json archive in the exports folder and returns its
+/// path.
+///
+/// The archive starts with a header, followed by the timeline pdus in order, the current state
+/// events that are not part of the timeline, the current state, the forward extremities and the
+/// media referenced by the events. The media itself is not copied.
+fn export_room(db: &Database, conduit_user: &UserId, room_id: &RoomId) -> Result<PathBuf> {
+    if !db.rooms.exists(room_id)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
+
+    let room_version = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomCreate, "")?
+        .and_then(|create| {
+            create
+                .content
+                .get("room_version")
+                .and_then(|version| version.as_str())
+                .map(str::to_owned)
+        })
+        .unwrap_or_else(|| "1".to_owned());
+
+    let folder = db.globals.get_export_folder();
+    fs::create_dir_all(&folder)?;
+    let file_name = room_id
+        .as_str()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let path = folder.join(format!("{}.ndjson", file_name));
+    let mut file = BufWriter::new(fs::File::create(&path)?);
+
+    writeln!(
+        file,
+        "{}",
+        serde_json::json!({
+            "type": "header",
+            "version": ROOM_ARCHIVE_VERSION,
+            "room_id": room_id,
+            "room_version": room_version,
+            "server_name": db.globals.server_name().as_str(),
+            "exported_at": utils::millis_since_unix_epoch(),
+        })
+    )?;
+
+    let mut timeline_ids = HashSet::new();
+    let mut media = BTreeSet::new();
+
+    for pdu in db.rooms.pdus_after(conduit_user, room_id, 0)? {
+        let (_, pdu) = pdu?;
+        let mut pdu_json = db
+            .rooms
+            .get_pdu_json(&pdu.event_id)?
+            .ok_or_else(|| Error::bad_database("Pdu in timeline has no json."))?;
+        if let Some(CanonicalJsonValue::Object(unsigned)) = pdu_json.get_mut("unsigned") {
+            unsigned.remove("transaction_id");
+        }
+
+        collect_media_references(&pdu.content, &mut media);
+        writeln!(
+            file,
+            "{}",
+            serde_json::json!({ "type": "pdu", "event": pdu_json })
+        )?;
+        timeline_ids.insert(pdu.event_id);
+    }
+
+    let state_ids = match db.rooms.current_shortstatehash(room_id)? {
+        Some(shortstatehash) => db
+            .rooms
+            .state_full_ids(shortstatehash)?
+            .into_iter()
+            .map(|(_, event_id)| (*event_id).clone())
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+
+    // State we got from other servers when joining is not part of our timeline
+    for event_id in state_ids.iter().filter(|id| !timeline_ids.contains(*id)) {
+        if let Some(pdu_json) = db.rooms.get_pdu_json(event_id)? {
+            let pdu_json =
+                serde_json::to_value(pdu_json).expect("CanonicalJsonObj is a valid JsonValue");
+            collect_media_references(&pdu_json["content"], &mut media);
+            writeln!(
+                file,
+                "{}",
+                serde_json::json!({ "type": "pdu", "outlier": true, "event": pdu_json })
+            )?;
+        }
+    }
+
+    let mut forward_extremities = db
+        .rooms
+        .get_pdu_leaves(room_id)?
+        .into_iter()
+        .collect::<Vec<_>>();
+    forward_extremities.sort();
+
+    writeln!(
+        file,
+        "{}",
+        serde_json::json!({ "type": "state", "event_ids": state_ids })
+    )?;
+    writeln!(
+        file,
+        "{}",
+        serde_json::json!({ "type": "forward_extremities", "event_ids": forward_extremities })
+    )?;
+    for mxc in media {
+        writeln!(
+            file,
+            "{}",
+            serde_json::json!({ "type": "media", "mxc": mxc })
+        )?;
+    }
+
+    file.flush()?;
+
+    Ok(path)
+}
+
+fn collect_media_references(content: &serde_json::Value, media: &mut BTreeSet<String>) {
+    let urls = [
+        content.get("url"),
+        content.get("avatar_url"),
+        content
+            .get("info")
+            .and_then(|info| info.get("thumbnail_url")),
+    ];
+
+    for url in urls
+        .iter()
+        .filter_map(|url| url.and_then(|url| url.as_str()))
+    {
+        if url.starts_with("mxc://") {
+            media.insert(url.to_owned());
+        }
+    }
+}
+
+/// Restores a room from an archive written by `export_room`.
+///
+/// The room must not exist on this server yet. The pdus are added without notifying anyone and the
+/// memberships are taken from the archived room state.
+async fn import_room(db: &Database, path: &Path) -> Result<String> {
+    let invalid_archive = |message| Error::BadRequest(ErrorKind::BadJson, message);
+
+    let mut room_id = None;
+    let mut pdus = Vec::new();
+    let mut state_ids = Vec::new();
+    let mut forward_extremities = Vec::new();
+    let mut media_count = 0;
+
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = serde_json::from_str::<serde_json::Value>(&line)
+            .map_err(|_| invalid_archive("Invalid line in room archive."))?;
+
+        match record.get("type").and_then(|t| t.as_str()) {
+            Some("header") => {
+                if record.get("version").and_then(|v| v.as_u64()) != Some(ROOM_ARCHIVE_VERSION) {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Unrecognized,
+                        "Unsupported room archive version.",
+                    ));
+                }
+                room_id = Some(
+                    serde_json::from_value::<RoomId>(record["room_id"].clone())
+                        .map_err(|_| invalid_archive("Invalid room id in room archive."))?,
+                );
+            }
+            Some("pdu") => {
+                let pdu_json =
+                    serde_json::from_value::<CanonicalJsonObject>(record["event"].clone())
+                        .map_err(|_| invalid_archive("Invalid pdu in room archive."))?;
+                let event_id = match pdu_json.get("event_id") {
+                    Some(CanonicalJsonValue::String(event_id)) => EventId::try_from(&**event_id)
+                        .map_err(|_| invalid_archive("Invalid event id in room archive."))?,
+                    _ => return Err(invalid_archive("Pdu in room archive has no event id.")),
+                };
+                let pdu = PduEvent::from_id_val(&event_id, pdu_json.clone())
+                    .map_err(|_| invalid_archive("Invalid pdu in room archive."))?;
+                let outlier = record
+                    .get("outlier")
+                    .and_then(|o| o.as_bool())
+                    .unwrap_or(false);
+
+                pdus.push((pdu, pdu_json, outlier));
+            }
+            Some("state") => {
+                state_ids = serde_json::from_value::<Vec<EventId>>(record["event_ids"].clone())
+                    .map_err(|_| invalid_archive("Invalid state in room archive."))?;
+            }
+            Some("forward_extremities") => {
+                forward_extremities = serde_json::from_value::<Vec<EventId>>(
+                    record["event_ids"].clone(),
+                )
+                .map_err(|_| invalid_archive("Invalid forward extremities in room archive."))?;
+            }
+            Some("media") => media_count += 1,
+            _ => return Err(invalid_archive("Unknown record in room archive.")),
+        }
+    }
+
+    let room_id = room_id.ok_or_else(|| invalid_archive("Room archive has no header."))?;
+
+    if db.rooms.exists(&room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::RoomInUse,
+            "Room already exists on this server.",
+        ));
+    }
+
+    if pdus.iter().any(|(pdu, _, _)| pdu.room_id != room_id) {
+        return Err(invalid_archive(
+            "Room archive contains events of another room.",
+        ));
+    }
+
+    let state_pdus = pdus
+        .iter()
+        .map(|(pdu, _, _)| (&pdu.event_id, pdu))
+        .collect::<HashMap<_, _>>();
+
+    for event_id in &state_ids {
+        if state_pdus
+            .get(event_id)
+            .map_or(true, |pdu| pdu.state_key.is_none())
+        {
+            return Err(invalid_archive(
+                "Room archive state references unknown events.",
+            ));
+        }
+    }
+
+    if !state_ids
+        .iter()
+        .any(|id| state_pdus[id].kind == EventType::RoomCreate)
+    {
+        return Err(invalid_archive(
+            "Room archive state contains no create event.",
+        ));
+    }
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
+
+    for (pdu, pdu_json, _) in &pdus {
+        db.rooms.add_pdu_outlier(&pdu.event_id, pdu_json)?;
+    }
+
+    let mut timeline_count = 0;
+    for (pdu, pdu_json, _) in pdus.iter().filter(|(_, _, outlier)| !outlier) {
+        db.rooms.append_imported_pdu(pdu, pdu_json, &db.globals)?;
+        timeline_count += 1;
+    }
+
+    let mut state = HashSet::new();
+    for event_id in &state_ids {
+        let pdu = state_pdus[event_id];
+        let shortstatekey = db.rooms.get_or_create_shortstatekey(
+            &pdu.kind,
+            pdu.state_key.as_ref().expect("checked above"),
+            &db.globals,
+        )?;
+        state.insert(
+            db.rooms
+                .compress_state_event(shortstatekey, event_id, &db.globals)?,
+        );
+    }
+
+    db.rooms.force_state(&room_id, state, db)?;
+    db.rooms
+        .replace_pdu_leaves(&room_id, &forward_extremities)?;
+
+    if let Some(join_rules) = db
+        .rooms
+        .room_state_get(&room_id, &EventType::RoomJoinRules, "")?
+    {
+        db.rooms
+            .update_restricted_allow_rooms(&room_id, &join_rules.content)?;
+    }
+
+    drop(state_lock);
+
+    Ok(format!(
+        "Imported {} with {} events, {} of them in the timeline. The archive references {} media files, they have to be copied separately.",
+        room_id,
+        pdus.len(),
+        timeline_count,
+        media_count
+    ))
+}
+
+/// How long users can download their data exports.
+const DATA_EXPORT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Deletes the data exports that can't be downloaded anymore and returns how many were deleted.
+pub fn prune_data_exports(db: &Database) -> Result<usize> {
+    let expired = db.users.remove_expired_data_exports()?;
+
+    for export_id in &expired {
+        if let Err(e) = fs::remove_file(db.globals.get_data_export_file(export_id)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to delete data export {}: {}", export_id, e);
+            }
+        }
+    }
+
+    Ok(expired.len())
+}
+
+/// Collects all data we store about a local user into a json document in the exports folder.
+/// Only the user can download it, and only for `DATA_EXPORT_TTL`.
+///
+/// This contains the profile, devices, account data, media uploads and the events the user sent
+/// in each room they are or were in.
+fn export_user_data(db: &Database, user_id: &UserId) -> Result<String> {
+    if user_id.server_name() != db.globals.server_name() || !db.users.exists(user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Local user not found.",
+        ));
+    }
+
+    let devices = db
+        .users
+        .all_devices_metadata(user_id)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut room_memberships = Vec::new();
+    for room_id in db.rooms.rooms_joined(user_id) {
+        room_memberships.push((room_id?, "join"));
+    }
+    for room in db.rooms.rooms_invited(user_id) {
+        room_memberships.push((room?.0, "invite"));
+    }
+    for room in db.rooms.rooms_left(user_id) {
+        room_memberships.push((room?.0, "leave"));
+    }
+
+    let account_data_json = |room_id: Option<&RoomId>| -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(
+            db.account_data
+                .changes_since(room_id, user_id, 0)?
+                .into_iter()
+                .map(|(event_type, event)| (event_type.to_string(), event))
+                .collect::<HashMap<_, _>>(),
+        )
+        .expect("account data is valid json"))
+    };
+
+    let mut rooms = serde_json::Map::new();
+    let mut room_account_data = serde_json::Map::new();
+    for (room_id, membership) in room_memberships {
+        let events = if db.rooms.exists(&room_id)? {
+            db.rooms
+                .pdus_after(user_id, &room_id, 0)?
+                .filter_map(|r| r.ok())
+                .map(|(_, pdu)| pdu)
+                .filter(|pdu| &pdu.sender == user_id)
+                .map(|pdu| serde_json::to_value(pdu).expect("PduEvent is valid json"))
+                .collect::<Vec<_>>()
+        } else {
+            // Rooms we were only invited to over federation
+            Vec::new()
+        };
+
+        room_account_data.insert(room_id.to_string(), account_data_json(Some(&room_id))?);
+        rooms.insert(
+            room_id.to_string(),
+            serde_json::json!({
+                "membership": membership,
+                "events": events,
+            }),
+        );
+    }
+
+    let media = db.media.uploads_of(user_id).collect::<Result<Vec<_>>>()?;
+
+    let export = serde_json::json!({
+        "user_id": user_id,
+        "exported_at": utils::millis_since_unix_epoch(),
+        "profile": {
+            "displayname": db.users.displayname(user_id)?,
+            "avatar_url": db.users.avatar_url(user_id)?,
+            "blurhash": db.users.blurhash(user_id)?,
+        },
+        "devices": devices,
+        "account_data": {
+            "global": account_data_json(None)?,
+            "rooms": room_account_data,
+        },
+        "rooms": rooms,
+        "media": media,
+    });
+
+    prune_data_exports(db)?;
+
+    let export_id = utils::random_string(32);
+    fs::create_dir_all(db.globals.get_export_folder())?;
+    fs::write(
+        db.globals.get_data_export_file(&export_id),
+        serde_json::to_string_pretty(&export).expect("json is valid"),
+    )?;
+
+    let expires_at = utils::millis_since_unix_epoch() + DATA_EXPORT_TTL.as_millis() as u64;
+    db.users.add_data_export(&export_id, user_id, expires_at)?;
+
+    Ok(format!(
+        "The data of {} was exported. {} can download it from /_conduit/client/v1/data_exports/{} for the next {} days.",
+        user_id,
+        user_id,
+        export_id,
+        DATA_EXPORT_TTL.as_secs() / (24 * 60 * 60)
+    ))
+}
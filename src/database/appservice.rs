@@ -1,4 +1,6 @@
 use crate::{utils, Error, Result};
+use regex::Regex;
+use ruma::{events::room::history_visibility::HistoryVisibility, Int, ServerName, UserId};
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
@@ -8,6 +10,9 @@ use super::abstraction::Tree;
 
 pub struct Appservice {
     pub(super) cached_registrations: Arc<RwLock<HashMap<String, serde_yaml::Value>>>,
+    /// The compiled namespaces and `rs.conduit.default_power_level` of each registration, so
+    /// invites and new events don't parse the registrations again.
+    pub(super) cached_namespaces: RwLock<HashMap<String, Arc<(Namespaces, Option<Int>)>>>,
     pub(super) id_appserviceregistrations: Arc<dyn Tree>,
 }
 
@@ -23,6 +28,7 @@ impl Appservice {
             .write()
             .unwrap()
             .insert(id.to_owned(), yaml);
+        self.cached_namespaces.write().unwrap().remove(id);
 
         Ok(())
     }
@@ -56,6 +62,65 @@ impl Appservice {
         }))
     }
 
+    /// Returns the compiled namespaces and the `rs.conduit.default_power_level` of a
+    /// registration.
+    pub fn namespaces(
+        &self,
+        id: &str,
+        globals: &super::globals::Globals,
+    ) -> Result<Option<Arc<(Namespaces, Option<Int>)>>> {
+        if let Some(entry) = self.cached_namespaces.read().unwrap().get(id) {
+            return Ok(Some(Arc::clone(entry)));
+        }
+
+        let registration = match self.get_registration(id)? {
+            Some(registration) => registration,
+            None => return Ok(None),
+        };
+
+        let entry = Arc::new((
+            Namespaces::from_registration(&registration, globals.server_name()),
+            registration
+                .get("rs.conduit.default_power_level")
+                .and_then(|level| level.as_i64())
+                .and_then(Int::new),
+        ));
+        self.cached_namespaces
+            .write()
+            .unwrap()
+            .insert(id.to_owned(), Arc::clone(&entry));
+
+        Ok(Some(entry))
+    }
+
+    /// Returns the power level users of an appservice get in rooms they are invited to. The
+    /// `rs.conduit.default_power_level` of the registration wins over the config.
+    pub fn default_power_level(
+        &self,
+        user_id: &UserId,
+        globals: &super::globals::Globals,
+    ) -> Result<Option<Int>> {
+        for id in self.iter_ids()?.filter_map(|id| id.ok()) {
+            let entry = match self.namespaces(&id, globals)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let (namespaces, level) = &*entry;
+            if !namespaces.matches_user(user_id.as_str()) {
+                continue;
+            }
+
+            let level = level.or_else(|| globals.appservice_default_power_level());
+
+            if level.is_some() {
+                return Ok(level);
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn all(&self) -> Result<Vec<(String, serde_yaml::Value)>> {
         self.iter_ids()?
             .filter_map(|id| id.ok())
@@ -69,3 +134,272 @@ impl Appservice {
             .collect()
     }
 }
+
+/// The namespaces of an appservice registration.
+pub struct Namespaces {
+    users: Vec<Regex>,
+    /// User ids that nobody but the appservice may register.
+    exclusive_users: Vec<Regex>,
+    aliases: Vec<Regex>,
+    rooms: Vec<Regex>,
+    /// The user of the appservice itself.
+    bridge_user_id: Option<UserId>,
+}
+
+impl Namespaces {
+    /// Reads the namespaces of a registration. The user of the appservice itself is added to the
+    /// user namespace.
+    pub fn from_registration(registration: &serde_yaml::Value, server_name: &ServerName) -> Self {
+        let regexes = |kind: &str, exclusive_only: bool| {
+            registration
+                .get("namespaces")
+                .and_then(|namespaces| namespaces.get(kind))
+                .and_then(|namespace| namespace.as_sequence())
+                .map_or_else(Vec::new, |namespace| {
+                    namespace
+                        .iter()
+                        .filter(|entry| {
+                            !exclusive_only
+                                || entry.get("exclusive").and_then(|e| e.as_bool()) == Some(true)
+                        })
+                        .filter_map(|entry| Regex::new(entry.get("regex")?.as_str()?).ok())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        let mut users = regexes("users", false);
+        let mut exclusive_users = regexes("users", true);
+        let bridge_user_id = registration
+            .get("sender_localpart")
+            .and_then(|string| string.as_str())
+            .and_then(|string| UserId::parse_with_server_name(string, server_name).ok());
+        if let Some(bridge_user_id) = &bridge_user_id {
+            let regex = Regex::new(&format!("^{}$", regex::escape(bridge_user_id.as_str())))
+                .expect("escaped regex is valid");
+            users.push(regex.clone());
+            exclusive_users.push(regex);
+        }
+
+        Self {
+            users,
+            exclusive_users,
+            aliases: regexes("aliases", false),
+            rooms: regexes("rooms", false),
+            bridge_user_id,
+        }
+    }
+
+    pub fn bridge_user_id(&self) -> Option<&UserId> {
+        self.bridge_user_id.as_ref()
+    }
+
+    pub fn matches_user(&self, user_id: &str) -> bool {
+        self.users.iter().any(|r| r.is_match(user_id))
+    }
+
+    pub fn matches_exclusive_user(&self, user_id: &str) -> bool {
+        self.exclusive_users.iter().any(|r| r.is_match(user_id))
+    }
+
+    pub fn matches_alias(&self, alias: &str) -> bool {
+        self.aliases.iter().any(|r| r.is_match(alias))
+    }
+
+    pub fn matches_room(&self, room_id: &str) -> bool {
+        self.rooms.iter().any(|r| r.is_match(room_id))
+    }
+}
+
+/// What we know about an event when deciding if an appservice may receive it.
+pub struct EventContext<'a> {
+    pub room_id: &'a str,
+    pub room_aliases: &'a [String],
+    pub sender: &'a str,
+    /// The user whose membership is changed, if this is a member event.
+    pub membership_target: Option<&'a str>,
+    /// Whether a user of the appservice is joined to the room.
+    pub joined: bool,
+    /// Whether a user of the appservice is invited to the room.
+    pub invited: bool,
+    pub history_visibility: HistoryVisibility,
+}
+
+/// Decides if an appservice is entitled to receive an event.
+///
+/// - Events in rooms of the room or alias namespace are always sent
+/// - Events sent by users of the namespace or changing their membership are always sent
+/// - Other events are only sent if the appservice shares the room with one of its users and that
+/// user is allowed to see the event. Invited users only see events if the history is visible to
+/// invited users.
+pub fn is_entitled(namespaces: &Namespaces, event: &EventContext<'_>) -> bool {
+    if namespaces.matches_room(event.room_id)
+        || event
+            .room_aliases
+            .iter()
+            .any(|alias| namespaces.matches_alias(alias))
+    {
+        return true;
+    }
+
+    if namespaces.matches_user(event.sender)
+        || event
+            .membership_target
+            .map_or(false, |target| namespaces.matches_user(target))
+    {
+        return true;
+    }
+
+    event.joined
+        || event.invited
+            && matches!(
+                event.history_visibility,
+                HistoryVisibility::Invited | HistoryVisibility::WorldReadable
+            )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_entitled, EventContext, Namespaces};
+    use ruma::{events::room::history_visibility::HistoryVisibility, ServerName};
+    use std::convert::TryFrom;
+
+    fn namespaces() -> Namespaces {
+        let registration = serde_yaml::from_str(
+            r##"
+id: bridge
+sender_localpart: bridgebot
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@_bridge_.*:example.com"
+  aliases:
+    - exclusive: true
+      regex: "#_bridge_.*:example.com"
+  rooms:
+    - exclusive: false
+      regex: "!bridged:example.com"
+"##,
+        )
+        .unwrap();
+
+        Namespaces::from_registration(
+            &registration,
+            &Box::<ServerName>::try_from("example.com").unwrap(),
+        )
+    }
+
+    fn event<'a>() -> EventContext<'a> {
+        EventContext {
+            room_id: "!other:example.com",
+            room_aliases: &[],
+            sender: "@alice:example.com",
+            membership_target: None,
+            joined: false,
+            invited: false,
+            history_visibility: HistoryVisibility::Shared,
+        }
+    }
+
+    #[test]
+    fn unrelated_events_are_not_sent() {
+        assert!(!is_entitled(&namespaces(), &event()));
+    }
+
+    #[test]
+    fn rooms_namespace_gets_all_events() {
+        let event = EventContext {
+            room_id: "!bridged:example.com",
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &event));
+    }
+
+    #[test]
+    fn alias_namespace_gets_all_events() {
+        let aliases = ["#_bridge_portal:example.com".to_owned()];
+        let event = EventContext {
+            room_aliases: &aliases,
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &event));
+    }
+
+    #[test]
+    fn events_of_namespaced_users_are_sent() {
+        let sent = EventContext {
+            sender: "@_bridge_bob:example.com",
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &sent));
+
+        let invite = EventContext {
+            membership_target: Some("@_bridge_bob:example.com"),
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &invite));
+
+        let bridge_user = EventContext {
+            sender: "@bridgebot:example.com",
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &bridge_user));
+    }
+
+    #[test]
+    fn shared_rooms_need_a_joined_user() {
+        let joined = EventContext {
+            joined: true,
+            history_visibility: HistoryVisibility::Joined,
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &joined));
+
+        let invited = EventContext {
+            invited: true,
+            history_visibility: HistoryVisibility::Joined,
+            ..event()
+        };
+        assert!(!is_entitled(&namespaces(), &invited));
+
+        let invited_shared = EventContext {
+            invited: true,
+            history_visibility: HistoryVisibility::Shared,
+            ..event()
+        };
+        assert!(!is_entitled(&namespaces(), &invited_shared));
+    }
+
+    #[test]
+    fn invited_users_see_history_visible_to_invited() {
+        let invited = EventContext {
+            invited: true,
+            history_visibility: HistoryVisibility::Invited,
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &invited));
+
+        let world_readable = EventContext {
+            invited: true,
+            history_visibility: HistoryVisibility::WorldReadable,
+            ..event()
+        };
+        assert!(is_entitled(&namespaces(), &world_readable));
+    }
+
+    #[test]
+    fn rooms_namespace_only_matches_its_rooms() {
+        let event = EventContext {
+            room_id: "!unbridged:example.com",
+            ..event()
+        };
+        assert!(!is_entitled(&namespaces(), &event));
+    }
+
+    #[test]
+    fn exclusive_namespaces_reserve_user_ids() {
+        let namespaces = namespaces();
+        assert!(namespaces.matches_exclusive_user("@_bridge_bob:example.com"));
+        assert!(namespaces.matches_exclusive_user("@bridgebot:example.com"));
+        assert!(!namespaces.matches_exclusive_user("@alice:example.com"));
+    }
+}
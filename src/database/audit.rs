@@ -0,0 +1,225 @@
+use crate::{utils, Database, Error, Result};
+use ruma::{DeviceId, UserId};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::interval};
+use tracing::{error, info};
+
+use super::{abstraction::Tree, globals::Globals};
+
+/// A security-relevant action.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AuditAction {
+    Login {
+        user_id: UserId,
+        device_id: Box<DeviceId>,
+    },
+    /// The user id is missing if the user doesn't exist.
+    LoginFailed {
+        user_id: Option<UserId>,
+    },
+    PasswordChanged {
+        user_id: UserId,
+    },
+    AccountDeactivated {
+        user_id: UserId,
+    },
+    DevicesDeleted {
+        user_id: UserId,
+        device_ids: Vec<Box<DeviceId>>,
+    },
+    ScopedTokenCreated {
+        user_id: UserId,
+        device_id: Box<DeviceId>,
+    },
+    /// Only the first line of the command is recorded, the rest may contain secrets.
+    AdminCommand {
+        sender: UserId,
+        command: String,
+    },
+}
+
+impl AuditAction {
+    /// The name of the action, as it appears in the `action` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditAction::Login { .. } => "login",
+            AuditAction::LoginFailed { .. } => "login_failed",
+            AuditAction::PasswordChanged { .. } => "password_changed",
+            AuditAction::AccountDeactivated { .. } => "account_deactivated",
+            AuditAction::DevicesDeleted { .. } => "devices_deleted",
+            AuditAction::ScopedTokenCreated { .. } => "scoped_token_created",
+            AuditAction::AdminCommand { .. } => "admin_command",
+        }
+    }
+
+    /// The user the action was about or who did it.
+    pub fn user_id(&self) -> Option<&UserId> {
+        match self {
+            AuditAction::Login { user_id, .. }
+            | AuditAction::PasswordChanged { user_id }
+            | AuditAction::AccountDeactivated { user_id }
+            | AuditAction::DevicesDeleted { user_id, .. }
+            | AuditAction::ScopedTokenCreated { user_id, .. } => Some(user_id),
+            AuditAction::LoginFailed { user_id } => user_id.as_ref(),
+            AuditAction::AdminCommand { sender, .. } => Some(sender),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// When the action happened, in milliseconds since the unix epoch.
+    pub ts: u64,
+    /// The address of the client, if the action came from a request. The address of the peer,
+    /// unless it is a trusted proxy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(flatten)]
+    pub action: AuditAction,
+}
+
+/// The audit log is append-only. Entries are only removed when they are older than the configured
+/// retention.
+pub struct Audit {
+    pub(super) auditid_entry: Arc<dyn Tree>, // AuditId = Timestamp + Count
+    pub(super) userauditid: Arc<dyn Tree>,   // Entries about a user, UserAuditId = UserId + AuditId
+}
+
+impl Audit {
+    /// Appends an entry to the audit log, if it is enabled.
+    pub fn record(&self, globals: &Globals, action: AuditAction, ip: Option<IpAddr>) -> Result<()> {
+        if !globals.audit_log_enabled() {
+            return Ok(());
+        }
+
+        let ts = utils::millis_since_unix_epoch();
+
+        let mut key = ts.to_be_bytes().to_vec();
+        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+
+        let entry = AuditEntry { ts, ip, action };
+        self.index_entry(&key, &entry)?;
+        self.auditid_entry.insert(
+            &key,
+            &serde_json::to_vec(&entry).expect("AuditEntry::to_vec always works"),
+        )
+    }
+
+    /// Adds an entry to the entries of its user.
+    pub fn index_entry(&self, key: &[u8], entry: &AuditEntry) -> Result<()> {
+        if let Some(user_id) = entry.action.user_id() {
+            self.userauditid.insert(&user_audit_id(user_id, key), &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entries before the entry with the key `before`, newest first, with their keys.
+    pub fn entries_before(
+        &self,
+        before: Option<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, AuditEntry)>> + '_ {
+        let from = before.clone().unwrap_or_else(|| vec![0xff; 16]);
+
+        self.auditid_entry
+            .iter_from(&from, true)
+            .filter(move |(key, _)| before.as_ref() != Some(key))
+            .map(|(key, value)| {
+                Ok((
+                    key,
+                    serde_json::from_slice(&value)
+                        .map_err(|_| Error::bad_database("Invalid entry in auditid_entry."))?,
+                ))
+            })
+    }
+
+    /// Returns the entries about `user_id` before the entry with the key `before`, newest first,
+    /// with their keys.
+    pub fn user_entries_before<'a>(
+        &'a self,
+        user_id: &UserId,
+        before: Option<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, AuditEntry)>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let from = user_audit_id(user_id, &before.clone().unwrap_or_else(|| vec![0xff; 16]));
+
+        self.userauditid
+            .iter_from(&from, true)
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .map(move |(key, _)| key[key.len().saturating_sub(16)..].to_vec())
+            .filter(move |key| before.as_ref() != Some(key))
+            .filter_map(move |key| {
+                // Pruning removes the entry before its index entry
+                let entry = match self.auditid_entry.get(&key) {
+                    Ok(entry) => entry?,
+                    Err(e) => return Some(Err(e)),
+                };
+                Some(
+                    serde_json::from_slice(&entry)
+                        .map(|entry| (key, entry))
+                        .map_err(|_| Error::bad_database("Invalid entry in auditid_entry.")),
+                )
+            })
+    }
+
+    /// Removes the entries that happened before `cutoff`. Returns how many were removed.
+    fn prune(&self, cutoff: u64) -> Result<usize> {
+        let mut removed = 0;
+
+        for (key, value) in self.auditid_entry.iter() {
+            let ts = utils::u64_from_bytes(&key[..8.min(key.len())])
+                .map_err(|_| Error::bad_database("Invalid key in auditid_entry."))?;
+            if ts >= cutoff {
+                break;
+            }
+
+            self.auditid_entry.remove(&key)?;
+            if let Some(user_id) = serde_json::from_slice::<AuditEntry>(&value)
+                .ok()
+                .as_ref()
+                .and_then(|entry| entry.action.user_id())
+            {
+                self.userauditid.remove(&user_audit_id(user_id, &key))?;
+            }
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes entries older than `audit_log_retention_days` once a day.
+    pub fn start_prune_task(&self, db: Arc<RwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(Duration::from_secs(60 * 60 * 24));
+
+            loop {
+                i.tick().await;
+
+                let guard = db.read().await;
+                let retention = match guard.globals.audit_log_retention() {
+                    Some(retention) => retention,
+                    None => continue,
+                };
+
+                let cutoff =
+                    utils::millis_since_unix_epoch().saturating_sub(retention.as_millis() as u64);
+                match guard.audit.prune(cutoff) {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Removed {} old audit log entries", removed),
+                    Err(e) => error!("Failed to prune the audit log: {}", e),
+                }
+            }
+        });
+    }
+}
+
+fn user_audit_id(user_id: &UserId, audit_id: &[u8]) -> Vec<u8> {
+    let mut key = user_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(audit_id);
+    key
+}
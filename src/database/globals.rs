@@ -1,53 +1,244 @@
-use crate::{database::Config, server_server::FedDest, utils, ConduitResult, Error, Result};
+use crate::{
+    database::{Config, RoomTemplate},
+    server_server::FedDest,
+    utils, Error, Result,
+};
+use lru_cache::LruCache;
+use ring::digest;
 use ruma::{
     api::{
-        client::r0::sync::sync_events,
-        federation::discovery::{ServerSigningKeys, VerifyKey},
+        client::{error::ErrorKind, r0::directory::get_public_rooms_filtered},
+        federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
     },
-    DeviceId, EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName, ServerSigningKeyId, UserId,
+    encryption::{CrossSigningKey, DeviceKeys},
+    events::room::power_levels::PowerLevelsEventContent,
+    DeviceId, EventId, Int, MilliSecondsSinceUnixEpoch, RoomId, RoomIdOrAliasId, RoomVersionId,
+    ServerName, ServerSigningKeyId, UInt, UserId,
 };
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
     fs,
     future::Future,
     net::IpAddr,
     path::PathBuf,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::{Duration, Instant},
 };
-use tokio::sync::{broadcast, watch::Receiver, Mutex as TokioMutex, Semaphore};
+use tokio::sync::{broadcast, watch::Receiver, Mutex as TokioMutex, Notify, Semaphore};
 use tracing::error;
 use trust_dns_resolver::TokioAsyncResolver;
 
-use super::abstraction::Tree;
+use super::{abstraction::Tree, users::InvitePolicy};
 
 pub const COUNTER: &[u8] = b"c";
+/// How many counts are reserved in the database at once.
+const COUNT_LEASE_SIZE: u64 = 1000;
+const SYNC_TOKEN_EPOCH: &[u8] = b"sync_token_epoch";
+const SYNC_TOKEN_VERSION: u64 = 1;
+const MEMBERSHIP_LOG_START: &[u8] = b"membership_log_start";
+const KEYPAIR: &[u8] = b"keypair";
+/// When the current keypair was created, in milliseconds since the unix epoch.
+const KEYPAIR_CREATEDTS: &[u8] = b"keypair_createdts";
+/// The public keys of keypairs that were rotated away, as a json map from key id to old key.
+const OLD_VERIFY_KEYS: &[u8] = b"old_verify_keys";
+/// Other servers may cache our current key for this long.
+const KEY_VALIDITY: Duration = Duration::from_secs(86400 * 7);
 
 type WellKnownMap = HashMap<Box<ServerName>, (FedDest, String)>;
 type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
 type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
-type SyncHandle = (
-    Option<String>,                                         // since
-    Receiver<Option<ConduitResult<sync_events::Response>>>, // rx
-);
+type MessageBudget = (Instant, f64); // Time of last update, messages left
+type CountLease = (u64, u64); // Last handed out count, last count reserved in the database
+type PublicRoomsCacheKey = (
+    Box<ServerName>,
+    Option<UInt>,
+    Option<String>,
+    Option<String>,
+); // server, limit, since, search term
+type PublicRoomsCache =
+    HashMap<PublicRoomsCacheKey, (Instant, get_public_rooms_filtered::Response)>; // Time of fetch, response
+type RemoteKeysHandle = Receiver<Option<Arc<RemoteUserKeys>>>; // None while the query is running
+
+/// The keys of a remote user, as their server returned them for /keys/query.
+#[derive(Clone, Debug, Default)]
+pub struct RemoteUserKeys {
+    pub device_keys: BTreeMap<Box<DeviceId>, DeviceKeys>,
+    pub master_key: Option<CrossSigningKey>,
+    pub self_signing_key: Option<CrossSigningKey>,
+}
+
+/// A count handed out by `next_appending_count`. Dropping it marks what was stored under the
+/// count as committed.
+pub struct AppendingCount<'a> {
+    globals: &'a Globals,
+    pub count: u64,
+}
+
+impl Drop for AppendingCount<'_> {
+    fn drop(&mut self) {
+        self.globals
+            .appending_counts
+            .lock()
+            .unwrap()
+            .remove(&self.count);
+    }
+}
 
 pub struct Globals {
     pub actual_destination_cache: Arc<RwLock<WellKnownMap>>, // actual_destination, host
     pub tls_name_override: Arc<RwLock<TlsNameMap>>,
     pub(super) globals: Arc<dyn Tree>,
+    count: Mutex<CountLease>,
+    appending_counts: Mutex<BTreeSet<u64>>, // Counts whose rows are not committed yet
     config: Config,
-    keypair: Arc<ruma::signatures::Ed25519KeyPair>,
+    keypair: RwLock<Arc<ruma::signatures::Ed25519KeyPair>>,
     dns_resolver: TokioAsyncResolver,
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey<'static>>,
     pub(super) server_signingkeys: Arc<dyn Tree>,
     pub bad_event_ratelimiter: Arc<RwLock<HashMap<EventId, RateLimitState>>>,
     pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
     pub servername_ratelimiter: Arc<RwLock<HashMap<Box<ServerName>, Arc<Semaphore>>>>,
-    pub sync_receivers: RwLock<HashMap<(UserId, Box<DeviceId>), SyncHandle>>,
+    pub federation_handler_semaphore: Semaphore, // Limits the rooms handling incoming pdus at once
+    pub message_budgets: Mutex<HashMap<UserId, MessageBudget>>,
+    pub public_rooms_cache: RwLock<PublicRoomsCache>,
+    pub remote_keys_cache: Mutex<LruCache<UserId, (u64, Arc<RemoteUserKeys>)>>, // device list version, keys
+    pub remote_keys_receivers: Mutex<HashMap<UserId, RemoteKeysHandle>>,
+    pub devicelist_resyncs: Mutex<HashSet<UserId>>, // Remote users whose device list is being fetched
     pub roomid_mutex_insert: RwLock<HashMap<RoomId, Arc<Mutex<()>>>>,
     pub roomid_mutex_state: RwLock<HashMap<RoomId, Arc<TokioMutex<()>>>>,
     pub roomid_mutex_federation: RwLock<HashMap<RoomId, Arc<TokioMutex<()>>>>, // this lock will be held longer
+    pub userid_mutex_createroom: RwLock<HashMap<UserId, Arc<TokioMutex<()>>>>,
+    pub sync_waiters: Mutex<HashMap<UserId, VecDeque<Arc<Notify>>>>, // Oldest first
     pub rotate: RotationHandler,
+    pub replica_advanced: RotationHandler, // Fired when a replica sees new changes of the primary
+    pub metrics: Metrics,
+    pub request_durations: Arc<RequestDurations>,
+}
+
+/// In-memory counters that help admins to spot misbehaving clients or servers.
+///
+/// The counters are reset when the server restarts.
+#[derive(Default)]
+pub struct Metrics {
+    pub room_key_requests: AtomicU64,
+    pub room_key_requests_deduplicated: AtomicU64,
+    pub room_key_requests_blocked: AtomicU64,
+    pub dummy_events_sent: AtomicU64,
+    pub to_device_events_evicted: AtomicU64,
+    pub to_device_events_pruned: AtomicU64,
+}
+
+impl Metrics {
+    /// Renders all counters as `name: value` lines.
+    pub fn render(&self) -> String {
+        let counters = [
+            ("room_key_requests", &self.room_key_requests),
+            (
+                "room_key_requests_deduplicated",
+                &self.room_key_requests_deduplicated,
+            ),
+            ("room_key_requests_blocked", &self.room_key_requests_blocked),
+            ("dummy_events_sent", &self.dummy_events_sent),
+            ("to_device_events_evicted", &self.to_device_events_evicted),
+            ("to_device_events_pruned", &self.to_device_events_pruned),
+        ];
+
+        counters
+            .iter()
+            .map(|(name, counter)| format!("{}: {}", name, counter.load(Ordering::Relaxed)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Upper bounds of the request duration histogram buckets in milliseconds. Slower requests land in
+/// an extra bucket.
+const DURATION_BUCKETS_MS: &[u64] = &[10, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// How long the requests to one route took.
+#[derive(Clone, Default)]
+pub struct DurationHistogram {
+    pub buckets: [u64; DURATION_BUCKETS_MS.len() + 1],
+    pub count: u64,
+    pub total: Duration,
+}
+
+impl DurationHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        let bucket = DURATION_BUCKETS_MS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(DURATION_BUCKETS_MS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += duration;
+    }
+}
+
+/// Request duration histograms per route, so admins can see which endpoints need capacity.
+///
+/// The histograms are reset when the server restarts.
+#[derive(Default)]
+pub struct RequestDurations {
+    routes: Mutex<HashMap<String, DurationHistogram>>,
+}
+
+impl RequestDurations {
+    /// Records a request to `route`, e.g. `GET /_matrix/client/r0/rooms/<_>/messages`.
+    pub fn record(&self, route: &str, duration: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.get_mut(route) {
+            Some(histogram) => histogram.record(duration),
+            None => {
+                let mut histogram = DurationHistogram::default();
+                histogram.record(duration);
+                routes.insert(route.to_owned(), histogram);
+            }
+        }
+    }
+
+    /// Renders the histograms of the `limit` routes with the most total time spent.
+    pub fn render(&self, limit: usize) -> String {
+        let mut routes = self
+            .routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(route, histogram)| (route.clone(), histogram.clone()))
+            .collect::<Vec<_>>();
+        routes.sort_by(|a, b| b.1.total.cmp(&a.1.total));
+
+        routes
+            .iter()
+            .take(limit)
+            .map(|(route, histogram)| {
+                let buckets = DURATION_BUCKETS_MS
+                    .iter()
+                    .map(|bound| format!("<={}ms", bound))
+                    .chain(std::iter::once("slower".to_owned()))
+                    .zip(histogram.buckets.iter())
+                    .filter(|(_, &count)| count > 0)
+                    .map(|(bucket, count)| format!("{}: {}", bucket, count))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                format!(
+                    "{}: {} requests, {}ms average ({})",
+                    route,
+                    histogram.count,
+                    histogram.total.as_millis() / u128::from(histogram.count.max(1)),
+                    buckets
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
 
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is similar to "rotation" of log files and the like.
@@ -87,45 +278,36 @@ impl Globals {
         server_signingkeys: Arc<dyn Tree>,
         config: Config,
     ) -> Result<Self> {
-        let keypair_bytes = globals.get(b"keypair")?.map_or_else(
+        let keypair_bytes = globals.get(KEYPAIR)?.map_or_else(
             || {
                 let keypair = utils::generate_keypair();
-                globals.insert(b"keypair", &keypair)?;
+                globals.insert(KEYPAIR, &keypair)?;
+                globals.insert(
+                    KEYPAIR_CREATEDTS,
+                    &utils::millis_since_unix_epoch().to_be_bytes(),
+                )?;
                 Ok::<_, Error>(keypair)
             },
             |s| Ok(s.to_vec()),
         )?;
 
-        let mut parts = keypair_bytes.splitn(2, |&b| b == 0xff);
-
-        let keypair = utils::string_from_bytes(
-            // 1. version
-            parts
-                .next()
-                .expect("splitn always returns at least one element"),
-        )
-        .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
-        .and_then(|version| {
-            // 2. key
-            parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
-                .map(|key| (version, key))
-        })
-        .and_then(|(version, key)| {
-            ruma::signatures::Ed25519KeyPair::from_der(&key, version)
-                .map_err(|_| Error::bad_database("Private or public keys are invalid."))
-        });
-
-        let keypair = match keypair {
+        let keypair = match parse_keypair(&keypair_bytes) {
             Ok(k) => k,
             Err(e) => {
                 error!("Keypair invalid. Deleting...");
-                globals.remove(b"keypair")?;
+                globals.remove(KEYPAIR)?;
                 return Err(e);
             }
         };
 
+        // Keypairs from before rotation was supported start their lifetime now
+        if globals.get(KEYPAIR_CREATEDTS)?.is_none() {
+            globals.insert(
+                KEYPAIR_CREATEDTS,
+                &utils::millis_since_unix_epoch().to_be_bytes(),
+            )?;
+        }
+
         let tls_name_override = Arc::new(RwLock::new(TlsNameMap::new()));
 
         let jwt_decoding_key = config
@@ -133,10 +315,27 @@ impl Globals {
             .as_ref()
             .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()).into_static());
 
+        // The database holds the end of the last lease, which is never lower than any count that
+        // was handed out before a restart
+        let count = globals.get(COUNTER)?.map_or(Ok(0_u64), |bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Count has invalid bytes."))
+        })?;
+
+        let federation_handler_semaphore =
+            Semaphore::new(config.max_concurrent_federation_rooms.max(1) as usize);
+
         let s = Self {
             globals,
+            // A replica starts at the position of the primary instead, the lease may be ahead
+            count: Mutex::new(if config.replica_of.is_some() {
+                (0, 0)
+            } else {
+                (count, count)
+            }),
+            appending_counts: Mutex::new(BTreeSet::new()),
             config,
-            keypair: Arc::new(keypair),
+            keypair: RwLock::new(Arc::new(keypair)),
             dns_resolver: TokioAsyncResolver::tokio_from_system_conf().map_err(|_| {
                 Error::bad_config("Failed to set up trust dns resolver with system config.")
             })?,
@@ -147,21 +346,148 @@ impl Globals {
             bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             servername_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
+            federation_handler_semaphore,
+            message_budgets: Mutex::new(HashMap::new()),
+            public_rooms_cache: RwLock::new(HashMap::new()),
             roomid_mutex_state: RwLock::new(HashMap::new()),
             roomid_mutex_insert: RwLock::new(HashMap::new()),
             roomid_mutex_federation: RwLock::new(HashMap::new()),
-            sync_receivers: RwLock::new(HashMap::new()),
+            userid_mutex_createroom: RwLock::new(HashMap::new()),
+            sync_waiters: Mutex::new(HashMap::new()),
+            remote_keys_cache: Mutex::new(LruCache::new(10_000)),
+            remote_keys_receivers: Mutex::new(HashMap::new()),
+            devicelist_resyncs: Mutex::new(HashSet::new()),
             rotate: RotationHandler::new(),
+            replica_advanced: RotationHandler::new(),
+            metrics: Metrics::default(),
+            request_durations: Arc::new(RequestDurations::default()),
         };
 
+        // Otherwise every createRoom would fail after creating half the room
+        let mut power_levels = serde_json::to_value(PowerLevelsEventContent::default())
+            .expect("PowerLevelsEventContent::to_value always works");
+        s.apply_default_power_levels(&mut power_levels);
+        if serde_json::from_value::<PowerLevelsEventContent>(power_levels).is_err() {
+            return Err(Error::bad_config("Invalid default_power_levels."));
+        }
+
         fs::create_dir_all(s.get_media_folder())?;
 
         Ok(s)
     }
 
-    /// Returns this server's keypair.
-    pub fn keypair(&self) -> &ruma::signatures::Ed25519KeyPair {
-        &self.keypair
+    /// Returns this server's current keypair.
+    pub fn keypair(&self) -> Arc<ruma::signatures::Ed25519KeyPair> {
+        Arc::clone(&self.keypair.read().unwrap())
+    }
+
+    /// Returns the id of the current key, e.g. `ed25519:abcdefgh`.
+    pub fn keypair_id(&self) -> ServerSigningKeyId {
+        ServerSigningKeyId::try_from(format!("ed25519:{}", self.keypair().version()).as_str())
+            .expect("found invalid server signing keys in DB")
+    }
+
+    /// Returns when the current keypair was created, in milliseconds since the unix epoch.
+    pub fn keypair_created(&self) -> Result<u64> {
+        self.globals.get(KEYPAIR_CREATEDTS)?.map_or(Ok(0), |bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Keypair creation time is invalid."))
+        })
+    }
+
+    /// Returns until when other servers may use our current key without asking again. This
+    /// is never after the next automatic rotation.
+    pub fn keypair_valid_until(&self) -> Result<MilliSecondsSinceUnixEpoch> {
+        let mut valid_until = utils::millis_since_unix_epoch() + KEY_VALIDITY.as_millis() as u64;
+
+        if let Some(lifetime) = self.signing_key_lifetime() {
+            let rotation = self.keypair_created()? + lifetime.as_millis() as u64;
+            valid_until = valid_until.min(rotation.max(utils::millis_since_unix_epoch()));
+        }
+
+        Ok(MilliSecondsSinceUnixEpoch(
+            UInt::try_from(valid_until).expect("time is valid"),
+        ))
+    }
+
+    /// Returns the public keys this server signed with before, keyed by key id.
+    pub fn old_verify_keys(&self) -> Result<BTreeMap<ServerSigningKeyId, OldVerifyKey>> {
+        self.globals.get(OLD_VERIFY_KEYS)?.map_or_else(
+            || Ok(BTreeMap::new()),
+            |bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Old verify keys in db are invalid."))
+            },
+        )
+    }
+
+    /// Replaces the current keypair with a new one. The public key of the old keypair is still
+    /// published as an old verify key, so other servers can verify what it signed. Returns the
+    /// id of the new key.
+    pub fn rotate_keypair(&self) -> Result<ServerSigningKeyId> {
+        let new_keypair_bytes = utils::generate_keypair();
+        let new_keypair = parse_keypair(&new_keypair_bytes)?;
+
+        let mut keypair = self.keypair.write().unwrap();
+        let old_id =
+            ServerSigningKeyId::try_from(format!("ed25519:{}", keypair.version()).as_str())
+                .expect("found invalid server signing keys in DB");
+
+        let mut old_verify_keys = self.old_verify_keys()?;
+        old_verify_keys.insert(
+            old_id,
+            OldVerifyKey::new(
+                MilliSecondsSinceUnixEpoch::now(),
+                base64::encode_config(keypair.public_key(), base64::STANDARD_NO_PAD),
+            ),
+        );
+
+        self.globals.insert(
+            OLD_VERIFY_KEYS,
+            &serde_json::to_vec(&old_verify_keys).expect("old verify keys can be serialized"),
+        )?;
+        self.globals.insert(KEYPAIR, &new_keypair_bytes)?;
+        self.globals.insert(
+            KEYPAIR_CREATEDTS,
+            &utils::millis_since_unix_epoch().to_be_bytes(),
+        )?;
+
+        *keypair = Arc::new(new_keypair);
+        drop(keypair);
+
+        Ok(self.keypair_id())
+    }
+
+    /// Rotates the keypair if it is older than `signing_key_lifetime_days` and forgets old
+    /// verify keys that expired more than `old_verify_key_retention_days` ago. Returns the id of
+    /// the new key if the keypair was rotated.
+    pub fn rotate_expired_keys(&self) -> Result<Option<ServerSigningKeyId>> {
+        let now = utils::millis_since_unix_epoch();
+
+        if let Some(retention) = self.old_verify_key_retention() {
+            let mut old_verify_keys = self.old_verify_keys()?;
+            let before = old_verify_keys.len();
+            old_verify_keys.retain(|_, key| {
+                now.saturating_sub(key.expired_ts.get().into()) < retention.as_millis() as u64
+            });
+
+            if old_verify_keys.len() != before {
+                self.globals.insert(
+                    OLD_VERIFY_KEYS,
+                    &serde_json::to_vec(&old_verify_keys)
+                        .expect("old verify keys can be serialized"),
+                )?;
+            }
+        }
+
+        match self.signing_key_lifetime() {
+            Some(lifetime)
+                if now.saturating_sub(self.keypair_created()?) >= lifetime.as_millis() as u64 =>
+            {
+                self.rotate_keypair().map(Some)
+            }
+            _ => Ok(None),
+        }
     }
 
     /// Returns a reqwest client which can be used to send requests.
@@ -177,24 +503,302 @@ impl Globals {
         Ok(reqwest_client_builder)
     }
 
+    /// Returns a new count that is higher than all counts before.
+    ///
+    /// Counts are reserved in the database in blocks of `COUNT_LEASE_SIZE`, so only every
+    /// `COUNT_LEASE_SIZE`th call has to write to the database. After a crash the unused rest of
+    /// the last block is skipped. All callers share one block because sync relies on counts being
+    /// handed out in order.
+    ///
+    /// Replicas can't hand out counts, only the primary writes new data.
     #[tracing::instrument(skip(self))]
     pub fn next_count(&self) -> Result<u64> {
-        utils::u64_from_bytes(&self.globals.increment(COUNTER)?)
-            .map_err(|_| Error::bad_database("Count has invalid bytes."))
+        self.next_count_locked(&mut self.count.lock().unwrap())
+    }
+
+    /// Like `next_count`, but the count stays above `committed_count` until the returned guard is
+    /// dropped. Used for everything that syncs read by count (pdus, EDUs, account data, to-device
+    /// events and key changes), so replicas don't skip rows that are still being written.
+    pub fn next_appending_count(&self) -> Result<AppendingCount<'_>> {
+        let mut lease = self.count.lock().unwrap();
+        let count = self.next_count_locked(&mut lease)?;
+        self.appending_counts.lock().unwrap().insert(count);
+
+        Ok(AppendingCount {
+            globals: self,
+            count,
+        })
     }
 
+    fn next_count_locked(&self, lease: &mut CountLease) -> Result<u64> {
+        if self.is_replica() {
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "This server is a replica and can't write to the database.",
+            ));
+        }
+
+        let (last, reserved) = lease;
+
+        if *last == *reserved {
+            let new_reserved = *reserved + COUNT_LEASE_SIZE;
+            self.globals.insert(COUNTER, &new_reserved.to_be_bytes())?;
+            *reserved = new_reserved;
+        }
+
+        *last += 1;
+        Ok(*last)
+    }
+
+    /// Returns the last count that was handed out. On replicas this is the last position of the
+    /// primary the replica saw.
     #[tracing::instrument(skip(self))]
     pub fn current_count(&self) -> Result<u64> {
-        self.globals.get(COUNTER)?.map_or(Ok(0_u64), |bytes| {
+        Ok(self.count.lock().unwrap().0)
+    }
+
+    /// Returns the highest count up to which everything syncs read is committed, i.e. the count
+    /// before the lowest count from `next_appending_count` that is still in use.
+    pub fn committed_count(&self) -> Result<u64> {
+        let lease = self.count.lock().unwrap();
+        Ok(self
+            .appending_counts
+            .lock()
+            .unwrap()
+            .iter()
+            .next()
+            .map_or(lease.0, |lowest| lowest - 1))
+    }
+
+    /// Remembers the current count of the primary and wakes up the waiting syncs if it moved.
+    pub fn set_replica_position(&self, position: u64) {
+        let mut lease = self.count.lock().unwrap();
+        if position > lease.0 {
+            *lease = (position, position);
+            drop(lease);
+            self.replica_advanced.fire();
+        }
+    }
+
+    /// Returns the count since which membership changes are logged in `roomchangeid_membership`.
+    pub fn membership_log_start(&self) -> Result<u64> {
+        self.globals
+            .get(MEMBERSHIP_LOG_START)?
+            .map_or(Ok(u64::MAX), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Membership log start has invalid bytes."))
+            })
+    }
+
+    pub fn set_membership_log_start(&self, count: u64) -> Result<()> {
+        self.globals
+            .insert(MEMBERSHIP_LOG_START, &count.to_be_bytes())
+    }
+
+    /// Returns the current sync token epoch. Tokens from older epochs are rejected.
+    pub fn sync_token_epoch(&self) -> Result<u64> {
+        self.globals.get(SYNC_TOKEN_EPOCH)?.map_or(Ok(0), |bytes| {
             utils::u64_from_bytes(&bytes)
-                .map_err(|_| Error::bad_database("Count has invalid bytes."))
+                .map_err(|_| Error::bad_database("Sync token epoch has invalid bytes."))
         })
     }
 
+    /// Invalidates all sync tokens that were handed out so far.
+    ///
+    /// This has to be called after anything that makes old counts meaningless, like purging
+    /// events or migrating the database.
+    pub fn bump_sync_token_epoch(&self) -> Result<u64> {
+        utils::u64_from_bytes(&self.globals.increment(SYNC_TOKEN_EPOCH)?)
+            .map_err(|_| Error::bad_database("Sync token epoch has invalid bytes."))
+    }
+
+    fn sync_token_checksum(&self, version: u64, epoch: u64, count: u64) -> String {
+        let mut bytes = self.server_name().as_bytes().to_vec();
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.extend_from_slice(&epoch.to_be_bytes());
+        bytes.extend_from_slice(&count.to_be_bytes());
+
+        digest::digest(&digest::SHA256, &bytes).as_ref()[..4]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Creates a token of the form `version_epoch_count_checksum` that can be used as the
+    /// `next_batch` of a sync response.
+    pub fn sync_token(&self, count: u64) -> Result<String> {
+        let epoch = self.sync_token_epoch()?;
+
+        Ok(format!(
+            "{}_{}_{}_{}",
+            SYNC_TOKEN_VERSION,
+            epoch,
+            count,
+            self.sync_token_checksum(SYNC_TOKEN_VERSION, epoch, count)
+        ))
+    }
+
+    /// Returns the count of a token created with `sync_token`.
+    ///
+    /// Plain counts from before tokens were versioned are only accepted in the first epoch.
+    /// Fails with M_UNKNOWN_TOKEN if the token was not created by this server, is from an old
+    /// epoch or points to the future.
+    pub fn parse_sync_token(&self, token: &str) -> Result<u64> {
+        let unknown_token = || {
+            Error::BadRequest(
+                ErrorKind::UnknownToken { soft_logout: false },
+                "Unknown or expired sync token, please do an initial sync.",
+            )
+        };
+
+        let epoch = self.sync_token_epoch()?;
+
+        let count = if let Ok(count) = token.parse::<u64>() {
+            if epoch != 0 {
+                return Err(unknown_token());
+            }
+            count
+        } else {
+            let parts = token.split('_').collect::<Vec<_>>();
+            let numbers = parts
+                .iter()
+                .take(3)
+                .map(|part| part.parse::<u64>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|_| unknown_token())?;
+
+            match (&numbers[..], parts.get(3)) {
+                (&[version, token_epoch, count], Some(checksum))
+                    if parts.len() == 4
+                        && version == SYNC_TOKEN_VERSION
+                        && token_epoch == epoch
+                        && *checksum == self.sync_token_checksum(version, token_epoch, count) =>
+                {
+                    count
+                }
+                _ => return Err(unknown_token()),
+            }
+        };
+
+        if count > self.current_count()? {
+            return Err(unknown_token());
+        }
+
+        Ok(count)
+    }
+
+    /// Returns the count of a pagination token. Our own pagination tokens are plain counts, but
+    /// clients also paginate from the `next_batch` of a sync.
+    pub fn parse_pagination_token(&self, token: &str) -> Result<u64> {
+        match token.parse::<u64>() {
+            Ok(count) => Ok(count),
+            Err(_) => self.parse_sync_token(token),
+        }
+    }
+
     pub fn server_name(&self) -> &ServerName {
         self.config.server_name.as_ref()
     }
 
+    pub fn room_template(&self, name: &str) -> Option<&RoomTemplate> {
+        self.config.room_templates.get(name)
+    }
+
+    /// Merges `default_power_levels` into the power levels of a new room. They extend the levels
+    /// of events, notifications and users instead of replacing them, so the creator keeps their
+    /// power level.
+    pub fn apply_default_power_levels(&self, power_levels: &mut serde_json::Value) {
+        for (key, value) in &self.config.default_power_levels {
+            match (power_levels.get_mut(key), value) {
+                (Some(serde_json::Value::Object(current)), serde_json::Value::Object(defaults)) => {
+                    current.extend(defaults.clone());
+                }
+                _ => power_levels[key.as_str()] = value.clone(),
+            }
+        }
+    }
+
+    pub fn report_escalation_threshold(&self) -> Option<u32> {
+        self.config.report_escalation_threshold.filter(|&n| n > 0)
+    }
+
+    pub fn quarantine_reported_media(&self) -> bool {
+        self.config.quarantine_reported_media
+    }
+
+    pub fn status_msg_ttl(&self) -> Option<Duration> {
+        Some(self.config.status_msg_ttl_secs)
+            .filter(|&secs| secs > 0)
+            .map(|secs| Duration::from_secs(secs.into()))
+    }
+
+    pub fn sync_room_concurrency(&self) -> usize {
+        self.config.sync_room_concurrency.max(1) as usize
+    }
+
+    pub fn max_sync_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.max_sync_timeout_secs.into())
+    }
+
+    /// Registers a sync of the user that waits for new events. If the user has more than
+    /// `max_concurrent_syncs_per_user` waiting syncs, the oldest ones are told to stop waiting.
+    pub fn register_sync_waiter(&self, user_id: &UserId) -> SyncWaiter<'_> {
+        SyncWaiter {
+            globals: self,
+            user_id: user_id.clone(),
+            closed: self.add_sync_waiter(user_id),
+        }
+    }
+
+    /// Like `register_sync_waiter`, for waiters that can't borrow the globals, e.g. sync streams.
+    /// The returned notify is notified when newer syncs push the waiter out. It has to be removed
+    /// with `remove_sync_waiter`.
+    pub fn add_sync_waiter(&self, user_id: &UserId) -> Arc<Notify> {
+        let closed = Arc::new(Notify::new());
+
+        let mut waiters = self.sync_waiters.lock().unwrap();
+        let user_waiters = waiters.entry(user_id.clone()).or_default();
+        user_waiters.push_back(Arc::clone(&closed));
+
+        while user_waiters.len() > self.config.max_concurrent_syncs_per_user.max(1) as usize {
+            if let Some(oldest) = user_waiters.pop_front() {
+                // Stores a permit if the sync didn't start waiting yet
+                oldest.notify_one();
+            }
+        }
+
+        closed
+    }
+
+    pub fn remove_sync_waiter(&self, user_id: &UserId, closed: &Arc<Notify>) {
+        let mut waiters = self.sync_waiters.lock().unwrap();
+        if let Some(user_waiters) = waiters.get_mut(user_id) {
+            user_waiters.retain(|waiter| !Arc::ptr_eq(waiter, closed));
+            if user_waiters.is_empty() {
+                waiters.remove(user_id);
+            }
+        }
+    }
+
+    pub fn create_room_txn_id_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.create_room_txn_id_ttl_secs.into())
+    }
+
+    /// The room versions this server can create and join.
+    pub fn supported_room_versions(&self) -> Vec<RoomVersionId> {
+        vec![
+            RoomVersionId::Version5,
+            RoomVersionId::Version6,
+            RoomVersionId::Version7,
+            RoomVersionId::Version8,
+            RoomVersionId::Version9,
+        ]
+    }
+
+    pub fn supports_room_version(&self, room_version: &RoomVersionId) -> bool {
+        self.supported_room_versions().contains(room_version)
+    }
+
     pub fn max_request_size(&self) -> u32 {
         self.config.max_request_size
     }
@@ -203,6 +807,22 @@ impl Globals {
         self.config.allow_registration
     }
 
+    pub fn signing_key_lifetime(&self) -> Option<Duration> {
+        Some(self.config.signing_key_lifetime_days)
+            .filter(|&days| days > 0)
+            .map(|days| Duration::from_secs(u64::from(days) * 86400))
+    }
+
+    pub fn old_verify_key_retention(&self) -> Option<Duration> {
+        Some(self.config.old_verify_key_retention_days)
+            .filter(|&days| days > 0)
+            .map(|days| Duration::from_secs(u64::from(days) * 86400))
+    }
+
+    pub fn appservice_default_power_level(&self) -> Option<Int> {
+        self.config.appservice_default_power_level
+    }
+
     pub fn allow_encryption(&self) -> bool {
         self.config.allow_encryption
     }
@@ -211,10 +831,234 @@ impl Globals {
         self.config.allow_federation
     }
 
+    pub fn invalid_event_ttl(&self) -> Option<Duration> {
+        Some(self.config.invalid_event_ttl_secs)
+            .filter(|&secs| secs > 0)
+            .map(|secs| Duration::from_secs(secs.into()))
+    }
+
     pub fn trusted_servers(&self) -> &[Box<ServerName>] {
         &self.config.trusted_servers
     }
 
+    /// Contact URI that is sent to clients when they run into a resource limit.
+    ///
+    /// Defaults to a matrix.to link pointing to the admin room.
+    pub fn admin_contact(&self) -> String {
+        self.config
+            .admin_contact
+            .clone()
+            .unwrap_or_else(|| format!("https://matrix.to/#/#admins:{}", self.server_name()))
+    }
+
+    pub fn block_key_requests_to_untrusted_devices(&self) -> bool {
+        self.config.block_key_requests_to_untrusted_devices
+    }
+
+    pub fn require_verified_key_backups(&self) -> bool {
+        self.config.require_verified_key_backups
+    }
+
+    pub fn max_forward_extremities(&self) -> u32 {
+        self.config.max_forward_extremities
+    }
+
+    /// Takes one message from the send budget of the user.
+    ///
+    /// The budget refills with `messages_per_second` and holds at most `message_burst_count`
+    /// messages. Returns M_LIMIT_EXCEEDED with the time until the next message can be sent if the
+    /// budget is exhausted.
+    ///
+    /// Users who wait for the review of reports against them get `messages_per_second_under_review`
+    /// without a burst instead, even if they send through an appservice.
+    pub fn check_message_limit(
+        &self,
+        user_id: &UserId,
+        from_appservice: bool,
+        under_review: bool,
+    ) -> Result<()> {
+        let (rate, burst) = if under_review && self.config.messages_per_second_under_review > 0.0 {
+            (self.config.messages_per_second_under_review, 1.0)
+        } else {
+            let rate = match self.config.messages_per_second {
+                Some(rate) if rate > 0.0 => rate,
+                _ => return Ok(()),
+            };
+
+            if from_appservice && self.config.exempt_appservices_from_message_limit {
+                return Ok(());
+            }
+
+            (rate, f64::from(self.config.message_burst_count.max(1)))
+        };
+
+        let now = Instant::now();
+
+        let mut budgets = self.message_budgets.lock().unwrap();
+        let (last_update, left) = budgets.entry(user_id.clone()).or_insert((now, burst));
+
+        *left = (*left + now.duration_since(*last_update).as_secs_f64() * rate).min(burst);
+        *last_update = now;
+
+        if *left >= 1.0 {
+            *left -= 1.0;
+            Ok(())
+        } else {
+            Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(Duration::from_secs_f64((1.0 - *left) / rate)),
+                },
+                "You are sending messages too fast.",
+            ))
+        }
+    }
+
+    pub fn login_failures_before_lockout(&self) -> u32 {
+        self.config.login_failures_before_lockout
+    }
+
+    pub fn login_lockout(&self) -> Duration {
+        Duration::from_secs(self.config.login_lockout_secs.into())
+    }
+
+    /// Returns the address of the client. The X-Real-IP header is only used if the request comes
+    /// from one of the trusted proxies.
+    pub fn client_ip(&self, remote: Option<IpAddr>, real_ip: Option<IpAddr>) -> Option<IpAddr> {
+        utils::client_ip(remote, real_ip, &self.config.trusted_proxies)
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpAddr] {
+        &self.config.trusted_proxies
+    }
+
+    pub fn registrations_per_ip(&self) -> Option<u32> {
+        self.config.registrations_per_ip
+    }
+
+    pub fn registration_ip_window(&self) -> Duration {
+        Duration::from_secs(self.config.registration_ip_window_secs.into())
+    }
+
+    /// Returns the site key and the secret key for reCAPTCHA if both are configured.
+    pub fn recaptcha_keys(&self) -> Option<(&str, &str)> {
+        match (
+            &self.config.recaptcha_public_key,
+            &self.config.recaptcha_private_key,
+        ) {
+            (Some(public_key), Some(private_key)) => Some((public_key, private_key)),
+            _ => None,
+        }
+    }
+
+    pub fn auto_join_rooms(&self) -> &[RoomIdOrAliasId] {
+        &self.config.auto_join_rooms
+    }
+
+    /// Returns true if nobody may register this localpart. The comparison ignores case.
+    pub fn is_reserved_username(&self, localpart: &str) -> bool {
+        self.config
+            .reserved_usernames
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(localpart))
+    }
+
+    pub fn welcome_message(&self) -> Option<&str> {
+        self.config.welcome_message.as_deref()
+    }
+
+    pub fn default_invite_policy(&self) -> InvitePolicy {
+        self.config.default_invite_policy
+    }
+
+    pub fn mentions_only_room_size(&self) -> Option<u32> {
+        self.config.mentions_only_room_size
+    }
+
+    pub fn max_reaction_keys_per_event(&self) -> u32 {
+        self.config.max_reaction_keys_per_event
+    }
+
+    pub fn purge_media_of_deactivated_users(&self) -> bool {
+        self.config.purge_media_of_deactivated_users
+    }
+
+    pub fn max_to_device_events_per_device(&self) -> u32 {
+        self.config.max_to_device_events_per_device
+    }
+
+    pub fn removed_device_queue_retention(&self) -> Duration {
+        Duration::from_secs(self.config.removed_device_queue_retention_secs.into())
+    }
+
+    pub fn reject_confusable_aliases(&self) -> bool {
+        self.config.reject_confusable_aliases
+    }
+
+    /// Returns the cached keys of a remote user if they are from the given device list version.
+    pub fn cached_remote_keys(
+        &self,
+        user_id: &UserId,
+        version: u64,
+    ) -> Option<Arc<RemoteUserKeys>> {
+        self.remote_keys_cache
+            .lock()
+            .unwrap()
+            .get_mut(user_id)
+            .filter(|(cached_version, _)| *cached_version == version)
+            .map(|(_, keys)| Arc::clone(keys))
+    }
+
+    pub fn public_rooms_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.public_rooms_cache_ttl_secs.into())
+    }
+
+    pub fn federated_directory_peers(&self) -> &[Box<ServerName>] {
+        &self.config.federated_directory_peers
+    }
+
+    pub fn federated_directory_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.federated_directory_timeout_ms)
+    }
+
+    /// The base url of the primary if this server is a read-only replica.
+    pub fn replica_of(&self) -> Option<&str> {
+        self.config.replica_of.as_deref()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.config.replica_of.is_some()
+    }
+
+    pub fn replication_secret(&self) -> Option<&str> {
+        self.config.replication_secret.as_deref()
+    }
+
+    pub fn replica_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.config.replica_poll_interval_ms.max(1))
+    }
+
+    pub fn audit_log_enabled(&self) -> bool {
+        self.config.audit_log
+    }
+
+    pub fn audit_log_retention(&self) -> Option<Duration> {
+        Some(self.config.audit_log_retention_days)
+            .filter(|&days| days > 0)
+            .map(|days| Duration::from_secs(u64::from(days) * 86400))
+    }
+
+    pub fn max_joined_rooms_per_user(&self) -> Option<u32> {
+        self.config.max_joined_rooms_per_user
+    }
+
+    pub fn max_local_members_per_room(&self) -> Option<u32> {
+        self.config.max_local_members_per_room
+    }
+
+    pub fn max_pending_invites_per_user(&self) -> Option<u32> {
+        self.config.max_pending_invites_per_user
+    }
+
     pub fn dns_resolver(&self) -> &TokioAsyncResolver {
         &self.dns_resolver
     }
@@ -286,6 +1130,24 @@ impl Globals {
             })
             .unwrap_or_else(BTreeMap::new);
 
+        // Our own keys are not stored with the keys of other servers
+        if origin == self.server_name() {
+            let mut signingkeys = signingkeys;
+            signingkeys.insert(
+                self.keypair_id(),
+                VerifyKey::new(base64::encode_config(
+                    self.keypair().public_key(),
+                    base64::STANDARD_NO_PAD,
+                )),
+            );
+            signingkeys.extend(
+                self.old_verify_keys()?
+                    .into_iter()
+                    .map(|(id, old)| (id, VerifyKey::new(old.key))),
+            );
+            return Ok(signingkeys);
+        }
+
         Ok(signingkeys)
     }
 
@@ -309,6 +1171,20 @@ impl Globals {
         r
     }
 
+    pub fn get_export_folder(&self) -> PathBuf {
+        let mut r = PathBuf::new();
+        r.push(self.config.database_path.clone());
+        r.push("exports");
+        r
+    }
+
+    /// The file of a user data export. Only the user can download it, see `add_data_export`.
+    pub fn get_data_export_file(&self, export_id: &str) -> PathBuf {
+        let mut r = self.get_export_folder();
+        r.push(format!("user-{}.json", export_id));
+        r
+    }
+
     pub fn get_media_file(&self, key: &[u8]) -> PathBuf {
         let mut r = PathBuf::new();
         r.push(self.config.database_path.clone());
@@ -317,3 +1193,47 @@ impl Globals {
         r
     }
 }
+
+/// Parses a keypair stored as `version 0xff der`.
+fn parse_keypair(bytes: &[u8]) -> Result<ruma::signatures::Ed25519KeyPair> {
+    let mut parts = bytes.splitn(2, |&b| b == 0xff);
+
+    utils::string_from_bytes(
+        // 1. version
+        parts
+            .next()
+            .expect("splitn always returns at least one element"),
+    )
+    .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
+    .and_then(|version| {
+        // 2. key
+        parts
+            .next()
+            .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
+            .map(|key| (version, key))
+    })
+    .and_then(|(version, key)| {
+        ruma::signatures::Ed25519KeyPair::from_der(&key, version)
+            .map_err(|_| Error::bad_database("Private or public keys are invalid."))
+    })
+}
+
+/// A sync that waits for new events. It stops being counted when it is dropped.
+pub struct SyncWaiter<'a> {
+    globals: &'a Globals,
+    user_id: UserId,
+    closed: Arc<Notify>,
+}
+
+impl SyncWaiter<'_> {
+    /// Completes when newer syncs of the same user pushed this one out.
+    pub async fn closed(&self) {
+        self.closed.notified().await
+    }
+}
+
+impl Drop for SyncWaiter<'_> {
+    fn drop(&mut self) {
+        self.globals.remove_sync_waiter(&self.user_id, &self.closed);
+    }
+}
@@ -0,0 +1,506 @@
+use crate::{utils, Database, Error, PduEvent, Result};
+use ruma::{
+    events::{
+        room::{member::MembershipState, power_levels::PowerLevelsEventContent},
+        EventType,
+    },
+    serde::Raw,
+    RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    mem::size_of,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, RwLock},
+    time::interval,
+};
+use tracing::{error, info, warn};
+
+use super::abstraction::Tree;
+
+/// How many items a job processes before it saves its progress and lets other tasks run.
+const JOB_BATCH_SIZE: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// Clears the message search index and adds all messages again.
+    RebuildSearchIndex,
+    /// Clears the directory of remote users and adds everyone we share a room with.
+    RebuildUserDirectory,
+    /// Counts the unread notifications and highlights of every local user again.
+    RecomputeNotificationCounts,
+    /// Leaves all rooms of a deactivated user and removes their aliases, pushers, to-device
+    /// events and optionally their media.
+    CleanupDeactivatedUser { user_id: UserId, purge_media: bool },
+    /// Deletes to-device events of devices that were removed a while ago and forgets about these
+    /// devices.
+    PruneToDeviceQueues,
+    /// Deletes the createRoom transaction ids that are older than `create_room_txn_id_ttl_secs`.
+    PruneCreateRoomTxnIds,
+}
+
+impl JobKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_owned())).ok()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Job {
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// The key of the last item the job processed.
+    pub cursor: Option<Vec<u8>>,
+    pub processed: u64,
+    pub queued_at: u64,
+}
+
+pub struct Jobs {
+    pub(super) jobid_job: Arc<dyn Tree>, // JobId = Count
+    pub(super) job_queued: Arc<Notify>,
+}
+
+impl Jobs {
+    /// Adds a job to the queue and returns its id.
+    pub fn queue(&self, kind: JobKind, globals: &super::globals::Globals) -> Result<u64> {
+        let id = globals.next_count()?;
+        self.save(
+            id,
+            &Job {
+                kind,
+                status: JobStatus::Queued,
+                cursor: None,
+                processed: 0,
+                queued_at: utils::millis_since_unix_epoch(),
+            },
+        )?;
+        self.job_queued.notify_one();
+
+        Ok(id)
+    }
+
+    /// Returns all jobs, oldest first.
+    pub fn all(&self) -> impl Iterator<Item = Result<(u64, Job)>> + '_ {
+        self.jobid_job.iter().map(|(id, job)| {
+            Ok((
+                utils::u64_from_bytes(&id).map_err(|_| Error::bad_database("Invalid job id."))?,
+                serde_json::from_slice(&job).map_err(|_| Error::bad_database("Invalid job."))?,
+            ))
+        })
+    }
+
+    fn save(&self, id: u64, job: &Job) -> Result<()> {
+        self.jobid_job.insert(
+            &id.to_be_bytes(),
+            &serde_json::to_vec(job).expect("job is valid json"),
+        )
+    }
+
+    /// Returns the oldest job that has not finished yet. Jobs that were running when the server
+    /// stopped are continued.
+    fn next_pending(&self) -> Result<Option<(u64, Job)>> {
+        for job in self.all() {
+            let (id, job) = job?;
+            if matches!(job.status, JobStatus::Queued | JobStatus::Running) {
+                return Ok(Some((id, job)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Queues the `PruneToDeviceQueues` and `PruneCreateRoomTxnIds` jobs once a day. Only the
+    /// latest finished run of each is kept.
+    pub fn start_prune_task(&self, db: Arc<RwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(Duration::from_secs(60 * 60 * 24));
+
+            loop {
+                i.tick().await;
+
+                let guard = db.read().await;
+                for kind in &[JobKind::PruneToDeviceQueues, JobKind::PruneCreateRoomTxnIds] {
+                    if let Err(e) = guard.jobs.queue_prune_job(kind.clone(), &guard.globals) {
+                        error!("Failed to queue {:?}: {}", kind, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Checks once an hour if the signing key has to be rotated or old keys expired.
+    pub fn start_key_rotation_task(&self, db: Arc<RwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(Duration::from_secs(60 * 60));
+
+            loop {
+                i.tick().await;
+
+                let guard = db.read().await;
+                match guard.globals.rotate_expired_keys() {
+                    Ok(Some(key_id)) => {
+                        info!("Rotated the signing key, now signing with {}", key_id)
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to rotate the signing key: {}", e),
+                }
+            }
+        });
+    }
+
+    fn queue_prune_job(&self, kind: JobKind, globals: &super::globals::Globals) -> Result<()> {
+        for job in self.all().collect::<Vec<_>>() {
+            let (id, job) = job?;
+            if job.kind != kind {
+                continue;
+            }
+
+            match job.status {
+                // The last run is not done yet
+                JobStatus::Queued | JobStatus::Running => return Ok(()),
+                _ => self.jobid_job.remove(&id.to_be_bytes())?,
+            }
+        }
+
+        self.queue(kind, globals)?;
+
+        Ok(())
+    }
+
+    /// Runs the queued jobs one after another in the background.
+    pub fn start_handler(&self, db: Arc<RwLock<Database>>) {
+        let job_queued = Arc::clone(&self.job_queued);
+
+        tokio::spawn(async move {
+            loop {
+                let guard = db.read().await;
+
+                let (id, mut job) = match guard.jobs.next_pending() {
+                    Ok(Some(next)) => next,
+                    Ok(None) => {
+                        drop(guard);
+                        job_queued.notified().await;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Failed to load the job queue: {}", e);
+                        return;
+                    }
+                };
+
+                if job.status == JobStatus::Queued {
+                    info!("Starting job {} ({:?})", id, job.kind);
+                    job.status = JobStatus::Running;
+                }
+
+                match run_batch(&guard, &mut job).await {
+                    Ok(true) => {
+                        info!("Finished job {} after {} items", id, job.processed);
+                        job.status = JobStatus::Finished;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Job {} failed: {}", id, e);
+                        job.status = JobStatus::Failed(e.to_string());
+                    }
+                }
+
+                if let Err(e) = guard.jobs.save(id, &job) {
+                    error!("Failed to save the progress of job {}: {}", id, e);
+                    return;
+                }
+
+                // Let requests that need write access to the database go first
+                drop(guard);
+                tokio::task::yield_now().await;
+            }
+        });
+    }
+}
+
+/// Processes the next batch of a job and returns true when the job is done.
+async fn run_batch(db: &Database, job: &mut Job) -> Result<bool> {
+    if let JobKind::CleanupDeactivatedUser {
+        user_id,
+        purge_media,
+    } = &job.kind
+    {
+        let (left, done) = cleanup_deactivated_user(db, user_id, *purge_media).await?;
+        job.processed += left;
+        job.cursor = Some(Vec::new());
+        return Ok(done);
+    }
+
+    let first_batch = job.cursor.is_none();
+    let cursor = job.cursor.clone().unwrap_or_default();
+
+    let (tree, clear): (&Arc<dyn Tree>, Option<&Arc<dyn Tree>>) = match job.kind {
+        JobKind::RebuildSearchIndex => (&db.rooms.pduid_pdu, Some(&db.rooms.tokenids)),
+        JobKind::RebuildUserDirectory => (
+            &db.rooms.userroomid_joined,
+            Some(&db.users.remoteuserid_displayname),
+        ),
+        JobKind::RecomputeNotificationCounts => (&db.rooms.userroomid_joined, None),
+        JobKind::PruneToDeviceQueues => (&db.users.todeviceid_events, None),
+        JobKind::PruneCreateRoomTxnIds => (&db.transaction_ids.userdevicetxnid_createdroom, None),
+        JobKind::CleanupDeactivatedUser { .. } => unreachable!("handled above"),
+    };
+
+    if first_batch {
+        if let Some(clear) = clear {
+            clear.clear()?;
+        }
+    }
+
+    let batch = tree
+        .iter_from(&cursor, false)
+        .filter(|(key, _)| first_batch || key != &cursor)
+        .take(JOB_BATCH_SIZE)
+        .collect::<Vec<_>>();
+
+    for (key, value) in &batch {
+        match job.kind {
+            JobKind::RebuildSearchIndex => index_pdu(db, key, value)?,
+            JobKind::RebuildUserDirectory => add_directory_entry(db, key)?,
+            JobKind::RecomputeNotificationCounts => recompute_notification_counts(db, key)?,
+            JobKind::PruneToDeviceQueues => {
+                if db
+                    .users
+                    .prune_to_device_event(key, db.globals.removed_device_queue_retention())?
+                {
+                    db.globals
+                        .metrics
+                        .to_device_events_pruned
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            JobKind::PruneCreateRoomTxnIds => {
+                db.transaction_ids.prune_create_room_txnid(
+                    key,
+                    value,
+                    db.globals.create_room_txn_id_ttl(),
+                )?;
+            }
+            JobKind::CleanupDeactivatedUser { .. } => unreachable!("handled above"),
+        }
+    }
+
+    job.processed += batch.len() as u64;
+
+    match batch.into_iter().last() {
+        Some((key, _)) => {
+            job.cursor = Some(key);
+            Ok(false)
+        }
+        None => {
+            if job.kind == JobKind::PruneToDeviceQueues {
+                db.users
+                    .prune_removed_devices(db.globals.removed_device_queue_retention())?;
+            }
+            Ok(true)
+        }
+    }
+}
+
+fn index_pdu(db: &Database, pdu_id: &[u8], pdu: &[u8]) -> Result<()> {
+    let pdu = match serde_json::from_slice::<PduEvent>(pdu) {
+        Ok(pdu) => pdu,
+        Err(_) => return Ok(()),
+    };
+
+    if pdu.kind != EventType::RoomMessage {
+        return Ok(());
+    }
+
+    if let Some(body) = pdu.content.get("body").and_then(|b| b.as_str()) {
+        let shortroomid = utils::u64_from_bytes(&pdu_id[..size_of::<u64>()])
+            .map_err(|_| Error::bad_database("Invalid pdu id in db."))?;
+        db.rooms.index_message_body(shortroomid, pdu_id, body)?;
+    }
+
+    Ok(())
+}
+
+fn parse_userroom_id(userroom_id: &[u8]) -> Result<(UserId, RoomId)> {
+    let mut parts = userroom_id.split(|&b| b == 0xff);
+
+    let user_id = UserId::try_from(
+        utils::string_from_bytes(parts.next().expect("split always returns one element"))
+            .map_err(|_| Error::bad_database("Invalid user id in userroomid_joined."))?,
+    )
+    .map_err(|_| Error::bad_database("Invalid user id in userroomid_joined."))?;
+
+    let room_id = RoomId::try_from(
+        utils::string_from_bytes(
+            parts
+                .next()
+                .ok_or_else(|| Error::bad_database("Invalid userroomid_joined in db."))?,
+        )
+        .map_err(|_| Error::bad_database("Invalid room id in userroomid_joined."))?,
+    )
+    .map_err(|_| Error::bad_database("Invalid room id in userroomid_joined."))?;
+
+    Ok((user_id, room_id))
+}
+
+fn add_directory_entry(db: &Database, userroom_id: &[u8]) -> Result<()> {
+    let (user_id, room_id) = parse_userroom_id(userroom_id)?;
+
+    if user_id.server_name() == db.globals.server_name() {
+        return Ok(());
+    }
+
+    let displayname = db
+        .rooms
+        .room_state_get(&room_id, &EventType::RoomMember, user_id.as_str())?
+        .and_then(|member| {
+            member
+                .content
+                .get("displayname")
+                .and_then(|d| d.as_str())
+                .map(str::to_owned)
+        });
+
+    db.users
+        .set_directory_entry(&user_id, displayname.as_deref())
+}
+
+fn recompute_notification_counts(db: &Database, userroom_id: &[u8]) -> Result<()> {
+    let (user_id, room_id) = parse_userroom_id(userroom_id)?;
+
+    if user_id.server_name() != db.globals.server_name() {
+        return Ok(());
+    }
+
+    let power_levels = db
+        .rooms
+        .room_state_get(&room_id, &EventType::RoomPowerLevels, "")?
+        .map(|ev| {
+            serde_json::from_value::<Raw<PowerLevelsEventContent>>(ev.content.clone())
+                .expect("Raw::from_value always works.")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let since = db
+        .rooms
+        .edus
+        .private_read_get(&room_id, &user_id)?
+        .unwrap_or(0);
+
+    let mut notifications = 0_u64;
+    let mut highlights = 0_u64;
+
+    for pdu in db.rooms.pdus_after(&user_id, &room_id, since)? {
+        let (_, pdu) = pdu?;
+        if pdu.sender == user_id {
+            continue;
+        }
+
+        let (notify, highlight) = db.rooms.notify_and_highlight(
+            &user_id,
+            &pdu.to_sync_room_event(),
+            &room_id,
+            &power_levels,
+            db,
+        )?;
+
+        if notify {
+            notifications += 1;
+        }
+        if highlight {
+            highlights += 1;
+        }
+    }
+
+    db.rooms
+        .userroomid_notificationcount
+        .insert(userroom_id, &notifications.to_be_bytes())?;
+    db.rooms
+        .userroomid_highlightcount
+        .insert(userroom_id, &highlights.to_be_bytes())?;
+
+    Ok(())
+}
+
+/// Leaves the next batch of rooms of a deactivated user. Once the user is in no room anymore the
+/// rest is removed. Returns how many rooms were left and if the cleanup is done.
+async fn cleanup_deactivated_user(
+    db: &Database,
+    user_id: &UserId,
+    purge_media: bool,
+) -> Result<(u64, bool)> {
+    let rooms = db
+        .rooms
+        .rooms_joined(user_id)
+        .chain(
+            db.rooms
+                .rooms_invited(user_id)
+                .map(|r| r.map(|(room_id, _)| room_id)),
+        )
+        .take(JOB_BATCH_SIZE)
+        .collect::<Result<Vec<_>>>()?;
+
+    if !rooms.is_empty() {
+        for room_id in &rooms {
+            if let Err(e) = db.rooms.leave_room(user_id, room_id, db).await {
+                warn!("Failed to leave {} for {}: {}", room_id, user_id, e);
+                // Don't try this room again
+                db.rooms.update_membership(
+                    room_id,
+                    user_id,
+                    MembershipState::Leave,
+                    user_id,
+                    None,
+                    db,
+                    true,
+                )?;
+            }
+        }
+
+        return Ok((rooms.len() as u64, false));
+    }
+
+    for alias in db.rooms.aliases_created_by(user_id).collect::<Vec<_>>() {
+        db.rooms.set_alias(&alias?, None, &db.globals)?;
+    }
+
+    for senderkey in db.pusher.get_pusher_senderkeys(user_id).collect::<Vec<_>>() {
+        db.pusher.senderkey_pusher.remove(&senderkey)?;
+    }
+
+    let mut prefix = user_id.as_bytes().to_vec();
+    prefix.push(0xff);
+    for (key, _) in db.users.todeviceid_events.scan_prefix(prefix.clone()) {
+        db.users.todeviceid_events.remove(&key)?;
+    }
+    for (key, _) in db.users.userdeviceid_todevicecount.scan_prefix(prefix) {
+        db.users.userdeviceid_todevicecount.remove(&key)?;
+    }
+
+    if purge_media {
+        for mxc in db.media.uploads_of(user_id).collect::<Vec<_>>() {
+            db.media.purge(&mxc?, &db.globals).await?;
+        }
+    }
+
+    info!("Cleaned up deactivated user {}", user_id);
+
+    Ok((0, true))
+}
@@ -3,8 +3,13 @@ use image::{imageops::FilterType, GenericImageView};
 
 use super::abstraction::Tree;
 use crate::{utils, Error, Result};
-use std::{mem, sync::Arc};
-use tokio::{fs::File, io::AsyncReadExt, io::AsyncWriteExt};
+use ruma::UserId;
+use std::{io, mem, sync::Arc};
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
+    io::AsyncWriteExt,
+};
 
 pub struct FileMeta {
     pub content_disposition: Option<String>,
@@ -14,9 +19,48 @@ pub struct FileMeta {
 
 pub struct Media {
     pub(super) mediaid_file: Arc<dyn Tree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) userid_mxc: Arc<dyn Tree>,   // UserMxc = UserId + MXC
+    pub(super) mxc_quarantined: Arc<dyn Tree>,
 }
 
 impl Media {
+    /// Remembers that a user uploaded the file with this mxc uri.
+    pub fn record_upload(&self, user_id: &UserId, mxc: &str) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(mxc.as_bytes());
+
+        self.userid_mxc.insert(&key, &[])
+    }
+
+    /// Returns the mxc uris of all files a user uploaded.
+    pub fn uploads_of<'a>(&'a self, user_id: &UserId) -> impl Iterator<Item = Result<String>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.userid_mxc.scan_prefix(prefix).map(|(key, _)| {
+            utils::string_from_bytes(
+                key.rsplit(|&b| b == 0xff)
+                    .next()
+                    .expect("rsplit always returns an element"),
+            )
+            .map_err(|_| Error::bad_database("Invalid mxc in userid_mxc."))
+        })
+    }
+
+    /// Nobody can download quarantined files until they are released again.
+    pub fn quarantine(&self, mxc: &str) -> Result<()> {
+        self.mxc_quarantined.insert(mxc.as_bytes(), &[])
+    }
+
+    pub fn release(&self, mxc: &str) -> Result<()> {
+        self.mxc_quarantined.remove(mxc.as_bytes())
+    }
+
+    pub fn is_quarantined(&self, mxc: &str) -> Result<bool> {
+        Ok(self.mxc_quarantined.get(mxc.as_bytes())?.is_some())
+    }
+
     /// Uploads a file.
     pub async fn create(
         &self,
@@ -93,6 +137,18 @@ impl Media {
         Ok(())
     }
 
+    /// Returns true if the file is in the media repository, uploaded here or fetched from another
+    /// server.
+    pub fn exists(&self, mxc: &str) -> Result<bool> {
+        let mut prefix = mxc.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(&0_u32.to_be_bytes()); // Width = 0 if it's not a thumbnail
+        prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
+        prefix.push(0xff);
+
+        Ok(self.mediaid_file.scan_prefix(prefix).next().is_some())
+    }
+
     /// Downloads a file.
     pub async fn get(&self, globals: &Globals, mxc: &str) -> Result<Option<FileMeta>> {
         let mut prefix = mxc.as_bytes().to_vec();
@@ -143,6 +199,23 @@ impl Media {
         }
     }
 
+    /// Deletes a file and all of its thumbnails.
+    pub async fn purge(&self, mxc: &str, globals: &Globals) -> Result<()> {
+        let mut prefix = mxc.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for (key, _) in self.mediaid_file.scan_prefix(prefix).collect::<Vec<_>>() {
+            if let Err(e) = fs::remove_file(globals.get_media_file(&key)).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+            self.mediaid_file.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
     /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
     /// the server should send the original file.
     pub fn thumbnail_properties(&self, width: u32, height: u32) -> Option<(u32, u32, bool)> {
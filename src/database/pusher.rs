@@ -243,11 +243,13 @@ pub fn get_actions<'a>(
         notification_power_levels: power_levels.notifications.clone(),
     };
 
+    // Ruma checks sender_notification_permission conditions with the power levels of the
+    // context, so @room only notifies if the sender is allowed to
     Ok(ruleset.get_actions(pdu, &ctx))
 }
 
 #[tracing::instrument(skip(unread, pusher, tweaks, event, db))]
-async fn send_notice(
+pub async fn send_notice(
     unread: UInt,
     pusher: &get_pushers::Pusher,
     tweaks: Vec<Tweak>,
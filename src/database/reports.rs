@@ -0,0 +1,149 @@
+use crate::{utils, Error, Result};
+use ruma::{EventId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::abstraction::Tree;
+
+/// A report of an event by a user of this server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    /// The sender of the reported event.
+    pub sender: UserId,
+    pub reporter: UserId,
+    /// From -100 (most offensive) to 0 (inoffensive).
+    pub score: i64,
+    pub reason: String,
+    /// When the event was reported, in milliseconds since the unix epoch.
+    pub ts: u64,
+}
+
+/// A user whose events enough users reported. The user stays escalated until an admin reviewed
+/// the reports.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Escalation {
+    /// When the user was escalated, in milliseconds since the unix epoch.
+    pub ts: u64,
+    /// The media of the reported events that nobody can download until the review.
+    #[serde(default)]
+    pub quarantined_media: Vec<String>,
+}
+
+pub struct Reports {
+    pub(super) eventidreporter_report: Arc<dyn Tree>, // EventIdReporter = EventId + UserId
+    pub(super) senderreporter: Arc<dyn Tree>,         // SenderReporter = UserId + UserId
+    pub(super) userid_escalation: Arc<dyn Tree>,
+    pub(super) userid_lastresolvedts: Arc<dyn Tree>, // LastResolvedTs = When an admin last resolved the reports
+}
+
+impl Reports {
+    /// Stores a report. Reporting the same event again replaces the old report.
+    ///
+    /// Returns how many different users reported the event and how many reported any event of its
+    /// sender since the last review. Reports from before the last review don't count.
+    pub fn add(&self, report: &Report) -> Result<(usize, usize)> {
+        let last_resolved = self.last_resolved(&report.sender)?;
+
+        let mut event_prefix = report.event_id.as_bytes().to_vec();
+        event_prefix.push(0xff);
+        let mut event_key = event_prefix.clone();
+        event_key.extend_from_slice(report.reporter.as_bytes());
+
+        self.eventidreporter_report.insert(
+            &event_key,
+            &serde_json::to_vec(report).expect("Report::to_vec always works"),
+        )?;
+
+        let mut sender_prefix = report.sender.as_bytes().to_vec();
+        sender_prefix.push(0xff);
+        let mut sender_key = sender_prefix.clone();
+        sender_key.extend_from_slice(report.reporter.as_bytes());
+
+        self.senderreporter.insert(&sender_key, &[])?;
+
+        let event_reporters = self
+            .eventidreporter_report
+            .scan_prefix(event_prefix)
+            .filter(|(_, value)| {
+                serde_json::from_slice::<Report>(value)
+                    .map_or(false, |report| report.ts > last_resolved)
+            })
+            .count();
+
+        Ok((
+            event_reporters,
+            self.senderreporter.scan_prefix(sender_prefix).count(),
+        ))
+    }
+
+    /// Returns when an admin last resolved the reports of the user, 0 if never.
+    fn last_resolved(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_lastresolvedts
+            .get(user_id.as_bytes())?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid timestamp in userid_lastresolvedts."))
+            })
+    }
+
+    /// Returns all reports of an event.
+    pub fn reports_of_event<'a>(
+        &'a self,
+        event_id: &EventId,
+    ) -> impl Iterator<Item = Result<Report>> + 'a {
+        let mut prefix = event_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.eventidreporter_report
+            .scan_prefix(prefix)
+            .map(|(_, value)| {
+                serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid report in eventidreporter_report."))
+            })
+    }
+
+    /// Returns the escalation of the user, or None if they are not waiting for a review.
+    pub fn escalation(&self, user_id: &UserId) -> Result<Option<Escalation>> {
+        self.userid_escalation
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid escalation in userid_escalation."))
+            })
+            .transpose()
+    }
+
+    /// Whether the events of the user were reported often enough that an admin has to review them.
+    pub fn is_under_review(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_escalation.get(user_id.as_bytes())?.is_some())
+    }
+
+    pub fn escalate(&self, user_id: &UserId, escalation: &Escalation) -> Result<()> {
+        self.userid_escalation.insert(
+            user_id.as_bytes(),
+            &serde_json::to_vec(escalation).expect("Escalation::to_vec always works"),
+        )
+    }
+
+    /// Ends the review of a user and forgets who reported them, so the count starts over. The
+    /// reports of single events are kept, but only newer reports count. Returns the escalation, if
+    /// there was one.
+    pub fn resolve(&self, user_id: &UserId) -> Result<Option<Escalation>> {
+        let escalation = self.escalation(user_id)?;
+        self.userid_escalation.remove(user_id.as_bytes())?;
+        self.userid_lastresolvedts.insert(
+            user_id.as_bytes(),
+            &utils::millis_since_unix_epoch().to_be_bytes(),
+        )?;
+
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        for (key, _) in self.senderreporter.scan_prefix(prefix) {
+            self.senderreporter.remove(&key)?;
+        }
+
+        Ok(escalation)
+    }
+}
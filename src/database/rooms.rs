@@ -1,9 +1,12 @@
 mod edus;
 
-pub use edus::RoomEdus;
+pub use edus::{presence_is_active, RoomEdus, UserPresence, BUSY};
 use member::MembershipState;
 
-use crate::{pdu::PduBuilder, server_server, utils, Database, Error, PduEvent, Result};
+use crate::{
+    pdu::{PduBuilder, DUMMY_EVENT_TYPE},
+    server_server, utils, Database, Error, PduEvent, Result,
+};
 use lru_cache::LruCache;
 use regex::Regex;
 use ring::digest;
@@ -13,26 +16,39 @@ use ruma::{
     events::{
         ignored_user_list, push_rules,
         room::{
-            create::CreateEventContent, member, message, power_levels::PowerLevelsEventContent,
+            create::CreateEventContent,
+            history_visibility::{HistoryVisibility, HistoryVisibilityEventContent},
+            join_rules::{self, AllowRule, JoinRule},
+            member, message,
+            power_levels::PowerLevelsEventContent,
         },
-        AnyStrippedStateEvent, AnySyncStateEvent, EventType,
+        AnyStrippedStateEvent, AnySyncRoomEvent, AnySyncStateEvent, EventType,
     },
     push::{self, Action, Tweak},
     serde::{CanonicalJsonObject, CanonicalJsonValue, Raw},
     state_res::{self, RoomVersion, StateMap},
-    uint, EventId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    uint, DeviceId, EventId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem::size_of,
-    sync::{Arc, Mutex, RwLock},
-    time::Instant,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 use tokio::sync::MutexGuard;
 use tracing::{error, warn};
 
-use super::{abstraction::Tree, admin::AdminCommand, pusher};
+use super::{
+    abstraction::{Transaction, Tree},
+    activity::RoomActivity,
+    admin::AdminCommand,
+    appservice,
+    audit::AuditAction,
+    jobs, pusher,
+};
 
 /// The unique identifier of each state group.
 ///
@@ -41,13 +57,43 @@ use super::{abstraction::Tree, admin::AdminCommand, pusher};
 pub type StateHashId = Vec<u8>;
 pub type CompressedStateEvent = [u8; 2 * size_of::<u64>()];
 
+/// (space, suggested_only, max_depth)
+pub type SpaceHierarchyKey = (RoomId, bool, Option<u64>);
+
+/// A room of a space hierarchy that we have the state of.
+#[derive(Clone, Debug)]
+pub struct SpaceHierarchyRoom {
+    pub room_id: RoomId,
+    pub depth: u64,
+    /// The valid `m.space.child` events of this room, in the order of the spec.
+    pub children_state: Vec<Arc<PduEvent>>,
+}
+
+/// The rooms of a space, in breadth-first order, as they are cached by `Rooms::space_hierarchy`.
+#[derive(Clone, Debug, Default)]
+pub struct SpaceHierarchy {
+    pub rooms: Vec<SpaceHierarchyRoom>,
+    /// Child rooms we don't have the state of, and the servers that should be able to tell us
+    /// about them. Each room only appears once, even if several spaces point to it.
+    pub inaccessible: Vec<(RoomId, Vec<Box<ServerName>>)>,
+}
+
+impl SpaceHierarchy {
+    pub fn contains(&self, room_id: &RoomId) -> bool {
+        self.rooms.iter().any(|room| &room.room_id == room_id)
+            || self.inaccessible.iter().any(|(id, _)| id == room_id)
+    }
+}
+
 pub struct Rooms {
     pub edus: edus::RoomEdus,
+    pub(super) activity: Arc<RoomActivity>,
     pub(super) pduid_pdu: Arc<dyn Tree>, // PduId = ShortRoomId + Count
     pub(super) eventid_pduid: Arc<dyn Tree>,
     pub(super) roomid_pduleaves: Arc<dyn Tree>,
     pub(super) alias_roomid: Arc<dyn Tree>,
     pub(super) aliasid_alias: Arc<dyn Tree>, // AliasId = RoomId + Count
+    pub(super) alias_userid: Arc<dyn Tree>,  // The user that created the alias
     pub(super) publicroomids: Arc<dyn Tree>,
 
     pub(super) tokenids: Arc<dyn Tree>, // TokenId = ShortRoomId + Token + PduIdCount
@@ -63,8 +109,11 @@ pub struct Rooms {
     pub(super) roomuseroncejoinedids: Arc<dyn Tree>,
     pub(super) userroomid_invitestate: Arc<dyn Tree>, // InviteState = Vec<Raw<Pdu>>
     pub(super) roomuserid_invitecount: Arc<dyn Tree>, // InviteCount = Count
+    pub(super) roomuserid_knockcount: Arc<dyn Tree>,  // KnockCount = Count
+    pub(super) userroomid_knockstate: Arc<dyn Tree>,  // KnockState = Vec<Raw<Pdu>>
     pub(super) userroomid_leftstate: Arc<dyn Tree>,
     pub(super) roomuserid_leftcount: Arc<dyn Tree>,
+    pub(super) roomchangeid_membership: Arc<dyn Tree>, // RoomChangeId = RoomId + Count + UserId
 
     pub(super) userroomid_notificationcount: Arc<dyn Tree>, // NotifyCount = u64
     pub(super) userroomid_highlightcount: Arc<dyn Tree>,    // HightlightCount = u64
@@ -91,11 +140,47 @@ pub struct Rooms {
     /// RoomId + EventId -> outlier PDU.
     /// Any pdu that has passed the steps 1-8 in the incoming event /federation/send/txn.
     pub(super) eventid_outlierpdu: Arc<dyn Tree>,
-    pub(super) softfailedeventids: Arc<dyn Tree>,
+    pub(super) softfailedeventids: Arc<dyn Tree>, // Value = Reason
+    pub(super) eventid_rejectionreason: Arc<dyn Tree>,
+    /// Origin + EventId -> Timestamp + Reason, remote events whose copy from the origin failed
+    /// validation.
+    pub(super) origineventid_invalidity: Arc<dyn Tree>,
+
+    /// RestrictedRoomId + AllowRoomId, the members of AllowRoomId may join RestrictedRoomId.
+    pub(super) restrictedroomid_allowroomids: Arc<dyn Tree>,
+    pub(super) allowroomid_restrictedroomids: Arc<dyn Tree>,
+
+    /// RoomId + Day -> Number of non-state events sent that day.
+    pub(super) roomidday_messagecount: Arc<dyn Tree>,
+    /// RoomId + Day + UserId, the user sent at least one non-state event that day.
+    pub(super) roomiddayuserid: Arc<dyn Tree>,
 
     /// RoomId + EventId -> Parent PDU EventId.
     pub(super) referencedevents: Arc<dyn Tree>,
 
+    /// EventId + Key + UserId -> EventId of the reaction.
+    pub(super) annotationid_reactionid: Arc<dyn Tree>,
+    /// EventId of the reaction -> AnnotationId.
+    pub(super) reactionid_annotationid: Arc<dyn Tree>,
+    /// EventId + Key -> Number of users that reacted with this key.
+    pub(super) annotationkey_count: Arc<dyn Tree>,
+
+    /// RelationId = EventId + PduId of the relating event -> RelType.
+    pub(super) relationid_reltype: Arc<dyn Tree>,
+    /// TypedRelationId = EventId + RelType + PduId of the relating event, so the relations of one
+    /// type can be read without the others.
+    pub(super) typedrelationid: Arc<dyn Tree>,
+    /// EventId -> UserId of the sender, for events in the timeline.
+    pub(super) eventid_sender: Arc<dyn Tree>,
+
+    /// RootEventId -> ThreadId = PduId of the latest event in the thread.
+    pub(super) eventid_threadid: Arc<dyn Tree>,
+    pub(super) threadid_eventid: Arc<dyn Tree>,
+    /// RootEventId -> Count of replies in the thread.
+    pub(super) eventid_threadcount: Arc<dyn Tree>,
+    /// RootEventId + UserId, the user sent the root or a reply of the thread.
+    pub(super) threaduserids: Arc<dyn Tree>,
+
     pub(super) pdu_cache: Mutex<LruCache<EventId, Arc<PduEvent>>>,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
     pub(super) auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<HashSet<u64>>>>,
@@ -103,7 +188,11 @@ pub struct Rooms {
     pub(super) statekeyshort_cache: Mutex<LruCache<(EventType, String), u64>>,
     pub(super) shortstatekey_cache: Mutex<LruCache<u64, (EventType, String)>>,
     pub(super) our_real_users_cache: RwLock<HashMap<RoomId, Arc<HashSet<UserId>>>>,
-    pub(super) appservice_in_room_cache: RwLock<HashMap<RoomId, HashMap<String, bool>>>,
+    /// Whether users of an appservice are (joined, invited) in a room
+    pub(super) appservice_in_room_cache: RwLock<HashMap<RoomId, HashMap<String, (bool, bool)>>>,
+    pub(super) restricted_join_cache: Mutex<LruCache<(UserId, RoomId), bool>>,
+    pub(super) statepdus_cache: Mutex<LruCache<u64, Arc<Vec<Arc<PduEvent>>>>>,
+    pub(super) space_hierarchy_cache: Mutex<LruCache<SpaceHierarchyKey, Arc<SpaceHierarchy>>>,
     pub(super) stateinfo_cache: Mutex<
         LruCache<
             u64,
@@ -165,6 +254,36 @@ impl Rooms {
             .collect())
     }
 
+    /// Returns all state events of the given state hash. The result is cached, so syncs that
+    /// need the complete state don't read every event from the database again.
+    #[tracing::instrument(skip(self))]
+    pub fn state_full_pdus(&self, shortstatehash: u64) -> Result<Arc<Vec<Arc<PduEvent>>>> {
+        if let Some(pdus) = self
+            .statepdus_cache
+            .lock()
+            .unwrap()
+            .get_mut(&shortstatehash)
+        {
+            return Ok(Arc::clone(pdus));
+        }
+
+        // Don't cache incomplete states when reading an event fails
+        let pdus = Arc::new(
+            self.state_full_ids(shortstatehash)?
+                .values()
+                .map(|id| self.get_pdu(id))
+                .filter_map(|r| r.transpose())
+                .collect::<Result<Vec<_>>>()?,
+        );
+
+        self.statepdus_cache
+            .lock()
+            .unwrap()
+            .insert(shortstatehash, Arc::clone(&pdus));
+
+        Ok(pdus)
+    }
+
     /// Returns a single PDU from `room_id` with key (`event_type`, `state_key`).
     #[tracing::instrument(skip(self))]
     pub fn state_get_id(
@@ -252,13 +371,21 @@ impl Rooms {
                 return Ok(HashMap::new());
             };
 
-        let auth_events = state_res::auth_types_for_event(
+        let mut auth_events = state_res::auth_types_for_event(
             kind,
             sender,
             state_key.map(|s| s.to_string()),
             content.clone(),
         );
 
+        // Knocks are only allowed if the join rules say so
+        if kind == &EventType::RoomMember
+            && content.get("membership").and_then(|m| m.as_str()) == Some("knock")
+            && !auth_events.contains(&(EventType::RoomJoinRules, "".to_owned()))
+        {
+            auth_events.push((EventType::RoomJoinRules, "".to_owned()));
+        }
+
         let mut sauthevents = auth_events
             .into_iter()
             .filter_map(|(event_type, state_key)| {
@@ -433,6 +560,7 @@ impl Rooms {
 
         self.roomid_shortstatehash
             .insert(room_id.as_bytes(), &new_shortstatehash.to_be_bytes())?;
+        self.activity.bump(room_id, db.globals.next_count()?)?;
 
         Ok(())
     }
@@ -958,6 +1086,20 @@ impl Rooms {
         }
     }
 
+    /// Returns the version of the room from its create event, or None if the room is unknown.
+    #[tracing::instrument(skip(self))]
+    pub fn room_version(&self, room_id: &RoomId) -> Result<Option<RoomVersionId>> {
+        self.room_state_get(room_id, &EventType::RoomCreate, "")?
+            .map(|create_event| {
+                serde_json::from_value::<Raw<CreateEventContent>>(create_event.content.clone())
+                    .expect("Raw::from_value always works.")
+                    .deserialize()
+                    .map(|content| content.room_version)
+                    .map_err(|_| Error::bad_database("Invalid create event in db."))
+            })
+            .transpose()
+    }
+
     /// Returns the `count` of this pdu's id.
     #[tracing::instrument(skip(self))]
     pub fn pdu_count(&self, pdu_id: &[u8]) -> Result<u64> {
@@ -1078,7 +1220,8 @@ impl Rooms {
 
     /// Returns the pdu.
     ///
-    /// Checks the `eventid_outlierpdu` Tree if not found in the timeline.
+    /// Checks the `eventid_outlierpdu` Tree if not found in the timeline. Rejected events are
+    /// left out, see `get_pdu_including_rejected`.
     #[tracing::instrument(skip(self))]
     pub fn get_pdu(&self, event_id: &EventId) -> Result<Option<Arc<PduEvent>>> {
         if let Some(p) = self.pdu_cache.lock().unwrap().get_mut(&event_id) {
@@ -1090,8 +1233,11 @@ impl Rooms {
             .get(event_id.as_bytes())?
             .map_or_else::<Result<_>, _, _>(
                 || {
-                    let r = self.eventid_outlierpdu.get(event_id.as_bytes());
-                    r
+                    // Rejected events are only ever stored as outliers
+                    if self.is_event_rejected(event_id)? {
+                        return Ok(None);
+                    }
+                    self.eventid_outlierpdu.get(event_id.as_bytes())
                 },
                 |pduid| {
                     Ok(Some(self.pduid_pdu.get(&pduid)?.ok_or_else(|| {
@@ -1116,6 +1262,26 @@ impl Rooms {
         }
     }
 
+    /// Returns the pdu, even if it was rejected. Rejected events can't be part of the timeline or
+    /// the state, but they are still needed to resolve auth chains and to inspect them.
+    ///
+    /// Rejected events are not cached.
+    #[tracing::instrument(skip(self))]
+    pub fn get_pdu_including_rejected(&self, event_id: &EventId) -> Result<Option<Arc<PduEvent>>> {
+        if let Some(pdu) = self.get_pdu(event_id)? {
+            return Ok(Some(pdu));
+        }
+
+        self.eventid_outlierpdu
+            .get(event_id.as_bytes())?
+            .map(|pdu| {
+                serde_json::from_slice(&pdu)
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))
+                    .map(Arc::new)
+            })
+            .transpose()
+    }
+
     /// Returns the pdu.
     ///
     /// This does __NOT__ check the outliers `Tree`.
@@ -1174,6 +1340,106 @@ impl Rooms {
             .collect()
     }
 
+    /// Splits the leaves of a room into the prev_events of a new event and the leaves that will not
+    /// be referenced.
+    ///
+    /// The most recent leaves (by depth) are preferred.
+    #[tracing::instrument(skip(self))]
+    pub fn select_prev_events(
+        &self,
+        room_id: &RoomId,
+        max: usize,
+    ) -> Result<(Vec<EventId>, Vec<EventId>)> {
+        let mut leaves = self
+            .get_pdu_leaves(room_id)?
+            .into_iter()
+            .map(|event_id| {
+                let depth = self.get_pdu(&event_id)?.map(|pdu| pdu.depth);
+                Ok((depth, event_id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        leaves.sort_by(|a, b| b.cmp(a));
+
+        let mut prev_events = leaves
+            .into_iter()
+            .map(|(_, event_id)| event_id)
+            .collect::<Vec<_>>();
+        let unreferenced = prev_events.split_off(max.min(prev_events.len()));
+
+        Ok((prev_events, unreferenced))
+    }
+
+    /// Returns the number of forward extremities of every room that has more than one.
+    #[tracing::instrument(skip(self))]
+    pub fn forward_extremity_counts(&self) -> Result<HashMap<RoomId, usize>> {
+        let mut counts = HashMap::new();
+
+        for (key, _) in self.roomid_pduleaves.iter() {
+            let room_id = key
+                .splitn(2, |&b| b == 0xff)
+                .next()
+                .expect("splitn always returns at least one element");
+            let room_id = RoomId::try_from(utils::string_from_bytes(room_id).map_err(|_| {
+                Error::bad_database("RoomId in roomid_pduleaves is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("RoomId in roomid_pduleaves is invalid."))?;
+
+            *counts.entry(room_id).or_default() += 1;
+        }
+
+        counts.retain(|_, count| *count > 1);
+
+        Ok(counts)
+    }
+
+    /// Sends a dummy event that references the forward extremities of the room if it has more
+    /// than `max_forward_extremities`.
+    ///
+    /// Many extremities make state resolution expensive for us and for everyone else in the room.
+    /// This needs a local user in the room who can send events.
+    #[tracing::instrument(skip(self, db, mutex_lock))]
+    pub fn merge_forward_extremities(
+        &self,
+        room_id: &RoomId,
+        db: &Database,
+        mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room mutex
+    ) -> Result<bool> {
+        if self.get_pdu_leaves(room_id)?.len() <= db.globals.max_forward_extremities() as usize {
+            return Ok(false);
+        }
+
+        let sender = match self
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .find(|user_id| user_id.server_name() == db.globals.server_name())
+        {
+            Some(sender) => sender,
+            None => return Ok(false),
+        };
+
+        self.build_and_append_pdu(
+            PduBuilder {
+                event_type: EventType::from(DUMMY_EVENT_TYPE),
+                content: serde_json::json!({}),
+                unsigned: None,
+                state_key: None,
+                redacts: None,
+            },
+            &sender,
+            room_id,
+            db,
+            mutex_lock,
+        )?;
+
+        db.globals
+            .metrics
+            .dummy_events_sent
+            .fetch_add(1, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
     #[tracing::instrument(skip(self, room_id, event_ids))]
     pub fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[EventId]) -> Result<()> {
         for prev in event_ids {
@@ -1236,8 +1502,9 @@ impl Rooms {
     }
 
     #[tracing::instrument(skip(self))]
-    pub fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()> {
-        self.softfailedeventids.insert(&event_id.as_bytes(), &[])
+    pub fn mark_event_soft_failed(&self, event_id: &EventId, reason: &str) -> Result<()> {
+        self.softfailedeventids
+            .insert(&event_id.as_bytes(), reason.as_bytes())
     }
 
     #[tracing::instrument(skip(self))]
@@ -1247,6 +1514,250 @@ impl Rooms {
             .map(|o| o.is_some())
     }
 
+    /// Returns why the event was soft failed. Events that were soft failed before the reason was
+    /// recorded return an empty string.
+    #[tracing::instrument(skip(self))]
+    pub fn soft_fail_reason(&self, event_id: &EventId) -> Result<Option<String>> {
+        self.softfailedeventids
+            .get(&event_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid soft fail reason in db."))
+            })
+            .transpose()
+    }
+
+    /// Persists an event that was rejected by the auth rules.
+    ///
+    /// The event is kept as an outlier, so it can be inspected and is available when resolving
+    /// auth chains, but it will never become part of the timeline or the room state.
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn reject_pdu(
+        &self,
+        event_id: &EventId,
+        pdu: &CanonicalJsonObject,
+        reason: &str,
+    ) -> Result<()> {
+        self.add_pdu_outlier(event_id, pdu)?;
+        self.eventid_rejectionreason
+            .insert(event_id.as_bytes(), reason.as_bytes())?;
+        self.pdu_cache.lock().unwrap().remove(event_id);
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn is_event_rejected(&self, event_id: &EventId) -> Result<bool> {
+        Ok(self
+            .eventid_rejectionreason
+            .get(event_id.as_bytes())?
+            .is_some())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn rejection_reason(&self, event_id: &EventId) -> Result<Option<String>> {
+        self.eventid_rejectionreason
+            .get(event_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid rejection reason in db."))
+            })
+            .transpose()
+    }
+
+    /// Remembers that the copy of a remote event `origin` sent failed validation, e.g. because it
+    /// isn't canonical JSON, so it isn't fetched from `origin` and checked again. Other servers
+    /// may still have a valid copy.
+    #[tracing::instrument(skip(self))]
+    pub fn mark_event_invalid(
+        &self,
+        origin: &ServerName,
+        event_id: &EventId,
+        reason: &str,
+    ) -> Result<()> {
+        let mut value = utils::millis_since_unix_epoch().to_be_bytes().to_vec();
+        value.extend_from_slice(reason.as_bytes());
+
+        self.origineventid_invalidity
+            .insert(&origin_event_id(origin, event_id), &value)
+    }
+
+    /// Returns why the copy of an event from `origin` failed validation, if that was less than
+    /// `ttl` ago. Older entries are forgotten, so the event is checked again.
+    #[tracing::instrument(skip(self))]
+    pub fn invalidity_reason(
+        &self,
+        origin: &ServerName,
+        event_id: &EventId,
+        ttl: Duration,
+    ) -> Result<Option<String>> {
+        let key = origin_event_id(origin, event_id);
+
+        let value = match self.origineventid_invalidity.get(&key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        if value.len() < size_of::<u64>() {
+            return Err(Error::bad_database(
+                "Invalid entry in origineventid_invalidity.",
+            ));
+        }
+
+        let ts = utils::u64_from_bytes(&value[..size_of::<u64>()])
+            .map_err(|_| Error::bad_database("Invalid timestamp in origineventid_invalidity."))?;
+
+        if utils::millis_since_unix_epoch().saturating_sub(ts) > ttl.as_millis() as u64 {
+            self.origineventid_invalidity.remove(&key)?;
+            return Ok(None);
+        }
+
+        utils::string_from_bytes(&value[size_of::<u64>()..])
+            .map(Some)
+            .map_err(|_| Error::bad_database("Invalid reason in origineventid_invalidity."))
+    }
+
+    /// Forgets all events that failed validation more than `ttl` ago. Returns how many were
+    /// removed.
+    #[tracing::instrument(skip(self))]
+    pub fn prune_invalid_events(&self, ttl: Duration) -> Result<usize> {
+        let cutoff = utils::millis_since_unix_epoch().saturating_sub(ttl.as_millis() as u64);
+
+        let outdated = self
+            .origineventid_invalidity
+            .iter()
+            .filter(|(_, value)| {
+                value
+                    .get(..size_of::<u64>())
+                    .and_then(|ts| utils::u64_from_bytes(ts).ok())
+                    .map_or(true, |ts| ts < cutoff)
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in &outdated {
+            self.origineventid_invalidity.remove(key)?;
+        }
+
+        Ok(outdated.len())
+    }
+
+    /// Returns whether an event notifies the user and whether it is highlighted, according to the
+    /// push rules of the user.
+    #[tracing::instrument(skip(self, sync_pdu, power_levels, db))]
+    pub(super) fn notify_and_highlight(
+        &self,
+        user: &UserId,
+        sync_pdu: &Raw<AnySyncRoomEvent>,
+        room_id: &RoomId,
+        power_levels: &PowerLevelsEventContent,
+        db: &Database,
+    ) -> Result<(bool, bool)> {
+        let rules_for_user = db
+            .account_data
+            .get::<push_rules::PushRulesEvent>(None, user, EventType::PushRules)?
+            .map(|ev| ev.content.global)
+            .unwrap_or_else(|| push::Ruleset::server_default(user));
+
+        let mut highlight = false;
+        let mut notify = false;
+
+        for action in
+            pusher::get_actions(user, &rules_for_user, power_levels, sync_pdu, room_id, db)?
+        {
+            match action {
+                Action::DontNotify => notify = false,
+                // TODO: Implement proper support for coalesce
+                Action::Notify | Action::Coalesce => notify = true,
+                Action::SetTweak(Tweak::Highlight(true)) => {
+                    highlight = true;
+                }
+                _ => {}
+            };
+        }
+
+        Ok((notify, highlight))
+    }
+
+    /// Adds a pdu from a room archive to the timeline.
+    ///
+    /// Unlike `append_pdu` this does not notify anyone, update the leaves or the membership
+    /// indices. Those are restored from the archive after all pdus have been added.
+    #[tracing::instrument(skip(self, pdu, pdu_json, globals))]
+    pub fn append_imported_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &CanonicalJsonObject,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        let shortroomid = self.get_shortroomid(&pdu.room_id)?.expect("room exists");
+
+        self.mark_as_referenced(&pdu.room_id, &pdu.prev_events)?;
+
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&globals.next_count()?.to_be_bytes());
+
+        let mut transaction = Transaction::default();
+        transaction.insert(
+            &self.pduid_pdu,
+            &pdu_id,
+            &serde_json::to_vec(pdu_json).expect("CanonicalJsonObject is always a valid"),
+        );
+        transaction.insert(&self.eventid_pduid, pdu.event_id.as_bytes(), &pdu_id);
+        transaction.remove(&self.eventid_outlierpdu, pdu.event_id.as_bytes());
+        transaction.insert(
+            &self.eventid_sender,
+            pdu.event_id.as_bytes(),
+            pdu.sender.as_bytes(),
+        );
+        if let Some((target, rel_type)) = parse_relation(&pdu.content) {
+            transaction.insert(
+                &self.relationid_reltype,
+                &relation_id(&target, &pdu_id),
+                rel_type.as_bytes(),
+            );
+            transaction.insert(
+                &self.typedrelationid,
+                &typed_relation_id(&target, &rel_type, &pdu_id),
+                &[],
+            );
+        }
+        transaction.commit()?;
+
+        if pdu.kind == EventType::RoomMessage {
+            if let Some(body) = pdu.content.get("body").and_then(|b| b.as_str()) {
+                self.index_message_body(shortroomid, &pdu_id, body)?;
+            }
+        }
+
+        self.add_thread_reply(pdu, &pdu_id)?;
+
+        Ok(())
+    }
+
+    /// Adds the words of a message to the search index.
+    pub(super) fn index_message_body(
+        &self,
+        shortroomid: u64,
+        pdu_id: &[u8],
+        body: &str,
+    ) -> Result<()> {
+        let mut batch = body
+            .split_terminator(|c: char| !c.is_alphanumeric())
+            .filter(|s| !s.is_empty())
+            .filter(|word| word.len() <= 50)
+            .map(str::to_lowercase)
+            .map(|word| {
+                let mut key = shortroomid.to_be_bytes().to_vec();
+                key.extend_from_slice(word.as_bytes());
+                key.push(0xff);
+                key.extend_from_slice(pdu_id);
+                (key, Vec::new())
+            });
+
+        self.tokenids.insert_batch(&mut batch)
+    }
+
     /// Creates a new persisted data unit and adds it to a room.
     ///
     /// By this point the incoming event should be fully authenticated, no auth happens
@@ -1294,43 +1805,6 @@ impl Rooms {
         self.mark_as_referenced(&pdu.room_id, &pdu.prev_events)?;
         self.replace_pdu_leaves(&pdu.room_id, leaves)?;
 
-        let mutex_insert = Arc::clone(
-            db.globals
-                .roomid_mutex_insert
-                .write()
-                .unwrap()
-                .entry(pdu.room_id.clone())
-                .or_default(),
-        );
-        let insert_lock = mutex_insert.lock().unwrap();
-
-        let count1 = db.globals.next_count()?;
-        // Mark as read first so the sending client doesn't get a notification even if appending
-        // fails
-        self.edus
-            .private_read_set(&pdu.room_id, &pdu.sender, count1, &db.globals)?;
-        self.reset_notification_counts(&pdu.sender, &pdu.room_id)?;
-
-        let count2 = db.globals.next_count()?;
-        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
-        pdu_id.extend_from_slice(&count2.to_be_bytes());
-
-        // There's a brief moment of time here where the count is updated but the pdu does not
-        // exist. This could theoretically lead to dropped pdus, but it's extremely rare
-        //
-        // Update: We fixed this using insert_lock
-
-        self.pduid_pdu.insert(
-            &pdu_id,
-            &serde_json::to_vec(&pdu_json).expect("CanonicalJsonObject is always a valid"),
-        )?;
-
-        self.eventid_pduid
-            .insert(pdu.event_id.as_bytes(), &pdu_id)?;
-        self.eventid_outlierpdu.remove(pdu.event_id.as_bytes())?;
-
-        drop(insert_lock);
-
         // See if the event matches any known pushers
         let power_levels: PowerLevelsEventContent = db
             .rooms
@@ -1346,6 +1820,7 @@ impl Rooms {
 
         let mut notifies = Vec::new();
         let mut highlights = Vec::new();
+        let mut push_senderkeys = Vec::new();
 
         for user in self.get_our_real_users(&pdu.room_id, db)?.iter() {
             // Don't notify the user of their own events
@@ -1353,33 +1828,8 @@ impl Rooms {
                 continue;
             }
 
-            let rules_for_user = db
-                .account_data
-                .get::<push_rules::PushRulesEvent>(None, &user, EventType::PushRules)?
-                .map(|ev| ev.content.global)
-                .unwrap_or_else(|| push::Ruleset::server_default(&user));
-
-            let mut highlight = false;
-            let mut notify = false;
-
-            for action in pusher::get_actions(
-                &user,
-                &rules_for_user,
-                &power_levels,
-                &sync_pdu,
-                &pdu.room_id,
-                db,
-            )? {
-                match action {
-                    Action::DontNotify => notify = false,
-                    // TODO: Implement proper support for coalesce
-                    Action::Notify | Action::Coalesce => notify = true,
-                    Action::SetTweak(Tweak::Highlight(true)) => {
-                        highlight = true;
-                    }
-                    _ => {}
-                };
-            }
+            let (notify, highlight) =
+                self.notify_and_highlight(&user, &sync_pdu, &pdu.room_id, &power_levels, db)?;
 
             let mut userroom_id = user.as_bytes().to_vec();
             userroom_id.push(0xff);
@@ -1393,22 +1843,118 @@ impl Rooms {
                 highlights.push(userroom_id);
             }
 
-            for senderkey in db.pusher.get_pusher_senderkeys(&user) {
-                db.sending.send_push_pdu(&*pdu_id, senderkey)?;
-            }
+            push_senderkeys.extend(db.pusher.get_pusher_senderkeys(&user));
         }
 
-        self.userroomid_notificationcount
-            .increment_batch(&mut notifies.into_iter())?;
-        self.userroomid_highlightcount
-            .increment_batch(&mut highlights.into_iter())?;
+        let mutex_insert = Arc::clone(
+            db.globals
+                .roomid_mutex_insert
+                .write()
+                .unwrap()
+                .entry(pdu.room_id.clone())
+                .or_default(),
+        );
+        let insert_lock = mutex_insert.lock().unwrap();
 
-        match pdu.kind {
-            EventType::RoomRedaction => {
-                if let Some(redact_id) = &pdu.redacts {
-                    self.redact_pdu(&redact_id, &pdu)?;
-                }
-            }
+        let count1 = db.globals.next_count()?;
+        // Mark as read first so the sending client doesn't get a notification even if appending
+        // fails
+        self.edus
+            .private_read_set(&pdu.room_id, &pdu.sender, count1, &db.globals)?;
+        self.reset_notification_counts(&pdu.sender, &pdu.room_id)?;
+
+        // Replicas only see counts up to the oldest pdu that is not committed yet
+        let appending_count = db.globals.next_appending_count()?;
+        let count2 = appending_count.count;
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&count2.to_be_bytes());
+
+        // Sync waits for the insert lock before looking at the activity, so it can't miss this
+        self.activity.bump(&pdu.room_id, count2)?;
+
+        // There's a brief moment of time here where the count is updated but the pdu does not
+        // exist. This could theoretically lead to dropped pdus, but it's extremely rare
+        //
+        // Update: We fixed this using insert_lock
+
+        // The pdu, its indices and the notification counts are written together, so a crash can't
+        // leave behind a pdu without an event id or notifications for a missing pdu
+        let mut transaction = Transaction::default();
+        transaction.insert(
+            &self.pduid_pdu,
+            &pdu_id,
+            &serde_json::to_vec(&pdu_json).expect("CanonicalJsonObject is always a valid"),
+        );
+        transaction.insert(&self.eventid_pduid, pdu.event_id.as_bytes(), &pdu_id);
+        transaction.remove(&self.eventid_outlierpdu, pdu.event_id.as_bytes());
+        transaction.insert(
+            &self.eventid_sender,
+            pdu.event_id.as_bytes(),
+            pdu.sender.as_bytes(),
+        );
+        if let Some((target, rel_type)) = parse_relation(&pdu.content) {
+            transaction.insert(
+                &self.relationid_reltype,
+                &relation_id(&target, &pdu_id),
+                rel_type.as_bytes(),
+            );
+            transaction.insert(
+                &self.typedrelationid,
+                &typed_relation_id(&target, &rel_type, &pdu_id),
+                &[],
+            );
+        }
+        for userroom_id in &notifies {
+            transaction.increment(&self.userroomid_notificationcount, userroom_id);
+        }
+        for userroom_id in &highlights {
+            transaction.increment(&self.userroomid_highlightcount, userroom_id);
+        }
+        transaction.commit()?;
+
+        drop(appending_count);
+        drop(insert_lock);
+
+        if pdu.state_key.is_none() {
+            self.update_message_stats(&pdu.room_id, &pdu.sender)?;
+        }
+
+        for senderkey in push_senderkeys {
+            db.sending.send_push_pdu(&*pdu_id, senderkey)?;
+        }
+
+        self.add_thread_reply(&pdu, &pdu_id)?;
+
+        match pdu.kind {
+            EventType::RoomRedaction => {
+                if let Some(redact_id) = &pdu.redacts {
+                    // Since room version 3 the auth rules accept every redaction, whether it
+                    // takes effect is decided here
+                    if self.redaction_allowed(
+                        &pdu.room_id,
+                        &pdu.sender,
+                        pdu.event_id.server_name(),
+                        redact_id,
+                        &power_levels,
+                    )? {
+                        // The relation is gone with the content, so look it up first
+                        self.remove_relation(&redact_id)?;
+                        self.redact_pdu(&redact_id, &pdu)?;
+                        self.remove_annotation(&redact_id)?;
+                    } else {
+                        warn!("Not applying redaction {} of {}", pdu.event_id, redact_id);
+                    }
+                }
+            }
+            EventType::Reaction => {
+                self.add_annotation(&pdu)?;
+            }
+            EventType::RoomJoinRules => {
+                self.update_restricted_allow_rooms(&pdu.room_id, &pdu.content)?;
+            }
+            EventType::SpaceChild => {
+                self.invalidate_space_hierarchies(&pdu.room_id);
+            }
             EventType::RoomMember => {
                 if let Some(state_key) = &pdu.state_key {
                     // if the state_key fails
@@ -1431,8 +1977,9 @@ impl Rooms {
                         )
                     })?;
 
+                    // Knocking users see the same stripped state as invited users
                     let invite_state = match membership {
-                        member::MembershipState::Invite => {
+                        member::MembershipState::Invite | member::MembershipState::Knock => {
                             let state = self.calculate_invite_state(pdu)?;
 
                             Some(state)
@@ -1440,6 +1987,8 @@ impl Rooms {
                         _ => None,
                     };
 
+                    let is_join = matches!(membership, member::MembershipState::Join);
+
                     // Update our membership info, we do this here incase a user is invited
                     // and immediately leaves we need the DB to record the invite event for auth
                     self.update_membership(
@@ -1451,24 +2000,27 @@ impl Rooms {
                         db,
                         true,
                     )?;
+
+                    // Remote users are in the user directory while we share a room with them
+                    if target_user_id.server_name() != db.globals.server_name() {
+                        if is_join {
+                            db.users.set_directory_entry(
+                                &target_user_id,
+                                pdu.content.get("displayname").and_then(|d| d.as_str()),
+                            )?;
+                        } else if self.rooms_joined(&target_user_id).next().is_none() {
+                            db.users.remove_directory_entry(&target_user_id)?;
+                        }
+                    } else if is_join {
+                        // We might not have known the room before, so spaces that point to it
+                        // could have more rooms now
+                        self.invalidate_space_hierarchies(&pdu.room_id);
+                    }
                 }
             }
             EventType::RoomMessage => {
                 if let Some(body) = pdu.content.get("body").and_then(|b| b.as_str()) {
-                    let mut batch = body
-                        .split_terminator(|c: char| !c.is_alphanumeric())
-                        .filter(|s| !s.is_empty())
-                        .filter(|word| word.len() <= 50)
-                        .map(str::to_lowercase)
-                        .map(|word| {
-                            let mut key = shortroomid.to_be_bytes().to_vec();
-                            key.extend_from_slice(word.as_bytes());
-                            key.push(0xff);
-                            key.extend_from_slice(&pdu_id);
-                            (key, Vec::new())
-                        });
-
-                    self.tokenids.insert_batch(&mut batch)?;
+                    self.index_message_body(shortroomid, &pdu_id, body)?;
 
                     if body.starts_with(&format!("@conduit:{}: ", db.globals.server_name()))
                         && self
@@ -1488,6 +2040,19 @@ impl Rooms {
                         if let Some(command) = parts.next() {
                             let args = parts.collect::<Vec<_>>();
 
+                            if let Err(e) = db.audit.record(
+                                &db.globals,
+                                AuditAction::AdminCommand {
+                                    sender: pdu.sender.clone(),
+                                    command: format!("{} {}", command, args.join(" "))
+                                        .trim_end()
+                                        .to_owned(),
+                                },
+                                None,
+                            ) {
+                                warn!("Failed to record admin command in the audit log: {}", e);
+                            }
+
                             match command {
                                 "register_appservice" => {
                                     if body.len() > 2
@@ -1525,6 +2090,507 @@ impl Rooms {
                                 "list_appservices" => {
                                     db.admin.send(AdminCommand::ListAppservices);
                                 }
+                                "get_room_state" => {
+                                    let room_id =
+                                        args.get(0).and_then(|arg| RoomId::try_from(*arg).ok());
+                                    let limit = args
+                                        .get(1)
+                                        .map_or(Some(20), |arg| arg.parse::<usize>().ok());
+
+                                    match (room_id, limit) {
+                                        (Some(room_id), Some(limit))
+                                            if db.rooms.exists(&room_id)? =>
+                                        {
+                                            let mut state = db
+                                                .rooms
+                                                .room_state_full(&room_id)?
+                                                .into_iter()
+                                                .map(|(_, pdu)| pdu)
+                                                .collect::<Vec<_>>();
+                                            state.sort_by_key(|pdu| {
+                                                (pdu.kind.to_string(), pdu.state_key.clone())
+                                            });
+                                            let state = state
+                                                .iter()
+                                                .map(|pdu| {
+                                                    serde_json::to_value(&**pdu)
+                                                        .expect("PduEvent is valid json")
+                                                })
+                                                .collect::<Vec<_>>();
+
+                                            let mut forward_extremities = db
+                                                .rooms
+                                                .get_pdu_leaves(&room_id)?
+                                                .into_iter()
+                                                .collect::<Vec<_>>();
+                                            forward_extremities.sort();
+
+                                            // Only the DAG structure, the full events can be
+                                            // loaded with get_pdu
+                                            let recent_events = db
+                                                .rooms
+                                                .pdus_until(&pdu.sender, &room_id, u64::MAX)?
+                                                .filter_map(|r| r.ok())
+                                                .take(limit)
+                                                .map(|(_, pdu)| {
+                                                    serde_json::json!({
+                                                        "event_id": pdu.event_id,
+                                                        "type": pdu.kind,
+                                                        "sender": pdu.sender,
+                                                        "origin_server_ts": pdu.origin_server_ts,
+                                                        "depth": pdu.depth,
+                                                        "prev_events": pdu.prev_events,
+                                                    })
+                                                })
+                                                .collect::<Vec<_>>();
+
+                                            let json_text =
+                                                serde_json::to_string_pretty(&serde_json::json!({
+                                                    "room_id": room_id,
+                                                    "state": state,
+                                                    "forward_extremities": forward_extremities,
+                                                    "recent_events": recent_events,
+                                                }))
+                                                .expect("json is valid");
+
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_html(
+                                                    format!("```json\n{}\n```", json_text),
+                                                    format!(
+                                                        "<pre><code class=\"language-json\">{}\n</code></pre>\n",
+                                                        RawStr::new(&json_text).html_escape()
+                                                    ),
+                                                ),
+                                            ));
+                                        }
+                                        (Some(_), Some(_)) => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Room not found.",
+                                                ),
+                                            ));
+                                        }
+                                        _ => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: get_room_state <roomid> [number of recent events]",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "redact_user_events" => {
+                                    let user_id =
+                                        args.get(0).and_then(|arg| UserId::try_from(*arg).ok());
+                                    let room_id = match args.get(1) {
+                                        Some(&"*") => Some(None),
+                                        Some(arg) => RoomId::try_from(*arg).ok().map(Some),
+                                        None => None,
+                                    };
+                                    let since = args.get(2).map_or(Some(None), |arg| {
+                                        arg.parse::<u64>().ok().map(|hours| {
+                                            Some(utils::millis_since_unix_epoch().saturating_sub(
+                                                hours.saturating_mul(60 * 60 * 1000),
+                                            ))
+                                        })
+                                    });
+
+                                    match (user_id, room_id, since) {
+                                        (Some(user_id), Some(room_id), Some(since)) => {
+                                            db.admin.send(AdminCommand::RedactUserEvents {
+                                                user_id,
+                                                room_id,
+                                                since,
+                                            });
+                                        }
+                                        _ => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: redact_user_events <userid> <roomid|*> [only events of the last n hours]",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "export_room" => {
+                                    match args.get(0).and_then(|arg| RoomId::try_from(*arg).ok()) {
+                                        Some(room_id) => {
+                                            db.admin.send(AdminCommand::ExportRoom(room_id));
+                                        }
+                                        None => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: export_room <roomid>",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "import_room" => {
+                                    if args.len() == 1 {
+                                        db.admin
+                                            .send(AdminCommand::ImportRoom(PathBuf::from(args[0])));
+                                    } else {
+                                        db.admin.send(AdminCommand::SendMessage(
+                                            message::MessageEventContent::text_plain(
+                                                "Usage: import_room <path to room archive>",
+                                            ),
+                                        ));
+                                    }
+                                }
+                                "export_user_data" => {
+                                    match args.get(0).and_then(|arg| UserId::try_from(*arg).ok()) {
+                                        Some(user_id) => {
+                                            db.admin.send(AdminCommand::ExportUserData(user_id));
+                                        }
+                                        None => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: export_user_data <userid>",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "debug_push" => {
+                                    match args.get(0).and_then(|arg| UserId::try_from(*arg).ok()) {
+                                        Some(user_id) => {
+                                            db.admin.send(AdminCommand::DebugPush(user_id));
+                                        }
+                                        None => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: debug_push <userid>",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "start_job" => {
+                                    match args.get(0).and_then(|arg| jobs::JobKind::from_name(arg))
+                                    {
+                                        Some(kind) => {
+                                            let id = db.jobs.queue(kind, &db.globals)?;
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(format!(
+                                                    "Queued job {}. Use list_jobs to see its progress.",
+                                                    id
+                                                )),
+                                            ));
+                                        }
+                                        None => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: start_job <rebuild_search_index|rebuild_user_directory|recompute_notification_counts|prune_to_device_queues|prune_create_room_txn_ids>",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "list_registrations_from_ip" => {
+                                    let ip = match args.get(0) {
+                                        Some(arg) => match arg.parse::<IpAddr>() {
+                                            Ok(ip) => Some(ip),
+                                            Err(_) => match UserId::try_from(*arg) {
+                                                Ok(user_id) => {
+                                                    db.users.registration_ip(&user_id)?
+                                                }
+                                                Err(_) => None,
+                                            },
+                                        },
+                                        None => None,
+                                    };
+
+                                    let output = match ip {
+                                        Some(ip) => {
+                                            let mut registrations = db
+                                                .users
+                                                .registrations_from_ip(&ip)
+                                                .collect::<Result<Vec<_>>>()?;
+                                            registrations.reverse();
+
+                                            let now = utils::millis_since_unix_epoch();
+                                            format!(
+                                                "Accounts registered from {} ({}), newest first:\n{}",
+                                                ip,
+                                                registrations.len(),
+                                                registrations
+                                                    .iter()
+                                                    .take(100)
+                                                    .map(|(registered_at, user_id)| format!(
+                                                        "{} ({} minutes ago)",
+                                                        user_id,
+                                                        now.saturating_sub(*registered_at) / 60_000
+                                                    ))
+                                                    .collect::<Vec<_>>()
+                                                    .join("\n")
+                                            )
+                                        }
+                                        None => "Usage: list_registrations_from_ip <ip address|user id of an account registered from it>".to_owned(),
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
+                                "list_sessions" => {
+                                    let output = match args
+                                        .get(0)
+                                        .and_then(|arg| UserId::try_from(*arg).ok())
+                                    {
+                                        Some(user_id) => {
+                                            let now = utils::millis_since_unix_epoch();
+                                            let minutes_ago = |ts: Option<u64>| {
+                                                ts.map_or("unknown".to_owned(), |ts| {
+                                                    format!(
+                                                        "{} minutes ago",
+                                                        now.saturating_sub(ts) / 60_000
+                                                    )
+                                                })
+                                            };
+
+                                            let mut sessions = Vec::new();
+                                            for device in db.users.all_devices_metadata(&user_id) {
+                                                let device = device?;
+                                                let token_created = db.users.token_created_ts(
+                                                    &user_id,
+                                                    &device.device_id,
+                                                )?;
+                                                sessions.push(format!(
+                                                    "{} ({}): last seen {} from {}, token created {}",
+                                                    device.device_id,
+                                                    device.display_name.as_deref().unwrap_or("no name"),
+                                                    minutes_ago(
+                                                        device.last_seen_ts.map(|ts| u64::from(ts.get()))
+                                                    ),
+                                                    device.last_seen_ip.as_deref().unwrap_or("unknown"),
+                                                    minutes_ago(token_created),
+                                                ));
+                                            }
+
+                                            format!(
+                                                "Sessions of {} ({}):\n{}",
+                                                user_id,
+                                                sessions.len(),
+                                                sessions.join("\n")
+                                            )
+                                        }
+                                        None => "Usage: list_sessions <userid>".to_owned(),
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
+                                "revoke_session" => {
+                                    let user_id =
+                                        args.get(0).and_then(|arg| UserId::try_from(*arg).ok());
+                                    let device_id =
+                                        args.get(1).map(|&arg| Box::<DeviceId>::from(arg));
+
+                                    let output = match (user_id, device_id) {
+                                        (Some(user_id), Some(device_id)) => {
+                                            if db
+                                                .users
+                                                .get_device_metadata(&user_id, &device_id)?
+                                                .is_some()
+                                            {
+                                                db.users.remove_device(&user_id, &device_id)?;
+                                                format!(
+                                                    "Revoked session {} of {}. The device has to log in again.",
+                                                    device_id, user_id
+                                                )
+                                            } else {
+                                                "Session not found.".to_owned()
+                                            }
+                                        }
+                                        _ => "Usage: revoke_session <userid> <deviceid>".to_owned(),
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
+                                "resolve_reports" => {
+                                    let output = match args
+                                        .get(0)
+                                        .and_then(|arg| UserId::try_from(*arg).ok())
+                                    {
+                                        Some(user_id) => match db.reports.resolve(&user_id)? {
+                                            Some(escalation) => {
+                                                for mxc in &escalation.quarantined_media {
+                                                    db.media.release(mxc)?;
+                                                }
+                                                format!(
+                                                    "{} is no longer rate limited and {} files are released. New reports are counted from zero again.",
+                                                    user_id,
+                                                    escalation.quarantined_media.len()
+                                                )
+                                            }
+                                            None => format!("{} is not under review.", user_id),
+                                        },
+                                        None => "Usage: resolve_reports <userid>".to_owned(),
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
+                                "list_jobs" => {
+                                    let jobs = db.jobs.all().collect::<Result<Vec<_>>>()?;
+                                    let output = format!(
+                                        "Jobs ({}):\n{}",
+                                        jobs.len(),
+                                        jobs.iter()
+                                            .map(|(id, job)| format!(
+                                                "{}: {:?}, {:?}, {} items processed",
+                                                id, job.kind, job.status, job.processed
+                                            ))
+                                            .collect::<Vec<_>>()
+                                            .join("\n")
+                                    );
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
+                                "rotate_signing_key" => {
+                                    let old_key_id = db.globals.keypair_id();
+                                    let new_key_id = db.globals.rotate_keypair()?;
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(format!(
+                                            "Signing with {} now. {} is still published as an old key.",
+                                            new_key_id, old_key_id
+                                        )),
+                                    ));
+                                }
+                                "most_active_rooms" => {
+                                    match args.get(0).map_or(Some(1), |arg| arg.parse::<u64>().ok())
+                                    {
+                                        Some(days) => {
+                                            let rooms = db.rooms.most_active_rooms(days, 20)?;
+                                            let output = format!(
+                                                "Most active rooms in the last {} days (messages, senders):\n{}",
+                                                days,
+                                                rooms
+                                                    .iter()
+                                                    .map(|(room_id, messages, senders)| format!(
+                                                        "{}: {}, {}",
+                                                        room_id, messages, senders
+                                                    ))
+                                                    .collect::<Vec<_>>()
+                                                    .join("\n")
+                                            );
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(output),
+                                            ));
+                                        }
+                                        None => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: most_active_rooms [days]",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "room_activity" => {
+                                    let room_id =
+                                        args.get(0).and_then(|arg| RoomId::try_from(*arg).ok());
+                                    let days =
+                                        args.get(1).map_or(Some(7), |arg| arg.parse::<u64>().ok());
+
+                                    match (room_id, days) {
+                                        (Some(room_id), Some(days)) => {
+                                            let activity =
+                                                db.rooms.room_activity(&room_id, days)?;
+                                            let output = format!(
+                                                "Activity of {} (messages, senders):\n{}",
+                                                room_id,
+                                                activity
+                                                    .iter()
+                                                    .map(|(days_ago, messages, senders)| format!(
+                                                        "{} days ago: {}, {}",
+                                                        days_ago, messages, senders
+                                                    ))
+                                                    .collect::<Vec<_>>()
+                                                    .join("\n")
+                                            );
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(output),
+                                            ));
+                                        }
+                                        _ => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                message::MessageEventContent::text_plain(
+                                                    "Usage: room_activity <roomid> [days]",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "invalidate_sync_tokens" => {
+                                    let epoch = db.globals.bump_sync_token_epoch()?;
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(format!(
+                                            "Sync tokens invalidated, clients will do an initial sync. New epoch: {}",
+                                            epoch
+                                        )),
+                                    ));
+                                }
+                                "show_sending_queue" => {
+                                    let reports = db.sending.destination_reports();
+                                    let output = format!(
+                                        "Destinations with undelivered events or failed requests ({}):\n{}",
+                                        reports.len(),
+                                        reports
+                                            .iter()
+                                            .map(|report| format!(
+                                                "{}: {} queued, oldest pending for {}s, {} retries, last error: {}",
+                                                report.kind.describe(),
+                                                report.queued,
+                                                report
+                                                    .status
+                                                    .pending_since
+                                                    .map_or(0, |since| since.elapsed().as_secs()),
+                                                report.status.retries,
+                                                report.status.last_error.as_deref().unwrap_or("none")
+                                            ))
+                                            .collect::<Vec<_>>()
+                                            .join("\n")
+                                    );
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
+                                "show_metrics" => {
+                                    let mut extremities = db
+                                        .rooms
+                                        .forward_extremity_counts()?
+                                        .into_iter()
+                                        .collect::<Vec<_>>();
+                                    extremities.sort_by(|a, b| b.1.cmp(&a.1));
+
+                                    let mut output = db.globals.metrics.render();
+                                    output += "\n\nRoutes with the most time spent:\n";
+                                    output += &db.globals.request_durations.render(10);
+                                    output += "\n\nRooms with the most forward extremities:";
+                                    for (room_id, count) in extremities.iter().take(10) {
+                                        output += &format!("\n{}: {}", room_id, count);
+                                    }
+
+                                    output += "\n\nDestinations with the most queued events:";
+                                    for report in db.sending.destination_reports().iter().take(10) {
+                                        output += &format!(
+                                            "\n{}: {}",
+                                            report.kind.describe(),
+                                            report.queued
+                                        );
+                                    }
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        message::MessageEventContent::text_plain(output),
+                                    ));
+                                }
                                 "get_auth_chain" => {
                                     if args.len() == 1 {
                                         if let Ok(event_id) = EventId::try_from(args[0]) {
@@ -1630,6 +2696,19 @@ impl Rooms {
                                                 outlier = true;
                                                 pdu_json = db.rooms.get_pdu_json(&event_id)?;
                                             }
+                                            let status = if let Some(reason) =
+                                                db.rooms.rejection_reason(&event_id)?
+                                            {
+                                                format!("PDU was rejected: {}", reason)
+                                            } else if let Some(reason) =
+                                                db.rooms.soft_fail_reason(&event_id)?
+                                            {
+                                                format!("PDU was soft failed: {}", reason)
+                                            } else if outlier {
+                                                "PDU is outlier".to_owned()
+                                            } else {
+                                                "PDU was accepted".to_owned()
+                                            };
                                             match pdu_json {
                                                 Some(json) => {
                                                     let json_text =
@@ -1638,13 +2717,9 @@ impl Rooms {
                                                     db.admin.send(AdminCommand::SendMessage(
                                                         message::MessageEventContent::text_html(
                                                             format!("{}\n```json\n{}\n```",
-                                                            if outlier {
-                                                                "PDU is outlier"
-                                                            } else { "PDU was accepted"}, json_text),
+                                                            status, json_text),
                                                             format!("<p>{}</p>\n<pre><code class=\"language-json\">{}\n</code></pre>\n",
-                                                            if outlier {
-                                                                "PDU is outlier"
-                                                            } else { "PDU was accepted"}, RawStr::new(&json_text).html_escape())
+                                                            RawStr::new(&status).html_escape(), RawStr::new(&json_text).html_escape())
                                                         ),
                                                     ));
                                                 }
@@ -1957,11 +3032,8 @@ impl Rooms {
             redacts,
         } = pdu_builder;
 
-        let prev_events = self
-            .get_pdu_leaves(&room_id)?
-            .into_iter()
-            .take(20)
-            .collect::<Vec<_>>();
+        let (prev_events, unreferenced_leaves) =
+            self.select_prev_events(room_id, db.globals.max_forward_extremities() as usize)?;
 
         let create_event = self.room_state_get(&room_id, &EventType::RoomCreate, "")?;
 
@@ -2044,6 +3116,19 @@ impl Rooms {
             signatures: BTreeMap::new(),
         };
 
+        if pdu.kind == EventType::RoomMember
+            && pdu.content.get("membership").and_then(|m| m.as_str()) == Some("knock")
+            && !knocking_allowed(
+                &room_version_id,
+                auth_events.get(&(EventType::RoomJoinRules, "".to_owned())),
+            )
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This room does not allow knocking.",
+            ));
+        }
+
         let auth_check = state_res::auth_check(
             &room_version,
             &Arc::new(pdu.clone()),
@@ -2077,7 +3162,7 @@ impl Rooms {
 
         ruma::signatures::hash_and_sign_event(
             db.globals.server_name().as_str(),
-            db.globals.keypair(),
+            &*db.globals.keypair(),
             &mut pdu_json,
             &room_version_id,
         )
@@ -2103,14 +3188,12 @@ impl Rooms {
         // pdu without it's state. This is okay because append_pdu can't fail.
         let statehashid = self.append_to_state(&pdu, &db.globals)?;
 
-        let pdu_id = self.append_pdu(
-            &pdu,
-            pdu_json,
-            // Since this PDU references all pdu_leaves we can update the leaves
-            // of the room
-            &[pdu.event_id.clone()],
-            db,
-        )?;
+        // This PDU references the selected pdu_leaves, so they are replaced by it. Leaves that were
+        // left out (because there were too many) stay forward extremities
+        let mut leaves = unreferenced_leaves;
+        leaves.push(pdu.event_id.clone());
+
+        let pdu_id = self.append_pdu(&pdu, pdu_json, &leaves, db)?;
 
         // We set the room state after inserting the pdu, so that we never have a moment in time
         // where events in the current room state do not exist
@@ -2125,54 +3208,8 @@ impl Rooms {
         }
 
         for appservice in db.appservice.all()? {
-            if self.appservice_in_room(room_id, &appservice, db)? {
+            if self.appservice_should_receive(&pdu, &appservice, db)? {
                 db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
-                continue;
-            }
-
-            if let Some(namespaces) = appservice.1.get("namespaces") {
-                let users = namespaces
-                    .get("users")
-                    .and_then(|users| users.as_sequence())
-                    .map_or_else(Vec::new, |users| {
-                        users
-                            .iter()
-                            .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let aliases = namespaces
-                    .get("aliases")
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let rooms = namespaces
-                    .get("rooms")
-                    .and_then(|rooms| rooms.as_sequence());
-
-                let matching_users = |users: &Regex| {
-                    users.is_match(pdu.sender.as_str())
-                        || pdu.kind == EventType::RoomMember
-                            && pdu
-                                .state_key
-                                .as_ref()
-                                .map_or(false, |state_key| users.is_match(&state_key))
-                };
-                let matching_aliases = |aliases: &Regex| {
-                    self.room_aliases(&room_id)
-                        .filter_map(|r| r.ok())
-                        .any(|room_alias| aliases.is_match(room_alias.as_str()))
-                };
-
-                if aliases.iter().any(matching_aliases)
-                    || rooms.map_or(false, |rooms| rooms.contains(&room_id.as_str().into()))
-                    || users.iter().any(matching_users)
-                {
-                    db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
-                }
             }
         }
 
@@ -2220,6 +3257,7 @@ impl Rooms {
                 if pdu.sender != user_id {
                     pdu.unsigned.remove("transaction_id");
                 }
+                self.add_bundled_aggregations(&mut pdu, &user_id)?;
                 Ok((pdu_id, pdu))
             }))
     }
@@ -2257,6 +3295,7 @@ impl Rooms {
                 if pdu.sender != user_id {
                     pdu.unsigned.remove("transaction_id");
                 }
+                self.add_bundled_aggregations(&mut pdu, &user_id)?;
                 Ok((pdu_id, pdu))
             }))
     }
@@ -2294,41 +3333,666 @@ impl Rooms {
                 if pdu.sender != user_id {
                     pdu.unsigned.remove("transaction_id");
                 }
+                self.add_bundled_aggregations(&mut pdu, &user_id)?;
                 Ok((pdu_id, pdu))
             }))
     }
 
-    /// Replace a PDU with the redacted form.
-    #[tracing::instrument(skip(self, reason))]
-    pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent) -> Result<()> {
-        if let Some(pdu_id) = self.get_pdu_id(event_id)? {
-            let mut pdu = self
-                .get_pdu_from_id(&pdu_id)?
-                .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
-            pdu.redact(&reason)?;
-            self.replace_pdu(&pdu_id, &pdu)?;
-            Ok(())
-        } else {
-            Err(Error::BadRequest(
-                ErrorKind::NotFound,
-                "Event ID does not exist.",
-            ))
+    /// Returns the events in a room that relate to the event `target`, with their tokens and
+    /// relation types. The newest come first, starting before `from`, or the oldest after `from`
+    /// if `backwards` is false. With `rel_type`, only relations of that type are read.
+    #[tracing::instrument(skip(self))]
+    pub fn relating_pdus<'a>(
+        &'a self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        target: &EventId,
+        rel_type: Option<&str>,
+        from: Option<u64>,
+        backwards: bool,
+    ) -> Result<impl Iterator<Item = Result<(u64, String, PduEvent)>> + 'a> {
+        let shortroomid = self.get_shortroomid(room_id)?.expect("room exists");
+
+        let mut prefix = target.as_bytes().to_vec();
+        prefix.push(0xff);
+        if let Some(rel_type) = rel_type {
+            prefix.extend_from_slice(rel_type.as_bytes());
+            prefix.push(0xff);
         }
-    }
+        let pduid_start = prefix.len();
+        prefix.extend_from_slice(&shortroomid.to_be_bytes());
 
-    /// Update current membership data.
-    #[tracing::instrument(skip(self, last_state, db))]
-    pub fn update_membership(
-        &self,
-        room_id: &RoomId,
-        user_id: &UserId,
-        membership: member::MembershipState,
-        sender: &UserId,
-        last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
-        db: &Database,
-        update_joined_count: bool,
-    ) -> Result<()> {
-        // Keep track what remote users exist by adding them as "deactivated" users
+        let mut current = prefix.clone();
+        current.extend_from_slice(
+            &match (from, backwards) {
+                (Some(from), true) => from.saturating_sub(1),
+                (Some(from), false) => from.saturating_add(1),
+                (None, true) => u64::MAX,
+                (None, false) => 0,
+            }
+            .to_be_bytes(),
+        );
+
+        let user_id = user_id.clone();
+        let tree = match rel_type {
+            Some(_) => &self.typedrelationid,
+            None => &self.relationid_reltype,
+        };
+        let rel_type = rel_type.map(ToOwned::to_owned);
+
+        Ok(tree
+            .iter_from(&current, backwards)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(move |(relation_id, value)| {
+                let pdu_id = &relation_id[pduid_start..];
+                let count = utils::u64_from_bytes(&pdu_id[size_of::<u64>()..])
+                    .map_err(|_| Error::bad_database("Invalid count in relation id."))?;
+                let rel_type = match &rel_type {
+                    Some(rel_type) => rel_type.clone(),
+                    None => utils::string_from_bytes(&value).map_err(|_| {
+                        Error::bad_database("Invalid rel type in relationid_reltype.")
+                    })?,
+                };
+
+                let mut pdu = self
+                    .get_pdu_from_id(pdu_id)?
+                    .ok_or_else(|| Error::bad_database("Relation points to a missing PDU."))?;
+                if pdu.sender != user_id {
+                    pdu.unsigned.remove("transaction_id");
+                }
+                self.add_bundled_aggregations(&mut pdu, &user_id)?;
+
+                Ok((count, rel_type, pdu))
+            }))
+    }
+
+    /// Adds an event with an `m.thread` relation to the thread of its root. The thread becomes
+    /// the most recent thread of the room and the reply becomes the `latest_event` in the bundled
+    /// aggregation of the root. Redacted replies still count.
+    pub(super) fn add_thread_reply(&self, pdu: &PduEvent, pdu_id: &[u8]) -> Result<()> {
+        let root = match parse_relation(&pdu.content) {
+            Some((root, rel_type)) if rel_type == "m.thread" => root,
+            _ => return Ok(()),
+        };
+
+        // Threads can only start at known events of the same room
+        let root_pdu_id = match self.get_pdu_id(&root)? {
+            Some(root_pdu_id)
+                if root_pdu_id.get(..size_of::<u64>()) == pdu_id.get(..size_of::<u64>()) =>
+            {
+                root_pdu_id
+            }
+            _ => return Ok(()),
+        };
+        let root_pdu = match self.get_pdu_from_id(&root_pdu_id)? {
+            Some(root_pdu) => root_pdu,
+            None => return Ok(()),
+        };
+
+        if let Some(old_threadid) = self.eventid_threadid.get(root.as_bytes())? {
+            self.threadid_eventid.remove(&old_threadid)?;
+        }
+        self.eventid_threadid.insert(root.as_bytes(), pdu_id)?;
+        self.threadid_eventid.insert(pdu_id, root.as_bytes())?;
+
+        for user_id in &[&root_pdu.sender, &pdu.sender] {
+            self.threaduserids
+                .insert(&thread_user_id(&root, user_id), &[])?;
+        }
+
+        self.eventid_threadcount.increment(root.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Adds the aggregated relations of an event to its `unsigned` field: the thread it starts and
+    /// the reactions to it.
+    fn add_bundled_aggregations(&self, pdu: &mut PduEvent, user_id: &UserId) -> Result<()> {
+        self.add_thread_aggregation(pdu, user_id)?;
+
+        let chunk = self
+            .annotation_counts(&pdu.event_id)
+            .map(|r| {
+                r.map(|(key, count)| {
+                    serde_json::json!({
+                        "type": "m.reaction",
+                        "key": key,
+                        "count": count,
+                    })
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        let relations = pdu
+            .unsigned
+            .entry("m.relations".to_owned())
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let Some(relations) = relations.as_object_mut() {
+            relations.insert(
+                "m.annotation".to_owned(),
+                serde_json::json!({ "chunk": chunk }),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Adds the bundled `m.thread` aggregation if the event is a thread root. The latest reply is
+    /// read when the root is served, so later redactions of it show up.
+    fn add_thread_aggregation(&self, pdu: &mut PduEvent, user_id: &UserId) -> Result<()> {
+        let threadid = match self.eventid_threadid.get(pdu.event_id.as_bytes())? {
+            Some(threadid) => threadid,
+            None => return Ok(()),
+        };
+
+        let mut latest_event = self
+            .get_pdu_from_id(&threadid)?
+            .ok_or_else(|| Error::bad_database("Latest event in eventid_threadid is missing."))?;
+        latest_event.unsigned.remove("transaction_id");
+
+        let count = self
+            .eventid_threadcount
+            .get(pdu.event_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid thread count in eventid_threadcount.")
+                })
+            })
+            .transpose()?
+            .unwrap_or(0);
+
+        let participated = self
+            .threaduserids
+            .get(&thread_user_id(&pdu.event_id, user_id))?
+            .is_some();
+
+        let relations = pdu
+            .unsigned
+            .entry("m.relations".to_owned())
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let Some(relations) = relations.as_object_mut() {
+            relations.insert(
+                "m.thread".to_owned(),
+                serde_json::json!({
+                    "latest_event": latest_event.to_room_event(),
+                    "count": count,
+                    "current_user_participated": participated,
+                }),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the roots of the threads in a room, the thread with the most recent reply first,
+    /// starting before the thread token `from`. With `participant`, only the threads that user
+    /// started or replied to are returned.
+    #[tracing::instrument(skip(self))]
+    pub fn threads_until<'a>(
+        &'a self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        from: Option<u64>,
+        participant: Option<&UserId>,
+    ) -> Result<impl Iterator<Item = Result<(u64, PduEvent)>> + 'a> {
+        let prefix = self
+            .get_shortroomid(room_id)?
+            .expect("room exists")
+            .to_be_bytes()
+            .to_vec();
+
+        let mut current = prefix.clone();
+        current.extend_from_slice(
+            &from
+                .map_or(u64::MAX, |from| from.saturating_sub(1))
+                .to_be_bytes(),
+        );
+
+        let user_id = user_id.clone();
+        let participant = participant.cloned();
+
+        Ok(self
+            .threadid_eventid
+            .iter_from(&current, true)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .filter_map(move |(threadid, root)| {
+                let root = EventId::try_from(utils::string_from_bytes(&root).ok()?).ok()?;
+
+                if let Some(participant) = &participant {
+                    match self.threaduserids.get(&thread_user_id(&root, participant)) {
+                        Ok(Some(_)) => {}
+                        Ok(None) => return None,
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+
+                let count = match utils::u64_from_bytes(&threadid[size_of::<u64>()..]) {
+                    Ok(count) => count,
+                    Err(_) => {
+                        return Some(Err(Error::bad_database(
+                            "Invalid thread id in threadid_eventid.",
+                        )))
+                    }
+                };
+
+                Some(self.get_pdu(&root).and_then(|pdu| {
+                    let mut pdu = (*pdu.ok_or_else(|| {
+                        Error::bad_database("Thread root in threadid_eventid is missing.")
+                    })?)
+                    .clone();
+                    if pdu.sender != user_id {
+                        pdu.unsigned.remove("transaction_id");
+                    }
+                    self.add_bundled_aggregations(&mut pdu, &user_id)?;
+                    Ok((count, pdu))
+                }))
+            }))
+    }
+
+    /// Removes a redacted event from the relations of the event it related to.
+    fn remove_relation(&self, event_id: &EventId) -> Result<()> {
+        let pdu_id = match self.get_pdu_id(event_id)? {
+            Some(pdu_id) => pdu_id,
+            None => return Ok(()),
+        };
+
+        if let Some(pdu) = self.get_pdu_from_id(&pdu_id)? {
+            if let Some((target, rel_type)) = parse_relation(&pdu.content) {
+                self.relationid_reltype
+                    .remove(&relation_id(&target, &pdu_id))?;
+                self.typedrelationid
+                    .remove(&typed_relation_id(&target, &rel_type, &pdu_id))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the sender of an event in the timeline without loading the event.
+    pub fn event_sender(&self, event_id: &EventId) -> Result<Option<UserId>> {
+        self.eventid_sender
+            .get(event_id.as_bytes())?
+            .map(|bytes| {
+                UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Sender in eventid_sender is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("Sender in eventid_sender is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Whether a redaction of `redacts` by `sender` takes effect. Everyone may redact their own
+    /// events, the events of others need the redact power level. In rooms of version 1 and 2,
+    /// a redaction also takes effect if its event id has the same domain as the id of `redacts`.
+    /// `redaction_server` is the domain of the id of the redaction event.
+    ///
+    /// Events that are not in the timeline of the room can't be redacted.
+    pub fn redaction_allowed(
+        &self,
+        room_id: &RoomId,
+        sender: &UserId,
+        redaction_server: Option<&ServerName>,
+        redacts: &EventId,
+        power_levels: &PowerLevelsEventContent,
+    ) -> Result<bool> {
+        let in_room = match (self.get_pdu_id(redacts)?, self.get_shortroomid(room_id)?) {
+            (Some(pdu_id), Some(shortroomid)) => pdu_id.starts_with(&shortroomid.to_be_bytes()),
+            _ => false,
+        };
+
+        let redacted_sender = match self.event_sender(redacts)? {
+            Some(redacted_sender) if in_room => redacted_sender,
+            _ => return Ok(false),
+        };
+
+        if redacted_sender == *sender
+            || power_levels
+                .users
+                .get(sender)
+                .unwrap_or(&power_levels.users_default)
+                >= &power_levels.redact
+        {
+            return Ok(true);
+        }
+
+        Ok(matches!(
+            self.room_version(room_id)?,
+            Some(RoomVersionId::Version1) | Some(RoomVersionId::Version2)
+        ) && redacts.server_name().is_some()
+            && redacts.server_name() == redaction_server)
+    }
+
+    /// Replace a PDU with the redacted form.
+    #[tracing::instrument(skip(self, reason))]
+    pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent) -> Result<()> {
+        if let Some(pdu_id) = self.get_pdu_id(event_id)? {
+            let mut pdu = self
+                .get_pdu_from_id(&pdu_id)?
+                .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+            pdu.redact(&reason)?;
+            self.replace_pdu(&pdu_id, &pdu)?;
+
+            self.pdu_cache.lock().unwrap().remove(event_id);
+            if pdu.state_key.is_some() {
+                // Cached states could contain the unredacted event
+                self.statepdus_cache.lock().unwrap().clear();
+            }
+
+            Ok(())
+        } else {
+            Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Event ID does not exist.",
+            ))
+        }
+    }
+
+    /// Makes sure a local user reacts to an event only once with the same key and doesn't use
+    /// more than `max_keys` different keys on one event.
+    pub fn check_annotation(
+        &self,
+        sender: &UserId,
+        content: &serde_json::Value,
+        max_keys: u32,
+    ) -> Result<()> {
+        let (target, key) = match parse_annotation(content) {
+            Some(annotation) => annotation,
+            None => return Ok(()),
+        };
+
+        if self
+            .annotationid_reactionid
+            .get(&annotation_id(&target, &key, sender))?
+            .is_some()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "You already reacted with this key.",
+            ));
+        }
+
+        if self.annotation_keys_of_user(&target, sender)? >= max_keys as usize {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "You reacted to this event too often.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns how many different keys the user reacted with to this event.
+    fn annotation_keys_of_user(&self, target: &EventId, sender: &UserId) -> Result<usize> {
+        let mut prefix = target.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Ok(self
+            .annotationid_reactionid
+            .scan_prefix(prefix)
+            .filter(|(key, _)| {
+                key.rsplit(|&b| b == 0xff)
+                    .next()
+                    .map_or(false, |user| user == sender.as_bytes())
+            })
+            .count())
+    }
+
+    /// Adds a reaction to the aggregated annotations of its event. Reactions with a key the
+    /// sender already used on that event are ignored.
+    fn add_annotation(&self, pdu: &PduEvent) -> Result<()> {
+        let (target, key) = match parse_annotation(&pdu.content) {
+            Some(annotation) => annotation,
+            None => return Ok(()),
+        };
+
+        let annotation_id = annotation_id(&target, &key, &pdu.sender);
+        if self.annotationid_reactionid.get(&annotation_id)?.is_some() {
+            return Ok(());
+        }
+
+        self.annotationid_reactionid
+            .insert(&annotation_id, pdu.event_id.as_bytes())?;
+        self.reactionid_annotationid
+            .insert(pdu.event_id.as_bytes(), &annotation_id)?;
+
+        let count_key = annotation_count_key(&target, &key);
+        let count = self.annotation_count(&count_key)?;
+        self.annotationkey_count
+            .insert(&count_key, &(count + 1).to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Removes a redacted reaction from the aggregated annotations.
+    fn remove_annotation(&self, reaction_id: &EventId) -> Result<()> {
+        let annotation_id = match self.reactionid_annotationid.get(reaction_id.as_bytes())? {
+            Some(annotation_id) => annotation_id,
+            None => return Ok(()),
+        };
+
+        self.reactionid_annotationid
+            .remove(reaction_id.as_bytes())?;
+        self.annotationid_reactionid.remove(&annotation_id)?;
+
+        // The count key is the annotation id without the user id
+        let user_start = annotation_id
+            .iter()
+            .rposition(|&b| b == 0xff)
+            .ok_or_else(|| Error::bad_database("Invalid annotation id in db."))?;
+        let count_key = &annotation_id[..user_start];
+
+        match self.annotation_count(count_key)? {
+            0 | 1 => self.annotationkey_count.remove(count_key)?,
+            count => self
+                .annotationkey_count
+                .insert(count_key, &(count - 1).to_be_bytes())?,
+        }
+
+        Ok(())
+    }
+
+    fn annotation_count(&self, count_key: &[u8]) -> Result<u64> {
+        self.annotationkey_count
+            .get(count_key)?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid annotation count in db."))
+            })
+    }
+
+    /// Returns the keys used to react to this event and how many users used them.
+    fn annotation_counts<'a>(
+        &'a self,
+        target: &EventId,
+    ) -> impl Iterator<Item = Result<(String, u64)>> + 'a {
+        let mut prefix = target.as_bytes().to_vec();
+        prefix.push(0xff);
+        let prefix_len = prefix.len();
+
+        self.annotationkey_count
+            .scan_prefix(prefix)
+            .map(move |(count_key, count)| {
+                Ok((
+                    utils::string_from_bytes(&count_key[prefix_len..])
+                        .map_err(|_| Error::bad_database("Invalid annotation key in db."))?,
+                    utils::u64_from_bytes(&count)
+                        .map_err(|_| Error::bad_database("Invalid annotation count in db."))?,
+                ))
+            })
+    }
+
+    /// Counts a new message of `sender` for the current day.
+    #[tracing::instrument(skip(self))]
+    fn update_message_stats(&self, room_id: &RoomId, sender: &UserId) -> Result<()> {
+        let mut roomday_id = room_id.as_bytes().to_vec();
+        roomday_id.push(0xff);
+        roomday_id.extend_from_slice(&current_day().to_be_bytes());
+
+        self.roomidday_messagecount.increment(&roomday_id)?;
+
+        let mut roomdayuser_id = roomday_id;
+        roomdayuser_id.push(0xff);
+        roomdayuser_id.extend_from_slice(sender.as_bytes());
+
+        self.roomiddayuserid.insert(&roomdayuser_id, &[])?;
+
+        Ok(())
+    }
+
+    /// Returns the message count and the number of active senders of each day in the last `days`
+    /// days, starting with today.
+    #[tracing::instrument(skip(self))]
+    pub fn room_activity(&self, room_id: &RoomId, days: u64) -> Result<Vec<(u64, u64, usize)>> {
+        let today = current_day();
+
+        (0..days)
+            .map(|days_ago| {
+                let mut roomday_id = room_id.as_bytes().to_vec();
+                roomday_id.push(0xff);
+                roomday_id.extend_from_slice(&(today.saturating_sub(days_ago)).to_be_bytes());
+
+                let messages =
+                    self.roomidday_messagecount
+                        .get(&roomday_id)?
+                        .map_or(Ok(0), |bytes| {
+                            utils::u64_from_bytes(&bytes)
+                                .map_err(|_| Error::bad_database("Invalid message count in db."))
+                        })?;
+
+                roomday_id.push(0xff);
+                let senders = self.roomiddayuserid.scan_prefix(roomday_id).count();
+
+                Ok((days_ago, messages, senders))
+            })
+            .collect()
+    }
+
+    /// Returns the rooms with the most messages in the last `days` days together with the number
+    /// of messages and distinct senders.
+    #[tracing::instrument(skip(self))]
+    pub fn most_active_rooms(&self, days: u64, limit: usize) -> Result<Vec<(RoomId, u64, usize)>> {
+        let first_day = (current_day() + 1).saturating_sub(days);
+        let day_len = size_of::<u64>();
+
+        let mut messages = HashMap::<Vec<u8>, u64>::new();
+        for (key, value) in self.roomidday_messagecount.iter() {
+            if key.len() <= day_len + 1 {
+                continue;
+            }
+            let (room_id, day) = key.split_at(key.len() - day_len - 1);
+            let day = utils::u64_from_bytes(&day[1..])
+                .map_err(|_| Error::bad_database("Invalid day in roomidday_messagecount."))?;
+
+            if day >= first_day {
+                *messages.entry(room_id.to_vec()).or_default() += utils::u64_from_bytes(&value)
+                    .map_err(|_| Error::bad_database("Invalid message count in db."))?;
+            }
+        }
+
+        let mut rooms = messages.into_iter().collect::<Vec<_>>();
+        rooms.sort_by(|a, b| b.1.cmp(&a.1));
+        rooms.truncate(limit);
+
+        rooms
+            .into_iter()
+            .map(|(room_id, messages)| {
+                let mut senders = HashSet::new();
+                for days_ago in 0..days {
+                    let mut prefix = room_id.clone();
+                    prefix.push(0xff);
+                    prefix
+                        .extend_from_slice(&(current_day().saturating_sub(days_ago)).to_be_bytes());
+                    prefix.push(0xff);
+
+                    senders.extend(
+                        self.roomiddayuserid
+                            .scan_prefix(prefix.clone())
+                            .map(|(key, _)| key[prefix.len()..].to_vec()),
+                    );
+                }
+
+                let room_id =
+                    RoomId::try_from(utils::string_from_bytes(&room_id).map_err(|_| {
+                        Error::bad_database("Room ID in roomidday_messagecount is invalid unicode.")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in roomidday_messagecount is invalid.")
+                    })?;
+
+                Ok((room_id, messages, senders.len()))
+            })
+            .collect()
+    }
+
+    /// Makes big rooms mentions-only for local users that join them for the first time, so they
+    /// don't get a notification for every message.
+    fn add_default_room_push_rule(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        db: &Database,
+    ) -> Result<()> {
+        let min_members = match db.globals.mentions_only_room_size() {
+            Some(min_members) => min_members,
+            None => return Ok(()),
+        };
+
+        if user_id.server_name() != db.globals.server_name()
+            || self.room_joined_count(room_id)?.unwrap_or(0) < u64::from(min_members)
+        {
+            return Ok(());
+        }
+
+        let mut event = match db.account_data.get::<push_rules::PushRulesEvent>(
+            None,
+            user_id,
+            EventType::PushRules,
+        )? {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+
+        // Don't override a rule the user made
+        if event.content.global.room.get(room_id.as_str()).is_some() {
+            return Ok(());
+        }
+
+        event.content.global.room.insert(
+            push::SimplePushRuleInit {
+                actions: vec![Action::DontNotify],
+                default: false,
+                enabled: true,
+                rule_id: room_id.to_string(),
+            }
+            .into(),
+        );
+
+        db.account_data
+            .update(None, user_id, EventType::PushRules, &event, &db.globals)
+    }
+
+    /// Update current membership data.
+    #[tracing::instrument(skip(self, last_state, db))]
+    pub fn update_membership(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        membership: member::MembershipState,
+        sender: &UserId,
+        last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
+        db: &Database,
+        update_joined_count: bool,
+    ) -> Result<()> {
+        let count = db.globals.next_count()?;
+        self.activity.bump(room_id, count)?;
+
+        // Keep track what remote users exist by adding them as "deactivated" users
         if user_id.server_name() != db.globals.server_name() {
             db.users.create(user_id, None)?;
             // TODO: displayname, avatar url
@@ -2350,13 +4014,28 @@ impl Rooms {
         roomuser_id.push(0xff);
         roomuser_id.extend_from_slice(user_id.as_bytes());
 
+        // All membership indices are updated together so they never disagree after a crash
+        let mut transaction = Transaction::default();
+
         match &membership {
             member::MembershipState::Join => {
+                // Accepting a direct chat invite makes the room a direct chat for the user too
+                if user_id.server_name() == db.globals.server_name() {
+                    if let Some(inviter) = self
+                        .invite_state(user_id, room_id)?
+                        .and_then(|state| direct_invite_sender(&state, user_id))
+                    {
+                        self.mark_direct_room(user_id, &inviter, room_id, db)?;
+                    }
+                }
+
                 // Check if the user never joined this room
                 if !self.once_joined(&user_id, &room_id)? {
                     // Add the user ID to the join list then
                     self.roomuseroncejoinedids.insert(&userroom_id, &[])?;
 
+                    self.add_default_room_push_rule(user_id, room_id, db)?;
+
                     // Check if the room has a predecessor
                     if let Some(predecessor) = self
                         .room_state_get(&room_id, &EventType::RoomCreate, "")?
@@ -2425,7 +4104,9 @@ impl Rooms {
                             let mut room_ids_updated = false;
 
                             for room_ids in direct_event.content.0.values_mut() {
-                                if room_ids.iter().any(|r| r == &predecessor.room_id) {
+                                if room_ids.iter().any(|r| r == &predecessor.room_id)
+                                    && !room_ids.contains(room_id)
+                                {
                                     room_ids.push(room_id.clone());
                                     room_ids_updated = true;
                                 }
@@ -2445,15 +4126,17 @@ impl Rooms {
                 }
 
                 if update_joined_count {
-                    self.roomserverids.insert(&roomserver_id, &[])?;
-                    self.serverroomids.insert(&serverroom_id, &[])?;
+                    transaction.insert(&self.roomserverids, &roomserver_id, &[]);
+                    transaction.insert(&self.serverroomids, &serverroom_id, &[]);
                 }
-                self.userroomid_joined.insert(&userroom_id, &[])?;
-                self.roomuserid_joined.insert(&roomuser_id, &[])?;
-                self.userroomid_invitestate.remove(&userroom_id)?;
-                self.roomuserid_invitecount.remove(&roomuser_id)?;
-                self.userroomid_leftstate.remove(&userroom_id)?;
-                self.roomuserid_leftcount.remove(&roomuser_id)?;
+                transaction.insert(&self.userroomid_joined, &userroom_id, &[]);
+                transaction.insert(&self.roomuserid_joined, &roomuser_id, &[]);
+                transaction.remove(&self.userroomid_invitestate, &userroom_id);
+                transaction.remove(&self.roomuserid_invitecount, &roomuser_id);
+                transaction.remove(&self.userroomid_leftstate, &userroom_id);
+                transaction.remove(&self.roomuserid_leftcount, &roomuser_id);
+                transaction.remove(&self.roomuserid_knockcount, &roomuser_id);
+                transaction.remove(&self.userroomid_knockstate, &userroom_id);
             }
             member::MembershipState::Invite => {
                 // We want to know if the sender is ignored by the receiver
@@ -2472,21 +4155,37 @@ impl Rooms {
                     return Ok(());
                 }
 
+                if sender.server_name() == db.globals.server_name()
+                    && last_state
+                        .as_ref()
+                        .and_then(|state| direct_invite_sender(state, user_id))
+                        .as_ref()
+                        == Some(sender)
+                {
+                    self.mark_direct_room(sender, user_id, room_id, db)?;
+                }
+
                 if update_joined_count {
-                    self.roomserverids.insert(&roomserver_id, &[])?;
-                    self.serverroomids.insert(&serverroom_id, &[])?;
+                    transaction.insert(&self.roomserverids, &roomserver_id, &[]);
+                    transaction.insert(&self.serverroomids, &serverroom_id, &[]);
                 }
-                self.userroomid_invitestate.insert(
+                transaction.insert(
+                    &self.userroomid_invitestate,
                     &userroom_id,
                     &serde_json::to_vec(&last_state.unwrap_or_default())
                         .expect("state to bytes always works"),
-                )?;
-                self.roomuserid_invitecount
-                    .insert(&roomuser_id, &db.globals.next_count()?.to_be_bytes())?;
-                self.userroomid_joined.remove(&userroom_id)?;
-                self.roomuserid_joined.remove(&roomuser_id)?;
-                self.userroomid_leftstate.remove(&userroom_id)?;
-                self.roomuserid_leftcount.remove(&roomuser_id)?;
+                );
+                transaction.insert(
+                    &self.roomuserid_invitecount,
+                    &roomuser_id,
+                    &db.globals.next_count()?.to_be_bytes(),
+                );
+                transaction.remove(&self.userroomid_joined, &userroom_id);
+                transaction.remove(&self.roomuserid_joined, &roomuser_id);
+                transaction.remove(&self.userroomid_leftstate, &userroom_id);
+                transaction.remove(&self.roomuserid_leftcount, &roomuser_id);
+                transaction.remove(&self.roomuserid_knockcount, &roomuser_id);
+                transaction.remove(&self.userroomid_knockstate, &userroom_id);
             }
             member::MembershipState::Leave | member::MembershipState::Ban => {
                 if update_joined_count
@@ -2496,28 +4195,234 @@ impl Rooms {
                         .filter_map(|r| r.ok())
                         .all(|u| u.server_name() != user_id.server_name())
                 {
-                    self.roomserverids.remove(&roomserver_id)?;
-                    self.serverroomids.remove(&serverroom_id)?;
+                    transaction.remove(&self.roomserverids, &roomserver_id);
+                    transaction.remove(&self.serverroomids, &serverroom_id);
                 }
-                self.userroomid_leftstate.insert(
+                transaction.insert(
+                    &self.userroomid_leftstate,
                     &userroom_id,
                     &serde_json::to_vec(&Vec::<Raw<AnySyncStateEvent>>::new()).unwrap(),
-                )?; // TODO
-                self.roomuserid_leftcount
-                    .insert(&roomuser_id, &db.globals.next_count()?.to_be_bytes())?;
-                self.userroomid_joined.remove(&userroom_id)?;
-                self.roomuserid_joined.remove(&roomuser_id)?;
-                self.userroomid_invitestate.remove(&userroom_id)?;
-                self.roomuserid_invitecount.remove(&roomuser_id)?;
+                ); // TODO
+                transaction.insert(
+                    &self.roomuserid_leftcount,
+                    &roomuser_id,
+                    &db.globals.next_count()?.to_be_bytes(),
+                );
+                transaction.remove(&self.userroomid_joined, &userroom_id);
+                transaction.remove(&self.roomuserid_joined, &roomuser_id);
+                transaction.remove(&self.userroomid_invitestate, &userroom_id);
+                transaction.remove(&self.roomuserid_invitecount, &roomuser_id);
+                transaction.remove(&self.roomuserid_knockcount, &roomuser_id);
+                transaction.remove(&self.userroomid_knockstate, &userroom_id);
+            }
+            member::MembershipState::Knock => {
+                // Moderators answer knocks by inviting or kicking the user
+                transaction.insert(
+                    &self.roomuserid_knockcount,
+                    &roomuser_id,
+                    &db.globals.next_count()?.to_be_bytes(),
+                );
+                transaction.insert(
+                    &self.userroomid_knockstate,
+                    &userroom_id,
+                    &serde_json::to_vec(&last_state.unwrap_or_default())
+                        .expect("state to bytes always works"),
+                );
+                transaction.remove(&self.userroomid_leftstate, &userroom_id);
+                transaction.remove(&self.roomuserid_leftcount, &roomuser_id);
             }
             _ => {}
         }
 
-        if update_joined_count {
-            self.update_joined_count(room_id, db)?;
+        let mut roomchange_id = room_id.as_bytes().to_vec();
+        roomchange_id.push(0xff);
+        roomchange_id.extend_from_slice(&count.to_be_bytes());
+        roomchange_id.extend_from_slice(user_id.as_bytes());
+        transaction.insert(
+            &self.roomchangeid_membership,
+            &roomchange_id,
+            membership.as_ref().as_bytes(),
+        );
+
+        transaction.commit()?;
+
+        if update_joined_count {
+            self.update_joined_count(room_id, db)?;
+        }
+
+        // Joining restricted rooms that depend on this room might now be allowed or forbidden
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let restricted_room_ids = self
+            .allowroomid_restrictedroomids
+            .scan_prefix(prefix.clone())
+            .filter_map(|(key, _)| {
+                RoomId::try_from(utils::string_from_bytes(&key[prefix.len()..]).ok()?).ok()
+            })
+            .collect::<Vec<_>>();
+
+        if !restricted_room_ids.is_empty() {
+            let mut cache = self.restricted_join_cache.lock().unwrap();
+            for restricted_room_id in restricted_room_ids {
+                cache.remove(&(user_id.clone(), restricted_room_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the room to the direct chats with `partner` in the m.direct account data of the user.
+    /// The account data is only written if the room wasn't there yet, so sync doesn't hand out
+    /// unchanged m.direct events.
+    #[tracing::instrument(skip(self, db))]
+    fn mark_direct_room(
+        &self,
+        user_id: &UserId,
+        partner: &UserId,
+        room_id: &RoomId,
+        db: &Database,
+    ) -> Result<()> {
+        let mut direct_event = db
+            .account_data
+            .get::<ruma::events::direct::DirectEvent>(None, user_id, EventType::Direct)?
+            .unwrap_or_else(|| ruma::events::direct::DirectEvent {
+                content: ruma::events::direct::DirectEventContent(BTreeMap::new()),
+            });
+
+        let room_ids = direct_event
+            .content
+            .0
+            .entry(partner.clone())
+            .or_insert_with(Vec::new);
+
+        if room_ids.contains(room_id) {
+            return Ok(());
+        }
+        room_ids.push(room_id.clone());
+
+        db.account_data
+            .update(None, user_id, EventType::Direct, &direct_event, &db.globals)
+    }
+
+    /// Replaces the rooms that give access to the restricted room `room_id` with the allow list of
+    /// the new join rules.
+    #[tracing::instrument(skip(self, join_rules))]
+    pub fn update_restricted_allow_rooms(
+        &self,
+        room_id: &RoomId,
+        join_rules: &serde_json::Value,
+    ) -> Result<()> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let old_keys = self
+            .restrictedroomid_allowroomids
+            .scan_prefix(prefix.clone())
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in old_keys {
+            let mut allowroom_id = key[prefix.len()..].to_vec();
+            allowroom_id.push(0xff);
+            allowroom_id.extend_from_slice(room_id.as_bytes());
+
+            self.allowroomid_restrictedroomids.remove(&allowroom_id)?;
+            self.restrictedroomid_allowroomids.remove(&key)?;
+        }
+
+        let join_rule =
+            serde_json::from_value::<Raw<join_rules::JoinRulesEventContent>>(join_rules.clone())
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .map_or(JoinRule::Invite, |content| content.join_rule);
+
+        if let JoinRule::Restricted(restricted) = join_rule {
+            for rule in restricted.allow {
+                if let AllowRule::RoomMembership(membership) = rule {
+                    let mut restrictedroom_id = prefix.clone();
+                    restrictedroom_id.extend_from_slice(membership.room_id.as_bytes());
+
+                    let mut allowroom_id = membership.room_id.as_bytes().to_vec();
+                    allowroom_id.push(0xff);
+                    allowroom_id.extend_from_slice(room_id.as_bytes());
+
+                    self.restrictedroomid_allowroomids
+                        .insert(&restrictedroom_id, &[])?;
+                    self.allowroomid_restrictedroomids
+                        .insert(&allowroom_id, &[])?;
+                }
+            }
+        }
+
+        let mut cache = self.restricted_join_cache.lock().unwrap();
+        let outdated = cache
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(_, restricted_room_id)| restricted_room_id == room_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in outdated {
+            cache.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the rooms whose members may join the restricted room `room_id`.
+    #[tracing::instrument(skip(self))]
+    pub fn restricted_allow_rooms<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> impl Iterator<Item = Result<RoomId>> + 'a {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.restrictedroomid_allowroomids
+            .scan_prefix(prefix.clone())
+            .map(move |(key, _)| {
+                RoomId::try_from(utils::string_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                    Error::bad_database(
+                        "Room ID in restrictedroomid_allowroomids is invalid unicode.",
+                    )
+                })?)
+                .map_err(|_| {
+                    Error::bad_database("Room ID in restrictedroomid_allowroomids is invalid.")
+                })
+            })
+    }
+
+    /// Checks if the user is joined to one of the rooms in the allow list of the restricted room.
+    ///
+    /// Decisions are cached until the user's membership in one of the allow list rooms or the join
+    /// rules of the restricted room change.
+    #[tracing::instrument(skip(self))]
+    pub fn can_join_restricted(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+        let cache_key = (user_id.clone(), room_id.clone());
+
+        if let Some(allowed) = self
+            .restricted_join_cache
+            .lock()
+            .unwrap()
+            .get_mut(&cache_key)
+        {
+            return Ok(*allowed);
+        }
+
+        let mut allowed = false;
+        for allow_room_id in self.restricted_allow_rooms(room_id) {
+            if self.is_joined(user_id, &allow_room_id?)? {
+                allowed = true;
+                break;
+            }
         }
 
-        Ok(())
+        self.restricted_join_cache
+            .lock()
+            .unwrap()
+            .insert(cache_key, allowed);
+
+        Ok(allowed)
     }
 
     #[tracing::instrument(skip(self, room_id, db))]
@@ -2617,61 +4522,103 @@ impl Rooms {
         }
     }
 
-    #[tracing::instrument(skip(self, room_id, appservice, db))]
-    pub fn appservice_in_room(
+    /// Checks if an appservice is entitled to a pdu, see `appservice::is_entitled`.
+    #[tracing::instrument(skip(self, pdu, appservice, db))]
+    pub fn appservice_should_receive(
         &self,
-        room_id: &RoomId,
+        pdu: &PduEvent,
         appservice: &(String, serde_yaml::Value),
         db: &Database,
     ) -> Result<bool> {
-        let maybe = self
+        let entry = match db.appservice.namespaces(&appservice.0, &db.globals)? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let namespaces = &entry.0;
+
+        let room_aliases = self
+            .room_aliases(&pdu.room_id)
+            .filter_map(|r| r.ok())
+            .map(|alias| alias.to_string())
+            .collect::<Vec<_>>();
+
+        let history_visibility = self
+            .room_state_get(&pdu.room_id, &EventType::RoomHistoryVisibility, "")?
+            .map(|event| {
+                serde_json::from_value::<HistoryVisibilityEventContent>(event.content.clone())
+                    .map_err(|_| Error::bad_database("Invalid history visibility event in db."))
+            })
+            .transpose()?
+            .map_or(HistoryVisibility::Shared, |content| {
+                content.history_visibility
+            });
+
+        let (joined, invited) =
+            self.appservice_membership(&pdu.room_id, &appservice.0, namespaces)?;
+
+        Ok(appservice::is_entitled(
+            namespaces,
+            &appservice::EventContext {
+                room_id: pdu.room_id.as_str(),
+                room_aliases: &room_aliases,
+                sender: pdu.sender.as_str(),
+                membership_target: if pdu.kind == EventType::RoomMember {
+                    pdu.state_key.as_deref()
+                } else {
+                    None
+                },
+                joined,
+                invited,
+                history_visibility,
+            },
+        ))
+    }
+
+    /// Returns whether users of the appservice are joined to and invited to the room. The result
+    /// is cached until the members of the room change.
+    #[tracing::instrument(skip(self, room_id, appservice_id, namespaces))]
+    fn appservice_membership(
+        &self,
+        room_id: &RoomId,
+        appservice_id: &str,
+        namespaces: &appservice::Namespaces,
+    ) -> Result<(bool, bool)> {
+        if let Some(membership) = self
             .appservice_in_room_cache
             .read()
             .unwrap()
             .get(room_id)
-            .and_then(|map| map.get(&appservice.0))
-            .copied();
-
-        if let Some(b) = maybe {
-            Ok(b)
-        } else if let Some(namespaces) = appservice.1.get("namespaces") {
-            let users = namespaces
-                .get("users")
-                .and_then(|users| users.as_sequence())
-                .map_or_else(Vec::new, |users| {
-                    users
-                        .iter()
-                        .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
+            .and_then(|map| map.get(appservice_id))
+        {
+            return Ok(*membership);
+        }
 
-            let bridge_user_id = appservice
-                .1
-                .get("sender_localpart")
-                .and_then(|string| string.as_str())
-                .and_then(|string| {
-                    UserId::parse_with_server_name(string, db.globals.server_name()).ok()
-                });
-
-            let in_room = bridge_user_id
-                .map_or(false, |id| self.is_joined(&id, room_id).unwrap_or(false))
-                || self.room_members(&room_id).any(|userid| {
-                    userid.map_or(false, |userid| {
-                        users.iter().any(|r| r.is_match(userid.as_str()))
-                    })
-                });
+        // Most appservices are in their rooms with their own user, which is a single lookup
+        let bridge_user_id = namespaces.bridge_user_id();
+        let joined = match bridge_user_id {
+            Some(user_id) if self.is_joined(user_id, room_id)? => true,
+            _ => self
+                .room_members(room_id)
+                .filter_map(|r| r.ok())
+                .any(|user_id| namespaces.matches_user(user_id.as_str())),
+        };
+        let invited = !joined
+            && match bridge_user_id {
+                Some(user_id) if self.is_invited(user_id, room_id)? => true,
+                _ => self
+                    .room_members_invited(room_id)
+                    .filter_map(|r| r.ok())
+                    .any(|user_id| namespaces.matches_user(user_id.as_str())),
+            };
 
-            self.appservice_in_room_cache
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default()
-                .insert(appservice.0.clone(), in_room);
+        self.appservice_in_room_cache
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default()
+            .insert(appservice_id.to_owned(), (joined, invited));
 
-            Ok(in_room)
-        } else {
-            Ok(false)
-        }
+        Ok((joined, invited))
     }
 
     #[tracing::instrument(skip(self, db))]
@@ -2797,11 +4744,7 @@ impl Rooms {
         let (make_leave_response, remote_server) = make_leave_response_and_server?;
 
         let room_version_id = match make_leave_response.room_version {
-            Some(version)
-                if version == RoomVersionId::Version5 || version == RoomVersionId::Version6 =>
-            {
-                version
-            }
+            Some(version) if db.globals.supports_room_version(&version) => version,
             _ => return Err(Error::BadServerResponse("Room version is not supported")),
         };
 
@@ -2830,7 +4773,7 @@ impl Rooms {
         // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
         ruma::signatures::hash_and_sign_event(
             db.globals.server_name().as_str(),
-            db.globals.keypair(),
+            &*db.globals.keypair(),
             &mut leave_event_stub,
             &room_version_id,
         )
@@ -2892,14 +4835,20 @@ impl Rooms {
         room_id: Option<&RoomId>,
         globals: &super::globals::Globals,
     ) -> Result<()> {
+        // Both directions are updated together so they can't get out of sync
+        let mut transaction = Transaction::default();
+
         if let Some(room_id) = room_id {
             // New alias
-            self.alias_roomid
-                .insert(&alias.alias().as_bytes(), room_id.as_bytes())?;
+            transaction.insert(
+                &self.alias_roomid,
+                &alias.alias().as_bytes(),
+                room_id.as_bytes(),
+            );
             let mut aliasid = room_id.as_bytes().to_vec();
             aliasid.push(0xff);
             aliasid.extend_from_slice(&globals.next_count()?.to_be_bytes());
-            self.aliasid_alias.insert(&aliasid, &*alias.as_bytes())?;
+            transaction.insert(&self.aliasid_alias, &aliasid, &*alias.as_bytes());
         } else {
             // room_id=None means remove alias
             if let Some(room_id) = self.alias_roomid.get(&alias.alias().as_bytes())? {
@@ -2907,9 +4856,10 @@ impl Rooms {
                 prefix.push(0xff);
 
                 for (key, _) in self.aliasid_alias.scan_prefix(prefix) {
-                    self.aliasid_alias.remove(&key)?;
+                    transaction.remove(&self.aliasid_alias, &key);
                 }
-                self.alias_roomid.remove(&alias.alias().as_bytes())?;
+                transaction.remove(&self.alias_roomid, &alias.alias().as_bytes());
+                transaction.remove(&self.alias_userid, alias.as_bytes());
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::NotFound,
@@ -2918,7 +4868,32 @@ impl Rooms {
             }
         }
 
-        Ok(())
+        transaction.commit()
+    }
+
+    /// Remembers who created an alias, so it can be removed when the account is deactivated.
+    pub fn set_alias_creator(&self, alias: &RoomAliasId, user_id: &UserId) -> Result<()> {
+        self.alias_userid
+            .insert(alias.as_bytes(), user_id.as_bytes())
+    }
+
+    /// Returns all aliases the user created.
+    pub fn aliases_created_by<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<RoomAliasId>> + 'a {
+        let user_id = user_id.as_bytes().to_vec();
+
+        self.alias_userid
+            .iter()
+            .filter(move |(_, creator)| creator == &user_id)
+            .map(|(alias, _)| {
+                RoomAliasId::try_from(
+                    utils::string_from_bytes(&alias)
+                        .map_err(|_| Error::bad_database("Invalid alias bytes in alias_userid."))?,
+                )
+                .map_err(|_| Error::bad_database("Invalid alias in alias_userid."))
+            })
     }
 
     #[tracing::instrument(skip(self))]
@@ -2951,6 +4926,18 @@ impl Rooms {
         })
     }
 
+    /// Returns all aliases on this server.
+    #[tracing::instrument(skip(self))]
+    pub fn all_aliases(&self) -> impl Iterator<Item = Result<RoomAliasId>> + '_ {
+        self.aliasid_alias.iter().map(|(_, bytes)| {
+            RoomAliasId::try_from(
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid alias bytes in aliasid_alias."))?,
+            )
+            .map_err(|_| Error::bad_database("Invalid alias in aliasid_alias."))
+        })
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn set_public(&self, room_id: &RoomId, public: bool) -> Result<()> {
         if public {
@@ -3103,6 +5090,46 @@ impl Rooms {
         self.serverroomids.get(&key).map(|o| o.is_some())
     }
 
+    /// Checks the `m.room.server_acl` event of the room. Servers are allowed if the room has no
+    /// ACL.
+    #[tracing::instrument(skip(self))]
+    pub fn is_server_allowed_by_acl(&self, server: &ServerName, room_id: &RoomId) -> Result<bool> {
+        let acl = match self.room_state_get(room_id, &EventType::RoomServerAcl, "")? {
+            Some(acl) => acl,
+            None => return Ok(true),
+        };
+
+        // The port is not part of the match
+        let host = match server.as_str().rfind(':') {
+            Some(i) if !server.as_str().ends_with(']') => &server.as_str()[..i],
+            _ => server.as_str(),
+        };
+
+        let allow_ip_literals = acl
+            .content
+            .get("allow_ip_literals")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+
+        if !allow_ip_literals && (host.starts_with('[') || host.parse::<IpAddr>().is_ok()) {
+            return Ok(false);
+        }
+
+        let matches_any = |key: &str| {
+            acl.content
+                .get(key)
+                .and_then(|v| v.as_array())
+                .map_or(false, |globs| {
+                    globs
+                        .iter()
+                        .filter_map(|glob| glob.as_str())
+                        .any(|glob| acl_glob_matches(glob, host))
+                })
+        };
+
+        Ok(!matches_any("deny") && matches_any("allow"))
+    }
+
     /// Returns an iterator of all rooms a server participates in (as far as we know).
     #[tracing::instrument(skip(self))]
     pub fn server_rooms<'a>(
@@ -3199,6 +5226,38 @@ impl Rooms {
             })
     }
 
+    /// Returns an iterator over all users that knocked on a room and did not get an answer yet,
+    /// together with the count of their knock.
+    #[tracing::instrument(skip(self))]
+    pub fn room_members_knocked<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> impl Iterator<Item = Result<(UserId, u64)>> + 'a {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.roomuserid_knockcount
+            .scan_prefix(prefix)
+            .map(|(key, count)| {
+                let user_id = UserId::try_from(
+                    utils::string_from_bytes(
+                        &key.rsplit(|&b| b == 0xff)
+                            .next()
+                            .expect("rsplit always returns an element"),
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("User ID in roomuserid_knockcount is invalid unicode.")
+                    })?,
+                )
+                .map_err(|_| Error::bad_database("User ID in roomuserid_knockcount is invalid."))?;
+
+                let count = utils::u64_from_bytes(&count)
+                    .map_err(|_| Error::bad_database("Invalid knockcount in db."))?;
+
+                Ok((user_id, count))
+            })
+    }
+
     /// Returns an iterator over all invited members of a room.
     #[tracing::instrument(skip(self))]
     pub fn room_members_invited<'a>(
@@ -3240,6 +5299,21 @@ impl Rooms {
             })
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        self.roomuserid_knockcount
+            .get(&key)?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid knockcount in db.")
+                })?))
+            })
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
         let mut key = room_id.as_bytes().to_vec();
@@ -3255,6 +5329,50 @@ impl Rooms {
             })
     }
 
+    /// Returns the latest membership of every user whose membership in the room changed after
+    /// `since`. Returns None if the log of membership changes doesn't go back that far, because it
+    /// was added after `since`.
+    #[tracing::instrument(skip(self, globals))]
+    pub fn membership_changes_since(
+        &self,
+        room_id: &RoomId,
+        since: u64,
+        globals: &super::globals::Globals,
+    ) -> Result<Option<HashMap<UserId, member::MembershipState>>> {
+        if since < globals.membership_log_start()? {
+            return Ok(None);
+        }
+
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut first = prefix.clone();
+        first.extend_from_slice(&(since + 1).to_be_bytes());
+
+        let mut changes = HashMap::new();
+
+        for (key, membership) in self
+            .roomchangeid_membership
+            .iter_from(&first, false)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            let user_id = key
+                .get(prefix.len() + size_of::<u64>()..)
+                .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                .and_then(|user_id| UserId::try_from(user_id).ok())
+                .ok_or_else(|| Error::bad_database("Invalid RoomChangeId in db."))?;
+
+            let membership = utils::string_from_bytes(&membership).map_err(|_| {
+                Error::bad_database("Invalid membership in roomchangeid_membership.")
+            })?;
+
+            // Later changes come later in the tree, so the latest membership wins
+            changes.insert(user_id, member::MembershipState::from(membership.as_str()));
+        }
+
+        Ok(Some(changes))
+    }
+
     /// Returns an iterator over all rooms this user joined.
     #[tracing::instrument(skip(self))]
     pub fn rooms_joined<'a>(
@@ -3309,6 +5427,37 @@ impl Rooms {
             })
     }
 
+    /// Returns an iterator over all rooms a user knocked on and did not get an answer for yet.
+    #[tracing::instrument(skip(self))]
+    pub fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<(RoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.userroomid_knockstate
+            .scan_prefix(prefix)
+            .map(|(key, state)| {
+                let room_id = RoomId::try_from(
+                    utils::string_from_bytes(
+                        &key.rsplit(|&b| b == 0xff)
+                            .next()
+                            .expect("rsplit always returns an element"),
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in userroomid_knockstate is invalid unicode.")
+                    })?,
+                )
+                .map_err(|_| Error::bad_database("Room ID in userroomid_knockstate is invalid."))?;
+
+                let state = serde_json::from_slice(&state)
+                    .map_err(|_| Error::bad_database("Invalid state in userroomid_knockstate."))?;
+
+                Ok((room_id, state))
+            })
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn invite_state(
         &self,
@@ -3409,6 +5558,34 @@ impl Rooms {
         Ok(self.userroomid_invitestate.get(&userroom_id)?.is_some())
     }
 
+    /// Whether users can read the room without joining it, because its history visibility is
+    /// world_readable.
+    #[tracing::instrument(skip(self))]
+    pub fn is_world_readable(&self, room_id: &RoomId) -> Result<bool> {
+        history_visibility_is_world_readable(self.room_state_get(
+            room_id,
+            &EventType::RoomHistoryVisibility,
+            "",
+        )?)
+    }
+
+    /// Whether the history visibility was world_readable in the state of the room at the event,
+    /// so users that are not in the room may read it. Events sent while the room was not world
+    /// readable stay hidden after the room becomes world readable.
+    #[tracing::instrument(skip(self))]
+    pub fn is_world_readable_at(&self, event_id: &EventId) -> Result<bool> {
+        let shortstatehash = match self.pdu_shortstatehash(event_id)? {
+            Some(shortstatehash) => shortstatehash,
+            None => return Ok(false),
+        };
+
+        history_visibility_is_world_readable(self.state_get(
+            shortstatehash,
+            &EventType::RoomHistoryVisibility,
+            "",
+        )?)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
         let mut userroom_id = user_id.as_bytes().to_vec();
@@ -3476,4 +5653,271 @@ impl Rooms {
 
         Ok(())
     }
+
+    /// Walks the `m.space.child` events of `space_id` and returns all rooms of the space. Results
+    /// are cached until an `m.space.child` event of one of the rooms changes.
+    ///
+    /// - Every room is only visited once, even if it is the child of multiple spaces
+    /// - Children of rooms at `max_depth` are not visited
+    /// - With `suggested_only`, only children marked as suggested are followed
+    #[tracing::instrument(skip(self))]
+    pub fn space_hierarchy(
+        &self,
+        space_id: &RoomId,
+        suggested_only: bool,
+        max_depth: Option<u64>,
+    ) -> Result<Arc<SpaceHierarchy>> {
+        let key = (space_id.clone(), suggested_only, max_depth);
+        if let Some(cached) = self.space_hierarchy_cache.lock().unwrap().get_mut(&key) {
+            return Ok(Arc::clone(cached));
+        }
+
+        let mut hierarchy = SpaceHierarchy::default();
+        let mut visited = HashSet::new();
+        let mut queue = std::collections::VecDeque::new();
+        visited.insert(space_id.clone());
+        queue.push_back((space_id.clone(), 0, Vec::new()));
+
+        while let Some((room_id, depth, via)) = queue.pop_front() {
+            if self.current_shortstatehash(&room_id)?.is_none() {
+                hierarchy.inaccessible.push((room_id, via));
+                continue;
+            }
+
+            let children_state = self.space_children(&room_id, suggested_only)?;
+
+            if max_depth.map_or(true, |max_depth| depth < max_depth) {
+                for child in &children_state {
+                    let (child_id, child_via) = match parse_space_child(child) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+
+                    if visited.insert(child_id.clone()) {
+                        queue.push_back((child_id, depth + 1, child_via));
+                    }
+                }
+            }
+
+            hierarchy.rooms.push(SpaceHierarchyRoom {
+                room_id,
+                depth,
+                children_state,
+            });
+        }
+
+        let hierarchy = Arc::new(hierarchy);
+        self.space_hierarchy_cache
+            .lock()
+            .unwrap()
+            .insert(key, Arc::clone(&hierarchy));
+
+        Ok(hierarchy)
+    }
+
+    /// Returns the valid `m.space.child` events of a room, ordered by their `order` field, then by
+    /// timestamp and room id.
+    #[tracing::instrument(skip(self))]
+    pub fn space_children(
+        &self,
+        room_id: &RoomId,
+        suggested_only: bool,
+    ) -> Result<Vec<Arc<PduEvent>>> {
+        let mut children = self
+            .room_state_full(room_id)?
+            .into_iter()
+            .filter(|((kind, _), _)| kind == &EventType::SpaceChild)
+            .map(|(_, pdu)| pdu)
+            .filter(|pdu| parse_space_child(pdu).is_some())
+            .filter(|pdu| {
+                !suggested_only
+                    || pdu
+                        .content
+                        .get("suggested")
+                        .and_then(|s| s.as_bool())
+                        .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+
+        children.sort_by_cached_key(|pdu| {
+            // Only orders of printable ASCII with at most 50 characters are valid
+            let order = pdu
+                .content
+                .get("order")
+                .and_then(|o| o.as_str())
+                .filter(|o| o.len() <= 50 && o.chars().all(|c| ('\x20'..='\x7E').contains(&c)))
+                .map(ToOwned::to_owned);
+
+            (
+                order.is_none(),
+                order,
+                pdu.origin_server_ts,
+                pdu.state_key.clone(),
+            )
+        });
+
+        Ok(children)
+    }
+
+    /// Forgets all cached space hierarchies that contain this room.
+    #[tracing::instrument(skip(self))]
+    pub fn invalidate_space_hierarchies(&self, room_id: &RoomId) {
+        let mut cache = self.space_hierarchy_cache.lock().unwrap();
+        let stale = cache
+            .iter()
+            .filter(|(_, hierarchy)| hierarchy.contains(room_id))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in stale {
+            cache.remove(&key);
+        }
+    }
+}
+
+/// Knocking was added in room version 7 and only works if the join rule is `knock`.
+fn knocking_allowed(room_version: &RoomVersionId, join_rules: Option<&Arc<PduEvent>>) -> bool {
+    matches!(
+        room_version,
+        RoomVersionId::Version7 | RoomVersionId::Version8 | RoomVersionId::Version9
+    ) && join_rules.map_or(false, |join_rules| {
+        join_rules.content.get("join_rule").and_then(|j| j.as_str()) == Some("knock")
+    })
+}
+
+/// Returns the sender of the direct chat invite for `user_id` in the stripped invite state, or None
+/// if the invite wasn't marked with is_direct.
+fn direct_invite_sender(state: &[Raw<AnyStrippedStateEvent>], user_id: &UserId) -> Option<UserId> {
+    state.iter().find_map(|event| {
+        let event = serde_json::from_str::<serde_json::Value>(event.json().get()).ok()?;
+
+        if event.get("type")?.as_str()? != "m.room.member"
+            || event.get("state_key")?.as_str()? != user_id.as_str()
+            || !event
+                .get("content")?
+                .get("is_direct")
+                .and_then(|d| d.as_bool())
+                .unwrap_or(false)
+        {
+            return None;
+        }
+
+        UserId::try_from(event.get("sender")?.as_str()?).ok()
+    })
+}
+
+/// Returns the child room and the via servers of an `m.space.child` event. Events without via
+/// servers don't count as children.
+fn parse_space_child(pdu: &PduEvent) -> Option<(RoomId, Vec<Box<ServerName>>)> {
+    let child_id = RoomId::try_from(pdu.state_key.as_deref()?).ok()?;
+    let via = pdu
+        .content
+        .get("via")?
+        .as_array()?
+        .iter()
+        .filter_map(|server| Box::<ServerName>::try_from(server.as_str()?).ok())
+        .collect::<Vec<_>>();
+
+    if via.is_empty() {
+        None
+    } else {
+        Some((child_id, via))
+    }
+}
+
+/// Returns the event the content relates to and the type of the relation, e.g. `m.annotation`,
+/// `m.replace` or `m.reference`.
+pub(super) fn parse_relation(content: &serde_json::Value) -> Option<(EventId, String)> {
+    let relates_to = content.get("m.relates_to")?;
+
+    let rel_type = relates_to.get("rel_type")?.as_str()?.to_owned();
+    let target = EventId::try_from(relates_to.get("event_id")?.as_str()?).ok()?;
+
+    Some((target, rel_type))
+}
+
+fn history_visibility_is_world_readable(event: Option<Arc<PduEvent>>) -> Result<bool> {
+    Ok(event
+        .map(|event| {
+            serde_json::from_value::<HistoryVisibilityEventContent>(event.content.clone())
+                .map_err(|_| Error::bad_database("Invalid history visibility event in db."))
+        })
+        .transpose()?
+        .map_or(false, |content| {
+            content.history_visibility == HistoryVisibility::WorldReadable
+        }))
+}
+
+fn origin_event_id(origin: &ServerName, event_id: &EventId) -> Vec<u8> {
+    let mut origin_event_id = origin.as_bytes().to_vec();
+    origin_event_id.push(0xff);
+    origin_event_id.extend_from_slice(event_id.as_bytes());
+    origin_event_id
+}
+
+fn thread_user_id(root: &EventId, user_id: &UserId) -> Vec<u8> {
+    let mut thread_user_id = root.as_bytes().to_vec();
+    thread_user_id.push(0xff);
+    thread_user_id.extend_from_slice(user_id.as_bytes());
+    thread_user_id
+}
+
+pub(super) fn relation_id(target: &EventId, pdu_id: &[u8]) -> Vec<u8> {
+    let mut relation_id = target.as_bytes().to_vec();
+    relation_id.push(0xff);
+    relation_id.extend_from_slice(pdu_id);
+    relation_id
+}
+
+pub(super) fn typed_relation_id(target: &EventId, rel_type: &str, pdu_id: &[u8]) -> Vec<u8> {
+    let mut typed_relation_id = target.as_bytes().to_vec();
+    typed_relation_id.push(0xff);
+    typed_relation_id.extend_from_slice(rel_type.as_bytes());
+    typed_relation_id.push(0xff);
+    typed_relation_id.extend_from_slice(pdu_id);
+    typed_relation_id
+}
+
+/// Returns the annotated event and the key if the content is an m.annotation relation.
+fn parse_annotation(content: &serde_json::Value) -> Option<(EventId, String)> {
+    let relates_to = content.get("m.relates_to")?;
+
+    if relates_to.get("rel_type")?.as_str()? != "m.annotation" {
+        return None;
+    }
+
+    let target = EventId::try_from(relates_to.get("event_id")?.as_str()?).ok()?;
+    let key = relates_to.get("key")?.as_str()?.to_owned();
+
+    Some((target, key))
+}
+
+fn annotation_count_key(target: &EventId, key: &str) -> Vec<u8> {
+    let mut count_key = target.as_bytes().to_vec();
+    count_key.push(0xff);
+    count_key.extend_from_slice(key.as_bytes());
+    count_key
+}
+
+fn annotation_id(target: &EventId, key: &str, sender: &UserId) -> Vec<u8> {
+    let mut annotation_id = annotation_count_key(target, key);
+    annotation_id.push(0xff);
+    annotation_id.extend_from_slice(sender.as_bytes());
+    annotation_id
+}
+
+/// Returns the number of days since the unix epoch.
+fn current_day() -> u64 {
+    utils::millis_since_unix_epoch() / (24 * 60 * 60 * 1000)
+}
+
+/// Matches a server name against a server ACL glob, where `*` matches any number of characters
+/// and `?` matches exactly one.
+fn acl_glob_matches(glob: &str, host: &str) -> bool {
+    let pattern = format!(
+        "(?i)^{}$",
+        regex::escape(glob).replace("\\*", ".*").replace("\\?", ".")
+    );
+
+    Regex::new(&pattern).map_or(false, |regex| regex.is_match(host))
 }
@@ -1,4 +1,7 @@
-use crate::{database::abstraction::Tree, utils, Error, Result};
+use crate::{
+    database::{abstraction::Tree, activity::RoomActivity},
+    utils, Error, Result,
+};
 use ruma::{
     events::{
         presence::{PresenceEvent, PresenceEventContent},
@@ -9,24 +12,86 @@ use ruma::{
     signatures::CanonicalJsonObject,
     RoomId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
+/// The `busy` presence state of MSC3026. Ruma doesn't know it yet, so it is a custom state.
+pub const BUSY: &str = "busy";
+
+/// Online and busy users are both active, busy ones just don't want to be disturbed.
+pub fn presence_is_active(presence: &PresenceState) -> bool {
+    *presence == PresenceState::Online || presence.as_ref() == BUSY
+}
+
+/// The presence a user set last, independent of the rooms it was sent to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserPresence {
+    pub presence: PresenceState,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_msg: Option<String>,
+
+    /// When the user set the status message, in milliseconds since the unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_msg_ts: Option<u64>,
+
+    /// When the user set the presence, in milliseconds since the unix epoch.
+    pub last_active_ts: u64,
+}
+
+impl UserPresence {
+    /// Whether the status message is older than `ttl`. Status messages never expire without a ttl.
+    pub fn status_msg_expired(&self, ttl: Option<Duration>) -> bool {
+        match (self.status_msg_ts, ttl) {
+            (Some(ts), Some(ttl)) => {
+                ts.saturating_add(ttl.as_millis() as u64) < utils::millis_since_unix_epoch()
+            }
+            _ => false,
+        }
+    }
+}
+
 pub struct RoomEdus {
     pub(in super::super) readreceiptid_readreceipt: Arc<dyn Tree>, // ReadReceiptId = RoomId + Count + UserId
+    pub(in super::super) roomid_lastreceiptupdate: Arc<dyn Tree>,  // LastReceiptUpdate = Count
+    pub(in super::super) roomuserthreadid_readreceiptid: Arc<dyn Tree>, // RoomUserThreadId = RoomId + UserId + ThreadId
     pub(in super::super) roomuserid_privateread: Arc<dyn Tree>, // RoomUserId = Room + User, PrivateRead = Count
     pub(in super::super) roomuserid_lastprivatereadupdate: Arc<dyn Tree>, // LastPrivateReadUpdate = Count
     pub(in super::super) typingid_userid: Arc<dyn Tree>, // TypingId = RoomId + TimeoutTime + Count
     pub(in super::super) roomid_lasttypingupdate: Arc<dyn Tree>, // LastRoomTypingUpdate = Count
     pub(in super::super) presenceid_presence: Arc<dyn Tree>, // PresenceId = RoomId + Count + UserId
     pub(in super::super) userid_lastpresenceupdate: Arc<dyn Tree>, // LastPresenceUpdate = Count
+    pub(in super::super) userid_presence: Arc<dyn Tree>, // Presence = UserPresence
+    /// Makes sure the last update counts never go backwards when updates happen concurrently.
+    pub(in super::super) update_count_lock: Mutex<()>,
+    pub(in super::super) activity: Arc<RoomActivity>,
 }
 
 impl RoomEdus {
+    /// Stores `count` as the last update count of `key`, unless a newer update was stored by
+    /// someone else in the meantime.
+    fn raise_update_count(&self, tree: &dyn Tree, key: &[u8], count: u64) -> Result<()> {
+        let _lock = self.update_count_lock.lock().unwrap();
+
+        let current = tree
+            .get(key)?
+            .map(|bytes| utils::u64_from_bytes(&bytes))
+            .transpose()
+            .map_err(|_| Error::bad_database("Invalid last update count in db."))?;
+
+        if current.map_or(true, |current| current < count) {
+            tree.insert(key, &count.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
     /// Adds an event which will be saved until a new event replaces it (e.g. read receipt).
     pub fn readreceipt_update(
         &self,
@@ -35,30 +100,21 @@ impl RoomEdus {
         event: AnyEphemeralRoomEvent,
         globals: &super::super::globals::Globals,
     ) -> Result<()> {
-        let mut prefix = room_id.as_bytes().to_vec();
-        prefix.push(0xff);
-
-        let mut last_possible_key = prefix.clone();
-        last_possible_key.extend_from_slice(&u64::MAX.to_be_bytes());
+        let roomuserthread_id = roomuserthread_key(room_id, user_id, None);
 
         // Remove old entry
-        if let Some((old, _)) = self
-            .readreceiptid_readreceipt
-            .iter_from(&last_possible_key, true)
-            .take_while(|(key, _)| key.starts_with(&prefix))
-            .find(|(key, _)| {
-                key.rsplit(|&b| b == 0xff)
-                    .next()
-                    .expect("rsplit always returns an element")
-                    == user_id.as_bytes()
-            })
+        if let Some(old) = self
+            .roomuserthreadid_readreceiptid
+            .get(&roomuserthread_id)?
         {
-            // This is the old room_latest
             self.readreceiptid_readreceipt.remove(&old)?;
         }
 
-        let mut room_latest_id = prefix;
-        room_latest_id.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        let appending_count = globals.next_appending_count()?;
+        let count = appending_count.count;
+        let mut room_latest_id = room_id.as_bytes().to_vec();
+        room_latest_id.push(0xff);
+        room_latest_id.extend_from_slice(&count.to_be_bytes());
         room_latest_id.push(0xff);
         room_latest_id.extend_from_slice(&user_id.as_bytes());
 
@@ -66,10 +122,58 @@ impl RoomEdus {
             &room_latest_id,
             &serde_json::to_vec(&event).expect("EduEvent::to_string always works"),
         )?;
+        self.roomuserthreadid_readreceiptid
+            .insert(&roomuserthread_id, &room_latest_id)?;
+        self.raise_update_count(&*self.roomid_lastreceiptupdate, room_id.as_bytes(), count)?;
+        self.activity.bump(room_id, count)?;
 
         Ok(())
     }
 
+    /// Removes all read receipts that were replaced by a newer receipt of the same user in the
+    /// same room and thread, and indexes the remaining ones. Returns how many receipts were
+    /// removed.
+    #[tracing::instrument(skip(self))]
+    pub fn compact_readreceipts(&self) -> Result<usize> {
+        let mut latest = HashMap::new();
+        let mut outdated = Vec::new();
+
+        // Receipts are ordered by count within each room, so later ones replace earlier ones
+        for (key, _) in self.readreceiptid_readreceipt.iter() {
+            let mut parts = key.splitn(2, |&b| b == 0xff);
+            let room_id = parts.next().expect("splitn always returns one element");
+            let user_id = match parts.next() {
+                Some(rest) if rest.len() > mem::size_of::<u64>() + 1 => {
+                    &rest[mem::size_of::<u64>() + 1..]
+                }
+                _ => {
+                    outdated.push(key);
+                    continue;
+                }
+            };
+
+            let mut roomuserthread_id = room_id.to_vec();
+            roomuserthread_id.push(0xff);
+            roomuserthread_id.extend_from_slice(user_id);
+            roomuserthread_id.push(0xff);
+
+            if let Some(old) = latest.insert(roomuserthread_id, key) {
+                outdated.push(old);
+            }
+        }
+
+        for key in &outdated {
+            self.readreceiptid_readreceipt.remove(key)?;
+        }
+
+        for (roomuserthread_id, readreceipt_id) in latest {
+            self.roomuserthreadid_readreceiptid
+                .insert(&roomuserthread_id, &readreceipt_id)?;
+        }
+
+        Ok(outdated.len())
+    }
+
     /// Returns an iterator over the most recent read_receipts in a room that happened after the event with id `since`.
     #[tracing::instrument(skip(self))]
     pub fn readreceipts_since<'a>(
@@ -115,6 +219,32 @@ impl RoomEdus {
             })
     }
 
+    /// Returns the count of the last read receipt in this room. Receipts older than that don't
+    /// have to be looked at.
+    #[tracing::instrument(skip(self))]
+    pub fn last_readreceipt_update(&self, room_id: &RoomId) -> Result<u64> {
+        if let Some(bytes) = self.roomid_lastreceiptupdate.get(room_id.as_bytes())? {
+            return utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Count in roomid_lastreceiptupdate is invalid."));
+        }
+
+        // Receipts from before we tracked the last update: The newest one is the last key of the
+        // room
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        let mut last_possible_key = prefix.clone();
+        last_possible_key.extend_from_slice(&u64::MAX.to_be_bytes());
+
+        self.readreceiptid_readreceipt
+            .iter_from(&last_possible_key, true)
+            .next()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map_or(Ok(0), |(key, _)| {
+                utils::u64_from_bytes(&key[prefix.len()..prefix.len() + mem::size_of::<u64>()])
+                    .map_err(|_| Error::bad_database("Invalid readreceiptid count in db."))
+            })
+    }
+
     /// Sets a private read marker at `count`.
     #[tracing::instrument(skip(self, globals))]
     pub fn private_read_set(
@@ -131,8 +261,10 @@ impl RoomEdus {
         self.roomuserid_privateread
             .insert(&key, &count.to_be_bytes())?;
 
-        self.roomuserid_lastprivatereadupdate
-            .insert(&key, &globals.next_count()?.to_be_bytes())?;
+        let appending_count = globals.next_appending_count()?;
+        let update_count = appending_count.count;
+        self.raise_update_count(&*self.roomuserid_lastprivatereadupdate, &key, update_count)?;
+        self.activity.bump(room_id, update_count)?;
 
         Ok(())
     }
@@ -182,18 +314,19 @@ impl RoomEdus {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
-        let count = globals.next_count()?.to_be_bytes();
+        let appending_count = globals.next_appending_count()?;
+        let count = appending_count.count;
 
         let mut room_typing_id = prefix;
         room_typing_id.extend_from_slice(&timeout.to_be_bytes());
         room_typing_id.push(0xff);
-        room_typing_id.extend_from_slice(&count);
+        room_typing_id.extend_from_slice(&count.to_be_bytes());
 
         self.typingid_userid
             .insert(&room_typing_id, &*user_id.as_bytes())?;
 
-        self.roomid_lasttypingupdate
-            .insert(&room_id.as_bytes(), &count)?;
+        self.raise_update_count(&*self.roomid_lasttypingupdate, room_id.as_bytes(), count)?;
+        self.activity.bump(room_id, count)?;
 
         Ok(())
     }
@@ -223,8 +356,10 @@ impl RoomEdus {
         }
 
         if found_outdated {
-            self.roomid_lasttypingupdate
-                .insert(&room_id.as_bytes(), &globals.next_count()?.to_be_bytes())?;
+            let appending_count = globals.next_appending_count()?;
+            let count = appending_count.count;
+            self.raise_update_count(&*self.roomid_lasttypingupdate, room_id.as_bytes(), count)?;
+            self.activity.bump(room_id, count)?;
         }
 
         Ok(())
@@ -267,8 +402,10 @@ impl RoomEdus {
         }
 
         if found_outdated {
-            self.roomid_lasttypingupdate
-                .insert(&room_id.as_bytes(), &globals.next_count()?.to_be_bytes())?;
+            let appending_count = globals.next_appending_count()?;
+            let count = appending_count.count;
+            self.raise_update_count(&*self.roomid_lasttypingupdate, room_id.as_bytes(), count)?;
+            self.activity.bump(room_id, count)?;
         }
 
         Ok(())
@@ -336,11 +473,12 @@ impl RoomEdus {
     ) -> Result<()> {
         // TODO: Remove old entry? Or maybe just wipe completely from time to time?
 
-        let count = globals.next_count()?.to_be_bytes();
+        let appending_count = globals.next_appending_count()?;
+        let count = appending_count.count;
 
         let mut presence_id = room_id.as_bytes().to_vec();
         presence_id.push(0xff);
-        presence_id.extend_from_slice(&count);
+        presence_id.extend_from_slice(&count.to_be_bytes());
         presence_id.push(0xff);
         presence_id.extend_from_slice(&presence.sender.as_bytes());
 
@@ -354,6 +492,8 @@ impl RoomEdus {
             &utils::millis_since_unix_epoch().to_be_bytes(),
         )?;
 
+        self.activity.bump(room_id, count)?;
+
         Ok(())
     }
 
@@ -380,43 +520,21 @@ impl RoomEdus {
             .transpose()
     }
 
-    pub fn get_last_presence_event(
-        &self,
-        user_id: &UserId,
-        room_id: &RoomId,
-    ) -> Result<Option<PresenceEvent>> {
-        let last_update = match self.last_presence_update(user_id)? {
-            Some(last) => last,
-            None => return Ok(None),
-        };
-
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&last_update.to_be_bytes());
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&user_id.as_bytes());
-
-        self.presenceid_presence
-            .get(&presence_id)?
-            .map(|value| {
-                let mut presence = serde_json::from_slice::<PresenceEvent>(&value)
-                    .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
-                let current_timestamp: UInt = utils::millis_since_unix_epoch()
-                    .try_into()
-                    .expect("time is valid");
-
-                if presence.content.presence == PresenceState::Online {
-                    // Don't set last_active_ago when the user is online
-                    presence.content.last_active_ago = None;
-                } else {
-                    // Convert from timestamp to duration
-                    presence.content.last_active_ago = presence
-                        .content
-                        .last_active_ago
-                        .map(|timestamp| current_timestamp - timestamp);
-                }
+    /// Remembers the presence the user set last.
+    pub fn set_user_presence(&self, user_id: &UserId, presence: &UserPresence) -> Result<()> {
+        self.userid_presence.insert(
+            user_id.as_bytes(),
+            &serde_json::to_vec(presence).expect("UserPresence can be serialized"),
+        )
+    }
 
-                Ok(presence)
+    /// Returns the presence the user set last, or None if they never set one.
+    pub fn user_presence(&self, user_id: &UserId) -> Result<Option<UserPresence>> {
+        self.userid_presence
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid presence in userid_presence."))
             })
             .transpose()
     }
@@ -446,7 +564,8 @@ impl RoomEdus {
         // 5 Minutes
         {
             // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
+            let appending_count = globals.next_appending_count()?;
+            let count = appending_count.count;
             let user_id = utils::string_from_bytes(&user_id_bytes)
                 .map_err(|_| {
                     Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
@@ -456,7 +575,7 @@ impl RoomEdus {
             for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
                 let mut presence_id = room_id.as_bytes().to_vec();
                 presence_id.push(0xff);
-                presence_id.extend_from_slice(&count);
+                presence_id.extend_from_slice(&count.to_be_bytes());
                 presence_id.push(0xff);
                 presence_id.extend_from_slice(&user_id_bytes);
 
@@ -477,6 +596,7 @@ impl RoomEdus {
                     })
                     .expect("PresenceEvent can be serialized"),
                 )?;
+                self.activity.bump(&room_id, count)?;
             }
 
             self.userid_lastpresenceupdate.insert(
@@ -488,15 +608,18 @@ impl RoomEdus {
         Ok(())
     }
 
-    /// Returns an iterator over the most recent presence updates that happened after the event with id `since`.
-    #[tracing::instrument(skip(self, since, _rooms, _globals))]
+    /// Returns the most recent presence updates that happened after the event with id `since`,
+    /// with their counts.
+    ///
+    /// - Status messages that are older than the `status_msg_ttl_secs` of the config are removed
+    #[tracing::instrument(skip(self, since, _rooms, globals))]
     pub fn presence_since(
         &self,
         room_id: &RoomId,
         since: u64,
         _rooms: &super::Rooms,
-        _globals: &super::super::globals::Globals,
-    ) -> Result<HashMap<UserId, PresenceEvent>> {
+        globals: &super::super::globals::Globals,
+    ) -> Result<HashMap<UserId, (u64, PresenceEvent)>> {
         //self.presence_maintain(rooms, globals)?;
 
         let mut prefix = room_id.as_bytes().to_vec();
@@ -528,7 +651,7 @@ impl RoomEdus {
                 .try_into()
                 .expect("time is valid");
 
-            if presence.content.presence == PresenceState::Online {
+            if presence_is_active(&presence.content.presence) {
                 // Don't set last_active_ago when the user is online
                 presence.content.last_active_ago = None;
             } else {
@@ -539,9 +662,32 @@ impl RoomEdus {
                     .map(|timestamp| current_timestamp - timestamp);
             }
 
-            hashmap.insert(user_id, presence);
+            // Older events can only contain older status messages, so they expired too
+            if presence.content.status_msg.is_some()
+                && self
+                    .user_presence(&user_id)?
+                    .map_or(false, |p| p.status_msg_expired(globals.status_msg_ttl()))
+            {
+                presence.content.status_msg = None;
+            }
+
+            let count = utils::u64_from_bytes(&key[prefix.len()..prefix.len() + 8])
+                .map_err(|_| Error::bad_database("Invalid count in presenceid_presence."))?;
+
+            hashmap.insert(user_id, (count, presence));
         }
 
         Ok(hashmap)
     }
 }
+
+/// The key of the latest receipt of a user in a room. Receipts outside of threads use an empty
+/// thread id.
+fn roomuserthread_key(room_id: &RoomId, user_id: &UserId, thread_id: Option<&str>) -> Vec<u8> {
+    let mut key = room_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(user_id.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(thread_id.unwrap_or_default().as_bytes());
+    key
+}
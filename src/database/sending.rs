@@ -2,13 +2,14 @@ use std::{
     collections::{BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     fmt::Debug,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use crate::{
     appservice_server, database::pusher, server_server, utils, Database, Error, PduEvent, Result,
 };
+use bytes::Bytes;
 use federation::transactions::send_transaction_message;
 use ring::digest;
 use rocket::futures::{
@@ -73,6 +74,17 @@ impl OutgoingKind {
 
         prefix
     }
+
+    /// A short name of the destination for admins.
+    pub fn describe(&self) -> String {
+        match self {
+            OutgoingKind::Appservice(id) => format!("appservice {}", id),
+            OutgoingKind::Push(user, _) => {
+                format!("pusher of {}", String::from_utf8_lossy(user))
+            }
+            OutgoingKind::Normal(server) => server.to_string(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -88,6 +100,25 @@ pub struct Sending {
     pub(super) servercurrentevent_data: Arc<dyn Tree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) maximum_requests: Arc<Semaphore>,
     pub sender: mpsc::UnboundedSender<(Vec<u8>, Vec<u8>)>,
+    pub destination_status: Mutex<HashMap<OutgoingKind, DestinationStatus>>,
+}
+
+/// What we know about the delivery to a destination. Only destinations with undelivered events or
+/// failed requests are tracked and the status is reset when the server restarts.
+#[derive(Clone, Debug, Default)]
+pub struct DestinationStatus {
+    /// When the oldest event that was not delivered yet was queued.
+    pub pending_since: Option<Instant>,
+    /// How often the current transaction failed.
+    pub retries: u32,
+    pub last_error: Option<String>,
+}
+
+/// The status of a destination together with the number of events waiting for it.
+pub struct DestinationReport {
+    pub kind: OutgoingKind,
+    pub queued: usize,
+    pub status: DestinationStatus,
 }
 
 enum TransactionStatus {
@@ -141,6 +172,7 @@ impl Sending {
             drop(guard);
 
             for (outgoing_kind, events) in initial_transactions {
+                db.read().await.sending.mark_queued(&outgoing_kind);
                 current_transaction_status
                     .insert(outgoing_kind.get_prefix(), TransactionStatus::Running);
                 futures.push(Self::handle_events(
@@ -183,6 +215,7 @@ impl Sending {
                                         guard.sending.servernameevent_data.remove(&key).unwrap();
                                     }
 
+                                    guard.sending.mark_sent(&outgoing_kind, true);
                                     drop(guard);
 
                                     futures.push(
@@ -193,10 +226,12 @@ impl Sending {
                                         )
                                     );
                                 } else {
+                                    guard.sending.mark_sent(&outgoing_kind, false);
                                     current_transaction_status.remove(&prefix);
                                 }
                             }
-                            Err((outgoing_kind, _)) => {
+                            Err((outgoing_kind, e)) => {
+                                db.read().await.sending.mark_failed(&outgoing_kind, &e);
                                 current_transaction_status.entry(outgoing_kind.get_prefix()).and_modify(|e| *e = match e {
                                     TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
                                     TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
@@ -211,6 +246,7 @@ impl Sending {
                     Some((key, value)) = receiver.next() => {
                         if let Ok((outgoing_kind, event)) = Self::parse_servercurrentevent(&key, value) {
                             let guard = db.read().await;
+                            guard.sending.mark_queued(&outgoing_kind);
 
                             if let Ok(Some(events)) = Self::select_events(
                                 &outgoing_kind,
@@ -227,6 +263,65 @@ impl Sending {
         });
     }
 
+    /// Remembers that there are undelivered events for this destination.
+    fn mark_queued(&self, kind: &OutgoingKind) {
+        self.destination_status
+            .lock()
+            .unwrap()
+            .entry(kind.clone())
+            .or_default()
+            .pending_since
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// Resets the status of a destination after a transaction went through.
+    fn mark_sent(&self, kind: &OutgoingKind, more_pending: bool) {
+        let mut destination_status = self.destination_status.lock().unwrap();
+
+        if more_pending {
+            let status = destination_status.entry(kind.clone()).or_default();
+            status.pending_since = Some(Instant::now());
+            status.retries = 0;
+        } else {
+            destination_status.remove(kind);
+        }
+    }
+
+    fn mark_failed(&self, kind: &OutgoingKind, error: &Error) {
+        let mut destination_status = self.destination_status.lock().unwrap();
+        let status = destination_status.entry(kind.clone()).or_default();
+        status.retries += 1;
+        status.last_error = Some(error.to_string());
+    }
+
+    /// Returns the delivery status of all destinations that have undelivered events or failed
+    /// recently, the longest queues first.
+    pub fn destination_reports(&self) -> Vec<DestinationReport> {
+        let statuses = self.destination_status.lock().unwrap().clone();
+
+        let mut reports = statuses
+            .into_iter()
+            .map(|(kind, status)| {
+                let prefix = kind.get_prefix();
+                let queued = self
+                    .servernameevent_data
+                    .scan_prefix(prefix.clone())
+                    .count()
+                    + self.servercurrentevent_data.scan_prefix(prefix).count();
+
+                DestinationReport {
+                    kind,
+                    queued,
+                    status,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        reports.sort_by(|a, b| b.queued.cmp(&a.queued));
+
+        reports
+    }
+
     #[tracing::instrument(skip(outgoing_kind, new_events, current_transaction_status, db))]
     fn select_events(
         outgoing_kind: &OutgoingKind,
@@ -784,6 +879,24 @@ impl Sending {
         response
     }
 
+    /// Like `send_federation_request`, but also returns the raw response body.
+    #[tracing::instrument(skip(self, globals, destination, request))]
+    pub async fn send_federation_request_with_body<T: OutgoingRequest>(
+        &self,
+        globals: &crate::database::globals::Globals,
+        destination: &ServerName,
+        request: T,
+    ) -> Result<(T::IncomingResponse, Bytes)>
+    where
+        T: Debug,
+    {
+        let permit = self.maximum_requests.acquire().await;
+        let response = server_server::send_request_with_body(globals, destination, request).await;
+        drop(permit);
+
+        response
+    }
+
     #[tracing::instrument(skip(self, globals, registration, request))]
     pub async fn send_appservice_request<T: OutgoingRequest>(
         &self,
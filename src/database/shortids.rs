@@ -0,0 +1,89 @@
+use crate::{utils, Error, Result};
+use ruma::{events::EventType, UserId};
+use std::{convert::TryFrom, sync::Arc};
+
+use super::abstraction::Tree;
+
+/// Short ids are u64 counts that are used in database keys instead of user ids and event types,
+/// which keeps the keys small and fixed-size.
+///
+/// Only the account data keys use them for now. Membership and receipt keys still contain the
+/// full ids, so nothing has to map short user ids back to user ids yet.
+pub struct ShortIds {
+    pub(super) userid_shortuserid: Arc<dyn Tree>,
+    pub(super) eventtype_shorteventtype: Arc<dyn Tree>,
+    pub(super) shorteventtype_eventtype: Arc<dyn Tree>,
+}
+
+impl ShortIds {
+    #[tracing::instrument(skip(self))]
+    pub fn get_shortuserid(&self, user_id: &UserId) -> Result<Option<u64>> {
+        self.userid_shortuserid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid shortuserid in db."))
+            })
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self, globals))]
+    pub fn get_or_create_shortuserid(
+        &self,
+        user_id: &UserId,
+        globals: &super::globals::Globals,
+    ) -> Result<u64> {
+        if let Some(short) = self.get_shortuserid(user_id)? {
+            return Ok(short);
+        }
+
+        let short = globals.next_count()?;
+        self.userid_shortuserid
+            .insert(user_id.as_bytes(), &short.to_be_bytes())?;
+
+        Ok(short)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_shorteventtype(&self, event_type: &EventType) -> Result<Option<u64>> {
+        self.eventtype_shorteventtype
+            .get(event_type.as_ref().as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid shorteventtype in db."))
+            })
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self, globals))]
+    pub fn get_or_create_shorteventtype(
+        &self,
+        event_type: &EventType,
+        globals: &super::globals::Globals,
+    ) -> Result<u64> {
+        if let Some(short) = self.get_shorteventtype(event_type)? {
+            return Ok(short);
+        }
+
+        let short = globals.next_count()?;
+        self.eventtype_shorteventtype
+            .insert(event_type.as_ref().as_bytes(), &short.to_be_bytes())?;
+        self.shorteventtype_eventtype
+            .insert(&short.to_be_bytes(), event_type.as_ref().as_bytes())?;
+
+        Ok(short)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_eventtype_from_short(&self, shorteventtype: u64) -> Result<EventType> {
+        let bytes = self
+            .shorteventtype_eventtype
+            .get(&shorteventtype.to_be_bytes())?
+            .ok_or_else(|| Error::bad_database("Shorteventtype does not exist."))?;
+
+        EventType::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+            Error::bad_database("Event type in shorteventtype_eventtype is invalid unicode.")
+        })?)
+        .map_err(|_| Error::bad_database("Event type in shorteventtype_eventtype is invalid."))
+    }
+}
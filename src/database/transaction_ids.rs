@@ -1,12 +1,13 @@
-use std::sync::Arc;
+use std::{convert::TryFrom, sync::Arc, time::Duration};
 
-use crate::Result;
-use ruma::{DeviceId, UserId};
+use crate::{utils, Error, Result};
+use ruma::{DeviceId, RoomId, UserId};
 
 use super::abstraction::Tree;
 
 pub struct TransactionIds {
     pub(super) userdevicetxnid_response: Arc<dyn Tree>, // Response can be empty (/sendToDevice) or the event id (/send)
+    pub(super) userdevicetxnid_createdroom: Arc<dyn Tree>, // CreatedRoom = Timestamp + RoomId
 }
 
 impl TransactionIds {
@@ -17,13 +18,8 @@ impl TransactionIds {
         txn_id: &str,
         data: &[u8],
     ) -> Result<()> {
-        let mut key = user_id.as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(device_id.map(|d| d.as_bytes()).unwrap_or_default());
-        key.push(0xff);
-        key.extend_from_slice(txn_id.as_bytes());
-
-        self.userdevicetxnid_response.insert(&key, data)?;
+        self.userdevicetxnid_response
+            .insert(&txnid_key(user_id, device_id, txn_id), data)?;
 
         Ok(())
     }
@@ -34,13 +30,99 @@ impl TransactionIds {
         device_id: Option<&DeviceId>,
         txn_id: &str,
     ) -> Result<Option<Vec<u8>>> {
-        let mut key = user_id.as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(device_id.map(|d| d.as_bytes()).unwrap_or_default());
-        key.push(0xff);
-        key.extend_from_slice(txn_id.as_bytes());
-
         // If there's no entry, this is a new transaction
-        self.userdevicetxnid_response.get(&key)
+        self.userdevicetxnid_response
+            .get(&txnid_key(user_id, device_id, txn_id))
+    }
+
+    /// Remembers the room that a createRoom request with this transaction id created.
+    pub fn add_create_room_txnid(
+        &self,
+        user_id: &UserId,
+        device_id: Option<&DeviceId>,
+        txn_id: &str,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        let mut value = utils::millis_since_unix_epoch().to_be_bytes().to_vec();
+        value.extend_from_slice(room_id.as_bytes());
+
+        self.userdevicetxnid_createdroom
+            .insert(&txnid_key(user_id, device_id, txn_id), &value)?;
+
+        Ok(())
+    }
+
+    /// Returns the room that a createRoom request with this transaction id created, unless it
+    /// happened more than `max_age` ago.
+    pub fn existing_create_room_txnid(
+        &self,
+        user_id: &UserId,
+        device_id: Option<&DeviceId>,
+        txn_id: &str,
+        max_age: Duration,
+    ) -> Result<Option<RoomId>> {
+        let key = txnid_key(user_id, device_id, txn_id);
+
+        let value = match self.userdevicetxnid_createdroom.get(&key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        if value.len() < 8 {
+            return Err(Error::bad_database(
+                "Invalid value in userdevicetxnid_createdroom.",
+            ));
+        }
+        let (created, room_id) = value.split_at(8);
+
+        let created = utils::u64_from_bytes(created).map_err(|_| {
+            Error::bad_database("Invalid timestamp in userdevicetxnid_createdroom.")
+        })?;
+        let room_id = utils::string_from_bytes(room_id)
+            .ok()
+            .and_then(|room_id| RoomId::try_from(room_id).ok())
+            .ok_or_else(|| {
+                Error::bad_database("Invalid room id in userdevicetxnid_createdroom.")
+            })?;
+
+        if utils::millis_since_unix_epoch().saturating_sub(created) > max_age.as_millis() as u64 {
+            // The transaction id can be used for a new room now
+            self.userdevicetxnid_createdroom.remove(&key)?;
+            return Ok(None);
+        }
+
+        Ok(Some(room_id))
     }
+
+    /// Removes an entry of `userdevicetxnid_createdroom` if the room was created more than
+    /// `max_age` ago. Returns true if it was removed.
+    pub fn prune_create_room_txnid(
+        &self,
+        key: &[u8],
+        created_room: &[u8],
+        max_age: Duration,
+    ) -> Result<bool> {
+        let created = created_room
+            .get(..8)
+            .and_then(|created| utils::u64_from_bytes(created).ok());
+
+        // Broken entries can't be used anyway
+        if created.map_or(true, |created| {
+            utils::millis_since_unix_epoch().saturating_sub(created) > max_age.as_millis() as u64
+        }) {
+            self.userdevicetxnid_createdroom.remove(key)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+}
+
+fn txnid_key(user_id: &UserId, device_id: Option<&DeviceId>, txn_id: &str) -> Vec<u8> {
+    let mut key = user_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(device_id.map(|d| d.as_bytes()).unwrap_or_default());
+    key.push(0xff);
+    key.extend_from_slice(txn_id.as_bytes());
+    key
 }
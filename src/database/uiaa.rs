@@ -1,6 +1,11 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
-use crate::{client_server::SESSION_ID_LENGTH, utils, Error, Result};
+use crate::{
+    client_server::{
+        check_login_lockout, login_failure_keys, record_login_failure, SESSION_ID_LENGTH,
+    },
+    utils, Database, Error, Result,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -49,8 +54,8 @@ impl Uiaa {
         device_id: &DeviceId,
         auth: &IncomingAuthData,
         uiaainfo: &UiaaInfo,
-        users: &super::users::Users,
-        globals: &super::globals::Globals,
+        client_ip: Option<IpAddr>,
+        db: &Database,
     ) -> Result<(bool, UiaaInfo)> {
         let mut uiaainfo = auth
             .session()
@@ -79,17 +84,19 @@ impl Uiaa {
                 };
 
                 let user_id =
-                    UserId::parse_with_server_name(username.clone(), globals.server_name())
+                    UserId::parse_with_server_name(username.clone(), db.globals.server_name())
                         .map_err(|_| {
                             Error::BadRequest(ErrorKind::InvalidParam, "User ID is invalid.")
                         })?;
 
-                // Check if password is correct
-                if let Some(hash) = users.password_hash(&user_id)? {
-                    let hash_matches =
-                        argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
+                // Passwords can be guessed here just like on login
+                let failure_keys = login_failure_keys(Some(&user_id), client_ip);
+                check_login_lockout(db, &failure_keys)?;
 
-                    if !hash_matches {
+                // Check if password is correct
+                if let Some(hash) = db.users.password_hash(&user_id)? {
+                    if !utils::verify_password(&hash, password) {
+                        record_login_failure(db, &failure_keys)?;
                         uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
                             kind: ErrorKind::Forbidden,
                             message: "Invalid username or password.".to_owned(),
@@ -98,6 +105,10 @@ impl Uiaa {
                     }
                 }
 
+                for (key, _) in &failure_keys {
+                    db.users.reset_login_failures(key)?;
+                }
+
                 // Password was correct! Let's add it to `completed`
                 uiaainfo.completed.push("m.login.password".to_owned());
             }
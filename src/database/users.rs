@@ -1,16 +1,71 @@
 use crate::{utils, Error, Result};
+use lru_cache::LruCache;
 use ruma::{
-    api::client::{error::ErrorKind, r0::device::Device},
+    api::client::{
+        error::ErrorKind,
+        r0::{device::Device, filter::IncomingFilterDefinition},
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::{AnyToDeviceEvent, EventType},
     identifiers::MxcUri,
-    serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, UInt, UserId,
+    serde::{CanonicalJsonObject, CanonicalJsonValue, Raw},
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    mem,
+    net::IpAddr,
+    ops::RangeInclusive,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
 };
-use std::{collections::BTreeMap, convert::TryFrom, mem, sync::Arc};
 use tracing::warn;
 
-use super::abstraction::Tree;
+use super::{abstraction::Tree, activity::RoomActivity};
+
+/// The fallback key of a device for one algorithm. Devices hand it out when they run out of
+/// one-time keys.
+#[derive(Deserialize, Serialize)]
+struct FallbackKey {
+    key_id: DeviceKeyId,
+    /// Kept as we got it, so fields we don't know are still covered by the signature
+    key: serde_json::Value,
+    used: bool,
+}
+
+/// Limits what the access token of a device can do. Users create devices with a scope for bots
+/// and scripts. Devices without a scope can do everything.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenScope {
+    /// Only GET requests are allowed.
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Requests about other rooms and most requests about the whole account are refused and sync
+    /// only returns these rooms and the presence from them, without global account data. None
+    /// allows all rooms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<RoomId>>,
+
+    /// Allows the end-to-end encryption key endpoints and to-device messages.
+    #[serde(default)]
+    pub e2ee: bool,
+}
+
+/// Who may invite a local user. Users choose their policy with the `rs.conduit.invite_policy`
+/// account data event, e.g. `{"policy": "shared_rooms"}`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvitePolicy {
+    /// Invites from everyone are accepted.
+    Everyone,
+    /// Invites are only accepted from servers that share a room with the user.
+    KnownServers,
+    /// Invites are only accepted from users that share a room with the user.
+    SharedRooms,
+}
 
 pub struct Users {
     pub(super) userid_password: Arc<dyn Tree>,
@@ -21,16 +76,38 @@ pub struct Users {
     pub(super) userdeviceid_metadata: Arc<dyn Tree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn Tree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn Tree>,
+    pub(super) userdeviceid_tokencreatedts: Arc<dyn Tree>, // TokenCreatedTs = When the current token was set
+    pub(super) userdeviceid_tokenscope: Arc<dyn Tree>, // TokenScope = What the token of the device may do
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn Tree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn Tree>, // LastOneTimeKeyUpdate = Count
-    pub(super) keychangeid_userid: Arc<dyn Tree>,       // KeyChangeId = UserId/RoomId + Count
+    pub(super) fallbackkeyid_fallbackkey: Arc<dyn Tree>, // FallbackKeyId = UserId + DeviceId + Algorithm
+    pub(super) keychangeid_userid: Arc<dyn Tree>,        // KeyChangeId = UserId/RoomId + Count
     pub(super) keyid_key: Arc<dyn Tree>, // KeyId = UserId + KeyId (depends on key type)
     pub(super) userid_masterkeyid: Arc<dyn Tree>,
     pub(super) userid_selfsigningkeyid: Arc<dyn Tree>,
     pub(super) userid_usersigningkeyid: Arc<dyn Tree>,
 
     pub(super) todeviceid_events: Arc<dyn Tree>, // ToDeviceId = UserId + DeviceId + Count
+    pub(super) userdeviceid_todeviceack: Arc<dyn Tree>, // ToDeviceAck = Highest since token the device synced with
+    pub(super) userdeviceid_removedts: Arc<dyn Tree>,   // RemovedTs = When the device was removed
+    pub(super) userdeviceid_todevicecount: Arc<dyn Tree>, // ToDeviceCount = Number of waiting to-device events
+
+    pub(super) remoteuserid_devicelistid: Arc<dyn Tree>, // DeviceListId = Last known stream id
+    pub(super) remoteuserdeviceid_devicekeys: Arc<dyn Tree>,
+    pub(super) remoteuserid_displayname: Arc<dyn Tree>, // Remote users in the user directory
+    pub(super) loginfailureid_data: Arc<dyn Tree>, // LoginFailureId = "ip" + Ip or "userip" + UserId + Ip, Data = Count + LastFailure + Total
+    pub(super) ipregistrationid_userid: Arc<dyn Tree>, // IpRegistrationId = Ip + Timestamp
+    pub(super) userid_registrationip: Arc<dyn Tree>,
+    pub(super) lowercaselocalpart_userid: Arc<dyn Tree>, // LowercaseLocalpart = Localpart of a local user in lowercase
+    pub(super) userfilterid_filter: Arc<dyn Tree>,       // FilterId = UserId + FilterId
+    pub(super) exportid_expiryuserid: Arc<dyn Tree>,     // ExpiryUserId = ExpiresAt + UserId
+
+    pub(super) token_cache: Mutex<LruCache<String, (UserId, String)>>,
+    /// Makes sure concurrent changes of the to-device event counters don't overwrite each other.
+    pub(super) todevicecount_lock: Mutex<()>,
+    pub(super) password_hash_params: utils::PasswordHashParams,
+    pub(super) activity: Arc<RoomActivity>,
 }
 
 impl Users {
@@ -57,9 +134,27 @@ impl Users {
     #[tracing::instrument(skip(self, user_id, password))]
     pub fn create(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
         self.set_password(user_id, password)?;
+        self.index_lowercase_localpart(user_id)?;
         Ok(())
     }
 
+    /// Remembers the localpart of a local user in lowercase, so new user ids that only differ in
+    /// case can be found.
+    pub fn index_lowercase_localpart(&self, user_id: &UserId) -> Result<()> {
+        self.lowercaselocalpart_userid.insert(
+            user_id.localpart().to_lowercase().as_bytes(),
+            user_id.as_bytes(),
+        )
+    }
+
+    /// Checks if a local user has this localpart, ignoring case.
+    pub fn lowercase_localpart_exists(&self, localpart: &str) -> Result<bool> {
+        Ok(self
+            .lowercaselocalpart_userid
+            .get(localpart.to_lowercase().as_bytes())?
+            .is_some())
+    }
+
     /// Returns the number of users registered on this server.
     #[tracing::instrument(skip(self))]
     pub fn count(&self) -> Result<usize> {
@@ -69,7 +164,12 @@ impl Users {
     /// Find out which user an access token belongs to.
     #[tracing::instrument(skip(self, token))]
     pub fn find_from_token(&self, token: &str) -> Result<Option<(UserId, String)>> {
-        self.token_userdeviceid
+        if let Some(cached) = self.token_cache.lock().unwrap().get_mut(token) {
+            return Ok(Some(cached.clone()));
+        }
+
+        let found = self
+            .token_userdeviceid
             .get(token.as_bytes())?
             .map_or(Ok(None), |bytes| {
                 let mut parts = bytes.split(|&b| b == 0xff);
@@ -91,7 +191,23 @@ impl Users {
                         Error::bad_database("Device ID in token_userdeviceid is invalid.")
                     })?,
                 )))
-            })
+            })?;
+
+        if let Some(found) = &found {
+            self.token_cache
+                .lock()
+                .unwrap()
+                .insert(token.to_owned(), found.clone());
+        }
+
+        Ok(found)
+    }
+
+    /// Forgets a token that was removed from the database.
+    fn uncache_token(&self, token: &[u8]) {
+        if let Ok(token) = utils::string_from_bytes(token) {
+            self.token_cache.lock().unwrap().remove(&token);
+        }
     }
 
     /// Returns an iterator over all users on this homeserver.
@@ -117,11 +233,193 @@ impl Users {
             })
     }
 
+    /// Returns true if the hash should be replaced by one with the configured argon2 parameters.
+    pub fn password_needs_rehash(&self, hash: &str) -> bool {
+        utils::password_needs_rehash(hash, &self.password_hash_params)
+    }
+
+    /// Returns the failures in a row, the time of the last failure and all failures since the
+    /// last successful login.
+    fn login_failures(&self, key: &[u8]) -> Result<(u64, u64, u64)> {
+        self.loginfailureid_data
+            .get(key)?
+            .map_or(Ok((0, 0, 0)), |bytes| {
+                let count = utils::u64_from_bytes(bytes.get(..8).unwrap_or_default());
+                let last_failure = utils::u64_from_bytes(bytes.get(8..16).unwrap_or_default());
+                // Older entries don't have a total yet
+                let total = bytes.get(16..).map_or(count, utils::u64_from_bytes);
+                match (count, last_failure, total) {
+                    (Ok(count), Ok(last_failure), Ok(total)) => Ok((count, last_failure, total)),
+                    _ => Err(Error::bad_database("Invalid login failure data in db.")),
+                }
+            })
+    }
+
+    /// Returns how long login attempts for this key are still rejected.
+    ///
+    /// Logins are rejected for `lockout` after `max_failures` failed attempts in a row.
+    pub fn login_lockout(
+        &self,
+        key: &[u8],
+        max_failures: u32,
+        lockout: Duration,
+    ) -> Result<Option<Duration>> {
+        let (count, last_failure, _) = self.login_failures(key)?;
+        let elapsed =
+            Duration::from_millis(utils::millis_since_unix_epoch().saturating_sub(last_failure));
+
+        if count >= u64::from(max_failures) && elapsed < lockout {
+            Ok(Some(lockout - elapsed))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Counts a failed login attempt and returns how many attempts failed in a row and how many
+    /// failed since the last successful login. Failures in a row that are older than `lockout`
+    /// are forgotten, the total is only reset by `reset_login_failures`.
+    pub fn record_login_failure(&self, key: &[u8], lockout: Duration) -> Result<(u64, u64)> {
+        let (count, last_failure, total) = self.login_failures(key)?;
+        let now = utils::millis_since_unix_epoch();
+
+        let count = if Duration::from_millis(now.saturating_sub(last_failure)) < lockout {
+            count + 1
+        } else {
+            1
+        };
+        let total = total + 1;
+
+        let mut data = count.to_be_bytes().to_vec();
+        data.extend_from_slice(&now.to_be_bytes());
+        data.extend_from_slice(&total.to_be_bytes());
+        self.loginfailureid_data.insert(key, &data)?;
+
+        Ok((count, total))
+    }
+
+    pub fn reset_login_failures(&self, key: &[u8]) -> Result<()> {
+        self.loginfailureid_data.remove(key)
+    }
+
+    /// Remembers that the data export with this id belongs to the user and can be downloaded
+    /// until `expires_at`.
+    pub fn add_data_export(
+        &self,
+        export_id: &str,
+        user_id: &UserId,
+        expires_at: u64,
+    ) -> Result<()> {
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_bytes());
+
+        self.exportid_expiryuserid
+            .insert(export_id.as_bytes(), &value)
+    }
+
+    /// Returns the user a data export belongs to, unless it expired.
+    pub fn data_export_owner(&self, export_id: &str) -> Result<Option<UserId>> {
+        self.exportid_expiryuserid
+            .get(export_id.as_bytes())?
+            .map(|bytes| parse_data_export(&bytes))
+            .transpose()
+            .map(|export| {
+                export
+                    .filter(|(expires_at, _)| *expires_at > utils::millis_since_unix_epoch())
+                    .map(|(_, user_id)| user_id)
+            })
+    }
+
+    /// Forgets the expired data exports and returns their ids, so their files can be deleted.
+    pub fn remove_expired_data_exports(&self) -> Result<Vec<String>> {
+        let now = utils::millis_since_unix_epoch();
+        let mut expired = Vec::new();
+
+        for (export_id, bytes) in self.exportid_expiryuserid.iter() {
+            if parse_data_export(&bytes)?.0 <= now {
+                self.exportid_expiryuserid.remove(&export_id)?;
+                expired.push(
+                    utils::string_from_bytes(&export_id)
+                        .map_err(|_| Error::bad_database("Invalid export id in db."))?,
+                );
+            }
+        }
+
+        Ok(expired)
+    }
+
+    /// Remembers from which address a user registered.
+    pub fn record_registration(&self, user_id: &UserId, ip: &IpAddr) -> Result<()> {
+        let ip = ip.to_string();
+
+        let mut key = ip.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&utils::millis_since_unix_epoch().to_be_bytes());
+
+        self.ipregistrationid_userid
+            .insert(&key, user_id.as_bytes())?;
+        self.userid_registrationip
+            .insert(user_id.as_bytes(), ip.as_bytes())
+    }
+
+    /// Returns the users that registered from this address and when, oldest first.
+    pub fn registrations_from_ip<'a>(
+        &'a self,
+        ip: &IpAddr,
+    ) -> impl Iterator<Item = Result<(u64, UserId)>> + 'a {
+        let mut prefix = ip.to_string().into_bytes();
+        prefix.push(0xff);
+        let prefix_len = prefix.len();
+
+        self.ipregistrationid_userid
+            .scan_prefix(prefix)
+            .map(move |(key, user_id)| {
+                let registered_at = utils::u64_from_bytes(&key[prefix_len..])
+                    .map_err(|_| Error::bad_database("Invalid timestamp in ipregistrationid."))?;
+                let user_id =
+                    UserId::try_from(utils::string_from_bytes(&user_id).map_err(|_| {
+                        Error::bad_database(
+                            "User ID in ipregistrationid_userid is invalid unicode.",
+                        )
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("User ID in ipregistrationid_userid is invalid.")
+                    })?;
+
+                Ok((registered_at, user_id))
+            })
+    }
+
+    /// Returns the address a user registered from, if it is known.
+    pub fn registration_ip(&self, user_id: &UserId) -> Result<Option<IpAddr>> {
+        self.userid_registrationip
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .ok()
+                    .and_then(|ip| ip.parse().ok())
+                    .ok_or_else(|| Error::bad_database("Invalid registration ip in db."))
+            })
+            .transpose()
+    }
+
     /// Hash and set the user's password to the Argon2 hash
     #[tracing::instrument(skip(self, user_id, password))]
     pub fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
+        // Cached tokens of this user have to be checked against the database again
+        {
+            let mut token_cache = self.token_cache.lock().unwrap();
+            let tokens = token_cache
+                .iter()
+                .filter(|(_, (cached_user, _))| cached_user == user_id)
+                .map(|(token, _)| token.clone())
+                .collect::<Vec<_>>();
+            for token in tokens {
+                token_cache.remove(&token);
+            }
+        }
+
         if let Some(password) = password {
-            if let Ok(hash) = utils::calculate_hash(&password) {
+            if let Ok(hash) = utils::calculate_hash(&password, &self.password_hash_params) {
                 self.userid_password
                     .insert(user_id.as_bytes(), hash.as_bytes())?;
                 Ok(())
@@ -137,6 +435,44 @@ impl Users {
         }
     }
 
+    /// Adds a remote user we share a room with to the user directory.
+    #[tracing::instrument(skip(self, user_id, displayname))]
+    pub fn set_directory_entry(&self, user_id: &UserId, displayname: Option<&str>) -> Result<()> {
+        self.remoteuserid_displayname.insert(
+            user_id.as_bytes(),
+            displayname.unwrap_or_default().as_bytes(),
+        )
+    }
+
+    /// Removes a remote user from the user directory.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn remove_directory_entry(&self, user_id: &UserId) -> Result<()> {
+        self.remoteuserid_displayname.remove(user_id.as_bytes())
+    }
+
+    /// Returns the remote users of the user directory with their displayname.
+    #[tracing::instrument(skip(self))]
+    pub fn directory_entries(&self) -> impl Iterator<Item = Result<(UserId, Option<String>)>> + '_ {
+        self.remoteuserid_displayname
+            .iter()
+            .map(|(user_id, displayname)| {
+                let user_id =
+                    UserId::try_from(utils::string_from_bytes(&user_id).map_err(|_| {
+                        Error::bad_database(
+                            "User ID in remoteuserid_displayname is invalid unicode.",
+                        )
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("User ID in remoteuserid_displayname is invalid.")
+                    })?;
+                let displayname = utils::string_from_bytes(&displayname).map_err(|_| {
+                    Error::bad_database("Displayname in remoteuserid_displayname is invalid.")
+                })?;
+
+                Ok((user_id, Some(displayname).filter(|name| !name.is_empty())))
+            })
+    }
+
     /// Returns the displayname of a user on this homeserver.
     #[tracing::instrument(skip(self, user_id))]
     pub fn displayname(&self, user_id: &UserId) -> Result<Option<String>> {
@@ -245,6 +581,8 @@ impl Users {
             .expect("Device::to_string never fails."),
         )?;
 
+        self.userdeviceid_removedts.remove(&userdeviceid)?;
+
         self.set_token(user_id, &device_id, token)?;
 
         Ok(())
@@ -261,7 +599,10 @@ impl Users {
         if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
             self.userdeviceid_token.remove(&userdeviceid)?;
             self.token_userdeviceid.remove(&old_token)?;
+            self.uncache_token(&old_token);
         }
+        self.userdeviceid_tokencreatedts.remove(&userdeviceid)?;
+        self.userdeviceid_tokenscope.remove(&userdeviceid)?;
 
         // Remove todevice events
         let mut prefix = userdeviceid.clone();
@@ -270,6 +611,14 @@ impl Users {
         for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
             self.todeviceid_events.remove(&key)?;
         }
+        self.userdeviceid_todeviceack.remove(&userdeviceid)?;
+        self.userdeviceid_todevicecount.remove(&userdeviceid)?;
+
+        let mut prefix = userdeviceid.clone();
+        prefix.push(0xff);
+        for (key, _) in self.fallbackkeyid_fallbackkey.scan_prefix(prefix) {
+            self.fallbackkeyid_fallbackkey.remove(&key)?;
+        }
 
         // TODO: Remove onetimekeys
 
@@ -277,6 +626,10 @@ impl Users {
             .increment(user_id.as_bytes())?;
 
         self.userdeviceid_metadata.remove(&userdeviceid)?;
+        self.userdeviceid_removedts.insert(
+            &userdeviceid,
+            &utils::millis_since_unix_epoch().to_be_bytes(),
+        )?;
 
         Ok(())
     }
@@ -304,6 +657,48 @@ impl Users {
             })
     }
 
+    /// Stores a filter definition and returns the id clients can use to refer to it.
+    #[tracing::instrument(skip(self, user_id, filter))]
+    pub fn create_filter(&self, user_id: &UserId, filter: &CanonicalJsonValue) -> Result<String> {
+        // Make sure we can load the filter again later
+        serde_json::from_value::<IncomingFilterDefinition>(
+            serde_json::to_value(filter).expect("canonical json is valid json"),
+        )
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid filter definition."))?;
+
+        let filter_id = utils::random_string(10);
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.userfilterid_filter.insert(
+            &key,
+            &serde_json::to_vec(filter).expect("canonical json is valid json"),
+        )?;
+
+        Ok(filter_id)
+    }
+
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn get_filter(
+        &self,
+        user_id: &UserId,
+        filter_id: &str,
+    ) -> Result<Option<IncomingFilterDefinition>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.userfilterid_filter
+            .get(&key)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid filter in userfilterid_filter."))
+            })
+            .transpose()
+    }
+
     /// Replaces the access token of one device.
     #[tracing::instrument(skip(self, user_id, device_id, token))]
     pub fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()> {
@@ -317,6 +712,7 @@ impl Users {
         // Remove old token
         if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
             self.token_userdeviceid.remove(&old_token)?;
+            self.uncache_token(&old_token);
             // It will be removed from userdeviceid_token by the insert later
         }
 
@@ -325,6 +721,111 @@ impl Users {
             .insert(&userdeviceid, token.as_bytes())?;
         self.token_userdeviceid
             .insert(token.as_bytes(), &userdeviceid)?;
+        self.userdeviceid_tokencreatedts.insert(
+            &userdeviceid,
+            &utils::millis_since_unix_epoch().to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns when the current access token of the device was created. Tokens from before we
+    /// recorded this return None.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn token_created_ts(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokencreatedts
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid timestamp in userdeviceid_tokencreatedts.")
+                })
+            })
+            .transpose()
+    }
+
+    /// Restricts what the token of the device can do.
+    #[tracing::instrument(skip(self, user_id, device_id, scope))]
+    pub fn set_token_scope(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        scope: &TokenScope,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokenscope.insert(
+            &userdeviceid,
+            &serde_json::to_vec(scope).expect("TokenScope::to_vec always works"),
+        )
+    }
+
+    /// Returns the scope of the token of the device, or None if the token can do everything.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn token_scope(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<TokenScope>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokenscope
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid scope in userdeviceid_tokenscope."))
+            })
+            .transpose()
+    }
+
+    /// Remembers when and from where a device was last used. To avoid a write on every request,
+    /// the timestamp is only updated once every few minutes unless the address changed.
+    ///
+    /// This doesn't count as a device list change, so other users are not notified.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn update_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        const LAST_SEEN_INTERVAL: u64 = 5 * 60 * 1000;
+
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let mut device = match self.get_device_metadata(user_id, device_id)? {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+
+        let now = utils::millis_since_unix_epoch();
+        let ip = ip.map(|ip| ip.to_string());
+        let recently_seen = device.last_seen_ts.map_or(false, |ts| {
+            now.saturating_sub(u64::from(ts.get())) < LAST_SEEN_INTERVAL
+        });
+
+        if recently_seen && device.last_seen_ip == ip {
+            return Ok(());
+        }
+
+        device.last_seen_ts = Some(MilliSecondsSinceUnixEpoch::now());
+        if ip.is_some() {
+            device.last_seen_ip = ip;
+        }
+
+        self.userdeviceid_metadata.insert(
+            &userdeviceid,
+            &serde_json::to_vec(&device).expect("Device::to_string always works"),
+        )?;
 
         Ok(())
     }
@@ -367,8 +868,10 @@ impl Users {
             &serde_json::to_vec(&one_time_key_value).expect("OneTimeKey::to_vec always works"),
         )?;
 
-        self.userid_lastonetimekeyupdate
-            .insert(&user_id.as_bytes(), &globals.next_count()?.to_be_bytes())?;
+        self.userid_lastonetimekeyupdate.insert(
+            &user_id.as_bytes(),
+            &globals.next_appending_count()?.count.to_be_bytes(),
+        )?;
 
         Ok(())
     }
@@ -401,8 +904,10 @@ impl Users {
         prefix.extend_from_slice(key_algorithm.as_ref().as_bytes());
         prefix.push(b':');
 
-        self.userid_lastonetimekeyupdate
-            .insert(&user_id.as_bytes(), &globals.next_count()?.to_be_bytes())?;
+        self.userid_lastonetimekeyupdate.insert(
+            &user_id.as_bytes(),
+            &globals.next_appending_count()?.count.to_be_bytes(),
+        )?;
 
         self.onetimekeyid_onetimekeys
             .scan_prefix(prefix)
@@ -425,6 +930,106 @@ impl Users {
             .transpose()
     }
 
+    /// Stores the fallback key of a device for the algorithm of the key id, replacing the
+    /// previous one. Uploading the same key again keeps it marked as used.
+    #[tracing::instrument(skip(self, user_id, device_id, key_id, key))]
+    pub fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_id: &DeviceKeyId,
+        key: serde_json::Value,
+    ) -> Result<()> {
+        let mut fallbackkeyid = user_id.as_bytes().to_vec();
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(device_id.as_bytes());
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(key_id.algorithm().as_ref().as_bytes());
+
+        let used = self
+            .get_fallback_key(&fallbackkeyid)?
+            .map_or(false, |previous| {
+                &previous.key_id == key_id && previous.used
+            });
+
+        self.fallbackkeyid_fallbackkey.insert(
+            &fallbackkeyid,
+            &serde_json::to_vec(&FallbackKey {
+                key_id: key_id.clone(),
+                key,
+                used,
+            })
+            .expect("FallbackKey::to_vec always works"),
+        )
+    }
+
+    fn get_fallback_key(&self, fallbackkeyid: &[u8]) -> Result<Option<FallbackKey>> {
+        self.fallbackkeyid_fallbackkey
+            .get(fallbackkeyid)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("FallbackKey in db is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Returns the fallback key of a device as it was uploaded and marks it as used. The key stays
+    /// around until the device uploads a new one, so every claim gets it.
+    #[tracing::instrument(skip(self, user_id, device_id, key_algorithm))]
+    pub fn claim_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(DeviceKeyId, serde_json::Value)>> {
+        let mut fallbackkeyid = user_id.as_bytes().to_vec();
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(device_id.as_bytes());
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(key_algorithm.as_ref().as_bytes());
+
+        let mut fallback_key = match self.get_fallback_key(&fallbackkeyid)? {
+            Some(fallback_key) => fallback_key,
+            None => return Ok(None),
+        };
+
+        if !fallback_key.used {
+            fallback_key.used = true;
+            self.fallbackkeyid_fallbackkey.insert(
+                &fallbackkeyid,
+                &serde_json::to_vec(&fallback_key).expect("FallbackKey::to_vec always works"),
+            )?;
+        }
+
+        Ok(Some((fallback_key.key_id, fallback_key.key)))
+    }
+
+    /// Returns the algorithms of the fallback keys of a device that nobody claimed yet.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        let mut algorithms = Vec::new();
+
+        for (_, bytes) in self.fallbackkeyid_fallbackkey.scan_prefix(prefix) {
+            let fallback_key = serde_json::from_slice::<FallbackKey>(&bytes)
+                .map_err(|_| Error::bad_database("FallbackKey in db is invalid."))?;
+
+            if !fallback_key.used {
+                algorithms.push(fallback_key.key_id.algorithm());
+            }
+        }
+
+        Ok(algorithms)
+    }
+
     #[tracing::instrument(skip(self, user_id, device_id))]
     pub fn count_one_time_keys(
         &self,
@@ -679,7 +1284,8 @@ impl Users {
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals,
     ) -> Result<()> {
-        let count = globals.next_count()?.to_be_bytes();
+        let appending_count = globals.next_appending_count()?;
+        let count = appending_count.count;
         for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
             // Don't send key updates to unencrypted rooms
             if rooms
@@ -691,14 +1297,15 @@ impl Users {
 
             let mut key = room_id.as_bytes().to_vec();
             key.push(0xff);
-            key.extend_from_slice(&count);
+            key.extend_from_slice(&count.to_be_bytes());
 
             self.keychangeid_userid.insert(&key, user_id.as_bytes())?;
+            self.activity.bump(&room_id, count)?;
         }
 
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
-        key.extend_from_slice(&count);
+        key.extend_from_slice(&count.to_be_bytes());
         self.keychangeid_userid.insert(&key, user_id.as_bytes())?;
 
         Ok(())
@@ -808,29 +1415,251 @@ impl Users {
         content: serde_json::Value,
         globals: &super::globals::Globals,
     ) -> Result<()> {
-        let mut key = target_user_id.as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(target_device_id.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        let mut userdeviceid = target_user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(target_device_id.as_bytes());
 
         let mut json = serde_json::Map::new();
         json.insert("type".to_owned(), event_type.to_owned().into());
         json.insert("sender".to_owned(), sender.to_string().into());
         json.insert("content".to_owned(), content);
 
+        if EventType::from(event_type) == EventType::RoomKeyRequest {
+            globals
+                .metrics
+                .room_key_requests
+                .fetch_add(1, Ordering::Relaxed);
+
+            if globals.block_key_requests_to_untrusted_devices()
+                && !self.is_device_trusted(target_user_id, target_device_id)?
+            {
+                globals
+                    .metrics
+                    .room_key_requests_blocked
+                    .fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+
+            // Clients tend to repeat key requests until they get an answer, the device only needs
+            // to see each request once
+            if self.is_to_device_event_pending(target_user_id, target_device_id, &json)? {
+                globals
+                    .metrics
+                    .room_key_requests_deduplicated
+                    .fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+
         let value = serde_json::to_vec(&json).expect("Map::to_vec always works");
 
+        let _lock = self.todevicecount_lock.lock().unwrap();
+
+        let pending = self.evict_to_device_events(
+            target_user_id,
+            target_device_id,
+            &userdeviceid,
+            globals.max_to_device_events_per_device().into(),
+            globals,
+        )?;
+
+        // Syncs that see this count acknowledge the event, so it has to be taken right before the
+        // event is stored
+        let appending_count = globals.next_appending_count()?;
+        let mut key = userdeviceid.clone();
+        key.push(0xff);
+        key.extend_from_slice(&appending_count.count.to_be_bytes());
+
         self.todeviceid_events.insert(&key, &value)?;
+        self.set_to_device_event_count(&userdeviceid, pending + 1)?;
 
         Ok(())
     }
 
+    /// Makes room for a new to-device event by dropping the oldest events of a device that has
+    /// `max` or more events waiting. Returns how many events are still waiting.
+    #[tracing::instrument(skip(self, user_id, device_id, userdeviceid, globals))]
+    fn evict_to_device_events(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        userdeviceid: &[u8],
+        max: u64,
+        globals: &super::globals::Globals,
+    ) -> Result<u64> {
+        let pending = self.to_device_event_count(userdeviceid)?;
+
+        if pending < max {
+            return Ok(pending);
+        }
+
+        let evict = pending + 1 - max;
+
+        let mut prefix = userdeviceid.to_vec();
+        prefix.push(0xff);
+
+        // Keys end with the count, so the oldest events come first
+        for (key, _) in self
+            .todeviceid_events
+            .scan_prefix(prefix.clone())
+            .take(evict as usize)
+        {
+            self.todeviceid_events.remove(&key)?;
+        }
+
+        globals
+            .metrics
+            .to_device_events_evicted
+            .fetch_add(evict, Ordering::Relaxed);
+
+        warn!(
+            "Dropped {} to-device events of {} {} because it doesn't pick them up",
+            evict, user_id, device_id
+        );
+
+        // Evictions are rare, so count again in case the counter drifted
+        Ok(self.todeviceid_events.scan_prefix(prefix).count() as u64)
+    }
+
+    /// Returns how many to-device events are waiting for the device. Devices that got their
+    /// events before the counter existed are counted once.
+    fn to_device_event_count(&self, userdeviceid: &[u8]) -> Result<u64> {
+        match self.userdeviceid_todevicecount.get(userdeviceid)? {
+            Some(bytes) => utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("ToDeviceCount in db is invalid.")),
+            None => {
+                let mut prefix = userdeviceid.to_vec();
+                prefix.push(0xff);
+                Ok(self.todeviceid_events.scan_prefix(prefix).count() as u64)
+            }
+        }
+    }
+
+    fn set_to_device_event_count(&self, userdeviceid: &[u8], count: u64) -> Result<()> {
+        if count == 0 {
+            self.userdeviceid_todevicecount.remove(userdeviceid)
+        } else {
+            self.userdeviceid_todevicecount
+                .insert(userdeviceid, &count.to_be_bytes())
+        }
+    }
+
+    /// Deletes a to-device event if its device does not exist and was removed more than
+    /// `retention` ago. Returns true if the event was deleted.
+    pub fn prune_to_device_event(&self, todeviceid: &[u8], retention: Duration) -> Result<bool> {
+        // Cut off 0xff + Count
+        let userdeviceid = match todeviceid.len().checked_sub(mem::size_of::<u64>() + 1) {
+            Some(len) => &todeviceid[..len],
+            None => return Err(Error::bad_database("ToDeviceId in db is invalid.")),
+        };
+
+        if self.userdeviceid_metadata.get(userdeviceid)?.is_some() {
+            return Ok(false);
+        }
+
+        // Devices that were removed before this was tracked are treated as removed long ago
+        if let Some(removed_ts) = self.userdeviceid_removedts.get(userdeviceid)? {
+            let removed_ts = utils::u64_from_bytes(&removed_ts)
+                .map_err(|_| Error::bad_database("RemovedTs in db is invalid."))?;
+
+            if utils::millis_since_unix_epoch().saturating_sub(removed_ts)
+                < retention.as_millis() as u64
+            {
+                return Ok(false);
+            }
+        }
+
+        let _lock = self.todevicecount_lock.lock().unwrap();
+
+        self.todeviceid_events.remove(todeviceid)?;
+        let pending = self.to_device_event_count(userdeviceid)?;
+        self.set_to_device_event_count(userdeviceid, pending.saturating_sub(1))?;
+
+        Ok(true)
+    }
+
+    /// Forgets when devices were removed more than `retention` ago. Their to-device events are
+    /// pruned like the ones of devices that never existed.
+    pub fn prune_removed_devices(&self, retention: Duration) -> Result<()> {
+        let now = utils::millis_since_unix_epoch();
+
+        for (userdeviceid, removed_ts) in self.userdeviceid_removedts.iter() {
+            let removed_ts = utils::u64_from_bytes(&removed_ts)
+                .map_err(|_| Error::bad_database("RemovedTs in db is invalid."))?;
+
+            if now.saturating_sub(removed_ts) >= retention.as_millis() as u64 {
+                self.userdeviceid_removedts.remove(&userdeviceid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks if the same to-device event is still waiting to be picked up by the device.
+    #[tracing::instrument(skip(self, user_id, device_id, json))]
+    fn is_to_device_event_pending(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        json: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<bool> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
+            let pending =
+                serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&value)
+                    .map_err(|_| Error::bad_database("Event in todeviceid_events is invalid."))?;
+
+            if &pending == json {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Checks if the device keys of a local device are signed by the user's self-signing key.
+    ///
+    /// Devices of users without cross-signing keys are always considered trusted, because there
+    /// is nothing to check against.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn is_device_trusted(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        let self_signing_key = match self.get_self_signing_key(user_id, |_| false)? {
+            Some(key) => key,
+            None => return Ok(true),
+        };
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        let device_keys = match self.keyid_key.get(&key)? {
+            Some(bytes) => serde_json::from_slice::<CanonicalJsonObject>(&bytes)
+                .map_err(|_| Error::bad_database("DeviceKeys in db are invalid."))?,
+            // Devices without keys can't decrypt anything
+            None => return Ok(false),
+        };
+
+        Ok(is_signed_by_cross_signing_key(
+            user_id,
+            &self_signing_key,
+            &device_keys,
+        ))
+    }
+
+    /// Returns the to-device events of the device after `since` up to and including `until`.
+    /// Newer events are left for the next sync, which only acknowledges events up to its `since`.
+    /// Older events were acknowledged already, but replicas can't delete them.
     #[tracing::instrument(skip(self, user_id, device_id))]
     pub fn get_to_device_events(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
+        since: u64,
+        until: u64,
     ) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
         let mut events = Vec::new();
 
@@ -839,7 +1668,17 @@ impl Users {
         prefix.extend_from_slice(device_id.as_bytes());
         prefix.push(0xff);
 
-        for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
+        let mut first = prefix.clone();
+        first.extend_from_slice(&(since + 1).to_be_bytes());
+
+        let mut last = prefix.clone();
+        last.extend_from_slice(&until.to_be_bytes());
+
+        for (_, value) in self
+            .todeviceid_events
+            .iter_from(&first, false)
+            .take_while(|(key, _)| *key <= last)
+        {
             events.push(
                 serde_json::from_slice(&value)
                     .map_err(|_| Error::bad_database("Event in todeviceid_events is invalid."))?,
@@ -849,24 +1688,48 @@ impl Users {
         Ok(events)
     }
 
-    #[tracing::instrument(skip(self, user_id, device_id, until))]
-    pub fn remove_to_device_events(
+    /// Deletes the to-device events the device acknowledged by syncing with `since`.
+    ///
+    /// A sync with `since` proves that the client got the response that handed out `since`, so
+    /// all events up to and including `since` arrived. Syncs with a token older than an earlier
+    /// acknowledged one, e.g. because the client retried a request, don't delete anything.
+    #[tracing::instrument(skip(self, user_id, device_id, since))]
+    pub fn acknowledge_to_device_events(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
-        until: u64,
+        since: u64,
     ) -> Result<()> {
-        let mut prefix = user_id.as_bytes().to_vec();
-        prefix.push(0xff);
-        prefix.extend_from_slice(device_id.as_bytes());
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let acknowledged = self
+            .userdeviceid_todeviceack
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("ToDeviceAck in db is invalid."))
+            })
+            .transpose()?;
+
+        let counts = match newly_acknowledged_counts(acknowledged, since) {
+            Some(counts) => counts,
+            None => return Ok(()),
+        };
+
+        let mut prefix = userdeviceid.clone();
         prefix.push(0xff);
 
-        let mut last = prefix.clone();
-        last.extend_from_slice(&until.to_be_bytes());
+        let mut first = prefix.clone();
+        first.extend_from_slice(&counts.start().to_be_bytes());
 
+        let _lock = self.todevicecount_lock.lock().unwrap();
+
+        let mut removed = 0;
         for (key, _) in self
             .todeviceid_events
-            .iter_from(&last, true) // this includes last
+            .iter_from(&first, false)
             .take_while(move |(k, _)| k.starts_with(&prefix))
             .map(|(key, _)| {
                 Ok::<_, Error>((
@@ -876,11 +1739,20 @@ impl Users {
                 ))
             })
             .filter_map(|r| r.ok())
-            .take_while(|&(_, count)| count <= until)
+            .take_while(|(_, count)| counts.contains(count))
         {
             self.todeviceid_events.remove(&key)?;
+            removed += 1;
         }
 
+        if removed > 0 {
+            let pending = self.to_device_event_count(&userdeviceid)?;
+            self.set_to_device_event_count(&userdeviceid, pending.saturating_sub(removed))?;
+        }
+
+        self.userdeviceid_todeviceack
+            .insert(&userdeviceid, &since.to_be_bytes())?;
+
         Ok(())
     }
 
@@ -956,6 +1828,94 @@ impl Users {
             })
     }
 
+    /// Returns the stream id of the last device list update we know of for a remote user.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn remote_devicelist_id(&self, user_id: &UserId) -> Result<Option<u64>> {
+        self.remoteuserid_devicelistid
+            .get(user_id.as_bytes())?
+            .map_or(Ok(None), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid remote device list id in db."))
+                    .map(Some)
+            })
+    }
+
+    /// Applies a single device list update of a remote user. Passing no keys removes the device.
+    ///
+    /// Returns true if the devices of the user changed.
+    #[tracing::instrument(skip(self, user_id, device_id, device_keys))]
+    pub fn update_remote_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        device_keys: Option<&[u8]>,
+        stream_id: u64,
+    ) -> Result<bool> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        let old_keys = self.remoteuserdeviceid_devicekeys.get(&key)?;
+        let changed = !same_device_keys(old_keys.as_deref(), device_keys);
+
+        match device_keys {
+            Some(device_keys) => self
+                .remoteuserdeviceid_devicekeys
+                .insert(&key, device_keys)?,
+            None => self.remoteuserdeviceid_devicekeys.remove(&key)?,
+        }
+
+        self.remoteuserid_devicelistid
+            .insert(user_id.as_bytes(), &stream_id.to_be_bytes())?;
+
+        Ok(changed)
+    }
+
+    /// Replaces all known devices of a remote user with the full list from their server.
+    ///
+    /// Returns true if the full list differs from what we knew.
+    #[tracing::instrument(skip(self, user_id, devices))]
+    pub fn replace_remote_devices<'a>(
+        &self,
+        user_id: &UserId,
+        devices: impl Iterator<Item = (&'a DeviceId, &'a DeviceKeys)>,
+        stream_id: u64,
+    ) -> Result<bool> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut old_devices = self
+            .remoteuserdeviceid_devicekeys
+            .scan_prefix(prefix.clone())
+            .collect::<HashMap<_, _>>();
+        let mut changed = false;
+
+        for (device_id, device_keys) in devices {
+            let mut key = prefix.clone();
+            key.extend_from_slice(device_id.as_bytes());
+
+            let device_keys =
+                serde_json::to_vec(device_keys).expect("DeviceKeys::to_vec always works");
+            let old_keys = old_devices.remove(&key);
+            if !same_device_keys(old_keys.as_deref(), Some(device_keys.as_slice())) {
+                changed = true;
+                self.remoteuserdeviceid_devicekeys
+                    .insert(&key, &device_keys)?;
+            }
+        }
+
+        // Devices that are not in the full list anymore were deleted
+        for key in old_devices.keys() {
+            changed = true;
+            self.remoteuserdeviceid_devicekeys.remove(key)?;
+        }
+
+        self.remoteuserid_devicelistid
+            .insert(user_id.as_bytes(), &stream_id.to_be_bytes())?;
+
+        Ok(changed)
+    }
+
     /// Deactivate account
     #[tracing::instrument(skip(self, user_id))]
     pub fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
@@ -973,3 +1933,227 @@ impl Users {
         Ok(())
     }
 }
+
+/// Compares stored device keys as json, because the same keys can be serialized differently.
+fn same_device_keys(a: Option<&[u8]>, b: Option<&[u8]>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => {
+            a == b
+                || serde_json::from_slice::<serde_json::Value>(a).ok()
+                    == serde_json::from_slice::<serde_json::Value>(b).ok()
+        }
+        _ => false,
+    }
+}
+
+/// Checks if the json object carries a valid signature by one of the keys of the cross-signing key.
+pub fn is_signed_by_cross_signing_key(
+    user_id: &UserId,
+    cross_signing_key: &CrossSigningKey,
+    object: &CanonicalJsonObject,
+) -> bool {
+    let mut public_key_map = BTreeMap::new();
+    public_key_map.insert(user_id.to_string(), cross_signing_key.keys.clone());
+
+    ruma::signatures::verify_json(&public_key_map, object).is_ok()
+}
+
+/// Returns the counts of the to-device events a sync with `since` acknowledges, or None if it
+/// acknowledges nothing new. Everything up to `acknowledged` was deleted before.
+fn newly_acknowledged_counts(acknowledged: Option<u64>, since: u64) -> Option<RangeInclusive<u64>> {
+    match acknowledged {
+        Some(acknowledged) if since <= acknowledged => None,
+        Some(acknowledged) => Some(acknowledged + 1..=since),
+        // The initial sync acknowledges nothing
+        None if since == 0 => None,
+        None => Some(0..=since),
+    }
+}
+
+/// Parses the expiry and the user of a data export.
+fn parse_data_export(bytes: &[u8]) -> Result<(u64, UserId)> {
+    let expires_at = utils::u64_from_bytes(bytes.get(..8).unwrap_or_default())
+        .map_err(|_| Error::bad_database("Invalid data export expiry in db."))?;
+    let user_id = UserId::try_from(
+        utils::string_from_bytes(bytes.get(8..).unwrap_or_default())
+            .map_err(|_| Error::bad_database("Invalid data export user id in db."))?,
+    )
+    .map_err(|_| Error::bad_database("Invalid data export user id in db."))?;
+
+    Ok((expires_at, user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{newly_acknowledged_counts, Users};
+    use crate::database::{abstraction::memory::MemoryTree, activity::RoomActivity};
+    use crate::utils;
+    use lru_cache::LruCache;
+    use ruma::{DeviceId, UserId};
+    use std::{
+        collections::HashMap,
+        convert::TryFrom,
+        sync::{Arc, Mutex},
+    };
+
+    fn users() -> Users {
+        Users {
+            userid_password: MemoryTree::open(),
+            userid_displayname: MemoryTree::open(),
+            userid_avatarurl: MemoryTree::open(),
+            userid_blurhash: MemoryTree::open(),
+            userdeviceid_token: MemoryTree::open(),
+            userdeviceid_metadata: MemoryTree::open(),
+            userid_devicelistversion: MemoryTree::open(),
+            token_userdeviceid: MemoryTree::open(),
+            userdeviceid_tokencreatedts: MemoryTree::open(),
+            userdeviceid_tokenscope: MemoryTree::open(),
+            onetimekeyid_onetimekeys: MemoryTree::open(),
+            userid_lastonetimekeyupdate: MemoryTree::open(),
+            fallbackkeyid_fallbackkey: MemoryTree::open(),
+            keychangeid_userid: MemoryTree::open(),
+            keyid_key: MemoryTree::open(),
+            userid_masterkeyid: MemoryTree::open(),
+            userid_selfsigningkeyid: MemoryTree::open(),
+            userid_usersigningkeyid: MemoryTree::open(),
+            todeviceid_events: MemoryTree::open(),
+            userdeviceid_todeviceack: MemoryTree::open(),
+            userdeviceid_removedts: MemoryTree::open(),
+            userdeviceid_todevicecount: MemoryTree::open(),
+            remoteuserid_devicelistid: MemoryTree::open(),
+            remoteuserdeviceid_devicekeys: MemoryTree::open(),
+            remoteuserid_displayname: MemoryTree::open(),
+            loginfailureid_data: MemoryTree::open(),
+            ipregistrationid_userid: MemoryTree::open(),
+            userid_registrationip: MemoryTree::open(),
+            lowercaselocalpart_userid: MemoryTree::open(),
+            userfilterid_filter: MemoryTree::open(),
+            exportid_expiryuserid: MemoryTree::open(),
+            token_cache: Mutex::new(LruCache::new(0)),
+            todevicecount_lock: Mutex::new(()),
+            password_hash_params: utils::PasswordHashParams {
+                memory_kib: 8,
+                iterations: 1,
+                parallelism: 1,
+            },
+            activity: Arc::new(RoomActivity {
+                roomid_lastactivitycount: MemoryTree::open(),
+                update_lock: Mutex::new(()),
+                roomid_waker: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    fn user_id() -> UserId {
+        UserId::try_from("@alice:example.com").unwrap()
+    }
+
+    fn device_id() -> Box<DeviceId> {
+        "DEVICE".into()
+    }
+
+    /// Queues to-device events with these counts for the device, like `add_to_device_event`.
+    fn queue(users: &Users, counts: &[u64]) {
+        let mut userdeviceid = user_id().as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id().as_bytes());
+
+        for count in counts {
+            let mut key = userdeviceid.clone();
+            key.push(0xff);
+            key.extend_from_slice(&count.to_be_bytes());
+
+            users
+                .todeviceid_events
+                .insert(
+                    &key,
+                    br#"{"type":"m.dummy","sender":"@bob:example.com","content":{}}"#,
+                )
+                .unwrap();
+        }
+
+        users
+            .set_to_device_event_count(&userdeviceid, counts.len() as u64)
+            .unwrap();
+    }
+
+    fn sync(users: &Users, since: u64) {
+        users
+            .acknowledge_to_device_events(&user_id(), &device_id(), since)
+            .unwrap();
+    }
+
+    fn pending(users: &Users) -> usize {
+        users
+            .get_to_device_events(&user_id(), &device_id(), 0, u64::MAX)
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn initial_sync_acknowledges_nothing() {
+        assert_eq!(newly_acknowledged_counts(None, 0), None);
+    }
+
+    #[test]
+    fn events_up_to_since_are_acknowledged() {
+        assert_eq!(newly_acknowledged_counts(None, 10), Some(0..=10));
+        assert_eq!(newly_acknowledged_counts(Some(10), 15), Some(11..=15));
+    }
+
+    #[test]
+    fn event_at_since_is_deleted() {
+        let users = users();
+        queue(&users, &[9, 10, 11]);
+
+        // The response with next_batch 10 contained the event with count 10
+        sync(&users, 10);
+
+        assert_eq!(pending(&users), 1);
+    }
+
+    #[test]
+    fn events_after_next_batch_wait_for_the_next_sync() {
+        let users = users();
+        queue(&users, &[9, 10, 11]);
+
+        // The event with count 11 arrived after the response with next_batch 10 was built
+        let delivered = users
+            .get_to_device_events(&user_id(), &device_id(), 0, 10)
+            .unwrap();
+        assert_eq!(delivered.len(), 2);
+
+        sync(&users, 10);
+
+        assert_eq!(pending(&users), 1);
+    }
+
+    #[test]
+    fn retried_sync_with_same_token_deletes_nothing_new() {
+        let users = users();
+        queue(&users, &[5, 12]);
+
+        sync(&users, 10);
+        // The response got lost, the client tries again
+        sync(&users, 10);
+
+        assert_eq!(pending(&users), 1);
+    }
+
+    #[test]
+    fn retried_sync_with_older_token_deletes_nothing() {
+        let users = users();
+        queue(&users, &[12, 18]);
+
+        sync(&users, 15);
+        assert_eq!(pending(&users), 1);
+
+        // A retry of a request from before the last sync, the event at 18 was not handed out yet
+        sync(&users, 10);
+        assert_eq!(pending(&users), 1);
+
+        sync(&users, 18);
+        assert_eq!(pending(&users), 0);
+    }
+}
@@ -81,6 +81,24 @@ impl Error {
         error!("BadConfig: {}", message);
         Self::BadConfig(message)
     }
+
+    /// Problems of the database or the file system. Clients can't do anything about them, so they
+    /// only get a generic message and the details are logged.
+    fn is_internal(&self) -> bool {
+        match self {
+            #[cfg(feature = "sled")]
+            Self::SledError { .. } => true,
+            #[cfg(feature = "sqlite")]
+            Self::SqliteError { .. } => true,
+            #[cfg(feature = "heed")]
+            Self::HeedError { .. } => true,
+            Self::ImageError { .. }
+            | Self::IoError { .. }
+            | Self::BadDatabase(_)
+            | Self::BadConfig(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl Error {
@@ -95,16 +113,26 @@ impl Error {
             return RumaResponse(UiaaResponse::MatrixError(error));
         }
 
-        let message = format!("{}", self);
+        let message = if self.is_internal() {
+            error!("Internal error: {}", self);
+            "Internal server error.".to_owned()
+        } else if let Self::ReqwestError { .. } = self {
+            warn!("{}", self);
+            "Could not reach another server.".to_owned()
+        } else {
+            format!("{}", self)
+        };
 
         use ErrorKind::*;
         let (kind, status_code) = match self {
             Self::BadRequest(kind, _) => (
                 kind.clone(),
                 match kind {
-                    Forbidden | GuestAccessForbidden | ThreepidAuthFailed | ThreepidDenied => {
-                        StatusCode::FORBIDDEN
-                    }
+                    Forbidden
+                    | GuestAccessForbidden
+                    | ThreepidAuthFailed
+                    | ThreepidDenied
+                    | ResourceLimitExceeded { .. } => StatusCode::FORBIDDEN,
                     Unauthorized | UnknownToken { .. } | MissingToken => StatusCode::UNAUTHORIZED,
                     NotFound => StatusCode::NOT_FOUND,
                     LimitExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
@@ -114,6 +142,7 @@ impl Error {
                 },
             ),
             Self::Conflict(_) => (Unknown, StatusCode::CONFLICT),
+            Self::ReqwestError { .. } => (Unknown, StatusCode::BAD_GATEWAY),
             _ => (Unknown, StatusCode::INTERNAL_SERVER_ERROR),
         };
 
@@ -127,6 +156,33 @@ impl Error {
     }
 }
 
+/// Returns the error for requests that failed before they reached a route, e.g. unknown
+/// endpoints, bodies that are too large or handlers that panicked.
+#[cfg(feature = "conduit_bin")]
+pub fn status_response(status: u16) -> RumaResponse<UiaaResponse> {
+    let status_code = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    let (kind, message) = match status_code {
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {
+            (ErrorKind::Unrecognized, "Unrecognized request.")
+        }
+        StatusCode::PAYLOAD_TOO_LARGE => (ErrorKind::TooLarge, "The request is too large."),
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            (ErrorKind::BadJson, "The request is invalid.")
+        }
+        _ => (
+            ErrorKind::Unknown,
+            status_code.canonical_reason().unwrap_or("Unknown error."),
+        ),
+    };
+
+    RumaResponse(UiaaResponse::MatrixError(RumaError {
+        kind,
+        message: message.to_owned(),
+        status_code,
+    }))
+}
+
 #[cfg(feature = "conduit_bin")]
 impl<'r, 'o> Responder<'r, 'o> for Error
 where
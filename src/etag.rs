@@ -0,0 +1,107 @@
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status},
+    Request, Response,
+};
+use std::io::Cursor;
+use tracing::warn;
+
+/// Content behind an mxc:// URI never changes, so clients and CDNs can keep it forever
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Adds strong ETags to media and profile responses and answers matching If-None-Match requests
+/// with 304 Not Modified.
+pub struct ETag;
+
+fn is_media_path(path: &str) -> bool {
+    path.starts_with("/_matrix/media/r0/download/")
+        || path.starts_with("/_matrix/media/r0/thumbnail/")
+}
+
+fn is_profile_path(path: &str) -> bool {
+    path.starts_with("/_matrix/client/r0/profile/")
+}
+
+/// Checks an If-None-Match header against our ETag. If-None-Match always uses the weak
+/// comparison, so W/ prefixes are ignored.
+fn if_none_match_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+#[rocket::async_trait]
+impl Fairing for ETag {
+    fn info(&self) -> Info {
+        Info {
+            name: "ETag",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let path = req.uri().path();
+        let media = is_media_path(path);
+
+        if !(req.method() == Method::Get || req.method() == Method::Head)
+            || res.status() != Status::Ok
+            || !(media || is_profile_path(path))
+        {
+            return;
+        }
+
+        // Media never changes, so the ETag can come from the URI (mxc id and thumbnail
+        // parameters) instead of hashing the whole file on every request. Compressed responses
+        // are different bytes and need their own ETag.
+        let body = if media {
+            None
+        } else {
+            match res.body_mut().to_bytes().await {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    warn!("Failed to read response body for ETag: {}", e);
+                    return;
+                }
+            }
+        };
+
+        let digest = match &body {
+            Some(body) => ring::digest::digest(&ring::digest::SHA256, body),
+            None => {
+                let mut key = req.uri().to_string();
+                key.push('\n');
+                key.push_str(
+                    res.headers()
+                        .get_one("Content-Encoding")
+                        .unwrap_or_default(),
+                );
+                ring::digest::digest(&ring::digest::SHA256, key.as_bytes())
+            }
+        };
+        let etag = format!(
+            "\"{}\"",
+            base64::encode_config(digest.as_ref(), base64::URL_SAFE_NO_PAD)
+        );
+
+        res.set_raw_header("ETag", etag.clone());
+        if media {
+            res.set_raw_header("Cache-Control", IMMUTABLE_CACHE_CONTROL);
+        } else {
+            // Profiles can change, so caches have to ask again every time
+            res.set_raw_header("Cache-Control", "no-cache");
+        }
+
+        if req
+            .headers()
+            .get("If-None-Match")
+            .any(|header| if_none_match_matches(header, &etag))
+        {
+            res.set_status(Status::NotModified);
+            res.remove_header("Content-Length");
+            res.remove_header("Content-Disposition");
+            res.set_sized_body(0, Cursor::new(Vec::new()));
+        } else if let Some(body) = body {
+            res.set_sized_body(body.len(), Cursor::new(body));
+        }
+    }
+}
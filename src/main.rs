@@ -6,21 +6,26 @@ pub mod appservice_server;
 pub mod client_server;
 pub mod server_server;
 
+mod compression;
+mod cors;
 mod database;
 mod error;
+mod etag;
 mod pdu;
+mod replication;
 mod ruma_wrapper;
+mod timing;
 mod utils;
 
 use std::sync::Arc;
 
-use database::Config;
 pub use database::Database;
+use database::{Config, StartupStatus};
 pub use error::{Error, Result};
 use opentelemetry::trace::{FutureExt, Tracer};
 pub use pdu::PduEvent;
 pub use rocket::State;
-use ruma::api::client::error::ErrorKind;
+use ruma::api::client::{error::ErrorKind, r0::uiaa::UiaaResponse};
 pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
 
 use rocket::{
@@ -29,6 +34,8 @@ use rocket::{
         providers::{Env, Format, Toml},
         Figment,
     },
+    get,
+    http::Status,
     routes, Request,
 };
 use tokio::sync::RwLock;
@@ -39,7 +46,8 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
         .manage(data)
         .mount(
             "/",
-            routes![
+            timing::timed_routes(routes![
+                ready_route,
                 client_server::get_supported_versions_route,
                 client_server::get_register_available_route,
                 client_server::register_route,
@@ -96,11 +104,16 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::create_typing_event_route,
                 client_server::create_room_route,
                 client_server::redact_event_route,
+                client_server::report_event_route,
                 client_server::create_alias_route,
                 client_server::delete_alias_route,
                 client_server::get_alias_route,
                 client_server::join_room_by_id_route,
                 client_server::join_room_by_id_or_alias_route,
+                client_server::knock_room_route,
+                client_server::batch_membership_route,
+                client_server::event_auth_route,
+                client_server::audit_log_route,
                 client_server::joined_members_route,
                 client_server::leave_room_route,
                 client_server::forget_room_route,
@@ -113,8 +126,12 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::get_room_visibility_route,
                 client_server::get_public_rooms_route,
                 client_server::get_public_rooms_filtered_route,
+                client_server::get_hierarchy_route,
                 client_server::search_users_route,
                 client_server::get_member_events_route,
+                client_server::get_knocks_route,
+                client_server::get_bans_route,
+                client_server::import_bans_route,
                 client_server::get_protocols_route,
                 client_server::send_message_event_route,
                 client_server::send_state_event_for_key_route,
@@ -123,8 +140,17 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::get_state_events_for_key_route,
                 client_server::get_state_events_for_empty_key_route,
                 client_server::sync_events_route,
+                client_server::sync_stream_route,
+                client_server::create_scoped_token_route,
+                client_server::list_scoped_tokens_route,
+                client_server::revoke_scoped_token_route,
                 client_server::get_context_route,
+                client_server::get_relating_events_route,
+                client_server::get_relating_events_with_rel_type_route,
+                client_server::get_relating_events_with_rel_type_and_event_type_route,
+                client_server::get_threads_route,
                 client_server::get_message_events_route,
+                client_server::peek_events_route,
                 client_server::search_events_route,
                 client_server::turn_server_route,
                 client_server::send_event_to_device_route,
@@ -132,6 +158,7 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::create_content_route,
                 client_server::get_content_route,
                 client_server::get_content_thumbnail_route,
+                client_server::get_data_export_route,
                 client_server::get_devices_route,
                 client_server::get_device_route,
                 client_server::update_device_route,
@@ -153,6 +180,7 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 server_server::get_server_keys_deprecated_route,
                 server_server::get_public_rooms_route,
                 server_server::get_public_rooms_filtered_route,
+                server_server::get_hierarchy_route,
                 server_server::send_transaction_message_route,
                 server_server::get_event_route,
                 server_server::get_missing_events_route,
@@ -168,18 +196,71 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 server_server::get_profile_information_route,
                 server_server::get_keys_route,
                 server_server::claim_keys_route,
-            ],
+                replication::position_route,
+            ]),
         )
-        .register(
+        .register("/", error_catchers())
+}
+
+/// A replica answers sync and other endpoints that only read from the database. Everything else
+/// is forwarded to the primary.
+fn setup_replica_rocket(
+    config: Figment,
+    data: Arc<RwLock<Database>>,
+    forward: replication::Forward,
+) -> rocket::Rocket<rocket::Build> {
+    rocket::custom(config)
+        .manage(data)
+        .mount(
             "/",
-            catchers![
-                not_found_catcher,
-                forbidden_catcher,
-                unknown_token_catcher,
-                missing_token_catcher,
-                bad_json_catcher
-            ],
+            timing::timed_routes(routes![
+                ready_route,
+                client_server::get_supported_versions_route,
+                client_server::whoami_route,
+                client_server::get_capabilities_route,
+                client_server::get_pushrules_all_route,
+                client_server::get_pushrule_route,
+                client_server::get_pushrule_enabled_route,
+                client_server::get_pushrule_actions_route,
+                client_server::get_room_event_route,
+                client_server::get_filter_route,
+                client_server::get_global_account_data_route,
+                client_server::get_room_account_data_route,
+                client_server::joined_members_route,
+                client_server::joined_rooms_route,
+                client_server::get_member_events_route,
+                client_server::get_hierarchy_route,
+                client_server::get_state_events_route,
+                client_server::get_state_events_for_key_route,
+                client_server::get_state_events_for_empty_key_route,
+                client_server::sync_events_route,
+                client_server::sync_stream_route,
+                client_server::get_context_route,
+                client_server::get_relating_events_route,
+                client_server::get_relating_events_with_rel_type_route,
+                client_server::get_relating_events_with_rel_type_and_event_type_route,
+                client_server::get_threads_route,
+                client_server::get_devices_route,
+                client_server::get_device_route,
+                client_server::get_tags_route,
+                client_server::options_route,
+                client_server::get_key_changes_route,
+                client_server::get_pushers_route,
+            ]),
         )
+        .mount("/", forward.routes())
+        .register("/", error_catchers())
+}
+
+fn error_catchers() -> Vec<rocket::Catcher> {
+    catchers![
+        default_catcher,
+        forbidden_catcher,
+        unknown_token_catcher,
+        missing_token_catcher,
+        bad_json_catcher,
+        scope_catcher
+    ]
 }
 
 #[rocket::main]
@@ -196,6 +277,7 @@ async fn main() {
                 .nested(),
             )
             .merge(Env::prefixed("CONDUIT_").global());
+    let raw_config = load_secret_files(raw_config);
 
     std::env::set_var("RUST_LOG", "warn");
 
@@ -203,14 +285,72 @@ async fn main() {
         .extract::<Config>()
         .expect("It looks like your config is invalid. Please take a look at the error");
 
+    // `conduit migrate-db <from> <to> <target path>` copies the database to another backend
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("migrate-db") {
+        match &args[2..] {
+            [from, to, target_path] => {
+                if let Err(e) =
+                    database::abstraction::migrate::migrate_backend(&config, from, to, target_path)
+                {
+                    eprintln!("Migration failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                eprintln!("Usage: conduit migrate-db <from backend> <to backend> <target path>");
+                eprintln!("Backends: sqlite, sled, heed (if conduit was built with them)");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let start = async {
         config.warn_deprecated();
 
-        let db = Database::load_or_create(&config)
+        let status = Arc::new(StartupStatus::default());
+
+        // Answer /ready while the database is loading, so operators can see what's going on
+        let startup_rocket = rocket::custom(startup_config(&raw_config))
+            .manage(Arc::clone(&status))
+            .mount("/", routes![ready_route])
+            .ignite()
+            .await
+            .unwrap();
+        let startup_shutdown = startup_rocket.shutdown();
+        let startup_server = tokio::spawn(startup_rocket.launch());
+
+        let db = Database::load_or_create(&config, &status)
             .await
             .expect("config is valid");
 
-        let rocket = setup_rocket(raw_config, Arc::clone(&db))
+        startup_shutdown.notify();
+        let _ = startup_server.await;
+        status.set_ready();
+
+        let request_durations = Arc::clone(&db.read().await.globals.request_durations);
+        let mut rocket = if config.replica_of.is_some() {
+            replication::start_position_task(Arc::clone(&db))
+                .await
+                .expect("the primary is reachable");
+            let forward = replication::Forward::new(&db)
+                .await
+                .expect("config is valid");
+            setup_replica_rocket(raw_config, Arc::clone(&db), forward)
+        } else {
+            setup_rocket(raw_config, Arc::clone(&db))
+        }
+        .manage(status);
+        if config.allow_compression {
+            rocket = rocket.attach(compression::Compression::new(&config));
+        }
+        // Attached after compression, so the ETag matches the bytes we actually send
+        let rocket = rocket
+            .attach(etag::ETag)
+            .attach(cors::Cors::new(&config))
+            // Attached last, so compressing the response counts towards the duration
+            .attach(timing::RequestTimer::new(&config, request_durations))
             .ignite()
             .await
             .unwrap();
@@ -259,9 +399,22 @@ async fn main() {
     }
 }
 
-#[catch(404)]
-fn not_found_catcher(_: &Request<'_>) -> String {
-    "404 Not Found".to_owned()
+/// # `GET /ready`
+///
+/// Returns 200 once the server is ready to answer requests. While the database is loading, this
+/// returns 503 and what the server is doing, e.g. which migration is running.
+#[get("/ready")]
+fn ready_route(status: &State<Arc<StartupStatus>>) -> (Status, String) {
+    if status.is_ready() {
+        (Status::Ok, "Ready".to_owned())
+    } else {
+        (Status::ServiceUnavailable, status.describe())
+    }
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, _: &Request<'_>) -> RumaResponse<UiaaResponse> {
+    error::status_response(status.code)
 }
 
 #[catch(580)]
@@ -287,6 +440,54 @@ fn bad_json_catcher() -> Result<()> {
     Err(Error::BadRequest(ErrorKind::BadJson, "Bad json."))
 }
 
+#[catch(584)]
+fn scope_catcher() -> Result<()> {
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "The scope of this access token doesn't allow this request.",
+    ))
+}
+
+/// Sets every secret that is configured as `<key>_file`, e.g. with `CONDUIT_JWT_SECRET_FILE`,
+/// to the contents of that file. This allows using Docker or Kubernetes secrets without putting
+/// them into the config file.
+fn load_secret_files(mut config: Figment) -> Figment {
+    for key in Config::SECRET_KEYS {
+        let file_key = format!("{}_file", key);
+
+        let path = match config.extract_inner::<String>(&file_key) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+
+        if config.find_value(key).is_ok() {
+            panic!("Only one of {} and {} can be set.", key, file_key);
+        }
+
+        let secret = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Could not read {} from {}: {}", key, path, e));
+
+        // Editors and `echo` usually add a newline at the end of the file
+        let secret = secret.trim_end_matches(&['\r', '\n'][..]).to_owned();
+
+        config = config.merge((*key, secret));
+    }
+
+    config
+}
+
+/// The config of the server that only answers /ready while the database is loading. Signals are
+/// not handled by it, so Ctrl+C still stops the process during migrations.
+fn startup_config(raw_config: &Figment) -> rocket::Config {
+    #[allow(unused_mut)]
+    let mut config = rocket::Config::from(raw_config.clone());
+
+    #[cfg(unix)]
+    config.shutdown.signals.clear();
+
+    config
+}
+
 fn default_config() -> rocket::Config {
     let mut config = rocket::Config::release_default();
 
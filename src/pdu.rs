@@ -14,6 +14,9 @@ use serde_json::json;
 use std::{cmp::Ordering, collections::BTreeMap, convert::TryFrom};
 use tracing::warn;
 
+/// The type of the events we send to merge forward extremities.
+pub const DUMMY_EVENT_TYPE: &str = "org.matrix.dummy_event";
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct PduEvent {
     pub event_id: EventId,
@@ -82,6 +85,11 @@ impl PduEvent {
         Ok(())
     }
 
+    /// Dummy events only merge forward extremities, clients don't need to see them.
+    pub fn is_dummy(&self) -> bool {
+        self.kind.as_ref() == DUMMY_EVENT_TYPE
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn to_sync_room_event(&self) -> Raw<AnySyncRoomEvent> {
         let mut json = json!({
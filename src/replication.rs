@@ -0,0 +1,270 @@
+use crate::{database::DatabaseGuard, utils, ConduitResult, Database, Error, Result, Ruma};
+use rocket::{
+    data::ByteUnit,
+    get,
+    http::{Method, Status},
+    route::{Handler, Outcome},
+    Data, Request, Response, Route,
+};
+use ruma::api::client::error::ErrorKind;
+use serde::Deserialize;
+use std::{io::Cursor, net::IpAddr, sync::Arc};
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// These headers only concern one connection, so they are not passed on.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Custom endpoint for replicas to find out how far the primary is.
+pub mod position {
+    use ruma::api::ruma_api;
+
+    ruma_api! {
+        metadata: {
+            description: "Get the current count of the primary.",
+            method: GET,
+            name: "replication_position",
+            path: "/_conduit/replication/v1/position",
+            rate_limited: false,
+            authentication: None,
+        }
+
+        request: {
+            /// `Bearer <replication_secret>`
+            #[ruma_api(header = AUTHORIZATION)]
+            pub authorization: Option<String>,
+        }
+
+        response: {
+            /// The count up to which all pdus of the primary are committed.
+            pub position: u64,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_conduit/replication/v1/position`
+///
+/// Returns the count up to which all pdus of this server are committed, so replicas know which
+/// changes they can see.
+///
+/// - Only available if `replication_secret` is configured, replicas have to send it as a bearer
+/// token
+#[get("/_conduit/replication/v1/position", data = "<body>")]
+#[tracing::instrument(skip(db, body))]
+pub async fn position_route(
+    db: DatabaseGuard,
+    body: Ruma<position::Request>,
+) -> ConduitResult<position::Response> {
+    let secret = db.globals.replication_secret().ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "Replication is not enabled on this server.",
+    ))?;
+
+    let expected = format!("Bearer {}", secret);
+    let authorized = body.authorization.as_ref().map_or(false, |authorization| {
+        ring::constant_time::verify_slices_are_equal(authorization.as_bytes(), expected.as_bytes())
+            .is_ok()
+    });
+
+    if !authorized {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Wrong replication secret.",
+        ));
+    }
+
+    Ok(position::Response {
+        position: db.globals.committed_count()?,
+    }
+    .into())
+}
+
+#[derive(Deserialize)]
+struct PositionResponse {
+    position: u64,
+}
+
+/// Asks the primary for its current count.
+async fn fetch_position(
+    client: &reqwest::Client,
+    primary: &str,
+    secret: Option<&str>,
+) -> Result<u64> {
+    let mut request = client.get(&format!(
+        "{}/_conduit/replication/v1/position",
+        primary.trim_end_matches('/')
+    ));
+    if let Some(secret) = secret {
+        request = request.bearer_auth(secret);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        warn!(
+            "The primary answered the position request with {}",
+            response.status()
+        );
+        return Err(Error::BadServerResponse(
+            "The primary refused the position request.",
+        ));
+    }
+
+    Ok(response.json::<PositionResponse>().await?.position)
+}
+
+/// Fetches the position of the primary once and then keeps polling it in the background. New
+/// changes on the primary wake up the syncs that wait on this replica.
+///
+/// Fails if the primary can't be reached, because the replica would not know which changes it can
+/// show.
+pub async fn start_position_task(db: Arc<RwLock<Database>>) -> Result<()> {
+    let (client, primary, secret, poll_interval) = {
+        let guard = db.read().await;
+        let globals = &guard.globals;
+        (
+            globals.reqwest_client()?.build()?,
+            globals
+                .replica_of()
+                .expect("only replicas poll the primary")
+                .to_owned(),
+            globals.replication_secret().map(ToOwned::to_owned),
+            globals.replica_poll_interval(),
+        )
+    };
+
+    let position = fetch_position(&client, &primary, secret.as_deref()).await?;
+    db.read().await.globals.set_replica_position(position);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(poll_interval);
+
+        loop {
+            interval.tick().await;
+
+            match fetch_position(&client, &primary, secret.as_deref()).await {
+                Ok(position) => db.read().await.globals.set_replica_position(position),
+                Err(e) => warn!("Failed to fetch the position of the primary: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Passes requests on to the primary and returns its response. Replicas mount this for every path
+/// with a low priority, so only requests the replica can't answer itself reach the primary.
+#[derive(Clone)]
+pub struct Forward {
+    client: reqwest::Client,
+    primary: String,
+    max_request_size: u32,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl Forward {
+    pub async fn new(db: &RwLock<Database>) -> Result<Self> {
+        let guard = db.read().await;
+        Ok(Self {
+            client: guard.globals.reqwest_client()?.build()?,
+            primary: guard
+                .globals
+                .replica_of()
+                .expect("only replicas forward requests")
+                .trim_end_matches('/')
+                .to_owned(),
+            max_request_size: guard.globals.max_request_size(),
+            trusted_proxies: guard.globals.trusted_proxies().to_vec(),
+        })
+    }
+
+    pub fn routes(self) -> Vec<Route> {
+        [Method::Get, Method::Post, Method::Put, Method::Delete]
+            .iter()
+            .map(|&method| Route::ranked(100, method, "/<_..>", self.clone()))
+            .collect()
+    }
+
+    async fn forward(&self, req: &Request<'_>, data: Data<'_>) -> Result<Response<'static>> {
+        let body = data
+            .open(ByteUnit::Byte(self.max_request_size.into()))
+            .into_bytes()
+            .await?;
+
+        // Forwarding the truncated body would make the primary act on a different request
+        if !body.is_complete() {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "Request body is too large.",
+            ));
+        }
+
+        let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes())
+            .expect("rocket methods are valid http methods");
+        let mut request = self
+            .client
+            .request(method, &format!("{}{}", self.primary, req.uri()))
+            .body(body.into_inner());
+
+        for header in req.headers().iter() {
+            let name = header.name().as_str().to_lowercase();
+            // Clients can't pretend to come from another address
+            if !HOP_BY_HOP_HEADERS.contains(&&*name) && name != "x-real-ip" {
+                request = request.header(header.name().as_str(), header.value());
+            }
+        }
+
+        // The primary records the address of the client, e.g. for the last seen ip of devices
+        if let Some(ip) = utils::client_ip(
+            req.remote().map(|remote| remote.ip()),
+            req.real_ip(),
+            &self.trusted_proxies,
+        ) {
+            request = request.header("X-Real-IP", ip.to_string());
+        }
+
+        let response = request.send().await?;
+
+        let mut builder = Response::build();
+        builder.status(Status::new(response.status().as_u16()));
+        for (name, value) in response.headers() {
+            if HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if let Ok(value) = value.to_str() {
+                builder.raw_header_adjoin(name.as_str().to_owned(), value.to_owned());
+            }
+        }
+
+        let body = response.bytes().await?.to_vec();
+        builder.sized_body(body.len(), Cursor::new(body));
+
+        Ok(builder.finalize())
+    }
+}
+
+#[rocket::async_trait]
+impl Handler for Forward {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        match self.forward(req, data).await {
+            Ok(response) => Outcome::Success(response),
+            Err(e @ Error::BadRequest(..)) => Outcome::from(req, e),
+            Err(e) => {
+                warn!("Failed to forward {} to the primary: {}", req.uri(), e);
+                Outcome::from(
+                    req,
+                    Error::BadServerResponse("Failed to reach the primary server."),
+                )
+            }
+        }
+    }
+}
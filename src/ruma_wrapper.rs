@@ -1,15 +1,30 @@
-use crate::{database::DatabaseGuard, Error};
+use crate::{database::DatabaseGuard, Error, Result};
 use ruma::{
-    api::{client::r0::uiaa::UiaaResponse, OutgoingResponse},
+    api::{
+        client::{error::ErrorKind, r0::uiaa::UiaaResponse},
+        OutgoingResponse,
+    },
     identifiers::{DeviceId, UserId},
     signatures::CanonicalJsonValue,
     Outgoing, ServerName,
 };
-use std::ops::Deref;
+use std::{
+    cell::Cell,
+    future::Future,
+    net::IpAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 #[cfg(feature = "conduit_bin")]
 use {
+    crate::database::users::TokenScope,
     crate::server_server,
+    rocket::http::RawStr,
     rocket::{
         data::{self, ByteUnit, Data, FromData},
         http::Status,
@@ -18,13 +33,104 @@ use {
         tokio::io::AsyncReadExt,
         Request,
     },
-    ruma::api::{AuthScheme, IncomingRequest},
+    ruma::{
+        api::{AuthScheme, IncomingRequest, Metadata},
+        RoomAliasId, RoomId,
+    },
     std::collections::BTreeMap,
     std::convert::TryFrom,
     std::io::Cursor,
     tracing::{debug, warn},
 };
 
+/// Where the time of a request went. The Ruma guard, the responder and the timed route handlers
+/// fill this in the request-local cache, so the request timer can log it for slow requests.
+#[derive(Default)]
+pub struct RequestPhases {
+    parse_micros: AtomicU64,
+    serialize_micros: AtomicU64,
+    db_micros: AtomicU64,
+    waited_micros: AtomicU64,
+    user: Mutex<Option<UserId>>,
+}
+
+impl RequestPhases {
+    /// Time spent authenticating and parsing the request.
+    pub fn parse(&self) -> Duration {
+        Duration::from_micros(self.parse_micros.load(Ordering::Relaxed))
+    }
+
+    /// Time spent turning the response into json.
+    pub fn serialize(&self) -> Duration {
+        Duration::from_micros(self.serialize_micros.load(Ordering::Relaxed))
+    }
+
+    /// The user who sent the request, if it was authenticated as a user.
+    pub fn user(&self) -> Option<UserId> {
+        self.user.lock().unwrap().clone()
+    }
+
+    /// Time spent in the database.
+    pub fn db(&self) -> Duration {
+        Duration::from_micros(self.db_micros.load(Ordering::Relaxed))
+    }
+
+    /// Time spent waiting for news on purpose, like a long-polling /sync.
+    pub fn waited(&self) -> Duration {
+        Duration::from_micros(self.waited_micros.load(Ordering::Relaxed))
+    }
+
+    /// Stores the times of `RequestTimes::scope`.
+    pub fn set_task_times(&self, db: Duration, waited: Duration) {
+        self.db_micros
+            .store(db.as_micros() as u64, Ordering::Relaxed);
+        self.waited_micros
+            .store(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+tokio::task_local! {
+    static REQUEST_TIMES: RequestTimes;
+}
+
+/// Time the request handled by the current task spent in the database and waiting for news. The
+/// database runs on the thread of the task, so the trees can add their time without knowing the
+/// request.
+#[derive(Default)]
+pub struct RequestTimes {
+    db: Cell<Duration>,
+    waited: Cell<Duration>,
+}
+
+impl RequestTimes {
+    /// Runs the future with its own times and returns them with the output.
+    pub async fn scope<F: Future>(future: F) -> (F::Output, Duration, Duration) {
+        REQUEST_TIMES
+            .scope(RequestTimes::default(), async {
+                let output = future.await;
+                let (db, waited) = REQUEST_TIMES.with(|times| (times.db.get(), times.waited.get()));
+                (output, db, waited)
+            })
+            .await
+    }
+
+    /// Adds time spent in the database. Does nothing outside of `scope`, e.g. in background jobs.
+    pub fn add_db(duration: Duration) {
+        let _ = REQUEST_TIMES.try_with(|times| times.db.set(times.db.get() + duration));
+    }
+
+    /// Awaits a future that waits for news on purpose. Its time doesn't count as request duration.
+    pub async fn wait<F: Future>(future: F) -> F::Output {
+        let started = Instant::now();
+        let output = future.await;
+
+        let waited = started.elapsed();
+        let _ = REQUEST_TIMES.try_with(|times| times.waited.set(times.waited.get() + waited));
+
+        output
+    }
+}
+
 /// This struct converts rocket requests into ruma structs by converting them into http requests
 /// first.
 pub struct Ruma<T: Outgoing> {
@@ -35,6 +141,11 @@ pub struct Ruma<T: Outgoing> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    /// The id of the appservice registration whose token was used.
+    pub appservice_id: Option<String>,
+    /// The address of the client, taken from the X-Real-IP header if the server is behind a
+    /// reverse proxy.
+    pub client_ip: Option<IpAddr>,
 }
 
 #[cfg(feature = "conduit_bin")]
@@ -50,12 +161,18 @@ where
         request: &'a Request<'_>,
         data: Data<'a>,
     ) -> data::Outcome<'a, Self, Self::Error> {
+        let started = Instant::now();
         let metadata = T::Incoming::METADATA;
         let db = request
             .guard::<DatabaseGuard>()
             .await
             .expect("database was loaded");
 
+        let client_ip = db.globals.client_ip(
+            request.remote().map(|remote| remote.ip()),
+            request.real_ip(),
+        );
+
         // Get token from header or query value
         let token = request
             .headers()
@@ -74,8 +191,8 @@ where
 
         let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
-        let (sender_user, sender_device, sender_servername, from_appservice) = if let Some((
-            _id,
+        let (sender_user, sender_device, sender_servername, appservice_id) = if let Some((
+            id,
             registration,
         )) = db
             .appservice
@@ -114,10 +231,10 @@ where
                     }
 
                     // TODO: Check if appservice is allowed to be that user
-                    (Some(user_id), None, None, true)
+                    (Some(user_id), None, None, Some(id.clone()))
                 }
-                AuthScheme::ServerSignatures => (None, None, None, true),
-                AuthScheme::None => (None, None, None, true),
+                AuthScheme::ServerSignatures => (None, None, None, Some(id.clone())),
+                AuthScheme::None => (None, None, None, Some(id.clone())),
             }
         } else {
             match metadata.authentication {
@@ -126,12 +243,37 @@ where
                         match db.users.find_from_token(&token).unwrap() {
                             // Unknown Token
                             None => return Failure((Status::new(581), ())),
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(Box::<DeviceId>::from(device_id)),
-                                None,
-                                false,
-                            ),
+                            Some((user_id, device_id)) => {
+                                let device_id = Box::<DeviceId>::from(device_id);
+
+                                if let Some(scope) =
+                                    db.users.token_scope(&user_id, &device_id).unwrap()
+                                {
+                                    let room = || {
+                                        request_room(
+                                            metadata.path,
+                                            &request.uri().to_string(),
+                                            |alias| db.rooms.id_from_alias(alias).ok().flatten(),
+                                        )
+                                    };
+                                    if !scope_allows(&scope, &metadata.method, metadata.path, room)
+                                    {
+                                        // Not allowed by scope
+                                        return Failure((Status::new(584), ()));
+                                    }
+                                }
+
+                                // Replicas only read, the primary sees the device often enough
+                                if !db.globals.is_replica() {
+                                    if let Err(e) =
+                                        db.users.update_last_seen(&user_id, &device_id, client_ip)
+                                    {
+                                        warn!("Failed to update last seen of {}: {}", user_id, e);
+                                    }
+                                }
+
+                                (Some(user_id), Some(device_id), None, None)
+                            }
                         }
                     } else {
                         // Missing Token
@@ -259,7 +401,7 @@ where
                     pub_key_map.insert(origin.as_str().to_owned(), keys);
 
                     match ruma::signatures::verify_json(&pub_key_map, &request_map) {
-                        Ok(()) => (None, None, Some(origin), false),
+                        Ok(()) => (None, None, Some(origin), None),
                         Err(e) => {
                             warn!(
                                 "Failed to verify json request from {}: {}\n{:?}",
@@ -275,7 +417,7 @@ where
                         }
                     }
                 }
-                AuthScheme::None => (None, None, None, false),
+                AuthScheme::None => (None, None, None, None),
             }
         };
 
@@ -317,14 +459,24 @@ where
 
         let http_request = http_request.body(&*body).unwrap();
         debug!("{:?}", http_request);
-        match <T::Incoming as IncomingRequest>::try_from_http_request(http_request) {
+        let parsed = <T::Incoming as IncomingRequest>::try_from_http_request(http_request);
+
+        let phases = request.local_cache(RequestPhases::default);
+        phases
+            .parse_micros
+            .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        *phases.user.lock().unwrap() = sender_user.clone();
+
+        match parsed {
             Ok(t) => Success(Ruma {
                 body: t,
                 sender_user,
                 sender_device,
                 sender_servername,
-                from_appservice,
+                from_appservice: appservice_id.is_some(),
+                appservice_id,
                 json_body,
+                client_ip,
             }),
             Err(e) => {
                 warn!("{:?}", e);
@@ -335,6 +487,116 @@ where
     }
 }
 
+/// Endpoints that handle end-to-end encryption keys or send to-device messages.
+#[cfg(feature = "conduit_bin")]
+const E2EE_PATHS: &[&str] = &["/keys/", "/room_keys/", "/sendToDevice/"];
+
+/// Endpoints without a room in their path that tokens limited to some rooms may use, as method
+/// and end of the path. All other endpoints without a room act on the whole account.
+#[cfg(feature = "conduit_bin")]
+const ROOM_SCOPE_ENDPOINTS: &[(http::Method, &str)] = &[
+    (http::Method::GET, "/sync"),
+    (http::Method::GET, "/sync/stream"),
+    (http::Method::POST, "/user/:user_id/filter"),
+    (http::Method::GET, "/user/:user_id/filter/:filter_id"),
+    (http::Method::GET, "/profile/:user_id"),
+    (http::Method::GET, "/profile/:user_id/displayname"),
+    (http::Method::GET, "/profile/:user_id/avatar_url"),
+    (http::Method::GET, "/account/whoami"),
+    (http::Method::GET, "/capabilities"),
+];
+
+/// Checks a request against the scope of the access token it was sent with. `room` finds the
+/// room in the path of the request, see `request_room`.
+///
+/// Tokens limited to some rooms may only use endpoints with one of these rooms in their path and
+/// the endpoints in `ROOM_SCOPE_ENDPOINTS`. Scoped tokens can't manage scoped tokens.
+#[cfg(feature = "conduit_bin")]
+fn scope_allows(
+    scope: &TokenScope,
+    method: &http::Method,
+    path: &str,
+    room: impl FnOnce() -> Option<Option<RoomId>>,
+) -> bool {
+    if path.starts_with("/_conduit/client/v1/scoped_tokens") {
+        return false;
+    }
+
+    if scope.read_only && method != http::Method::GET {
+        return false;
+    }
+
+    let e2ee = E2EE_PATHS.iter().any(|e2ee_path| path.contains(e2ee_path));
+    if !scope.e2ee && e2ee {
+        return false;
+    }
+
+    if let Some(rooms) = &scope.rooms {
+        return match room() {
+            Some(room_id) => room_id.map_or(false, |room_id| rooms.contains(&room_id)),
+            None => {
+                e2ee || ROOM_SCOPE_ENDPOINTS
+                    .iter()
+                    .any(|(allowed_method, end)| allowed_method == method && path.ends_with(end))
+            }
+        };
+    }
+
+    true
+}
+
+/// Finds the room in the `uri` of a request to the endpoint with the given path. Returns None if
+/// the endpoint has no room in its path and Some(None) if the room is invalid or an unknown alias.
+#[cfg(feature = "conduit_bin")]
+fn request_room(
+    path: &str,
+    uri: &str,
+    resolve_alias: impl FnOnce(&RoomAliasId) -> Option<RoomId>,
+) -> Option<Option<RoomId>> {
+    let position = path
+        .split('/')
+        .position(|segment| segment == ":room_id" || segment == ":room_id_or_alias")?;
+
+    let segment = uri.split('?').next()?.split('/').nth(position)?;
+    let segment = match RawStr::new(segment).percent_decode() {
+        Ok(segment) => segment,
+        Err(_) => return Some(None),
+    };
+
+    if segment.starts_with('#') {
+        Some(
+            RoomAliasId::try_from(&*segment)
+                .ok()
+                .and_then(|alias| resolve_alias(&alias)),
+        )
+    } else {
+        Some(RoomId::try_from(&*segment).ok())
+    }
+}
+
+impl<T: Outgoing> Ruma<T> {
+    /// Returns the user who sent this request.
+    ///
+    /// Routes that need a user should use this instead of unwrapping `sender_user`, so requests
+    /// that were not authenticated as a user are rejected instead of crashing the handler.
+    pub fn authenticated_user(&self) -> Result<&UserId> {
+        self.sender_user.as_ref().ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "This endpoint requires an access token.",
+        ))
+    }
+
+    /// Returns the device that sent this request.
+    ///
+    /// Appservices authenticate without a device, so they can't use endpoints that need one.
+    pub fn authenticated_device(&self) -> Result<&DeviceId> {
+        self.sender_device.as_deref().ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "This endpoint requires the access token of a device.",
+        ))
+    }
+}
+
 impl<T: Outgoing> Deref for Ruma<T> {
     type Target = T::Incoming;
 
@@ -352,6 +614,10 @@ pub fn response<T: OutgoingResponse>(response: RumaResponse<T>) -> response::Res
         .try_into_http_response::<Vec<u8>>()
         .map_err(|_| Status::InternalServerError)?;
 
+    build_response(http_response)
+}
+
+fn build_response(http_response: http::Response<Vec<u8>>) -> response::Result<'static> {
     let mut response = rocket::response::Response::build();
 
     let status = http_response.status();
@@ -365,22 +631,89 @@ pub fn response<T: OutgoingResponse>(response: RumaResponse<T>) -> response::Res
 
     response.sized_body(http_body.len(), Cursor::new(http_body));
 
-    response.raw_header("Access-Control-Allow-Origin", "*");
-    response.raw_header(
-        "Access-Control-Allow-Methods",
-        "GET, POST, PUT, DELETE, OPTIONS",
-    );
-    response.raw_header(
-        "Access-Control-Allow-Headers",
-        "Origin, X-Requested-With, Content-Type, Accept, Authorization",
-    );
-    response.raw_header("Access-Control-Max-Age", "86400");
     response.ok()
 }
 
 #[derive(Clone)]
 pub struct RumaResponse<T>(pub T);
 
+/// A response with fields ruma doesn't know yet, e.g. from unstable features.
+pub struct ExtendedResponse<T> {
+    pub response: T,
+    /// Added to the top level of the json body. They must not be fields of the response, so they
+    /// can be appended without parsing the body again, which matters for big responses like /sync.
+    pub extra_fields: serde_json::Map<String, serde_json::Value>,
+    /// Merged into the json body, objects recursively. Only parsed if there is something to merge,
+    /// e.g. keys that ruma would lose fields of.
+    pub overrides: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Merges `overrides` into `value`. Objects are merged field by field, everything else replaced.
+fn merge_json(value: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (value, overrides) {
+        (serde_json::Value::Object(value), serde_json::Value::Object(overrides)) => {
+            for (key, override_) in overrides {
+                match value.get_mut(&key) {
+                    Some(current) => merge_json(current, override_),
+                    None => {
+                        value.insert(key, override_);
+                    }
+                }
+            }
+        }
+        (value, overrides) => *value = overrides,
+    }
+}
+
+#[cfg(feature = "conduit_bin")]
+impl<'r, 'o, T> Responder<'r, 'o> for ExtendedResponse<T>
+where
+    'o: 'r,
+    T: OutgoingResponse,
+{
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let started = Instant::now();
+
+        let mut http_response = self
+            .response
+            .try_into_http_response::<Vec<u8>>()
+            .map_err(|_| Status::InternalServerError)?;
+
+        if !self.overrides.is_empty() {
+            let mut body = serde_json::from_slice::<serde_json::Value>(http_response.body())
+                .map_err(|_| Status::InternalServerError)?;
+            merge_json(&mut body, serde_json::Value::Object(self.overrides));
+            *http_response.body_mut() =
+                serde_json::to_vec(&body).expect("json value can be serialized");
+        }
+
+        if !self.extra_fields.is_empty() {
+            let extra_fields =
+                serde_json::to_vec(&self.extra_fields).expect("json map can be serialized");
+            let body = http_response.body_mut();
+
+            // Both are json objects: `{"a":1}` and `{"b":2}` become `{"a":1,"b":2}`
+            let end = body
+                .iter()
+                .rposition(|&b| b == b'}')
+                .ok_or(Status::InternalServerError)?;
+            body.truncate(end);
+            if body.iter().rev().find(|b| !b.is_ascii_whitespace()) != Some(&b'{') {
+                body.push(b',');
+            }
+            body.extend_from_slice(&extra_fields[1..]);
+        }
+
+        let response = build_response(http_response);
+
+        req.local_cache(RequestPhases::default)
+            .serialize_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        response
+    }
+}
+
 impl<T> From<T> for RumaResponse<T> {
     fn from(t: T) -> Self {
         Self(t)
@@ -399,7 +732,188 @@ where
     'o: 'r,
     T: OutgoingResponse,
 {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
-        response(self)
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let started = Instant::now();
+        let response = response(self);
+
+        req.local_cache(RequestPhases::default)
+            .serialize_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        response
+    }
+}
+
+#[cfg(all(test, feature = "conduit_bin"))]
+mod tests {
+    use super::{request_room, scope_allows, TokenScope};
+    use ruma::{RoomAliasId, RoomId};
+    use std::convert::TryFrom;
+
+    const SEND_MESSAGE: &str = "/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id";
+    const JOIN: &str = "/_matrix/client/r0/join/:room_id_or_alias";
+
+    fn room(room_id: &str) -> RoomId {
+        RoomId::try_from(room_id).unwrap()
+    }
+
+    fn room_scope() -> TokenScope {
+        TokenScope {
+            read_only: false,
+            rooms: Some(vec![room("!allowed:example.com")]),
+            e2ee: false,
+        }
+    }
+
+    #[test]
+    fn request_room_reads_room_id_from_path() {
+        assert_eq!(
+            request_room(
+                SEND_MESSAGE,
+                "/_matrix/client/r0/rooms/%21allowed%3Aexample.com/send/m.room.message/1?a=b",
+                |_| None,
+            ),
+            Some(Some(room("!allowed:example.com")))
+        );
+    }
+
+    #[test]
+    fn request_room_resolves_aliases() {
+        assert_eq!(
+            request_room(
+                JOIN,
+                "/_matrix/client/r0/join/%23alias%3Aexample.com",
+                |alias| {
+                    assert_eq!(alias, &RoomAliasId::try_from("#alias:example.com").unwrap());
+                    Some(room("!allowed:example.com"))
+                }
+            ),
+            Some(Some(room("!allowed:example.com")))
+        );
+        assert_eq!(
+            request_room(
+                JOIN,
+                "/_matrix/client/r0/join/%23unknown%3Aexample.com",
+                |_| None
+            ),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn request_room_rejects_invalid_rooms() {
+        assert_eq!(
+            request_room(
+                SEND_MESSAGE,
+                "/_matrix/client/r0/rooms/not_a_room/send/m.room.message/1",
+                |_| None
+            ),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn request_room_ignores_endpoints_without_room() {
+        assert_eq!(
+            request_room("/_matrix/client/r0/sync", "/_matrix/client/r0/sync", |_| {
+                None
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn room_scope_allows_only_its_rooms() {
+        let scope = room_scope();
+        let put = http::Method::PUT;
+
+        assert!(scope_allows(&scope, &put, SEND_MESSAGE, || Some(Some(
+            room("!allowed:example.com")
+        ))));
+        assert!(!scope_allows(&scope, &put, SEND_MESSAGE, || Some(Some(
+            room("!other:example.com")
+        ))));
+        assert!(!scope_allows(&scope, &put, SEND_MESSAGE, || Some(None)));
+    }
+
+    #[test]
+    fn room_scope_denies_account_wide_endpoints() {
+        let scope = room_scope();
+
+        for (method, path) in &[
+            (http::Method::POST, "/_matrix/client/r0/pushers/set"),
+            (http::Method::POST, "/_matrix/client/r0/search"),
+            (http::Method::POST, "/_matrix/client/r0/logout/all"),
+            (http::Method::GET, "/_matrix/client/r0/joined_rooms"),
+            (
+                http::Method::GET,
+                "/_matrix/client/r0/user/:user_id/account_data/:type",
+            ),
+            (
+                http::Method::PUT,
+                "/_matrix/client/r0/profile/:user_id/displayname",
+            ),
+        ] {
+            assert!(!scope_allows(&scope, method, path, || None), "{}", path);
+        }
+    }
+
+    #[test]
+    fn room_scope_allows_sync_and_profile_reads() {
+        let scope = room_scope();
+
+        for (method, path) in &[
+            (http::Method::GET, "/_matrix/client/r0/sync"),
+            (http::Method::GET, "/_conduit/client/v1/sync/stream"),
+            (
+                http::Method::POST,
+                "/_matrix/client/r0/user/:user_id/filter",
+            ),
+            (http::Method::GET, "/_matrix/client/r0/profile/:user_id"),
+            (http::Method::GET, "/_matrix/client/r0/account/whoami"),
+        ] {
+            assert!(scope_allows(&scope, method, path, || None), "{}", path);
+        }
+    }
+
+    #[test]
+    fn e2ee_needs_to_be_allowed() {
+        let path = "/_matrix/client/r0/keys/upload";
+        let mut scope = room_scope();
+        assert!(!scope_allows(&scope, &http::Method::POST, path, || None));
+
+        scope.e2ee = true;
+        assert!(scope_allows(&scope, &http::Method::POST, path, || None));
+    }
+
+    #[test]
+    fn read_only_scope_denies_writes() {
+        let scope = TokenScope {
+            read_only: true,
+            ..TokenScope::default()
+        };
+
+        assert!(scope_allows(
+            &scope,
+            &http::Method::GET,
+            "/_matrix/client/r0/joined_rooms",
+            || None
+        ));
+        assert!(!scope_allows(
+            &scope,
+            &http::Method::POST,
+            "/_matrix/client/r0/search",
+            || None
+        ));
+    }
+
+    #[test]
+    fn scoped_tokens_cant_manage_scoped_tokens() {
+        assert!(!scope_allows(
+            &TokenScope::default(),
+            &http::Method::POST,
+            "/_conduit/client/v1/scoped_tokens",
+            || None
+        ));
     }
 }
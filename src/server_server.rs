@@ -1,11 +1,12 @@
 use crate::{
     client_server::{self, claim_keys_helper, get_keys_helper},
     database::{rooms::CompressedStateEvent, DatabaseGuard},
+    ruma_wrapper::ExtendedResponse,
     utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
 };
+use bytes::Bytes;
 use get_profile_information::v1::ProfileField;
 use http::header::{HeaderValue, AUTHORIZATION};
-use regex::Regex;
 use rocket::{
     futures::{prelude::*, stream::FuturesUnordered},
     response::content::Json,
@@ -53,7 +54,7 @@ use ruma::{
     state_res::{self, RoomVersion, StateMap},
     to_device::DeviceIdOrAllDevices,
     uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, ServerName,
-    ServerSigningKeyId,
+    ServerSigningKeyId, UserId,
 };
 use std::{
     collections::{btree_map, hash_map, BTreeMap, BTreeSet, HashMap, HashSet},
@@ -129,6 +130,22 @@ pub(crate) async fn send_request<T: OutgoingRequest>(
     destination: &ServerName,
     request: T,
 ) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    send_request_with_body(globals, destination, request)
+        .await
+        .map(|(response, _)| response)
+}
+
+/// Like `send_request`, but also returns the raw response body, for fields ruma doesn't know
+/// about yet.
+#[tracing::instrument(skip(globals, request))]
+pub(crate) async fn send_request_with_body<T: OutgoingRequest>(
+    globals: &crate::database::globals::Globals,
+    destination: &ServerName,
+    request: T,
+) -> Result<(T::IncomingResponse, Bytes)>
 where
     T: Debug,
 {
@@ -195,7 +212,7 @@ where
 
     ruma::signatures::sign_json(
         globals.server_name().as_str(),
-        globals.keypair(),
+        &*globals.keypair(),
         &mut request_json,
     )
     .expect("our request json is what ruma expects");
@@ -282,7 +299,7 @@ where
             }
 
             let http_response = http_response_builder
-                .body(body)
+                .body(body.clone())
                 .expect("reqwest body is valid http body");
 
             if status == 200 {
@@ -294,7 +311,7 @@ where
                     );
                 }
 
-                response.map_err(|e| {
+                response.map(|response| (response, body)).map_err(|e| {
                     warn!(
                         "Invalid 200 response from {} on: {} {}",
                         &destination, url, e
@@ -542,25 +559,26 @@ pub fn get_server_version_route(
 ///
 /// Gets the public signing keys of this server.
 ///
-/// - Matrix does not support invalidating public keys, so the key returned by this will be valid
-/// forever.
+/// - Keys that were rotated away are returned as old verify keys
+/// - Other servers may cache the current key for a week, or until the next automatic rotation
 // Response type for this endpoint is Json because we need to calculate a signature for the response
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/key/v2/server"))]
 #[tracing::instrument(skip(db))]
-pub fn get_server_keys_route(db: DatabaseGuard) -> Json<String> {
+pub fn get_server_keys_route(db: DatabaseGuard) -> Result<Json<String>> {
     if !db.globals.allow_federation() {
         // TODO: Use proper types
-        return Json("Federation is disabled.".to_owned());
+        return Ok(Json("Federation is disabled.".to_owned()));
     }
 
+    // Sign with the same keypair we publish, even if it is rotated in the meantime
+    let keypair = db.globals.keypair();
+
     let mut verify_keys = BTreeMap::new();
     verify_keys.insert(
-        ServerSigningKeyId::try_from(
-            format!("ed25519:{}", db.globals.keypair().version()).as_str(),
-        )
-        .expect("found invalid server signing keys in DB"),
+        ServerSigningKeyId::try_from(format!("ed25519:{}", keypair.version()).as_str())
+            .expect("found invalid server signing keys in DB"),
         VerifyKey {
-            key: base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD),
+            key: base64::encode_config(keypair.public_key(), base64::STANDARD_NO_PAD),
         },
     );
     let mut response = serde_json::from_slice(
@@ -568,12 +586,9 @@ pub fn get_server_keys_route(db: DatabaseGuard) -> Json<String> {
             server_key: ServerSigningKeys {
                 server_name: db.globals.server_name().to_owned(),
                 verify_keys,
-                old_verify_keys: BTreeMap::new(),
+                old_verify_keys: db.globals.old_verify_keys()?,
                 signatures: BTreeMap::new(),
-                valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-                    SystemTime::now() + Duration::from_secs(86400 * 7),
-                )
-                .expect("time is valid"),
+                valid_until_ts: db.globals.keypair_valid_until()?,
             },
         }
         .try_into_http_response::<Vec<u8>>()
@@ -582,25 +597,22 @@ pub fn get_server_keys_route(db: DatabaseGuard) -> Json<String> {
     )
     .unwrap();
 
-    ruma::signatures::sign_json(
-        db.globals.server_name().as_str(),
-        db.globals.keypair(),
-        &mut response,
-    )
-    .unwrap();
+    ruma::signatures::sign_json(db.globals.server_name().as_str(), &*keypair, &mut response)
+        .unwrap();
 
-    Json(serde_json::to_string(&response).expect("JSON is canonical"))
+    Ok(Json(
+        serde_json::to_string(&response).expect("JSON is canonical"),
+    ))
 }
 
 /// # `GET /_matrix/key/v2/server/{keyId}`
 ///
 /// Gets the public signing keys of this server.
 ///
-/// - Matrix does not support invalidating public keys, so the key returned by this will be valid
-/// forever.
+/// - Keys that were rotated away are returned as old verify keys
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/key/v2/server/<_>"))]
 #[tracing::instrument(skip(db))]
-pub fn get_server_keys_deprecated_route(db: DatabaseGuard) -> Json<String> {
+pub fn get_server_keys_deprecated_route(db: DatabaseGuard) -> Result<Json<String>> {
     get_server_keys_route(db)
 }
 
@@ -626,7 +638,9 @@ pub async fn get_public_rooms_filtered_route(
         body.limit,
         body.since.as_deref(),
         &body.filter,
+        None,
         &body.room_network,
+        false,
     )
     .await?
     .0;
@@ -673,7 +687,9 @@ pub async fn get_public_rooms_route(
         body.limit,
         body.since.as_deref(),
         &IncomingFilter::default(),
+        None,
         &IncomingRoomNetwork::Matrix,
+        false,
     )
     .await?
     .0;
@@ -698,9 +714,150 @@ pub async fn get_public_rooms_route(
     .into())
 }
 
+/// `GET /_matrix/federation/v1/hierarchy/{roomId}` of MSC2946, which ruma doesn't know yet.
+pub mod get_hierarchy {
+    use crate::client_server::get_hierarchy::SpaceHierarchyRoomsChunk;
+    use ruma::{api::ruma_api, RoomId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get a room and its direct children.",
+            method: GET,
+            name: "get_hierarchy",
+            path: "/_matrix/federation/v1/hierarchy/:room_id",
+            rate_limited: false,
+            authentication: ServerSignatures,
+        }
+
+        request: {
+            /// The room to summarise.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+
+            /// Only return children that are marked as suggested.
+            #[ruma_api(query)]
+            #[serde(default)]
+            pub suggested_only: bool,
+        }
+
+        response: {
+            pub room: SpaceHierarchyRoomsChunk,
+
+            pub children: Vec<SpaceHierarchyRoomsChunk>,
+
+            /// Children the requesting server can't see or this server doesn't know.
+            pub inaccessible_children: Vec<RoomId>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/federation/v1/hierarchy/{roomId}`
+///
+/// Summarises a room of this server and its direct children, so other servers can walk spaces.
+///
+/// - Only shows rooms anyone can join, knock on or peek into, or that the requesting server is
+/// already in. Restricted rooms are shown with their allow list, the other server decides which of
+/// its users can see them
+/// - Rooms with `m.federate: false` and rooms whose ACL denies the requesting server are hidden
+/// - Hidden children are listed as inaccessible
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/hierarchy/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_hierarchy_route(
+    db: DatabaseGuard,
+    body: Ruma<get_hierarchy::Request>,
+) -> ConduitResult<get_hierarchy::Response> {
+    if !db.globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    let hierarchy = db
+        .rooms
+        .space_hierarchy(&body.room_id, body.suggested_only, Some(1))?;
+
+    let mut rooms = hierarchy.rooms.iter();
+    let room = match rooms.next() {
+        Some(room) => {
+            let summary = client_server::local_summary(&db, &room.room_id, &room.children_state)?;
+            if visible_over_federation(&db, sender_servername, &room.room_id, &summary)? {
+                summary
+            } else {
+                return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+            }
+        }
+        None => return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found.")),
+    };
+
+    let mut children = Vec::new();
+    let mut inaccessible_children = Vec::new();
+
+    for child in rooms {
+        let summary = client_server::local_summary(&db, &child.room_id, &child.children_state)?;
+        if visible_over_federation(&db, sender_servername, &child.room_id, &summary)? {
+            children.push(summary);
+        } else {
+            inaccessible_children.push(child.room_id.clone());
+        }
+    }
+
+    inaccessible_children.extend(
+        hierarchy
+            .inaccessible
+            .iter()
+            .map(|(room_id, _)| room_id.clone()),
+    );
+
+    Ok(get_hierarchy::Response {
+        room,
+        children,
+        inaccessible_children,
+    }
+    .into())
+}
+
+/// Whether `server` may see the summary of a room in a hierarchy.
+fn visible_over_federation(
+    db: &Database,
+    server: &ServerName,
+    room_id: &RoomId,
+    summary: &client_server::get_hierarchy::SpaceHierarchyRoomsChunk,
+) -> Result<bool> {
+    let federate = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomCreate, "")?
+        .map(|create| {
+            serde_json::from_value::<Raw<CreateEventContent>>(create.content.clone())
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid create event in database."))
+        })
+        .transpose()?
+        .map_or(false, |create| create.federate);
+
+    if !federate || !db.rooms.is_server_allowed_by_acl(server, room_id)? {
+        return Ok(false);
+    }
+
+    Ok(db.rooms.server_in_room(server, room_id)?
+        || summary.chunk.chunk.world_readable
+        || matches!(&*summary.join_rule, "public" | "knock" | "restricted"))
+}
+
 /// # `PUT /_matrix/federation/v1/send/{txnId}`
 ///
 /// Push EDUs and PDUs to this server.
+///
+/// - PDUs of different rooms are handled concurrently, up to max_concurrent_federation_rooms
+/// - PDUs that are still being handled after 30 seconds are left out of the response
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/federation/v1/send/<_>", data = "<body>")
@@ -714,9 +871,11 @@ pub async fn send_transaction_message_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    let db = Arc::new(db);
+
     let mut resolved_map = BTreeMap::new();
 
-    let pub_key_map = RwLock::new(BTreeMap::new());
+    let pub_key_map = Arc::new(RwLock::new(BTreeMap::new()));
 
     // This is all the auth_events that have been recursively fetched so they don't have to be
     // deserialized over and over again.
@@ -726,6 +885,8 @@ pub async fn send_transaction_message_route(
     // events that it references.
     // let mut auth_cache = EventMap::new();
 
+    let mut pdus_by_room = HashMap::<_, Vec<_>>::new();
+
     for pdu in &body.pdus {
         // We do not add the event_id field to the pdu here because of signature and hashes checks
         let (event_id, value) = match crate::pdu::gen_event_id_canonical_json(pdu) {
@@ -749,41 +910,78 @@ pub async fn send_transaction_message_route(
             }
         };
 
-        let mutex = Arc::clone(
-            db.globals
-                .roomid_mutex_federation
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let mutex_lock = mutex.lock().await;
-        let start_time = Instant::now();
-        resolved_map.insert(
-            event_id.clone(),
-            handle_incoming_pdu(
-                &body.origin,
-                &event_id,
-                &room_id,
-                value,
-                true,
-                &db,
-                &pub_key_map,
-            )
-            .await
-            .map(|_| ()),
-        );
-        drop(mutex_lock);
-
-        let elapsed = start_time.elapsed();
-        warn!(
-            "Handling transaction of event {} took {}m{}s",
-            event_id,
-            elapsed.as_secs() / 60,
-            elapsed.as_secs() % 60
-        );
+        pdus_by_room
+            .entry(room_id)
+            .or_default()
+            .push((event_id, value));
     }
 
+    // Each room handles its pdus in order, but different rooms are handled concurrently so one
+    // busy room can't hold up the others
+    let mut room_handlers = pdus_by_room
+        .into_iter()
+        .map(|(room_id, pdus)| {
+            let db = Arc::clone(&db);
+            let pub_key_map = Arc::clone(&pub_key_map);
+            let origin = body.origin.clone();
+
+            tokio::spawn(async move {
+                let mutex = Arc::clone(
+                    db.globals
+                        .roomid_mutex_federation
+                        .write()
+                        .unwrap()
+                        .entry(room_id.clone())
+                        .or_default(),
+                );
+                let mutex_lock = mutex.lock().await;
+                let permit = db.globals.federation_handler_semaphore.acquire().await;
+
+                let mut results = Vec::new();
+                for (event_id, value) in pdus {
+                    let start_time = Instant::now();
+                    let result = handle_incoming_pdu(
+                        &origin,
+                        &event_id,
+                        &room_id,
+                        value,
+                        true,
+                        &db,
+                        &pub_key_map,
+                    )
+                    .await
+                    .map(|_| ());
+
+                    let elapsed = start_time.elapsed();
+                    warn!(
+                        "Handling transaction of event {} took {}m{}s",
+                        event_id,
+                        elapsed.as_secs() / 60,
+                        elapsed.as_secs() % 60
+                    );
+
+                    results.push((event_id, result));
+                }
+
+                drop(permit);
+                drop(mutex_lock);
+
+                results
+            })
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    // Rooms that take too long continue in the background, their pdus are left out of the response
+    let _ = tokio::time::timeout(Duration::from_secs(30), async {
+        while let Some(results) = room_handlers.next().await {
+            match results {
+                Ok(results) => resolved_map.extend(results),
+                Err(e) => error!("Handling incoming pdus panicked: {}", e),
+            }
+        }
+    })
+    .await;
+
     for pdu in &resolved_map {
         if let Err(e) = pdu.1 {
             if e != "Room is unknown to this server." {
@@ -792,6 +990,33 @@ pub async fn send_transaction_message_route(
         }
     }
 
+    // Ruma doesn't know signing key updates yet. The cached keys of the user still contain the old
+    // cross-signing keys, so forget them and let our users query the keys again.
+    for edu in body
+        .edus
+        .iter()
+        .filter_map(|edu| serde_json::from_str::<serde_json::Value>(edu.json().get()).ok())
+        .filter(|edu| edu.get("edu_type").and_then(|t| t.as_str()) == Some("m.signing_key_update"))
+    {
+        let user_id = match edu
+            .get("content")
+            .and_then(|content| content.get("user_id"))
+            .and_then(|user_id| user_id.as_str())
+            .and_then(|user_id| UserId::try_from(user_id).ok())
+        {
+            Some(user_id) if user_id.server_name() == &*body.origin => user_id,
+            _ => continue,
+        };
+
+        db.globals
+            .remote_keys_cache
+            .lock()
+            .unwrap()
+            .remove(&user_id);
+        db.users
+            .mark_device_key_update(&user_id, &db.rooms, &db.globals)?;
+    }
+
     for edu in body
         .edus
         .iter()
@@ -850,9 +1075,46 @@ pub async fn send_transaction_message_route(
                         .typing_remove(&typing.user_id, &typing.room_id, &db.globals)?;
                 }
             }
-            Edu::DeviceListUpdate(DeviceListUpdateContent { user_id, .. }) => {
-                db.users
-                    .mark_device_key_update(&user_id, &db.rooms, &db.globals)?;
+            Edu::DeviceListUpdate(DeviceListUpdateContent {
+                user_id,
+                device_id,
+                stream_id,
+                prev_id,
+                deleted,
+                keys,
+                ..
+            }) => {
+                if user_id.server_name() != &*body.origin {
+                    continue;
+                }
+
+                let stream_id = u64::from(stream_id);
+
+                // If we missed one of the previous updates, our view of the user's devices is
+                // outdated and we need to ask for the full list
+                let gap = match db.users.remote_devicelist_id(&user_id)? {
+                    Some(known) if stream_id <= known => continue,
+                    Some(known) => prev_id.iter().any(|id| u64::from(*id) > known),
+                    None => true,
+                };
+
+                if gap {
+                    // Fetching the full list can take a while, the transaction doesn't wait for it
+                    spawn_device_list_resync(&db, user_id);
+                    continue;
+                }
+
+                if db.users.update_remote_device(
+                    &user_id,
+                    &device_id,
+                    keys.as_ref()
+                        .filter(|_| !deleted.unwrap_or(false))
+                        .map(|keys| keys.json().get().as_bytes()),
+                    stream_id,
+                )? {
+                    db.users
+                        .mark_device_key_update(&user_id, &db.rooms, &db.globals)?;
+                }
             }
             Edu::DirectToDevice(DirectDeviceContent {
                 sender,
@@ -1145,6 +1407,10 @@ fn handle_outlier_pdu<'a>(
 ) -> AsyncRecursiveType<'a, StdResult<(Arc<PduEvent>, BTreeMap<String, CanonicalJsonValue>), String>>
 {
     Box::pin(async move {
+        if let Some(reason) = known_invalid(db, origin, event_id) {
+            return Err(format!("Event failed validation before: {}", reason));
+        }
+
         // TODO: For RoomVersion6 we must check that Raw<..> is canonical do we anywhere?: https://matrix.org/docs/spec/rooms/v6#canonical-json
 
         // We go through all the signatures we see on the value and fetch the corresponding signing
@@ -1175,6 +1441,8 @@ fn handle_outlier_pdu<'a>(
         ) {
             Err(e) => {
                 // Drop
+                // Not remembered: the signing key might just be unreachable right now, and in
+                // newer room versions a copy with stripped signatures has the same event id
                 warn!("Dropping bad event {}: {}", event_id, e);
                 return Err("Signature verification failed".to_string());
             }
@@ -1183,7 +1451,9 @@ fn handle_outlier_pdu<'a>(
                 warn!("Calculated hash does not match: {}", event_id);
                 match ruma::signatures::redact(&value, room_version_id) {
                     Ok(obj) => obj,
-                    Err(_) => return Err("Redaction failed".to_string()),
+                    Err(_) => {
+                        return Err("Redaction failed".to_string());
+                    }
                 }
             }
             Ok(ruma::signatures::Verified::All) => value,
@@ -1200,6 +1470,14 @@ fn handle_outlier_pdu<'a>(
         )
         .map_err(|_| "Event is not a valid PDU.".to_string())?;
 
+        if db
+            .rooms
+            .is_event_rejected(&incoming_pdu.event_id)
+            .map_err(|e| e.to_string())?
+        {
+            return Err("Event has been rejected".to_owned());
+        }
+
         // 4. fetch any missing auth events doing all checks listed here starting at 1. These are not timeline events
         // 5. Reject "due to auth events" if can't get all the auth events or some of the auth events are also rejected "due to auth events"
         // EDIT: Step 5 is not applied anymore because it failed too often
@@ -1228,7 +1506,11 @@ fn handle_outlier_pdu<'a>(
         // Build map of auth events
         let mut auth_events = HashMap::new();
         for id in &incoming_pdu.auth_events {
-            let auth_event = match db.rooms.get_pdu(id).map_err(|e| e.to_string())? {
+            let auth_event = match db
+                .rooms
+                .get_pdu_including_rejected(id)
+                .map_err(|e| e.to_string())?
+            {
                 Some(e) => e,
                 None => {
                     warn!("Could not find auth event {}", id);
@@ -1236,6 +1518,12 @@ fn handle_outlier_pdu<'a>(
                 }
             };
 
+            // Rejected events are kept around, but they can't authorize anything
+            if db.rooms.is_event_rejected(id).map_err(|e| e.to_string())? {
+                warn!("Auth event {} was rejected", id);
+                continue;
+            }
+
             match auth_events.entry((
                 auth_event.kind.clone(),
                 auth_event
@@ -1247,10 +1535,12 @@ fn handle_outlier_pdu<'a>(
                     v.insert(auth_event.clone());
                 }
                 hash_map::Entry::Occupied(_) => {
-                    return Err(
-                        "Auth event's type and state_key combination exists multiple times."
-                            .to_owned(),
-                    )
+                    return Err(reject_pdu(
+                        db,
+                        &incoming_pdu.event_id,
+                        &val,
+                        "Auth event's type and state_key combination exists multiple times.",
+                    ));
                 }
             }
         }
@@ -1261,7 +1551,12 @@ fn handle_outlier_pdu<'a>(
             .map(|a| a.as_ref())
             != Some(&create_event)
         {
-            return Err("Incoming event refers to wrong create event.".to_owned());
+            return Err(reject_pdu(
+                db,
+                &incoming_pdu.event_id,
+                &val,
+                "Incoming event refers to wrong create event.",
+            ));
         }
 
         // If the previous event was the create event special rules apply
@@ -1287,7 +1582,12 @@ fn handle_outlier_pdu<'a>(
         )
         .map_err(|_e| "Auth check failed".to_string())?
         {
-            return Err("Event has failed auth check with auth events.".to_string());
+            return Err(reject_pdu(
+                db,
+                &incoming_pdu.event_id,
+                &val,
+                "Event has failed auth check with auth events.",
+            ));
         }
 
         debug!("Validation successful.");
@@ -1302,6 +1602,22 @@ fn handle_outlier_pdu<'a>(
     })
 }
 
+/// Remembers that the event was rejected and returns the reason as the error of the pdu.
+fn reject_pdu(
+    db: &Database,
+    event_id: &EventId,
+    value: &CanonicalJsonObject,
+    reason: &str,
+) -> String {
+    warn!("Rejecting event {}: {}", event_id, reason);
+
+    if let Err(e) = db.rooms.reject_pdu(event_id, value, reason) {
+        error!("Failed to persist rejected event {}: {}", event_id, e);
+    }
+
+    reason.to_owned()
+}
+
 #[tracing::instrument(skip(incoming_pdu, val, create_event, origin, db, room_id, pub_key_map))]
 async fn upgrade_outlier_to_timeline_pdu(
     incoming_pdu: Arc<PduEvent>,
@@ -1324,6 +1640,14 @@ async fn upgrade_outlier_to_timeline_pdu(
         return Err("Event has been soft failed".into());
     }
 
+    if db
+        .rooms
+        .is_event_rejected(&incoming_pdu.event_id)
+        .map_err(|_| "Failed to ask db for rejection".to_owned())?
+    {
+        return Err("Event has been rejected".into());
+    }
+
     let create_event_content =
         serde_json::from_value::<Raw<CreateEventContent>>(create_event.content.clone())
             .expect("Raw::from_value always works.")
@@ -1585,7 +1909,12 @@ async fn upgrade_outlier_to_timeline_pdu(
     .map_err(|_e| "Auth check failed.".to_owned())?;
 
     if !check_result {
-        return Err("Event has failed auth check with state at the event.".into());
+        return Err(reject_pdu(
+            db,
+            &incoming_pdu.event_id,
+            &val,
+            "Event has failed auth check with state at the event.",
+        ));
     }
     debug!("Auth check succeeded.");
 
@@ -1676,7 +2005,10 @@ async fn upgrade_outlier_to_timeline_pdu(
         // Soft fail, we keep the event as an outlier but don't add it to the timeline
         warn!("Event was soft failed: {:?}", incoming_pdu);
         db.rooms
-            .mark_event_soft_failed(&incoming_pdu.event_id)
+            .mark_event_soft_failed(
+                &incoming_pdu.event_id,
+                "Event has failed auth check with the current state of the room.",
+            )
             .map_err(|_| "Failed to set soft failed flag".to_owned())?;
         return Err("Event has been soft failed".into());
     }
@@ -1861,6 +2193,13 @@ async fn upgrade_outlier_to_timeline_pdu(
 
     debug!("Appended incoming pdu.");
 
+    if let Err(e) = db
+        .rooms
+        .merge_forward_extremities(&room_id, &db, &state_lock)
+    {
+        debug!("Could not merge forward extremities of {}: {}", room_id, e);
+    }
+
     // Event has passed all auth/stateres checks
     drop(state_lock);
     Ok(pdu_id)
@@ -1917,6 +2256,27 @@ pub(crate) fn fetch_and_handle_outliers<'a>(
                     (pdu, None)
                 }
                 Ok(None) => {
+                    // get_pdu hides rejected events, fetching them again wouldn't change that
+                    match db.rooms.is_event_rejected(&id) {
+                        Ok(true) => {
+                            info!("Not fetching {} again, it was rejected", id);
+                            continue;
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            warn!("Error loading {}: {}", id, e);
+                            continue;
+                        }
+                    }
+
+                    if let Some(reason) = known_invalid(db, origin, &id) {
+                        info!(
+                            "Not fetching {} again, it failed validation: {}",
+                            id, reason
+                        );
+                        continue;
+                    }
+
                     // c. Ask origin server over federation
                     warn!("Fetching {} over federation.", id);
                     match db
@@ -1934,6 +2294,12 @@ pub(crate) fn fetch_and_handle_outliers<'a>(
                                 match crate::pdu::gen_event_id_canonical_json(&res.pdu) {
                                     Ok(t) => t,
                                     Err(_) => {
+                                        mark_invalid(
+                                            db,
+                                            origin,
+                                            &id,
+                                            "Event is not valid canonical json",
+                                        );
                                         back_off((**id).clone());
                                         continue;
                                     }
@@ -1982,6 +2348,31 @@ pub(crate) fn fetch_and_handle_outliers<'a>(
     })
 }
 
+/// Returns why the copy of the event from `origin` failed validation, if it did within
+/// `invalid_event_ttl_secs`.
+fn known_invalid(db: &Database, origin: &ServerName, event_id: &EventId) -> Option<String> {
+    let ttl = db.globals.invalid_event_ttl()?;
+
+    db.rooms
+        .invalidity_reason(origin, event_id, ttl)
+        .map_err(|e| warn!("Failed to look up why {} is invalid: {}", event_id, e))
+        .ok()
+        .flatten()
+}
+
+/// Remembers that the copy of the event from `origin` failed validation, unless
+/// `invalid_event_ttl_secs` is 0. Failures that can be temporary, like unreachable signing keys,
+/// must not be remembered.
+fn mark_invalid(db: &Database, origin: &ServerName, event_id: &EventId, reason: &str) {
+    if db.globals.invalid_event_ttl().is_none() {
+        return;
+    }
+
+    if let Err(e) = db.rooms.mark_event_invalid(origin, event_id, reason) {
+        warn!("Failed to remember that {} is invalid: {}", event_id, e);
+    }
+}
+
 /// Search the DB for the signing keys of the given server, if we don't have them
 /// fetch them from the server and save to our DB.
 #[tracing::instrument(skip(db, origin, signature_ids))]
@@ -2180,55 +2571,8 @@ fn append_incoming_pdu(
     )?;
 
     for appservice in db.appservice.all()? {
-        if db.rooms.appservice_in_room(&pdu.room_id, &appservice, db)? {
+        if db.rooms.appservice_should_receive(pdu, &appservice, db)? {
             db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
-            continue;
-        }
-
-        if let Some(namespaces) = appservice.1.get("namespaces") {
-            let users = namespaces
-                .get("users")
-                .and_then(|users| users.as_sequence())
-                .map_or_else(Vec::new, |users| {
-                    users
-                        .iter()
-                        .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
-            let aliases = namespaces
-                .get("aliases")
-                .and_then(|aliases| aliases.as_sequence())
-                .map_or_else(Vec::new, |aliases| {
-                    aliases
-                        .iter()
-                        .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
-            let rooms = namespaces
-                .get("rooms")
-                .and_then(|rooms| rooms.as_sequence());
-
-            let matching_users = |users: &Regex| {
-                users.is_match(pdu.sender.as_str())
-                    || pdu.kind == EventType::RoomMember
-                        && pdu
-                            .state_key
-                            .as_ref()
-                            .map_or(false, |state_key| users.is_match(&state_key))
-            };
-            let matching_aliases = |aliases: &Regex| {
-                db.rooms
-                    .room_aliases(&pdu.room_id)
-                    .filter_map(|r| r.ok())
-                    .any(|room_alias| aliases.is_match(room_alias.as_str()))
-            };
-
-            if aliases.iter().any(matching_aliases)
-                || rooms.map_or(false, |rooms| rooms.contains(&pdu.room_id.as_str().into()))
-                || users.iter().any(matching_users)
-            {
-                db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
-            }
         }
     }
 
@@ -2326,7 +2670,7 @@ fn get_auth_chain_inner(
     let mut found = HashSet::new();
 
     while let Some(event_id) = todo.pop() {
-        match db.rooms.get_pdu(&event_id) {
+        match db.rooms.get_pdu_including_rejected(&event_id) {
             Ok(Some(pdu)) => {
                 if &pdu.room_id != room_id {
                     return Err(Error::BadRequest(ErrorKind::Forbidden, "Evil event in db"));
@@ -2665,12 +3009,11 @@ pub fn create_join_event_template_route(
         ));
     }
 
-    let prev_events = db
+    client_server::check_restricted_join(&db, &body.user_id, &body.room_id)?;
+
+    let (prev_events, _) = db
         .rooms
-        .get_pdu_leaves(&body.room_id)?
-        .into_iter()
-        .take(20)
-        .collect::<Vec<_>>();
+        .select_prev_events(&body.room_id, db.globals.max_forward_extremities() as usize)?;
 
     let create_event = db
         .rooms
@@ -2712,7 +3055,7 @@ pub fn create_join_event_template_route(
         ));
     }
 
-    let content = serde_json::to_value(MemberEventContent {
+    let mut content = serde_json::to_value(MemberEventContent {
         avatar_url: None,
         blurhash: None,
         displayname: None,
@@ -2723,6 +3066,12 @@ pub fn create_join_event_template_route(
     })
     .expect("member event is valid value");
 
+    if let Some(authoriser) =
+        client_server::restricted_join_authoriser(&db, &body.user_id, &body.room_id)?
+    {
+        content["join_authorised_via_users_server"] = authoriser.as_str().into();
+    }
+
     let state_key = body.user_id.to_string();
     let kind = EventType::RoomMember;
 
@@ -2821,7 +3170,7 @@ async fn create_join_event(
     db: &DatabaseGuard,
     room_id: &RoomId,
     pdu: &Raw<ruma::events::pdu::Pdu>,
-) -> Result<RoomState> {
+) -> Result<(RoomState, Option<serde_json::Value>)> {
     if !db.globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
@@ -2839,7 +3188,7 @@ async fn create_join_event(
     // let mut auth_cache = EventMap::new();
 
     // We do not add the event_id field to the pdu here because of signature and hashes checks
-    let (event_id, value) = match crate::pdu::gen_event_id_canonical_json(&pdu) {
+    let (event_id, mut value) = match crate::pdu::gen_event_id_canonical_json(&pdu) {
         Ok(t) => t,
         Err(_) => {
             // Event could not be converted to canonical json
@@ -2859,6 +3208,18 @@ async fn create_join_event(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid."))?;
 
+    // The joining server needs our signature to send the join anywhere else
+    let signed_event = if sign_restricted_join(db, room_id, &mut value)? {
+        Some(
+            serde_json::to_value(PduEvent::convert_to_outgoing_federation_event(
+                value.clone(),
+            ))
+            .expect("CanonicalJson is valid json value"),
+        )
+    } else {
+        None
+    };
+
     let mutex = Arc::clone(
         db.globals
             .roomid_mutex_federation
@@ -2901,22 +3262,94 @@ async fn create_join_event(
 
     db.flush()?;
 
-    Ok(RoomState {
-        auth_chain: auth_chain_ids
-            .filter_map(|id| db.rooms.get_pdu_json(&id).ok().flatten())
-            .map(PduEvent::convert_to_outgoing_federation_event)
-            .collect(),
-        state: state_ids
-            .iter()
-            .filter_map(|(_, id)| db.rooms.get_pdu_json(&id).ok().flatten())
-            .map(PduEvent::convert_to_outgoing_federation_event)
-            .collect(),
-    })
+    Ok((
+        RoomState {
+            auth_chain: auth_chain_ids
+                .filter_map(|id| db.rooms.get_pdu_json(&id).ok().flatten())
+                .map(PduEvent::convert_to_outgoing_federation_event)
+                .collect(),
+            state: state_ids
+                .iter()
+                .filter_map(|(_, id)| db.rooms.get_pdu_json(&id).ok().flatten())
+                .map(PduEvent::convert_to_outgoing_federation_event)
+                .collect(),
+        },
+        signed_event,
+    ))
+}
+
+/// Adds our signature to a join to a restricted room that one of our users authorised, after
+/// making sure the user may join. Other joins are left alone.
+///
+/// Returns whether the event was signed.
+fn sign_restricted_join(
+    db: &Database,
+    room_id: &RoomId,
+    value: &mut CanonicalJsonObject,
+) -> Result<bool> {
+    let authoriser = match value.get("content") {
+        Some(CanonicalJsonValue::Object(content)) => {
+            match content.get("join_authorised_via_users_server") {
+                Some(CanonicalJsonValue::String(authoriser)) => {
+                    UserId::try_from(authoriser.as_str()).map_err(|_| {
+                        Error::BadRequest(
+                            ErrorKind::InvalidParam,
+                            "join_authorised_via_users_server is not a valid user id.",
+                        )
+                    })?
+                }
+                _ => return Ok(false),
+            }
+        }
+        _ => return Ok(false),
+    };
+
+    if authoriser.server_name() != db.globals.server_name() {
+        return Ok(false);
+    }
+
+    let sender = match value.get("sender") {
+        Some(CanonicalJsonValue::String(sender)) => UserId::try_from(sender.as_str())
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid sender."))?,
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Event needs a sender.",
+            ))
+        }
+    };
+
+    if !db.rooms.is_joined(&authoriser, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The authorising user is not in the room.",
+        ));
+    }
+
+    client_server::check_restricted_join(db, &sender, room_id)?;
+
+    let room_version_id = db
+        .rooms
+        .room_version(room_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Room is unknown."))?;
+
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        &*db.globals.keypair(),
+        value,
+        &room_version_id,
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Failed to sign the join event."))?;
+
+    Ok(true)
 }
 
 /// # `PUT /_matrix/federation/v1/send_join/{roomId}/{eventId}`
 ///
 /// Submits a signed join event.
+///
+/// Restricted joins are only signed by us here, the signed event is just returned by the v2
+/// endpoint.
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/federation/v1/send_join/<_>/<_>", data = "<body>")
@@ -2926,7 +3359,7 @@ pub async fn create_join_event_v1_route(
     db: DatabaseGuard,
     body: Ruma<create_join_event::v1::Request<'_>>,
 ) -> ConduitResult<create_join_event::v1::Response> {
-    let room_state = create_join_event(&db, &body.room_id, &body.pdu).await?;
+    let (room_state, _) = create_join_event(&db, &body.room_id, &body.pdu).await?;
 
     Ok(create_join_event::v1::Response { room_state }.into())
 }
@@ -2934,6 +3367,8 @@ pub async fn create_join_event_v1_route(
 /// # `PUT /_matrix/federation/v2/send_join/{roomId}/{eventId}`
 ///
 /// Submits a signed join event.
+///
+/// - If we signed a restricted join, the signed event is returned as `event`
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/federation/v2/send_join/<_>/<_>", data = "<body>")
@@ -2942,10 +3377,19 @@ pub async fn create_join_event_v1_route(
 pub async fn create_join_event_v2_route(
     db: DatabaseGuard,
     body: Ruma<create_join_event::v2::Request<'_>>,
-) -> ConduitResult<create_join_event::v2::Response> {
-    let room_state = create_join_event(&db, &body.room_id, &body.pdu).await?;
+) -> Result<ExtendedResponse<create_join_event::v2::Response>> {
+    let (room_state, signed_event) = create_join_event(&db, &body.room_id, &body.pdu).await?;
+
+    let mut extra_fields = serde_json::Map::new();
+    if let Some(signed_event) = signed_event {
+        extra_fields.insert("event".to_owned(), signed_event);
+    }
 
-    Ok(create_join_event::v2::Response { room_state }.into())
+    Ok(ExtendedResponse {
+        response: create_join_event::v2::Response { room_state },
+        extra_fields,
+        overrides: serde_json::Map::new(),
+    })
 }
 
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
@@ -2964,8 +3408,7 @@ pub async fn create_invite_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    if body.room_version != RoomVersionId::Version5 && body.room_version != RoomVersionId::Version6
-    {
+    if !db.globals.supports_room_version(&body.room_version) {
         return Err(Error::BadRequest(
             ErrorKind::IncompatibleRoomVersion {
                 room_version: body.room_version.clone(),
@@ -2979,7 +3422,7 @@ pub async fn create_invite_route(
 
     ruma::signatures::hash_and_sign_event(
         db.globals.server_name().as_str(),
-        db.globals.keypair(),
+        &*db.globals.keypair(),
         &mut signed_event,
         &body.room_version,
     )
@@ -3023,6 +3466,19 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "state_key is not a user id."))?;
 
+    check_invite_origin(
+        &db,
+        body.sender_servername
+            .as_ref()
+            .expect("server is authenticated"),
+        &body.room_id,
+        &sender,
+        &invited_user,
+    )?;
+
+    client_server::check_invite_limits(&db, &invited_user, &body.room_id)?;
+    client_server::check_invite_policy(&db, &sender, &invited_user)?;
+
     let mut invite_state = body.invite_room_state.clone();
 
     let mut event = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(
@@ -3060,6 +3516,59 @@ pub async fn create_invite_route(
     .into())
 }
 
+/// Makes sure the server that sent an invite is allowed to invite a local user into the room.
+///
+/// - The invite must be for a local user and the sender must belong to the sending server
+/// - The sending server must not be denied by the room's server ACL
+/// - If we know the room, the sending server has to be in it or be the server that created the
+///   room (we can't check this for rooms we are not in)
+fn check_invite_origin(
+    db: &Database,
+    sender_servername: &ServerName,
+    room_id: &RoomId,
+    sender: &UserId,
+    invited_user: &UserId,
+) -> Result<()> {
+    if invited_user.server_name() != db.globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to this server.",
+        ));
+    }
+
+    if sender.server_name() != sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Sender does not belong to the sending server.",
+        ));
+    }
+
+    if !db.rooms.exists(room_id)? {
+        return Ok(());
+    }
+
+    if !db
+        .rooms
+        .is_server_allowed_by_acl(sender_servername, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server was denied by the room's server ACL.",
+        ));
+    }
+
+    if room_id.server_name() != sender_servername
+        && !db.rooms.server_in_room(sender_servername, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not in the room.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// # `GET /_matrix/federation/v1/user/devices/{userId}`
 ///
 /// Gets information on all devices of the user.
@@ -3103,6 +3612,77 @@ pub fn get_devices_route(
     .into())
 }
 
+/// Resyncs the device list of a remote user in the background, unless that's already happening.
+/// Local users see the user in `device_lists.changed` if the devices changed.
+fn spawn_device_list_resync(db: &Arc<DatabaseGuard>, user_id: UserId) {
+    if !db
+        .globals
+        .devicelist_resyncs
+        .lock()
+        .unwrap()
+        .insert(user_id.clone())
+    {
+        return;
+    }
+
+    let db = Arc::clone(db);
+    tokio::spawn(async move {
+        let changed = match resync_remote_device_list(&user_id, &db).await {
+            Ok(changed) => changed,
+            Err(e) => {
+                // Clients can still ask the other server for the devices themselves
+                warn!("Failed to resync device list of {}: {}", user_id, e);
+                true
+            }
+        };
+
+        if changed {
+            if let Err(e) = db
+                .users
+                .mark_device_key_update(&user_id, &db.rooms, &db.globals)
+                .and_then(|()| db.flush())
+            {
+                warn!("Failed to mark device list update of {}: {}", user_id, e);
+            }
+        }
+
+        db.globals
+            .devicelist_resyncs
+            .lock()
+            .unwrap()
+            .remove(&user_id);
+    });
+}
+
+/// Fetches the full device list of a remote user and replaces what we know about their devices.
+/// Returns true if the devices changed.
+#[tracing::instrument(skip(db))]
+pub(crate) async fn resync_remote_device_list(user_id: &UserId, db: &Database) -> Result<bool> {
+    let response = db
+        .sending
+        .send_federation_request(
+            &db.globals,
+            user_id.server_name(),
+            get_devices::v1::Request { user_id },
+        )
+        .await?;
+
+    if &response.user_id != user_id {
+        return Err(Error::BadServerResponse(
+            "Server returned devices of another user.",
+        ));
+    }
+
+    db.users.replace_remote_devices(
+        user_id,
+        response
+            .devices
+            .iter()
+            .map(|device| (&*device.device_id, &device.keys)),
+        response.stream_id.into(),
+    )
+}
+
 /// # `GET /_matrix/federation/v1/query/directory`
 ///
 /// Resolve a room alias to a room id.
@@ -3222,19 +3802,22 @@ pub async fn get_keys_route(
 pub async fn claim_keys_route(
     db: DatabaseGuard,
     body: Ruma<claim_keys::v1::Request>,
-) -> ConduitResult<claim_keys::v1::Response> {
+) -> Result<ExtendedResponse<claim_keys::v1::Response>> {
     if !db.globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    let result = claim_keys_helper(&body.one_time_keys, &db).await?;
+    let (result, overrides) = claim_keys_helper(&body.one_time_keys, &db).await?;
 
     db.flush()?;
 
-    Ok(claim_keys::v1::Response {
-        one_time_keys: result.one_time_keys,
-    }
-    .into())
+    Ok(ExtendedResponse {
+        response: claim_keys::v1::Response {
+            one_time_keys: result.one_time_keys,
+        },
+        extra_fields: serde_json::Map::new(),
+        overrides,
+    })
 }
 
 #[tracing::instrument(skip(event, pub_key_map, db))]
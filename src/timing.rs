@@ -0,0 +1,125 @@
+use crate::{
+    database::{globals::RequestDurations, Config},
+    ruma_wrapper::{RequestPhases, RequestTimes},
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    route::{Handler, Outcome},
+    Data, Request, Response, Route,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// When the request arrived, kept in the request-local cache.
+struct RequestStart(Instant);
+
+/// Wraps the handlers of the routes, so the time they spend in the database and waiting for news
+/// ends up in the `RequestPhases` of the request.
+pub fn timed_routes(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(TimedHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct TimedHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for TimedHandler {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let (outcome, db, waited) = RequestTimes::scope(self.0.handle(req, data)).await;
+
+        req.local_cache(RequestPhases::default)
+            .set_task_times(db, waited);
+
+        outcome
+    }
+}
+
+/// Records how long each request took in a histogram per route and logs slow requests.
+pub struct RequestTimer {
+    durations: Arc<RequestDurations>,
+    slow_threshold: Option<Duration>,
+}
+
+impl RequestTimer {
+    pub fn new(config: &Config, durations: Arc<RequestDurations>) -> Self {
+        Self {
+            durations,
+            slow_threshold: Some(config.slow_request_threshold_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request timer",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let phases = req.local_cache(RequestPhases::default);
+
+        // Long-polling requests like /sync wait for news on purpose, that time says nothing about
+        // the load
+        let total = req
+            .local_cache(|| RequestStart(Instant::now()))
+            .0
+            .elapsed()
+            .checked_sub(phases.waited())
+            .unwrap_or_default();
+
+        // Group by the route instead of the path, so room ids etc. don't create a histogram each
+        let route = match req.route() {
+            Some(route) => format!("{} {}", route.method, route.uri),
+            None => format!("{} <unmatched>", req.method()),
+        };
+        self.durations.record(&route, total);
+
+        let slow_threshold = match self.slow_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        if total < slow_threshold {
+            return;
+        }
+
+        let parse = phases.parse();
+        let serialize = phases.serialize();
+        let user = phases
+            .user()
+            .map_or_else(|| "-".to_owned(), |user| user.to_string());
+
+        warn!(
+            "Slow request: {} took {}ms (user: {}, status: {}, parse: {}ms, handler: {}ms, serialize: {}ms, database: {}ms)",
+            route,
+            total.as_millis(),
+            user,
+            res.status().code,
+            parse.as_millis(),
+            total
+                .checked_sub(parse + serialize)
+                .unwrap_or_default()
+                .as_millis(),
+            serialize.as_millis(),
+            phases.db().as_millis(),
+        );
+    }
+}
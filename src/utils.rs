@@ -5,6 +5,7 @@ use ruma::serde::{try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
 use std::{
     cmp,
     convert::TryInto,
+    net::IpAddr,
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -17,6 +18,19 @@ pub fn millis_since_unix_epoch() -> u64 {
         .as_millis() as u64
 }
 
+/// Uses the address from the X-Real-IP header if the connection comes from a trusted proxy and
+/// the address of the peer otherwise.
+pub fn client_ip(
+    remote: Option<IpAddr>,
+    real_ip: Option<IpAddr>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    match remote {
+        Some(remote) if trusted_proxies.contains(&remote) => real_ip.or(Some(remote)),
+        remote => remote,
+    }
+}
+
 pub fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
     let number = match old.map(|bytes| bytes.try_into()) {
         Some(Ok(bytes)) => {
@@ -61,11 +75,25 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
+/// The argon2 parameters for new password hashes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PasswordHashParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
 /// Calculate a new hash for the given password
 #[tracing::instrument(skip(password))]
-pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {
+pub fn calculate_hash(
+    password: &str,
+    params: &PasswordHashParams,
+) -> Result<String, argon2::Error> {
     let hashing_config = Config {
         variant: Variant::Argon2id,
+        mem_cost: params.memory_kib,
+        time_cost: params.iterations,
+        lanes: params.parallelism,
         ..Default::default()
     };
 
@@ -73,6 +101,40 @@ pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {
     argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &hashing_config)
 }
 
+/// Checks a password against an argon2 hash or a bcrypt hash imported from Synapse.
+#[tracing::instrument(skip(hash, password))]
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    if is_bcrypt_hash(hash) {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+    }
+}
+
+/// Returns true if the hash was not calculated with the current argon2 parameters.
+#[tracing::instrument(skip(hash))]
+pub fn password_needs_rehash(hash: &str, params: &PasswordHashParams) -> bool {
+    if is_bcrypt_hash(hash) {
+        return true;
+    }
+
+    // Encoded argon2 hashes look like $argon2id$v=19$m=4096,t=3,p=1$salt$hash
+    let mut parts = hash.split('$').skip(1);
+    if parts.next() != Some("argon2id") {
+        return true;
+    }
+
+    let expected = format!(
+        "m={},t={},p={}",
+        params.memory_kib, params.iterations, params.parallelism
+    );
+    parts.nth(1) != Some(expected.as_str())
+}
+
+fn is_bcrypt_hash(hash: &str) -> bool {
+    hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")
+}
+
 #[tracing::instrument(skip(iterators, check_order))]
 pub fn common_elements(
     mut iterators: impl Iterator<Item = impl Iterator<Item = Vec<u8>>>,